@@ -0,0 +1,236 @@
+// read_scaling.rs
+// Copyright (C) 2017 authors and contributors (see AUTHORS file)
+//
+// This file is released under the MIT License.
+
+// Measures how reads scale with the number of threads serving connections.
+//
+// Run with `cargo bench --bench read_scaling`.
+
+// ===========================================================================
+// Externs
+// ===========================================================================
+
+
+// Stdlib externs
+
+// Third-party externs
+extern crate bytes;
+extern crate futures;
+extern crate rmpv;
+extern crate tempdir;
+extern crate tokio_io;
+
+// Local externs
+extern crate safesec;
+
+
+// ===========================================================================
+// Imports
+// ===========================================================================
+
+
+// Stdlib imports
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::TcpStream;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+// Third-party imports
+
+use bytes::BytesMut;
+use futures::{Future, Sink};
+use futures::sync::mpsc;
+use rmpv::Value;
+use tempdir::TempDir;
+use tokio_io::codec::{Decoder, Encoder};
+
+// Local imports
+
+use safesec::{Config, Storage, serve};
+use safesec::network::codec::MsgPackCodec;
+use safesec::network::rpc::{Message, RpcResponse};
+use safesec::network::server::ServerMessage;
+use safesec::protocol::message::{AuthError, AuthMessage, SessionType};
+use safesec::service::state::SessionInfo;
+use safesec::service::state::auth::{AuthRequest, AuthResponse};
+use safesec::storage::Quota;
+use safesec::storage::lmdb::Init;
+
+
+// ===========================================================================
+// Settings
+// ===========================================================================
+
+
+// Number of threads serving connections in each run
+const THREADS: [usize; 3] = [1, 2, 4];
+
+
+// Number of clients reading at the same time
+const CLIENTS: usize = 8;
+
+
+// Number of reads made by each client
+const READS: usize = 2000;
+
+
+// Port of the first server, each run uses the next one
+const PORT: u16 = 12360;
+
+
+// ===========================================================================
+// Helpers
+// ===========================================================================
+
+
+// Start a server on its own thread, returning its control channel
+fn start_server(tmpdir: &TempDir, port: u16, threads: usize)
+    -> (mpsc::Sender<ServerMessage>, JoinHandle<()>)
+{
+    let dbdir = tmpdir.path().join("sec.db");
+    fs::create_dir(&dbdir).unwrap();
+    let config = Config {
+        name: "safesec".to_string(),
+        dbdir: dbdir,
+        bindaddr: format!("127.0.0.1:{}", port).parse().unwrap(),
+        storage: Storage::Lmdb,
+        sweep_interval: 0,
+        quota: Quota::new(),
+        primary: None,
+        workers: threads,
+        threads: threads,
+        cache: 0,
+        backup_dir: None,
+        dbinit: Init::new(),
+    };
+    let (tx, rx) = mpsc::channel::<ServerMessage>(1);
+    let child = thread::spawn(move || if let Err(e) = serve(&config, rx) {
+        panic!("Server failed with {}", e);
+    });
+    thread::sleep(Duration::from_millis(500));
+    (tx, child)
+}
+
+
+// Auth session using a blocking socket
+struct Client {
+    socket: TcpStream,
+    buf: BytesMut,
+    msgid: u32,
+}
+
+
+impl Client {
+    fn connect(port: u16) -> Self
+    {
+        let socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        socket.set_nodelay(true).unwrap();
+        let mut client = Self {
+            socket: socket,
+            buf: BytesMut::new(),
+            msgid: 0,
+        };
+        client.send(SessionInfo::new(SessionType::Auth, vec![]).into());
+        client
+    }
+
+    fn send(&mut self, msg: Message)
+    {
+        let mut buf = BytesMut::new();
+        MsgPackCodec.encode(msg.into(), &mut buf).unwrap();
+        self.socket.write_all(&buf).unwrap();
+    }
+
+    fn request(&mut self, code: AuthMessage, args: Vec<Value>)
+        -> AuthResponse
+    {
+        self.msgid += 1;
+        self.send(AuthRequest::new(self.msgid, code, args).into());
+        loop {
+            if let Some(value) = MsgPackCodec.decode(&mut self.buf).unwrap() {
+                let msg = Message::from(value).unwrap();
+                let response = AuthResponse::from(msg).unwrap();
+                assert_eq!(response.message_id(), self.msgid);
+                return response;
+            }
+            let mut data = [0; 4096];
+            let n = self.socket.read(&mut data).unwrap();
+            assert!(n > 0, "Server closed the connection");
+            self.buf.extend_from_slice(&data[..n]);
+        }
+    }
+}
+
+
+// Return the number of reads per second made by every client reading the
+// same keyfile from a server with the given number of threads
+fn reads_per_sec(port: u16, threads: usize) -> f64
+{
+    let tmpdir = TempDir::new("safesec_bench").unwrap();
+    let (tx, server) = start_server(&tmpdir, port, threads);
+
+    // Store the keyfile every client reads
+    let key = Value::from(b"42".to_vec());
+    let file = Value::from(vec![0; 1024]);
+    let mut client = Client::connect(port);
+    let args = vec![key.clone(), file];
+    let response = client.request(AuthMessage::CreateKeyFile, args);
+    assert_eq!(response.error_code(), AuthError::Nil);
+
+    // Connect every client before starting the clock
+    let clients: Vec<Client> =
+        (0..CLIENTS).map(|_| Client::connect(port)).collect();
+    let start = Instant::now();
+    let readers: Vec<JoinHandle<()>> = clients
+        .into_iter()
+        .map(|mut client| {
+            let key = key.clone();
+            thread::spawn(move || for _ in 0..READS {
+                let args = vec![key.clone()];
+                let response = client.request(AuthMessage::GetKeyFile, args);
+                assert_eq!(response.error_code(), AuthError::Nil);
+            })
+        })
+        .collect();
+    for reader in readers {
+        reader.join().unwrap();
+    }
+    let elapsed = start.elapsed();
+    let secs =
+        elapsed.as_secs() as f64 + f64::from(elapsed.subsec_nanos()) / 1e9;
+
+    drop(client);
+    tx.send(ServerMessage::Shutdown).wait().unwrap();
+    server.join().unwrap();
+    (CLIENTS * READS) as f64 / secs
+}
+
+
+// ===========================================================================
+// Main
+// ===========================================================================
+
+
+fn main()
+{
+    println!("{} clients making {} reads each", CLIENTS, READS);
+    let mut baseline = None;
+    for (i, &threads) in THREADS.iter().enumerate() {
+        let rate = reads_per_sec(PORT + i as u16, threads);
+        let base = *baseline.get_or_insert(rate);
+        println!(
+            "threads: {:>2}  reads/sec: {:>10.0}  speedup: {:.2}x",
+            threads,
+            rate,
+            rate / base
+        );
+    }
+}
+
+
+// ===========================================================================
+//
+// ===========================================================================
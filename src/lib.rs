@@ -13,6 +13,7 @@
 // Third-party externs
 extern crate bytes;
 extern crate futures;
+extern crate hmac;
 extern crate lmdb;
 extern crate lmdb_sys;
 
@@ -20,10 +21,12 @@ extern crate lmdb_sys;
 #[macro_use]
 extern crate quickcheck;
 
+extern crate rand;
 extern crate rmp;
 extern crate rmp_serde as rmps;
 extern crate rmpv;
 extern crate serde;
+extern crate sha2;
 extern crate tokio_core;
 extern crate tokio_io;
 extern crate tokio_service;
@@ -55,21 +58,25 @@ pub mod util;
 
 // Stdlib imports
 
-use std::io;
+use std::cmp;
+use std::io::{self, Read, Write};
 use std::mem;
 use std::net::SocketAddr;
-use std::path::PathBuf;
-use std::rc::Rc;
-use std::sync::RwLock;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
 
 // Third-party imports
 
-use futures::{Async, AsyncSink, Future, Poll, Sink, Stream, future, task};
+use futures::{Async, AsyncSink, Future, Poll, Sink, Stream, future, stream,
+              task};
 use futures::stream::SplitSink;
 use futures::sync::mpsc;
 use rmpv::Value;
-use tokio_core::net::TcpListener;
-use tokio_core::reactor::Core;
+use tokio_core::net::{TcpListener, TcpStream};
+use tokio_core::reactor::{Core, Handle, Interval};
 use tokio_io::AsyncRead;
 use tokio_service::Service;
 
@@ -78,9 +85,17 @@ use tokio_service::Service;
 use network::codec::MsgPackCodec;
 use network::rpc::Message;
 use network::server::{Server, ServerMessage};
+use protocol::message::AuthNotice;
 use service::rpcservice::{RpcService, RpcState, ServiceWithShutdown};
-use storage::KeyFileBuilder;
-use storage::lmdb::KeyFile;
+use service::replication::{Follower, ReplicaStatusDB};
+use service::watch::{NOTIFY_BUFFER, WatchDB, Watcher, Watchers};
+use service::worker::WorkerPool;
+use service::state::{KeyFileDB, NamespaceDB, Start};
+use storage::{archive, KeyFileBuilder, KeyFileError, KeyFileStore, Quota};
+use storage::cache::CachedStore;
+use storage::lmdb::{Init, KeyFile};
+use storage::memory::{MemoryKeyFile, MemoryNamespaces};
+use util::Unpoison;
 
 
 // ===========================================================================
@@ -88,10 +103,71 @@ use storage::lmdb::KeyFile;
 // ===========================================================================
 
 
+/// Backend used to store keyfiles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Storage {
+    // LMDB database kept in dbdir
+    Lmdb,
+
+    // Nothing is saved once the server stops
+    Memory,
+}
+
+
+impl FromStr for Storage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Storage, String>
+    {
+        match s {
+            "lmdb" => Ok(Storage::Lmdb),
+            "memory" => Ok(Storage::Memory),
+            _ => Err(format!("Unknown storage backend: {}", s)),
+        }
+    }
+}
+
+
 pub struct Config {
     pub name: String,
     pub dbdir: PathBuf,
     pub bindaddr: SocketAddr,
+    pub storage: Storage,
+
+    // Seconds between purges of expired keyfiles. 0 disables purging.
+    pub sweep_interval: u64,
+
+    // Limits on the keyfiles that auth sessions can write
+    pub quota: Quota,
+
+    // Follow the change log of the server at this address. Followers serve
+    // reads but refuse auth requests that would change the store.
+    pub primary: Option<SocketAddr>,
+
+    // Number of threads that run storage requests, so that slow writes
+    // don't hold up other connections
+    pub workers: usize,
+
+    // Number of threads that serve connections, each with its own event
+    // loop
+    pub threads: usize,
+
+    // Number of keys whose reads are cached in memory. Only the default
+    // namespace is cached; sessions using any other namespace always read
+    // from the store. 0 disables the cache.
+    pub cache: usize,
+
+    // Directory that auth sessions can back up the store into. Each backup
+    // request names a directory relative to it. Backups copy every
+    // namespace, so sessions using any namespace other than the default
+    // one can't make them. None refuses every backup request.
+    pub backup_dir: Option<PathBuf>,
+
+    // Store settings. The path is always replaced by dbdir, and the history
+    // is kept by the memory store too. A read-only server refuses auth
+    // requests that would change the store and never purges expired
+    // keyfiles.
+    pub dbinit: Init,
 }
 
 
@@ -199,17 +275,242 @@ where
 // ===========================================================================
 
 
+// Name of the keyfile store inside the LMDB environment
+const STORE_NAME: &str = "temp";
+
+
+// Open the store chosen by config.storage, creating it if it doesn't exist,
+// along with the namespaces kept beside it
+fn open_store(config: &Config) -> io::Result<(KeyFileDB, NamespaceDB)>
+{
+    match config.storage {
+        Storage::Lmdb => {
+            let dbinit = config.dbinit.clone().path(config.dbdir.as_path());
+            let open_error = |e: KeyFileError| {
+                let errmsg = format!(
+                    "Unable to open db in {}: {}",
+                    config.dbdir.display(),
+                    e
+                );
+                io::Error::new(io::ErrorKind::Other, errmsg)
+            };
+            let keyfile =
+                KeyFile::open(STORE_NAME, dbinit).map_err(&open_error)?;
+            let namespaces = keyfile.open_namespaces().map_err(&open_error)?;
+            Ok((share(config, keyfile), Arc::new(RwLock::new(namespaces))))
+        }
+        Storage::Memory => {
+            let history = config.dbinit.history_len();
+            let keyfile =
+                MemoryKeyFile::new(STORE_NAME, None).history(history);
+            let namespaces = MemoryNamespaces::new().history(history);
+            Ok((share(config, keyfile), Arc::new(RwLock::new(namespaces))))
+        }
+    }
+}
+
+
+// Share a store between sessions, caching its reads if the config asks for
+// it. Only the default namespace goes through here: stores for other
+// namespaces are opened by KeyFileNamespaces and are never cached.
+fn share<S>(config: &Config, store: S) -> KeyFileDB
+where
+    S: KeyFileStore + 'static,
+{
+    if config.cache > 0 {
+        Arc::new(RwLock::new(CachedStore::new(store, config.cache)))
+    } else {
+        Arc::new(RwLock::new(store))
+    }
+}
+
+
+/// Write a copy of the store named in the config into dir.
+///
+/// The server can keep running while the copy is made.
+pub fn backup(config: &Config, dir: &Path, compact: bool) -> io::Result<()>
+{
+    if config.storage != Storage::Lmdb {
+        let errmsg = "Only lmdb storage can be backed up";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, errmsg));
+    }
+    let (db, _) = open_store(config)?;
+    let result = db.read().unpoison().backup(dir, compact);
+    result.map_err(|e| {
+        let errmsg = format!("Unable to back up to {}: {}", dir.display(), e);
+        io::Error::new(io::ErrorKind::Other, errmsg)
+    })
+}
+
+
+/// Replace the store named in the config with a backup made by `backup()`,
+/// returning the number of keyfiles restored.
+///
+/// The backup is verified before it replaces the store. The server must not
+/// be running.
+pub fn restore(config: &Config, dir: &Path) -> io::Result<usize>
+{
+    if config.storage != Storage::Lmdb {
+        let errmsg = "Only lmdb storage can be restored";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, errmsg));
+    }
+    let dbdir = config.dbdir.as_path();
+    storage::lmdb::restore(dir, dbdir, STORE_NAME).map_err(|e| {
+        let errmsg =
+            format!("Unable to restore from {}: {}", dir.display(), e);
+        io::Error::new(io::ErrorKind::Other, errmsg)
+    })
+}
+
+
+/// Rewrite the store named in the config so that it no longer holds
+/// deleted and replaced keyfiles, returning the number of keyfiles in the
+/// store.
+///
+/// The rewritten store is verified before it replaces the store. The server
+/// must not be running, and doesn't compact the store itself.
+pub fn compact(config: &Config) -> io::Result<usize>
+{
+    if config.storage != Storage::Lmdb {
+        let errmsg = "Only lmdb storage can be compacted";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, errmsg));
+    }
+    let dbdir = config.dbdir.as_path();
+    storage::lmdb::compact(dbdir, STORE_NAME).map_err(|e| {
+        let errmsg = format!("Unable to compact db: {}", e);
+        io::Error::new(io::ErrorKind::Other, errmsg)
+    })
+}
+
+
+/// Namespace, key and error of every damaged keyfile found by `scrub()`.
+/// Keyfiles in the default namespace have no namespace name.
+pub type Damaged = Vec<(Option<String>, Vec<u8>, KeyFileError)>;
+
+
+/// Check every keyfile in the store named in the config, and in each of its
+/// namespaces, for damage.
+pub fn scrub(config: &Config) -> io::Result<Damaged>
+{
+    if config.storage != Storage::Lmdb {
+        let errmsg = "Only lmdb storage can be scrubbed";
+        return Err(io::Error::new(io::ErrorKind::InvalidInput, errmsg));
+    }
+    let scrub_error = |e: KeyFileError| {
+        let errmsg = format!("Unable to scrub db: {}", e);
+        io::Error::new(io::ErrorKind::Other, errmsg)
+    };
+    let (db, namespaces) = open_store(config)?;
+    let mut damaged: Damaged = db.read()
+        .unpoison()
+        .scrub()
+        .map_err(&scrub_error)?
+        .into_iter()
+        .map(|(k, e)| (None, k, e))
+        .collect();
+
+    let mut namespaces = namespaces.write().unpoison();
+    for name in namespaces.list_namespaces().map_err(&scrub_error)? {
+        let store = namespaces.namespace(&name).map_err(&scrub_error)?;
+        let found = store.read().unpoison().scrub().map_err(&scrub_error)?;
+        for (k, e) in found {
+            damaged.push((Some(name.clone()), k, e));
+        }
+    }
+    Ok(damaged)
+}
+
+
+/// Write every keyfile in the store named in the config, including those
+/// in namespaces, to out as an archive, returning the number of keyfiles
+/// written.
+pub fn export<W: Write>(config: &Config, out: W) -> io::Result<usize>
+{
+    let (db, namespaces) = open_store(config)?;
+    let db = db.read().unpoison();
+    let mut namespaces = namespaces.write().unpoison();
+    archive::export(&*db, Some(&mut *namespaces), out)
+}
+
+
+/// Load every keyfile in an archive made by `export()` into the store named
+/// in the config, returning the number of keyfiles loaded.
+pub fn import<R: Read>(config: &Config, input: R) -> io::Result<usize>
+{
+    let (db, namespaces) = open_store(config)?;
+    let mut db = db.write().unpoison();
+    let mut namespaces = namespaces.write().unpoison();
+    archive::import(&mut *db, Some(&mut *namespaces), input)
+}
+
+
+/// Run the server using the storage backend named in the config.
 pub fn serve(config: &Config, control: mpsc::Receiver<ServerMessage>)
     -> io::Result<()>
+{
+    let (db, namespaces) = open_store(config)?;
+    serve_db(config, db, Some(namespaces), control)
+}
+
+
+/// Run the server using the given store.
+///
+/// The storage settings in the config are ignored, and sessions can only use
+/// the default namespace.
+pub fn serve_with<S>(
+    config: &Config, store: S, control: mpsc::Receiver<ServerMessage>
+) -> io::Result<()>
+where
+    S: KeyFileStore + 'static,
+{
+    serve_db(config, share(config, store), None, control)
+}
+
+
+// Delete expired keyfiles from the default namespace and every other
+// namespace, telling sessions watching them. Errors are ignored, the next
+// sweep tries again.
+fn sweep(db: &KeyFileDB, namespaces: Option<&NamespaceDB>, watchers: &WatchDB)
+{
+    // Namespace stores are collected first so that sessions can carry on
+    // using namespaces while they're swept
+    let mut stores = vec![(None, db.clone())];
+    if let Some(namespaces) = namespaces {
+        let mut namespaces = namespaces.write().unpoison();
+        let names = namespaces.list_namespaces().unwrap_or_default();
+        stores.extend(names.into_iter().filter_map(|name| {
+            let store = namespaces.namespace(&name).ok();
+            store.map(|store| (Some(name), store))
+        }));
+    }
+
+    // Stores that hash their keys can't say which keys were deleted
+    for (namespace, store) in stores {
+        let purged = {
+            let mut store = store.write().unpoison();
+            match store.purge_expired() {
+                Ok(_) if store.hashes_keys() => Vec::new(),
+                Ok(keys) => keys,
+                Err(_) => Vec::new(),
+            }
+        };
+        let mut watchers = watchers.write().unpoison();
+        for key in purged {
+            watchers.notify(&namespace, AuthNotice::KeyFileDeleted, &key);
+        }
+    }
+}
+
+
+fn serve_db(
+    config: &Config, db: KeyFileDB, namespaces: Option<NamespaceDB>,
+    control: mpsc::Receiver<ServerMessage>
+) -> io::Result<()>
 {
     // Create event loop
     let mut core = Core::new()?;
     let handle = core.handle();
 
-    // Open database, creating it if it doesn't exist
-    let keyfile = KeyFile::new("temp", Some(config.dbdir.as_path()));
-    let db = Rc::new(RwLock::new(keyfile));
-
     // Create server stream, binding to configured bind address
     let listener = match TcpListener::bind(&config.bindaddr, &handle) {
         Ok(l) => l,
@@ -229,6 +530,52 @@ pub fn serve(config: &Config, control: mpsc::Receiver<ServerMessage>)
     let server = Server::new(handle.clone(), listener.incoming(), 1);
     let tx = server.control();
 
+    // Sessions watching keys are told about changes made by other sessions,
+    // the primary and the sweeper
+    let watchers = Arc::new(RwLock::new(Watchers::new()));
+
+    // Copy changes from the primary. Expired keyfiles are purged by the
+    // primary, so followers don't sweep.
+    let replica = match config.primary {
+        Some(addr) => {
+            let follower = Follower::new(addr, db.clone()).map_err(|e| {
+                let errmsg =
+                    format!("Unable to replicate from {}: {}", addr, e);
+                io::Error::new(io::ErrorKind::Other, errmsg)
+            })?;
+            let follower = follower.watchers(watchers.clone());
+            let status = follower.status();
+            handle.spawn(follower.run(&handle));
+            Some(status)
+        }
+        None => None,
+    };
+    let read_only = config.dbinit.is_read_only() || replica.is_some();
+
+    // Run storage requests off the event loop
+    let workers = WorkerPool::new(config.workers)?;
+
+    // Periodically delete expired keyfiles from the store and namespaces
+    if config.sweep_interval > 0 && !read_only {
+        let (sweepdb, sweepers) = (db.clone(), workers.clone());
+        let (sweepns, sweepwatch) = (namespaces.clone(), watchers.clone());
+        let period = Duration::from_secs(config.sweep_interval);
+        let sweeper = Interval::new(period, &handle)?
+            .for_each(move |_| {
+                let (sweepdb, sweepns) = (sweepdb.clone(), sweepns.clone());
+                let sweepwatch = sweepwatch.clone();
+                // A sweep that fails or can't be queued is tried again
+                // at the next tick
+                sweepers
+                    .run(move || {
+                        sweep(&sweepdb, sweepns.as_ref(), &sweepwatch)
+                    })
+                    .then(|_| Ok(()))
+            })
+            .map_err(|_| ());
+        handle.spawn(sweeper);
+    }
+
     // Create stream of SIGINT/CTRL-C notifications
     let ctrl_c = tokio_signal::ctrl_c(&handle)
         .flatten_stream()
@@ -295,56 +642,42 @@ pub fn serve(config: &Config, control: mpsc::Receiver<ServerMessage>)
         });
 
 
+    // Serve connections on a pool of threads, each running its own event
+    // loop. Accepted sockets are handed to each thread in turn.
+    let sessions = Sessions {
+        backup_dir: config.backup_dir.clone(),
+        db: db,
+        namespaces: namespaces,
+        quota: config.quota,
+        read_only: read_only,
+        replica: replica,
+        watchers: watchers,
+        workers: workers,
+        control: tx.clone(),
+    };
+    let mut reactors = Vec::new();
+    let mut sockets = Vec::new();
+    for i in 0..cmp::max(config.threads, 1) {
+        let (socket_tx, socket_rx) = mpsc::unbounded();
+        let sessions = sessions.clone();
+        let reactor = thread::Builder::new()
+            .name(format!("safesec-reactor-{}", i))
+            .spawn(move || serve_connections(sessions, socket_rx))?;
+        reactors.push(reactor);
+        sockets.push(socket_tx);
+    }
+    drop(sessions);
+
     // Set up server future
+    let mut next = 0;
     let server = server
-        .for_each(|(socket, _peer_addr)| {
-            let (writer, reader) = socket.framed(MsgPackCodec).split();
-            let mut service = RpcService::new();
-            let mut rpcstate = RpcState::new(db.clone());
-            service.set_server_control(tx.clone(), handle.clone());
-            rpcstate.set_server_control(tx.clone(), handle.clone());
-
-            let responses = reader
-                .and_then(move |req| service.call(req))
-
-                // Close the stream if a None has been generated
-                .take_while(|v| Ok(v.is_some()))
-
-                // Don't send any Value::Nil values
-                .filter(|v| {
-                    match *v {
-                        Some(Value::Nil) => false,
-                        Some(_) => true,
-                        None => unreachable!()
-                    }
-                })
-
-                // Process the message and generate a response
-                .and_then(move |v| {
-                    let msg = Message::from(v.unwrap()).unwrap();
-                    rpcstate.process_message(msg)
-                })
-
-                // Close the stream if a None has been generated
-                .take_while(|v| Ok(v.is_some()))
-
-                // Don't send any Value::Nil values
-                .filter(|v| {
-                    match *v {
-                        Some(Value::Nil) => false,
-                        Some(_) => true,
-                        None => unreachable!()
-                    }
-                })
-
-                // Unwrap Some(Value)
-                .map(|some_val| some_val.unwrap());
-
-            let server = send_message(writer, responses).map_err(|_| ());
-
-            handle.spawn(server);
-
-            Ok(())
+        .for_each(move |(socket, _peer_addr)| {
+            // The socket isn't tied to an event loop until it's first used
+            let thread = &sockets[next % sockets.len()];
+            next += 1;
+            thread.unbounded_send(socket).map_err(|_| {
+                io::Error::new(io::ErrorKind::Other, "reactor thread stopped")
+            })
         })
         .map_err(|e| {
             eprintln!("ERROR HAPPENED: {}", e);
@@ -357,7 +690,131 @@ pub fn serve(config: &Config, control: mpsc::Receiver<ServerMessage>)
         Err(future::Either::B((err, _))) => Err(err),
     });
 
-    core.run(server)
+    // Stopping the server drops the senders of every reactor thread's
+    // sockets, which closes its connections
+    let result = core.run(server);
+    for reactor in reactors {
+        if reactor.join().is_err() {
+            let errmsg = "reactor thread panicked";
+            return Err(io::Error::new(io::ErrorKind::Other, errmsg));
+        }
+    }
+    result
+}
+
+
+// Shared by every thread serving connections
+#[derive(Clone)]
+struct Sessions {
+    backup_dir: Option<PathBuf>,
+    db: KeyFileDB,
+    namespaces: Option<NamespaceDB>,
+    quota: Quota,
+    read_only: bool,
+    replica: Option<ReplicaStatusDB>,
+    watchers: WatchDB,
+    workers: WorkerPool,
+    control: mpsc::Sender<ServerMessage>,
+}
+
+
+// Run an event loop serving every socket received until the sender is
+// dropped
+fn serve_connections(
+    sessions: Sessions, sockets: mpsc::UnboundedReceiver<TcpStream>
+) -> io::Result<()>
+{
+    let mut core = Core::new()?;
+    let handle = core.handle();
+    let connections = sockets.for_each(|socket| {
+        serve_connection(&sessions, socket, &handle);
+        Ok(())
+    });
+    core.run(connections).map_err(|_| {
+        io::Error::new(io::ErrorKind::Other, "error receiving sockets")
+    })
+}
+
+
+// Process the messages sent over socket, on the event loop of handle
+fn serve_connection(sessions: &Sessions, socket: TcpStream, handle: &Handle)
+{
+    let (writer, reader) = socket.framed(MsgPackCodec).split();
+    let (notifier, notifications) = mpsc::channel(NOTIFY_BUFFER);
+    let watcher = Watcher::new(sessions.watchers.clone(), notifier);
+    let tx = sessions.control.clone();
+    let mut service = RpcService::new();
+    let mut start = Start::new(sessions.db.clone())
+        .quota(sessions.quota)
+        .read_only(sessions.read_only)
+        .watcher(watcher);
+    if let Some(ref namespaces) = sessions.namespaces {
+        start = start.namespaces(namespaces.clone());
+    }
+    if let Some(ref status) = sessions.replica {
+        start = start.replica(status.clone());
+    }
+    if let Some(ref dir) = sessions.backup_dir {
+        start = start.backup_dir(dir.clone());
+    }
+    let mut rpcstate =
+        RpcState::from_start(start).workers(sessions.workers.clone());
+    service.set_server_control(tx.clone(), handle.clone());
+    rpcstate.set_server_control(tx, handle.clone());
+
+    let responses = reader
+        .and_then(move |req| service.call(req))
+
+        // Close the stream if a None has been generated
+        .take_while(|v| Ok(v.is_some()))
+
+        // Don't send any Value::Nil values
+        .filter(|v| {
+            match *v {
+                Some(Value::Nil) => false,
+                Some(_) => true,
+                None => unreachable!()
+            }
+        })
+
+        // Process the message and generate a response
+        .and_then(move |v| {
+            let msg = Message::from(v.unwrap()).unwrap();
+            rpcstate.process_message(msg)
+        })
+
+        // Close the stream if a None has been generated
+        .take_while(|v| Ok(v.is_some()))
+
+        // Don't send any Value::Nil values
+        .filter(|v| {
+            match *v {
+                Some(Value::Nil) => false,
+                Some(_) => true,
+                None => unreachable!()
+            }
+        })
+
+        // Unwrap Some(Value)
+        .map(|some_val| some_val.unwrap());
+
+    // Send notifications for watched keys along with the responses, until
+    // the connection is closed or falls too far behind its notifications
+    let notifications = notifications.map_err(|_| {
+        io::Error::new(
+            io::ErrorKind::Other,
+            "error with notification receiver",
+        )
+    });
+    let messages = responses
+        .map(Some)
+        .chain(stream::once(Ok(None)))
+        .select(notifications)
+        .take_while(|v| Ok(v.is_some()))
+        .map(|some_val| some_val.unwrap());
+
+    let server = send_message(writer, messages).map_err(|_| ());
+    handle.spawn(server);
 }
 
 
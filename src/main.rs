@@ -29,21 +29,26 @@ extern crate safesec;
 
 // Stdlib imports
 
-use std::fs;
-use std::io;
+use std::fs::{self, File};
+use std::io::{self, BufWriter};
 use std::net::SocketAddr;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::exit;
+use std::str::FromStr;
 
 // Third-party imports
 
-use clap::{App, Arg};
+use clap::{App, Arg, ArgMatches, SubCommand};
 use futures::sync::mpsc;
 
 // Local imports
 
-use safesec::{Config, serve};
+use safesec::{Config, Storage, backup, compact, export, import, restore,
+              scrub, serve};
 use safesec::network::server::ServerMessage;
+use safesec::storage::Quota;
+use safesec::storage::keyhash::KeyHasher;
+use safesec::storage::lmdb::Init;
 
 
 // ===========================================================================
@@ -55,6 +60,15 @@ pub struct ConfigBuilder {
     name: String,
     db: Option<PathBuf>,
     addr: Option<SocketAddr>,
+    storage: Storage,
+    sweep_interval: u64,
+    quota: Quota,
+    primary: Option<SocketAddr>,
+    workers: usize,
+    threads: usize,
+    cache: usize,
+    backup_dir: Option<PathBuf>,
+    dbinit: Init,
 }
 
 
@@ -88,6 +102,15 @@ impl ConfigBuilder {
             name: appname.to_string(),
             db: None,
             addr: None,
+            storage: Storage::Lmdb,
+            sweep_interval: 60,
+            quota: Quota::new(),
+            primary: None,
+            workers: 4,
+            threads: 1,
+            cache: 0,
+            backup_dir: None,
+            dbinit: Init::new(),
         }
     }
 
@@ -103,6 +126,60 @@ impl ConfigBuilder {
         self
     }
 
+    pub fn storage(mut self, storage: Storage) -> Self
+    {
+        self.storage = storage;
+        self
+    }
+
+    pub fn sweep_interval(mut self, secs: u64) -> Self
+    {
+        self.sweep_interval = secs;
+        self
+    }
+
+    pub fn quota(mut self, quota: Quota) -> Self
+    {
+        self.quota = quota;
+        self
+    }
+
+    pub fn primary(mut self, addr: SocketAddr) -> Self
+    {
+        self.primary = Some(addr);
+        self
+    }
+
+    pub fn workers(mut self, threads: usize) -> Self
+    {
+        self.workers = threads;
+        self
+    }
+
+    pub fn threads(mut self, threads: usize) -> Self
+    {
+        self.threads = threads;
+        self
+    }
+
+    pub fn cache(mut self, entries: usize) -> Self
+    {
+        self.cache = entries;
+        self
+    }
+
+    pub fn backup_dir(mut self, dir: PathBuf) -> Self
+    {
+        self.backup_dir = Some(dir);
+        self
+    }
+
+    pub fn dbinit(mut self, dbinit: Init) -> Self
+    {
+        self.dbinit = dbinit;
+        self
+    }
+
     pub fn create(self) -> io::Result<Config>
     {
         // Validate db dir
@@ -139,6 +216,15 @@ impl ConfigBuilder {
             name: name,
             dbdir: db,
             bindaddr: addr,
+            storage: self.storage,
+            sweep_interval: self.sweep_interval,
+            quota: self.quota,
+            primary: self.primary,
+            workers: self.workers,
+            threads: self.threads,
+            cache: self.cache,
+            backup_dir: self.backup_dir,
+            dbinit: self.dbinit,
         })
     }
 }
@@ -151,6 +237,15 @@ impl From<Config> for ConfigBuilder {
             name: config.name,
             db: Some(config.dbdir),
             addr: Some(config.bindaddr),
+            storage: config.storage,
+            sweep_interval: config.sweep_interval,
+            quota: config.quota,
+            primary: config.primary,
+            workers: config.workers,
+            threads: config.threads,
+            cache: config.cache,
+            backup_dir: config.backup_dir,
+            dbinit: config.dbinit,
         }
     }
 }
@@ -169,7 +264,114 @@ fn config(appname: &str) -> ConfigBuilder
 type AppResult<T> = Result<T, String>;
 
 
-fn cli() -> AppResult<Config>
+// What the program has been asked to do
+enum Command {
+    Serve,
+
+    // Write a copy of the db into a directory, optionally compacted
+    Backup(PathBuf, bool),
+
+    // Replace the db with the copy in a directory
+    Restore(PathBuf),
+
+    // Rewrite the db without its free pages
+    Compact,
+
+    // Write every keyfile to an archive file, or stdout if the path is -
+    Export(PathBuf),
+
+    // Load every keyfile from an archive file, or stdin if the path is -
+    Import(PathBuf),
+
+    // Report every damaged keyfile in the db
+    Scrub,
+}
+
+
+// Get an optional argument value, returning an error message if the value
+// can't be parsed
+fn value_of<T>(matches: &ArgMatches, name: &str) -> AppResult<Option<T>>
+where
+    T: FromStr,
+{
+    value_t!(matches, name, T).map(|v| Some(v)).or_else(
+        |e| match e.kind {
+            clap::ErrorKind::ArgumentNotFound => Ok(None),
+            _ => Err(format!("{}", e)),
+        },
+    )
+}
+
+
+fn dbinit(matches: &ArgMatches) -> AppResult<Init>
+{
+    let mut dbinit = Init::new();
+    if let Some(size) = value_of::<usize>(matches, "map_size")? {
+        dbinit = dbinit.map_size(size);
+    }
+    if let Some(size) = value_of::<usize>(matches, "max_map_size")? {
+        dbinit = dbinit.max_map_size(size);
+    }
+    if let Some(factor) = value_of::<f64>(matches, "map_growth")? {
+        if factor <= 1.0 {
+            return Err(format!("Invalid map growth factor: {}", factor));
+        }
+        dbinit = dbinit.map_growth(factor);
+    }
+    if let Some(readers) = value_of::<u32>(matches, "max_readers")? {
+        dbinit = dbinit.max_readers(readers);
+    }
+    if let Some(maxdbs) = value_of::<u32>(matches, "max_dbs")? {
+        dbinit = dbinit.max_dbs(maxdbs);
+    }
+    if let Some(revisions) = value_of::<usize>(matches, "history")? {
+        dbinit = dbinit.history(revisions);
+    }
+    if let Some(changes) = value_of::<usize>(matches, "change_log")? {
+        dbinit = dbinit.change_log(changes);
+    }
+    if let Some(mode) = matches.value_of("mode") {
+        let mode = u32::from_str_radix(mode, 8).map_err(|_| {
+            format!("Invalid octal file mode: {}", mode)
+        })?;
+        dbinit = dbinit.mode(mode);
+    }
+    if let Some(path) = matches.value_of("key_salt") {
+        let hasher = KeyHasher::open(Path::new(path)).map_err(|e| {
+            format!("Unable to read salt file {}: {}", path, e)
+        })?;
+        dbinit = dbinit.hash_keys(hasher);
+    }
+    let dbinit = dbinit
+        .no_sync(matches.is_present("no_sync"))
+        .no_meta_sync(matches.is_present("no_meta_sync"))
+        .write_map(matches.is_present("write_map"))
+        .read_only(matches.is_present("read_only"))
+        .secure_delete(matches.is_present("secure_delete"));
+    Ok(dbinit)
+}
+
+
+fn quota(matches: &ArgMatches) -> AppResult<Quota>
+{
+    let mut quota = Quota::new();
+    if let Some(len) = value_of::<u64>(matches, "max_key_length")? {
+        quota = quota.max_key_length(len);
+    }
+    if let Some(size) = value_of::<u64>(matches, "max_keyfile_size")? {
+        quota = quota.max_keyfile_size(size);
+    }
+    if let Some(entries) = value_of::<u64>(matches, "max_entries")? {
+        quota = quota.max_entries(entries);
+    }
+    if let Some(bytes) = value_of::<u64>(matches, "max_bytes")? {
+        quota = quota.max_bytes(bytes);
+    }
+    Ok(quota)
+}
+
+
+fn cli() -> AppResult<(Config, Command)>
 {
     let appname = "safesec";
     let default_dbdir = match ConfigBuilder::_default_db(appname) {
@@ -205,8 +407,248 @@ fn cli() -> AppResult<Config>
                 ))
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("storage")
+                .long("storage")
+                .value_name("BACKEND")
+                .possible_values(&["lmdb", "memory"])
+                .help("Where keyfiles are stored (default: lmdb)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("history")
+                .long("history")
+                .value_name("NUM")
+                .help("Previous revisions kept for each keyfile (default: 0)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("sweep_interval")
+                .long("sweep-interval")
+                .value_name("SECS")
+                .help(
+                    "Seconds between purges of expired keyfiles, 0 to \
+                     disable (default: 60)",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_key_length")
+                .long("max-key-length")
+                .value_name("BYTES")
+                .help("Longest key that can be written")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_keyfile_size")
+                .long("max-keyfile-size")
+                .value_name("BYTES")
+                .help("Largest keyfile that can be written")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_entries")
+                .long("max-entries")
+                .value_name("NUM")
+                .help("Most keyfiles kept in each namespace")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_bytes")
+                .long("max-bytes")
+                .value_name("BYTES")
+                .help("Most keyfile bytes kept in each namespace")
+                .takes_value(true),
+        )
+        .arg(Arg::with_name("read_only").long("read-only").help(
+            "Serve the db without changing it",
+        ))
+        .arg(
+            Arg::with_name("primary")
+                .long("primary")
+                .value_name("ADDR")
+                .help("Follow the changes made on the server at ADDR")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("change_log")
+                .long("change-log")
+                .value_name("CHANGES")
+                .help(
+                    "Keep the latest CHANGES changes for followers to copy \
+                     (default: 0)",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("workers")
+                .long("workers")
+                .value_name("THREADS")
+                .help("Threads that run storage requests (default: 4)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("threads")
+                .long("threads")
+                .value_name("THREADS")
+                .help("Threads that serve connections (default: 1)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("cache")
+                .long("cache")
+                .value_name("ENTRIES")
+                .help(
+                    "Number of keys in the default namespace whose reads \
+                     are cached (default: 0)",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("backup_dir")
+                .long("backup-dir")
+                .value_name("DIR")
+                .help(
+                    "Let clients back up the db into directories under DIR",
+                )
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("map_size")
+                .long("map-size")
+                .value_name("BYTES")
+                .help("Maximum size of the db in bytes")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_map_size")
+                .long("max-map-size")
+                .value_name("BYTES")
+                .help("Size in bytes the db may grow to when full")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("map_growth")
+                .long("map-growth")
+                .value_name("FACTOR")
+                .help("Factor the db size grows by when full (default: 2)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_readers")
+                .long("max-readers")
+                .value_name("NUM")
+                .help("Maximum number of concurrent db readers")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("max_dbs")
+                .long("max-dbs")
+                .value_name("NUM")
+                .help("Maximum number of named dbs (default: 128)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("mode")
+                .long("mode")
+                .value_name("MODE")
+                .help("Octal permissions of created db files (default: 600)")
+                .takes_value(true),
+        )
+        .arg(
+            Arg::with_name("key_salt")
+                .long("key-salt")
+                .value_name("FILE")
+                .help(
+                    "Store keys hashed with the salt in FILE, creating it \
+                     if needed",
+                )
+                .takes_value(true),
+        )
+        .arg(Arg::with_name("secure_delete").long("secure-delete").help(
+            "Overwrite keyfiles with zeros before deleting or replacing \
+             them, on a best effort basis (run compact to remove them)",
+        ))
+        .arg(Arg::with_name("no_sync").long("no-sync").help(
+            "Don't flush db to disk after each write",
+        ))
+        .arg(Arg::with_name("no_meta_sync").long("no-meta-sync").help(
+            "Don't flush db metadata to disk after each write",
+        ))
+        .arg(Arg::with_name("write_map").long("write-map").help(
+            "Use a writeable memory map for the db",
+        ))
+        .subcommand(
+            SubCommand::with_name("backup")
+                .about("Copy the db to a directory, even while serving")
+                .arg(
+                    Arg::with_name("dir")
+                        .value_name("DIR")
+                        .help("Directory the copy is written to")
+                        .required(true),
+                )
+                .arg(Arg::with_name("compact").long("compact").help(
+                    "Leave free space out of the copy",
+                )),
+        )
+        .subcommand(
+            SubCommand::with_name("restore")
+                .about("Replace the db with a backup once it is verified")
+                .arg(
+                    Arg::with_name("dir")
+                        .value_name("DIR")
+                        .help("Directory holding the backup")
+                        .required(true),
+                ),
+        )
+        .subcommand(SubCommand::with_name("compact").about(
+            "Rewrite the db without any deleted or replaced keyfiles, \
+             while the server is stopped",
+        ))
+        .subcommand(
+            SubCommand::with_name("export")
+                .about("Write every keyfile to a portable archive")
+                .arg(
+                    Arg::with_name("file")
+                        .value_name("FILE")
+                        .help("Archive to write, or - for stdout")
+                        .required(true),
+                ),
+        )
+        .subcommand(
+            SubCommand::with_name("import")
+                .about("Load every keyfile from an archive into the db")
+                .arg(
+                    Arg::with_name("file")
+                        .value_name("FILE")
+                        .help("Archive to read, or - for stdin")
+                        .required(true),
+                ),
+        )
+        .subcommand(SubCommand::with_name("scrub").about(
+            "Check every keyfile in the db for damage",
+        ))
         .get_matches();
 
+    let command = match matches.subcommand() {
+        ("backup", Some(sub)) => {
+            let dir = PathBuf::from(sub.value_of("dir").unwrap());
+            Command::Backup(dir, sub.is_present("compact"))
+        }
+        ("restore", Some(sub)) => {
+            Command::Restore(PathBuf::from(sub.value_of("dir").unwrap()))
+        }
+        ("compact", Some(_)) => Command::Compact,
+        ("export", Some(sub)) => {
+            Command::Export(PathBuf::from(sub.value_of("file").unwrap()))
+        }
+        ("import", Some(sub)) => {
+            Command::Import(PathBuf::from(sub.value_of("file").unwrap()))
+        }
+        ("scrub", Some(_)) => Command::Scrub,
+        _ => Command::Serve,
+    };
+
     // Get db value
     let db = matches
         .value_of("dbdir")
@@ -221,7 +663,30 @@ fn cli() -> AppResult<Config>
             _ => Err(format!("{}", e)),
         });
 
-    let mut config = config(appname);
+    let mut config = config(appname)
+        .dbinit(dbinit(&matches)?)
+        .quota(quota(&matches)?);
+    if let Some(storage) = value_of::<Storage>(&matches, "storage")? {
+        config = config.storage(storage);
+    }
+    if let Some(secs) = value_of::<u64>(&matches, "sweep_interval")? {
+        config = config.sweep_interval(secs);
+    }
+    if let Some(addr) = value_of::<SocketAddr>(&matches, "primary")? {
+        config = config.primary(addr);
+    }
+    if let Some(threads) = value_of::<usize>(&matches, "workers")? {
+        config = config.workers(threads);
+    }
+    if let Some(threads) = value_of::<usize>(&matches, "threads")? {
+        config = config.threads(threads);
+    }
+    if let Some(entries) = value_of::<usize>(&matches, "cache")? {
+        config = config.cache(entries);
+    }
+    if let Some(dir) = matches.value_of("backup_dir") {
+        config = config.backup_dir(PathBuf::from(dir));
+    }
     if let Some(db) = db {
         config = config.dbdir(db);
     }
@@ -236,16 +701,78 @@ fn cli() -> AppResult<Config>
 
     let config = config.create();
     match config {
-        Ok(c) => Ok(c),
+        Ok(c) => Ok((c, command)),
         Err(e) => Err(format!("{}", e)),
     }
 }
 
 
+fn export_archive(config: &Config, path: &Path) -> io::Result<usize>
+{
+    if path == Path::new("-") {
+        let stdout = io::stdout();
+        return export(config, stdout.lock());
+    }
+    let file = File::create(path)?;
+    export(config, BufWriter::new(file))
+}
+
+
+fn import_archive(config: &Config, path: &Path) -> io::Result<usize>
+{
+    if path == Path::new("-") {
+        let stdin = io::stdin();
+        return import(config, stdin.lock());
+    }
+    import(config, File::open(path)?)
+}
+
+
+// Print every damaged keyfile, returning 1 if there are any
+fn run_scrub(config: &Config) -> i32
+{
+    let damaged = match scrub(config) {
+        Ok(damaged) => damaged,
+        Err(e) => {
+            eprintln!("Scrub failed: {}", e);
+            return 1;
+        }
+    };
+    let count = damaged.len();
+    for (namespace, key, err) in damaged {
+        let key: Vec<String> =
+            key.iter().map(|b| format!("{:02x}", b)).collect();
+        match namespace {
+            Some(name) => println!("{} {}: {}", name, key.concat(), err),
+            None => println!("{}: {}", key.concat(), err),
+        }
+    }
+    println!("Found {} damaged keyfiles", count);
+    if count == 0 { 0 } else { 1 }
+}
+
+
+fn run_server(config: &Config) -> i32
+{
+    // Create channel (currently doesn't do anything)
+    // TODO send shutdown command when CTRL-C received
+    let (_tx, rx) = mpsc::channel::<ServerMessage>(1);
+
+    // Start server
+    println!("{} running", &config.name);
+    if let Err(e) = serve(config, rx) {
+        eprintln!("Server failed: {}", e);
+        1
+    } else {
+        0
+    }
+}
+
+
 fn main()
 {
     let exit_code = {
-        let config = match cli() {
+        let (config, command) = match cli() {
             Err(msg) => {
                 eprintln!("{}", msg);
                 exit(1)
@@ -253,17 +780,66 @@ fn main()
             Ok(c) => c,
         };
 
-        // Create channel (currently doesn't do anything)
-        // TODO send shutdown command when CTRL-C received
-        let (_tx, rx) = mpsc::channel::<ServerMessage>(1);
-
-        // Start server
-        println!("{} running", &config.name);
-        if let Err(e) = serve(&config, rx) {
-            eprintln!("Server failed: {}", e);
-            1
-        } else {
-            0
+        match command {
+            Command::Serve => run_server(&config),
+            Command::Backup(dir, compact) => {
+                match backup(&config, &dir, compact) {
+                    Ok(()) => 0,
+                    Err(e) => {
+                        eprintln!("Backup failed: {}", e);
+                        1
+                    }
+                }
+            }
+            Command::Restore(dir) => {
+                match restore(&config, &dir) {
+                    Ok(count) => {
+                        println!("Restored {} keyfiles", count);
+                        0
+                    }
+                    Err(e) => {
+                        eprintln!("Restore failed: {}", e);
+                        1
+                    }
+                }
+            }
+            Command::Compact => {
+                match compact(&config) {
+                    Ok(count) => {
+                        println!("Compacted db holding {} keyfiles", count);
+                        0
+                    }
+                    Err(e) => {
+                        eprintln!("Compact failed: {}", e);
+                        1
+                    }
+                }
+            }
+            Command::Export(path) => {
+                match export_archive(&config, &path) {
+                    Ok(count) => {
+                        eprintln!("Exported {} keyfiles", count);
+                        0
+                    }
+                    Err(e) => {
+                        eprintln!("Export failed: {}", e);
+                        1
+                    }
+                }
+            }
+            Command::Import(path) => {
+                match import_archive(&config, &path) {
+                    Ok(count) => {
+                        eprintln!("Imported {} keyfiles", count);
+                        0
+                    }
+                    Err(e) => {
+                        eprintln!("Import failed: {}", e);
+                        1
+                    }
+                }
+            }
+            Command::Scrub => run_scrub(&config),
         }
     };
 
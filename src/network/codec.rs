@@ -35,6 +35,8 @@ use tokio_io::codec::{Decoder, Encoder};
 
 // Local imports
 
+use util::{zeroize, zeroize_value};
+
 
 // ===========================================================================
 // Codec
@@ -48,13 +50,13 @@ impl MsgPackCodec {
     fn handle_decode_error(err: decode::Error) -> Option<io::Error>
     {
         match err {
-            decode::Error::InvalidDataRead(e) => {
+            decode::Error::InvalidDataRead(e) |
+            decode::Error::InvalidMarkerRead(e) => {
                 match e.kind() {
                     io::ErrorKind::UnexpectedEof => None,
                     _ => Some(e),
                 }
             }
-            decode::Error::InvalidMarkerRead(e) => Some(e),
             decode::Error::TypeMismatch(_) => {
                 let errmsg = "msgpack type mismatch".to_string();
                 Some(io::Error::new(io::ErrorKind::InvalidData, errmsg))
@@ -113,11 +115,15 @@ impl Decoder for MsgPackCodec {
             curpos = de.position() as usize;
         }
 
-        // Discard read bytes
-        buf.split_to(curpos);
-
         match result {
-            Ok(v) => Ok(Some(v)),
+            Ok(v) => {
+                // Discard read bytes
+                buf.split_to(curpos);
+                Ok(Some(v))
+            }
+
+            // Read bytes are kept when more data is needed, so the value
+            // can be decoded once the rest of it arrives
             Err(e) => {
                 match Self::handle_decode_error(e) {
                     Some(err) => Err(err),
@@ -133,11 +139,16 @@ impl Encoder for MsgPackCodec {
     type Item = Value;
     type Error = io::Error;
 
-    fn encode(&mut self, msg: Value, buf: &mut BytesMut) -> io::Result<()>
+    // Messages may hold keyfiles, so they are zeroized once encoded along
+    // with the buffer they were serialized into
+    fn encode(&mut self, mut msg: Value, buf: &mut BytesMut)
+        -> io::Result<()>
     {
         let mut tmpbuf = Vec::new();
         msg.serialize(&mut Serializer::new(&mut tmpbuf)).unwrap();
         buf.extend_from_slice(&tmpbuf[..]);
+        zeroize_value(&mut msg);
+        zeroize(&mut tmpbuf);
         Ok(())
     }
 }
@@ -291,6 +302,67 @@ mod tests {
         assert_eq!(&buf[..], &buf2[..newlength]);
     }
 
+    #[test]
+    fn decode_message_split_across_buffers()
+    {
+        // --------------------
+        // GIVEN
+        // --------------------
+        // A message pack serialized message
+        let mut buf = Vec::new();
+        let msg = Value::from("ANSWER");
+        msg.serialize(&mut Serializer::new(&mut buf)).unwrap();
+
+        // --------------------
+        // WHEN
+        // --------------------
+        // the first half of the message is decoded, and then decoded again
+        // once the second half has been added to the buffer
+        let half = buf.len() / 2;
+        let mut codec = MsgPackCodec;
+        let mut partial = BytesMut::from_buf(Vec::from(&buf[..half]));
+        let first = codec.decode(&mut partial).unwrap();
+        partial.extend_from_slice(&buf[half..]);
+        let second = codec.decode(&mut partial).unwrap();
+
+        // --------------------
+        // THEN
+        // --------------------
+        // Nothing is returned until the whole message has been added, and
+        // the whole message is consumed once it is decoded
+        assert_eq!(first, None);
+        assert_eq!(second, Some(msg));
+        assert_eq!(partial.len(), 0);
+    }
+
+    #[test]
+    fn decode_message_split_before_marker()
+    {
+        // --------------------
+        // GIVEN
+        // --------------------
+        // A message pack serialized array
+        let mut buf = Vec::new();
+        let msg = Value::Array(vec![Value::from(0), Value::from(42)]);
+        msg.serialize(&mut Serializer::new(&mut buf)).unwrap();
+
+        // --------------------
+        // WHEN
+        // --------------------
+        // the message is cut just before the marker of its last item and
+        // the start of the message is decoded
+        let mut codec = MsgPackCodec;
+        let mut partial = BytesMut::from_buf(Vec::from(&buf[..2]));
+        let result = codec.decode(&mut partial).unwrap();
+
+        // --------------------
+        // THEN
+        // --------------------
+        // Ok(None) is returned and the start of the message is kept
+        assert_eq!(result, None);
+        assert_eq!(&partial[..], &buf[..2]);
+    }
+
     #[test]
     fn decode_empty_buffer()
     {
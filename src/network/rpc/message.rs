@@ -122,6 +122,7 @@
 // Stdlib imports
 
 use std::clone::Clone;
+use std::mem;
 
 // Third-party imports
 
@@ -131,6 +132,7 @@ use rmpv::Value;
 
 use error::{Error, GeneralError, Result};
 use error::network::rpc::{RpcError, RpcResult};
+use util::zeroize_value;
 
 
 // ===========================================================================
@@ -366,9 +368,19 @@ impl Clone for Message {
 
 
 impl Into<Value> for Message {
-    fn into(self) -> Value
+    fn into(mut self) -> Value
     {
-        self.msg
+        mem::replace(&mut self.msg, Value::Nil)
+    }
+}
+
+
+// Messages may carry keys and keyfiles, so their buffers are zeroized
+// rather than being freed as they are
+impl Drop for Message {
+    fn drop(&mut self)
+    {
+        zeroize_value(&mut self.msg);
     }
 }
 
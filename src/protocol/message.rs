@@ -65,7 +65,9 @@ pub enum ProtocolError {
 
 // Session type.
 //
-// Used with the notification rpc message type.
+// Used with the notification rpc message type. The notification's only
+// argument is the name of the namespace the session uses, or nil for the
+// default namespace. Without an argument, the default namespace is used.
 #[derive(Debug, PartialEq, Clone, CodeConvert)]
 pub enum SessionType {
     // Session used when an agent is starting.
@@ -78,6 +80,13 @@ pub enum SessionType {
     //
     // All request types are available within an authenticated session.
     Auth,
+
+    // Session used by a follower to copy the change log of its primary.
+    //
+    // Only replica requests are available within a replica session. The
+    // change log covers every namespace, so the session's namespace is
+    // ignored.
+    Replica,
 }
 
 
@@ -94,6 +103,9 @@ pub enum BootMessage {
 
     // Retrieve the keyfile
     GetKeyFile,
+
+    // Retrieve the keyfile's metadata
+    GetKeyFileInfo,
 }
 
 
@@ -104,6 +116,24 @@ pub enum BootError {
 
     // Key file is not found.
     KeyFileNotFound,
+
+    // DB error
+    DatabaseError,
+
+    // Database has run out of space.
+    DatabaseFull,
+
+    // Database has too many concurrent readers.
+    DatabaseReadersFull,
+
+    // Database files are damaged.
+    DatabaseCorrupted,
+
+    // Database could not be accessed due to a permission or IO error.
+    DatabaseIo,
+
+    // Key or keyfile is larger than the database supports.
+    ValueTooLarge,
 }
 
 
@@ -131,13 +161,16 @@ pub enum AuthMessage {
     // Create the keyfile.
     //
     // Requires 2 arguments: key, keyfile. Only succeeds if the keyfile does
-    // not exist.
+    // not exist. An optional third argument gives the number of seconds
+    // until the keyfile expires; nil means it never expires. Expired
+    // keyfiles are treated as if they don't exist.
     CreateKeyFile,
 
     // Change only the keyfile
     //
     // Requires 2 arguments: key, new keyfile. Only succeeds if the keyfile
-    // already exists.
+    // already exists. Takes the same optional third argument as
+    // CreateKeyFile; without it, the new keyfile never expires.
     ChangeKeyFile,
 
     // Change only the key
@@ -161,6 +194,110 @@ pub enum AuthMessage {
     //
     // requires 1 argument: key. Always succeeds and returnes true or false.
     KeyExists,
+
+    // List keys in key order, one page at a time
+    //
+    // Requires 3 arguments: key prefix, page size, resume token. The resume
+    // token is nil for the first page. Returns an array of keys and the
+    // resume token for the next page, which is nil once there are no more
+    // keys.
+    ListKeys,
+
+    // Retrieve the keyfile's metadata
+    //
+    // Requires 1 argument: key. Only succeeds if the keyfile exists. Returns
+    // a map with the created, modified and expiry times, length and
+    // revision.
+    GetKeyFileInfo,
+
+    // Change only the keyfile if it hasn't changed since it was last read
+    //
+    // Requires 3 arguments: key, expected, new keyfile. Expected is either
+    // the keyfile's current revision as an integer, or the 8 byte big-endian
+    // 64-bit FNV-1a hash of the keyfile's current contents. Only succeeds if
+    // the keyfile exists and matches expected.
+    ChangeKeyFileIf,
+
+    // List the previous revisions of a keyfile
+    //
+    // Requires 1 argument: key. Always succeeds and returns an array of
+    // keyfile metadata maps, oldest first. Previous revisions are kept after
+    // the keyfile is deleted.
+    ListRevisions,
+
+    // Retrieve a previous revision of a keyfile
+    //
+    // Requires 2 arguments: key, revision. Only succeeds if the revision is
+    // kept in the history.
+    GetRevision,
+
+    // Make a previous revision of a keyfile the current keyfile
+    //
+    // Requires 2 arguments: key, revision. Only succeeds if the revision is
+    // kept in the history. The restored keyfile gets a new revision.
+    RestoreRevision,
+
+    // Write a copy of the database on the server
+    //
+    // Requires 2 arguments: directory, compact. The directory is a relative
+    // path under the server's backup directory, which is created if it
+    // doesn't exist but must not already hold a copy. If compact is true,
+    // free space is left out of the copy. Only succeeds if the server has a
+    // backup directory and the database can be backed up. Writes carry on
+    // while the copy is made.
+    Backup,
+
+    // Create a namespace
+    //
+    // Requires 1 argument: namespace name. Names are 1 to 64 ASCII letters,
+    // digits, underscores or dashes. Only succeeds if the namespace does not
+    // exist. A session uses a namespace by naming it in the argument of the
+    // session's start notification.
+    CreateNamespace,
+
+    // List namespaces
+    //
+    // Requires 0 arguments. Returns an array of namespace names in order.
+    ListNamespaces,
+
+    // Delete a namespace and every keyfile in it
+    //
+    // Requires 1 argument: namespace name. Only succeeds if the namespace
+    // exists.
+    DropNamespace,
+
+    // Get the server's replication progress
+    //
+    // Requires 0 arguments. Returns a map with the server's role ("primary"
+    // or "follower"), the sequence number of the last change it holds
+    // ("sequence"), the sequence number of the latest change on its primary
+    // ("latest"), the number of changes it is behind ("lag"), when it last
+    // caught up with its primary ("synced", nil if it never has) and why it
+    // last failed to copy changes from its primary ("error", nil unless the
+    // failure is ongoing). A primary is never behind.
+    ReplicationStatus,
+
+    // Watch a key for changes
+    //
+    // Requires 2 arguments: key, prefix. If prefix is true, every key
+    // starting with key is watched. Always succeeds and returns true. Until
+    // the session ends, the server then sends a KeyFileCreated,
+    // KeyFileChanged or KeyFileDeleted notification whenever a session
+    // changes a watched keyfile in the same namespace, when expired
+    // keyfiles are purged, and when a follower copies a change from its
+    // primary. Notifications for a session's own changes may arrive before
+    // its response. Purges and copied changes are not reported by servers
+    // that hash keys. A connection that falls too far behind reading its
+    // notifications is closed.
+    Watch,
+
+    // Get the server's read cache counters
+    //
+    // Requires 0 arguments. Returns a map with the number of reads the
+    // cache answered ("hits") and the number it passed on to the store
+    // ("misses"), or nil if the session's store isn't cached. Only the
+    // default namespace is cached.
+    CacheStatus,
 }
 
 
@@ -177,6 +314,41 @@ pub enum AuthError {
 
     // DB error
     DatabaseError,
+
+    // Database has run out of space.
+    DatabaseFull,
+
+    // Database has too many concurrent readers.
+    DatabaseReadersFull,
+
+    // Database files are damaged.
+    DatabaseCorrupted,
+
+    // Database could not be accessed due to a permission or IO error.
+    DatabaseIo,
+
+    // Key or keyfile is larger than the database supports.
+    ValueTooLarge,
+
+    // Keyfile does not have the expected revision or hash.
+    RevisionMismatch,
+
+    // Revision is not kept in the history.
+    RevisionNotFound,
+
+    // Namespace does not exist.
+    NamespaceNotFound,
+
+    // Namespace already exists.
+    NamespaceExists,
+
+    // Keyfile would go over a configured limit. The result is the name of
+    // the limit ("key_length", "keyfile_size", "entries" or "bytes") and its
+    // maximum.
+    QuotaExceeded,
+
+    // Server is read-only, so the request would change the database.
+    ReadOnly,
 }
 
 
@@ -185,6 +357,67 @@ pub enum AuthError {
 pub enum AuthNotice {
     // No more requests will be made
     Done = 2,
+
+    // Sent by the server when a watched keyfile is created. Has 1 argument:
+    // key.
+    KeyFileCreated,
+
+    // Sent by the server when a watched keyfile is changed. Has 1 argument:
+    // key.
+    KeyFileChanged,
+
+    // Sent by the server when a watched keyfile is deleted, or its key is
+    // changed. Has 1 argument: key.
+    KeyFileDeleted,
+}
+
+
+// ===========================================================================
+// Replica requests
+// ===========================================================================
+
+
+// Used with the request rpc message type.
+#[derive(Debug, PartialEq, Clone, CodeConvert)]
+pub enum ReplicaMessage {
+    // Retrieve changes from the change log
+    //
+    // Requires 2 arguments: sequence number, limit. Returns an array of the
+    // sequence number of the latest change and an array of up to limit
+    // changes made after the given sequence number, oldest first. Each
+    // change is an array of its sequence number and the change.
+    GetChanges,
+}
+
+
+// Used with the response rpc message type.
+#[derive(Debug, PartialEq, Clone, CodeConvert)]
+pub enum ReplicaError {
+    Nil,
+
+    // DB error, or the database doesn't keep a change log
+    DatabaseError,
+
+    // Database has too many concurrent readers.
+    DatabaseReadersFull,
+
+    // Database files are damaged.
+    DatabaseCorrupted,
+
+    // Database could not be accessed due to a permission or IO error.
+    DatabaseIo,
+
+    // Some of the changes asked for are no longer kept in the change log,
+    // so the follower has to be started again from a backup.
+    ChangesTrimmed,
+}
+
+
+// Used with the notification rpc message type.
+#[derive(Debug, PartialEq, Clone, CodeConvert)]
+pub enum ReplicaNotice {
+    // No more requests will be made
+    Done = 2,
 }
 
 
@@ -21,7 +21,10 @@
 
 
 pub mod state;
+pub mod replication;
 pub mod rpcservice;
+pub mod watch;
+pub mod worker;
 
 
 // ===========================================================================
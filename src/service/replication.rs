@@ -0,0 +1,356 @@
+// src/service/replication.rs
+// Copyright (C) 2017 authors and contributors (see AUTHORS file)
+//
+// This file is released under the MIT License.
+
+// ===========================================================================
+// Imports
+// ===========================================================================
+
+
+// Stdlib imports
+
+use std::io;
+use std::net::SocketAddr;
+use std::rc::Rc;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+// Third-party imports
+
+use futures::{Future, Sink, Stream, future};
+use futures::future::Loop;
+use rmpv::Value;
+use tokio_core::net::TcpStream;
+use tokio_core::reactor::{Handle, Timeout};
+use tokio_io::AsyncRead;
+
+// Local imports
+
+use network::codec::MsgPackCodec;
+use network::rpc::{Message, RpcResponse};
+use protocol::message::{AuthNotice, ReplicaError, ReplicaMessage,
+                        SessionType};
+use service::state::{KeyFileDB, SessionInfo};
+use service::state::replica::{ReplicaRequest, ReplicaResponse, MAX_CHANGES};
+use service::watch::WatchDB;
+use storage::{timestamp, Change, KeyFileResult};
+use util::Unpoison;
+
+
+// ===========================================================================
+// ReplicaStatus
+// ===========================================================================
+
+
+/// How far a server's store has caught up with the change log of its
+/// primary.
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct ReplicaStatus {
+    // Address of the primary, or None if the server is a primary
+    pub primary: Option<SocketAddr>,
+
+    // Sequence number of the last change held by the server
+    pub sequence: u64,
+
+    // Sequence number of the latest change on the primary, as of the last
+    // time it was asked
+    pub latest: u64,
+
+    // When the server last caught up with its primary, in seconds since the
+    // unix epoch
+    pub synced: Option<u64>,
+
+    // Why the last attempt to copy changes from the primary failed, until
+    // changes are copied again
+    pub error: Option<String>,
+}
+
+
+impl ReplicaStatus {
+    /// Status of a primary whose latest change has the given sequence
+    /// number.
+    pub fn primary(sequence: u64) -> Self
+    {
+        Self {
+            primary: None,
+            sequence: sequence,
+            latest: sequence,
+            synced: None,
+            error: None,
+        }
+    }
+
+    /// Number of changes the server is behind its primary.
+    pub fn lag(&self) -> u64
+    {
+        self.latest.saturating_sub(self.sequence)
+    }
+}
+
+
+// Encoded as the map returned by a ReplicationStatus request
+impl From<ReplicaStatus> for Value {
+    fn from(status: ReplicaStatus) -> Value
+    {
+        let role = match status.primary {
+            Some(_) => "follower",
+            None => "primary",
+        };
+        Value::Map(vec![
+            (Value::from("role"), Value::from(role)),
+            (Value::from("sequence"), Value::from(status.sequence)),
+            (Value::from("latest"), Value::from(status.latest)),
+            (Value::from("lag"), Value::from(status.lag())),
+            (
+                Value::from("synced"),
+                status.synced.map_or(Value::Nil, Value::from),
+            ),
+            (
+                Value::from("error"),
+                status.error.map_or(Value::Nil, Value::from),
+            ),
+        ])
+    }
+}
+
+
+pub type ReplicaStatusDB = Arc<RwLock<ReplicaStatus>>;
+
+
+// ===========================================================================
+// Follower
+// ===========================================================================
+
+
+// Milliseconds to wait before asking a primary for more changes once caught
+// up, and before connecting again after an error
+const POLL_INTERVAL: u64 = 500;
+
+
+type FutureSync = Box<Future<Item = (), Error = io::Error>>;
+
+
+fn invalid_response() -> io::Error
+{
+    let errmsg = "Invalid response from primary";
+    io::Error::new(io::ErrorKind::InvalidData, errmsg)
+}
+
+
+/// Copies the change log of a primary into a store.
+///
+/// The follower asks the primary for every change made after the last one
+/// it applied, using a replica session over the primary's usual transport.
+/// Once caught up, it asks again every half second. If the connection
+/// fails, the error is kept in the follower's status and it connects again.
+pub struct Follower {
+    primary: SocketAddr,
+    db: KeyFileDB,
+    status: ReplicaStatusDB,
+    watchers: Option<WatchDB>,
+}
+
+
+impl Follower {
+    /// Create a follower that applies the changes made on primary to db.
+    ///
+    /// Only stores that can replicate another store can be used.
+    pub fn new(primary: SocketAddr, db: KeyFileDB) -> KeyFileResult<Self>
+    {
+        let sequence = db.read().unpoison().replicated()?;
+        let status = ReplicaStatus {
+            primary: Some(primary),
+            sequence: sequence,
+            latest: sequence,
+            synced: None,
+            error: None,
+        };
+        Ok(Self {
+            primary: primary,
+            db: db,
+            status: Arc::new(RwLock::new(status)),
+            watchers: None,
+        })
+    }
+
+    /// Tell sessions watching keys about the changes applied.
+    pub fn watchers(mut self, watchers: WatchDB) -> Self
+    {
+        self.watchers = Some(watchers);
+        self
+    }
+
+    /// Replication progress, which is updated as changes are applied.
+    pub fn status(&self) -> ReplicaStatusDB
+    {
+        self.status.clone()
+    }
+
+    /// Return a future that follows the primary until the event loop stops.
+    pub fn run(self, handle: &Handle) -> Box<Future<Item = (), Error = ()>>
+    {
+        let follower = Rc::new(self);
+        let handle = handle.clone();
+        let run = future::loop_fn((), move |_| {
+            let (retry, handle) = (follower.clone(), handle.clone());
+            Follower::sync(follower.clone(), &handle).then(move |result| {
+                if let Err(e) = result {
+                    let primary = retry.primary;
+                    let errmsg =
+                        format!("Replication from {} failed: {}", primary, e);
+                    retry.status.write().unpoison().error = Some(errmsg);
+                }
+                let wait = Duration::from_millis(POLL_INTERVAL);
+                future::result(Timeout::new(wait, &handle))
+                    .flatten()
+                    .map(|_| Loop::<(), ()>::Continue(()))
+            })
+        });
+        Box::new(run.map_err(|_| ()))
+    }
+
+    // Connect to the primary and apply its changes until the connection
+    // fails
+    fn sync(follower: Rc<Follower>, handle: &Handle) -> FutureSync
+    {
+        let handle = handle.clone();
+        let start: Message = SessionInfo::new(SessionType::Replica, vec![])
+            .into();
+        let sync = TcpStream::connect(&follower.primary, &handle)
+            .and_then(|socket| {
+                socket.framed(MsgPackCodec).send(start.into())
+            })
+            .and_then(move |framed| {
+                future::loop_fn((framed, 0), move |(framed, msgid)| {
+                    let after = follower.status.read().unpoison().sequence;
+                    let args =
+                        vec![Value::from(after), Value::from(MAX_CHANGES)];
+                    let req: Message = ReplicaRequest::new(
+                        msgid,
+                        ReplicaMessage::GetChanges,
+                        args,
+                    ).into();
+                    let (follower, handle) =
+                        (follower.clone(), handle.clone());
+                    framed
+                        .send(req.into())
+                        .and_then(|framed| {
+                            framed.into_future().map_err(|(e, _)| e)
+                        })
+                        .and_then(move |(value, framed)| {
+                            let caught_up = follower.apply(msgid, value)?;
+                            let wait: FutureSync = if caught_up {
+                                let ms = Duration::from_millis(POLL_INTERVAL);
+                                Box::new(Timeout::new(ms, &handle)?)
+                            } else {
+                                Box::new(future::ok(()))
+                            };
+                            let next = (framed, msgid.wrapping_add(1));
+                            Ok(wait.map(|_| Loop::<(), _>::Continue(next)))
+                        })
+                        .flatten()
+                })
+            });
+        Box::new(sync)
+    }
+
+    // Apply the changes in a GetChanges response, returning true once the
+    // store has caught up with the primary
+    fn apply(&self, msgid: u32, value: Option<Value>) -> io::Result<bool>
+    {
+        let value = value.ok_or_else(|| {
+            let errmsg = "Primary closed the connection";
+            io::Error::new(io::ErrorKind::UnexpectedEof, errmsg)
+        })?;
+        let response = Message::from(value)
+            .and_then(ReplicaResponse::from)
+            .map_err(|_| invalid_response())?;
+        if response.message_id() != msgid {
+            return Err(invalid_response());
+        }
+        if response.error_code() != ReplicaError::Nil {
+            let errmsg =
+                format!("Primary returned {:?}", response.error_code());
+            return Err(io::Error::new(io::ErrorKind::Other, errmsg));
+        }
+
+        // Result is the latest sequence number and the changes after ours
+        let result = response
+            .result()
+            .as_array()
+            .ok_or_else(invalid_response)?;
+        let (latest, changes) = match (result.first(), result.get(1)) {
+            (Some(latest), Some(Value::Array(changes))) => {
+                (latest.as_u64().ok_or_else(invalid_response)?, changes)
+            }
+            _ => return Err(invalid_response()),
+        };
+        let mut batch = Vec::with_capacity(changes.len());
+        for entry in changes {
+            let entry = entry.as_array().ok_or_else(invalid_response)?;
+            match (entry.first().and_then(|v| v.as_u64()), entry.get(1)) {
+                (Some(seq), Some(change)) => {
+                    let change = Change::from_value(change)
+                        .map_err(|_| invalid_response())?;
+                    batch.push((seq, change));
+                }
+                _ => return Err(invalid_response()),
+            }
+        }
+
+        self.db.write().unpoison().replicate(&batch).map_err(|e| {
+            let errmsg = format!("Unable to apply changes: {}", e);
+            io::Error::new(io::ErrorKind::Other, errmsg)
+        })?;
+        self.notify(&batch);
+
+        let mut status = self.status.write().unpoison();
+        if let Some(&(seq, _)) = batch.last() {
+            status.sequence = seq;
+        }
+        status.latest = latest;
+        status.error = None;
+        let caught_up = status.sequence >= latest;
+        if caught_up {
+            status.synced = Some(timestamp());
+        }
+        Ok(caught_up)
+    }
+
+    // Tell watching sessions about applied changes. Stores that hash their
+    // keys can't say which keys changed, so nothing is sent for them.
+    fn notify(&self, batch: &[(u64, Change)])
+    {
+        let watchers = match self.watchers {
+            Some(ref watchers) => watchers,
+            None => return,
+        };
+        if self.db.read().unpoison().hashes_keys() {
+            return;
+        }
+        let mut watchers = watchers.write().unpoison();
+        for (_, change) in batch {
+            let (namespace, notice, key) = match change {
+                Change::Put(ns, key, _, info) => {
+                    let notice = if info.revision == 1 {
+                        AuthNotice::KeyFileCreated
+                    } else {
+                        AuthNotice::KeyFileChanged
+                    };
+                    (ns, notice, key)
+                }
+                Change::Delete(ns, key) => {
+                    (ns, AuthNotice::KeyFileDeleted, key)
+                }
+                _ => continue,
+            };
+            watchers.notify(namespace, notice, key);
+        }
+    }
+}
+
+
+// ===========================================================================
+//
+// ===========================================================================
@@ -12,6 +12,7 @@
 
 use std::cell::Cell;
 use std::io;
+use std::rc::Rc;
 
 // Third-party imports
 
@@ -25,7 +26,8 @@ use tokio_service::Service;
 
 use network::rpc::Message;
 use network::server::{ServerMessage, shutdown};
-use service::state::{KeyFileDB, Start, State};
+use service::state::{KeyFileDB, NamespaceDB, Start, State};
+use service::worker::{FutureJob, WorkerPool};
 
 
 // ===========================================================================
@@ -116,75 +118,117 @@ impl ServiceWithShutdown<ServerMessage> for RpcService<ServerMessage> {
 
 pub struct RpcState<T> {
     control: Option<(Handle, mpsc::Sender<T>)>,
-    state: Cell<State>,
+    state: Rc<Cell<State>>,
+    workers: Option<WorkerPool>,
 }
 
 
 impl RpcState<ServerMessage> {
     pub fn new(db: KeyFileDB) -> Self
+    {
+        Self::from_start(Start::new(db))
+    }
+
+    /// Create an RpcState whose sessions can use namespaces other than the
+    /// default one held in db.
+    pub fn with_namespaces(db: KeyFileDB, namespaces: NamespaceDB) -> Self
+    {
+        Self::from_start(Start::new(db).namespaces(namespaces))
+    }
+
+    /// Create an RpcState whose sessions begin in the given start state.
+    pub fn from_start(start: Start) -> Self
     {
         Self {
             control: None,
-            state: Cell::new(State::Start(Box::new(Start::new(db)))),
+            state: Rc::new(Cell::new(State::Start(Box::new(start)))),
+            workers: None,
         }
     }
 
+    /// Change state on the given workers, rather than on the thread
+    /// processing messages.
+    pub fn workers(mut self, pool: WorkerPool) -> Self
+    {
+        self.workers = Some(pool);
+        self
+    }
+
     pub fn process_message(&mut self, msg: Message)
-        -> BoxFuture<Option<Value>, io::Error>
+        -> FutureJob<Option<Value>>
     {
-        // Change state
+        // The state is taken until the message has been processed
         let state = self.state.replace(State::Nil);
-        let ret = match state {
-            State::Nil | State::BootEnd | State::AuthEnd => unreachable!(),
-            State::Start(s) => {
-                match s.change(msg) {
-                    // Send Value::Nil to signify we are done here but
-                    // connection should stay alive
-                    Ok(newstate) => {
-                        self.state.set(newstate);
-                        Some(Value::Nil)
-                    }
-
-                    // Close the connection if an error happened
-                    Err(e) => {
-                        eprintln!(
-                            "Error happened in process_message {:?}",
-                            e
-                        );
-                        None
-                    }
+        let result = match self.workers {
+            Some(ref pool) => pool.run(move || change_state(state, msg)),
+            None => Box::new(future::ok(change_state(state, msg))),
+        };
+        let next = self.state.clone();
+        Box::new(result.map(move |(state, ret)| {
+            next.set(state);
+            ret
+        }))
+    }
+}
+
+
+// Change state with msg, returning the new state and the value to send
+fn change_state(state: State, msg: Message) -> (State, Option<Value>)
+{
+    match state {
+        State::Nil |
+        State::BootEnd |
+        State::AuthEnd |
+        State::ReplicaEnd => unreachable!(),
+        State::Start(s) => {
+            match s.change(msg) {
+                // Send Value::Nil to signify we are done here but
+                // connection should stay alive
+                Ok(newstate) => (newstate, Some(Value::Nil)),
+
+                // Close the connection if an error happened
+                Err(e) => {
+                    eprintln!("Error happened in process_message {:?}", e);
+                    (State::Nil, None)
                 }
             }
-            State::ProcessBootMessage(s, _) => {
-                match s.change(msg) {
-                    Ok(State::ProcessBootMessage(s, Some(resp))) => {
-                        let newstate = State::ProcessBootMessage(s, None);
-                        self.state.set(newstate);
-                        let msg: Message = resp.into();
-                        let val: Value = msg.into();
-                        Some(val)
-                    }
-                    Ok(State::BootEnd) |
-                    Err(_) => None,
-                    Ok(_) => unreachable!(),
+        }
+        State::ProcessBootMessage(s, _) => {
+            match s.change(msg) {
+                Ok(State::ProcessBootMessage(s, Some(resp))) => {
+                    let newstate = State::ProcessBootMessage(s, None);
+                    let msg: Message = resp.into();
+                    (newstate, Some(msg.into()))
                 }
+                Ok(State::BootEnd) |
+                Err(_) => (State::Nil, None),
+                Ok(_) => unreachable!(),
             }
-            State::ProcessAuthMessage(s, _) => {
-                match s.change(msg) {
-                    Ok(State::ProcessAuthMessage(s, Some(resp))) => {
-                        let newstate = State::ProcessAuthMessage(s, None);
-                        self.state.set(newstate);
-                        let msg: Message = resp.into();
-                        let val: Value = msg.into();
-                        Some(val)
-                    }
-                    Ok(State::AuthEnd) |
-                    Err(_) => None,
-                    Ok(_) => unreachable!(),
+        }
+        State::ProcessAuthMessage(s, _) => {
+            match s.change(msg) {
+                Ok(State::ProcessAuthMessage(s, Some(resp))) => {
+                    let newstate = State::ProcessAuthMessage(s, None);
+                    let msg: Message = resp.into();
+                    (newstate, Some(msg.into()))
                 }
+                Ok(State::AuthEnd) |
+                Err(_) => (State::Nil, None),
+                Ok(_) => unreachable!(),
             }
-        };
-        future::ok::<Option<Value>, io::Error>(ret).boxed()
+        }
+        State::ProcessReplicaMessage(s, _) => {
+            match s.change(msg) {
+                Ok(State::ProcessReplicaMessage(s, Some(resp))) => {
+                    let newstate = State::ProcessReplicaMessage(s, None);
+                    let msg: Message = resp.into();
+                    (newstate, Some(msg.into()))
+                }
+                Ok(State::ReplicaEnd) |
+                Err(_) => (State::Nil, None),
+                Ok(_) => unreachable!(),
+            }
+        }
     }
 }
 
@@ -225,12 +269,12 @@ impl ServiceWithShutdown<ServerMessage> for RpcState<ServerMessage> {
 mod tests {
     // Stdlib imports
 
-    use std::rc::Rc;
-    use std::sync::RwLock;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
 
     // Third-party imports
 
-    use futures::Async;
+    use futures::{Async, Future};
     use rmpv::Value;
 
     // Local imports
@@ -243,6 +287,7 @@ mod tests {
     use service::state::{SessionInfo, State};
     use service::state::auth::{AuthInfo, AuthRequest, AuthResponse};
     use service::state::boot::{BootInfo, BootRequest, BootResponse};
+    use service::worker::WorkerPool;
     use storage::{KeyFileResult, KeyFileStore};
 
     type CustomService = RpcState<ServerMessage>;
@@ -278,7 +323,7 @@ mod tests {
                 unreachable!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "42".to_string().into_bytes();
         let mut messages: Vec<Message> =
@@ -331,7 +376,7 @@ mod tests {
         assert_eq!(result.pop().unwrap(), Some(Value::Nil));
 
         // Service state is State::Nil
-        match *service.state.get_mut() {
+        match service.state.replace(State::Nil) {
             State::Nil => assert!(true),
             _ => assert!(false),
         }
@@ -368,7 +413,7 @@ mod tests {
                 unreachable!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "42".to_string().into_bytes();
         let mut messages: Vec<Message> =
@@ -421,11 +466,74 @@ mod tests {
         assert_eq!(result.pop().unwrap(), Some(Value::Nil));
 
         // Service state is State::Nil
-        match *service.state.get_mut() {
+        match service.state.replace(State::Nil) {
             State::Nil => assert!(true),
             _ => assert!(false),
         }
     }
+
+    #[test]
+    fn rpcstate_process_message_workers()
+    {
+        // -----------------------------------------------------------
+        // GIVEN
+        // A fake KeyFileDB whose keys only exist on worker threads and
+        // a SessionInfo message with code SessionType::Auth and
+        // a AuthRequest message with code AuthRequest::KeyExists and
+        // an RpcState<ServerMessage> instance using a pool of workers
+        // -----------------------------------------------------------
+        struct FakeDB;
+        impl KeyFileStore for FakeDB {
+            fn exists(&self, _k: &Vec<u8>) -> bool
+            {
+                let thread = thread::current();
+                thread.name().unwrap_or("").starts_with("safesec-worker-")
+            }
+            fn get(&self, _k: &Vec<u8>) -> KeyFileResult<Vec<u8>>
+            {
+                unreachable!()
+            }
+            fn set(&mut self, _k: &Vec<u8>, _file: &Vec<u8>)
+                -> KeyFileResult<()>
+            {
+                unreachable!()
+            }
+            fn delete(&mut self, _k: &Vec<u8>) -> KeyFileResult<()>
+            {
+                unreachable!()
+            }
+        }
+        let db = Arc::new(RwLock::new(FakeDB));
+
+        let key = vec![Value::from("42".to_string().into_bytes())];
+        let start = SessionInfo::new(SessionType::Auth, vec![Value::Nil]);
+        let req = AuthRequest::new(42, AuthMessage::KeyExists, key);
+        let pool = WorkerPool::new(1).unwrap();
+        let mut service: CustomService = RpcState::new(db).workers(pool);
+
+        // ----------------------------------------------
+        // WHEN
+        // RpcState.process_message() is called with
+        // each message and its future is waited on
+        // ----------------------------------------------
+        let started = service.process_message(start.into()).wait();
+        let result = service.process_message(req.into()).wait();
+
+        // ----------------------------------------------------------
+        // THEN
+        // The session is started and
+        // the key exists, so the request was run on a worker thread
+        // ----------------------------------------------------------
+        assert_eq!(started.unwrap(), Some(Value::Nil));
+        let msg = Message::from(result.unwrap().unwrap()).unwrap();
+        let resp = AuthResponse::from(msg).unwrap();
+        assert_eq!(resp.message_id(), 42);
+        assert_eq!(resp.result(), &Value::Boolean(true));
+        match service.state.replace(State::Nil) {
+            State::ProcessAuthMessage(_, None) => {}
+            _ => panic!("Expected the auth session to carry on"),
+        }
+    }
 }
 
 
@@ -15,18 +15,26 @@
 
 // Stdlib imports
 
+use std::path::{Component, Path, PathBuf};
+use std::str;
+
 // Third-party imports
 
 use rmpv::Value;
 
 // Local imports
 
-use super::{KeyFileDB, SessionState, State, StateResult};
-use network::rpc::{Message, MessageType, NotificationMessage,
+use super::{namespace_arg, KeyFileDB, NamespaceDB, SessionState, State,
+            StateResult};
+use network::rpc::{CodeConvert, Message, MessageType, NotificationMessage,
                    RequestMessage, ResponseMessage, RpcMessage, RpcNotice,
-                   RpcRequest};
+                   RpcRequest, RpcResponse};
 use protocol::message::{AuthError, AuthMessage, AuthNotice, ProtocolError};
-use storage::KeyFileError;
+use service::replication::{ReplicaStatus, ReplicaStatusDB};
+use service::watch::Watcher;
+use storage::{timestamp, KeyFileCheck, KeyFileError, KeyFileResult,
+              KeyFileStore, Quota};
+use util::{Unpoison, Zeroizing};
 
 
 // ===========================================================================
@@ -49,14 +57,73 @@ pub type AuthInfo = NotificationMessage<AuthNotice>;
 
 
 pub struct ProcessAuthMessage {
+    backup_dir: Option<PathBuf>,
     db: KeyFileDB,
+    namespaces: Option<NamespaceDB>,
+    quota: Quota,
+    read_only: bool,
+    replica: Option<ReplicaStatusDB>,
+    watcher: Option<Watcher>,
 }
 
 
 impl ProcessAuthMessage {
     pub fn new(db: KeyFileDB) -> Self
     {
-        Self { db: db }
+        Self {
+            backup_dir: None,
+            db: db,
+            namespaces: None,
+            quota: Quota::new(),
+            read_only: false,
+            replica: None,
+            watcher: None,
+        }
+    }
+
+    /// Handle backup requests, writing each copy into a directory under
+    /// dir.
+    pub fn backup_dir(mut self, dir: PathBuf) -> Self
+    {
+        self.backup_dir = Some(dir);
+        self
+    }
+
+    /// Handle namespace requests using the given namespaces.
+    pub fn namespaces(mut self, namespaces: NamespaceDB) -> Self
+    {
+        self.namespaces = Some(namespaces);
+        self
+    }
+
+    /// Refuse keyfile writes that would go over the quota.
+    pub fn quota(mut self, quota: Quota) -> Self
+    {
+        self.quota = quota;
+        self
+    }
+
+    /// Refuse every request that would change the database.
+    pub fn read_only(mut self, val: bool) -> Self
+    {
+        self.read_only = val;
+        self
+    }
+
+    /// Answer replication status requests with the given progress of a
+    /// follower.
+    pub fn replica(mut self, status: ReplicaStatusDB) -> Self
+    {
+        self.replica = Some(status);
+        self
+    }
+
+    /// Handle watch requests, and report the keyfiles changed by the
+    /// session, using the given watcher.
+    pub fn watcher(mut self, watcher: Watcher) -> Self
+    {
+        self.watcher = Some(watcher);
+        self
     }
 }
 
@@ -69,11 +136,55 @@ impl SessionState for ProcessAuthMessage {
             // If the message is a request, process as an AuthMethod and change
             // state back to ProcessAuthMessage
             MessageType::Request => {
-                let response = ProcessAuthRequest.run(self.db.clone(), m)?;
-                Ok(State::ProcessAuthMessage(
-                    Box::new(Self { db: self.db }),
-                    Some(response),
-                ))
+                let code = request_code(&m);
+                let response = match self.namespaces {
+                    _ if self.read_only && is_write_request(&code) => {
+                        let id = AuthRequest::from(m).unwrap().message_id();
+                        let result = Value::Boolean(false);
+                        AuthResponse::new(id, AuthError::ReadOnly, result)
+                    }
+                    Some(ref namespaces) if is_namespace_request(&code) => {
+                        ProcessNamespaceRequest.run(namespaces.clone(), m)?
+                    }
+                    _ if self.replica.is_some() &&
+                        is_status_request(&code) => {
+                        let id = AuthRequest::from(m).unwrap().message_id();
+                        let status = self.replica.as_ref().unwrap();
+                        let status = status.read().unpoison().clone();
+                        let result = Value::from(status);
+                        AuthResponse::new(id, AuthError::Nil, result)
+                    }
+                    _ if self.watcher.is_some() &&
+                        is_watch_request(&code) => {
+                        let watcher = self.watcher.as_ref().unwrap();
+                        ProcessWatchRequest.run(watcher, m)?
+                    }
+                    _ if self.backup_dir.is_some() &&
+                        is_backup_request(&code) => {
+                        let dir = self.backup_dir.as_ref().unwrap();
+                        ProcessBackupRequest.run(dir, self.db.clone(), m)?
+                    }
+                    _ => {
+                        let changes = match self.watcher {
+                            Some(_) => changed_keys(&code, &m),
+                            None => Vec::new(),
+                        };
+                        let db = self.db.clone();
+                        let response =
+                            ProcessAuthRequest.run(db, &self.quota, m)?;
+
+                        // Tell watching sessions about the change
+                        if response.error_code() == AuthError::Nil {
+                            if let Some(ref watcher) = self.watcher {
+                                for (notice, key) in changes {
+                                    watcher.notify(notice, &key);
+                                }
+                            }
+                        }
+                        response
+                    }
+                };
+                Ok(State::ProcessAuthMessage(self, Some(response)))
             }
 
             // If the message is a done notification, change state to BootEnd
@@ -83,6 +194,13 @@ impl SessionState for ProcessAuthMessage {
                 })?;
                 match notice.message_code() {
                     AuthNotice::Done => Ok(State::AuthEnd),
+
+                    // Only the server reports changes
+                    AuthNotice::KeyFileCreated |
+                    AuthNotice::KeyFileChanged |
+                    AuthNotice::KeyFileDeleted => {
+                        Err(ProtocolError::UnexpectedMessage)
+                    }
                 }
             }
 
@@ -97,22 +215,64 @@ struct ProcessAuthRequest;
 
 
 impl ProcessAuthRequest {
-    fn run(&self, db: KeyFileDB, m: Message) -> StateResult<AuthResponse>
+    fn run(&self, db: KeyFileDB, quota: &Quota, m: Message)
+        -> StateResult<AuthResponse>
     {
         let req = AuthRequest::from(m).unwrap();
         match req.message_code() {
             AuthMessage::KeyExists => self.req_key_exists(req, db),
             AuthMessage::GetKeyFile => self.req_get_keyfile(req, db),
-            AuthMessage::CreateKeyFile => self.req_create_keyfile(req, db),
-            AuthMessage::ChangeKeyFile => self.req_change_keyfile(req, db),
+            AuthMessage::CreateKeyFile => {
+                self.req_create_keyfile(req, db, quota)
+            }
+            AuthMessage::ChangeKeyFile => {
+                self.req_change_keyfile(req, db, quota)
+            }
             AuthMessage::DeleteKeyFile => self.req_del_keyfile(req, db),
-            AuthMessage::ChangeKey => self.req_change_key(req, db),
-            AuthMessage::ReplaceKeyFile => self.req_replace_keyfile(req, db),
+            AuthMessage::ChangeKey => self.req_change_key(req, db, quota),
+            AuthMessage::ReplaceKeyFile => {
+                self.req_replace_keyfile(req, db, quota)
+            }
+            AuthMessage::ListKeys => self.req_list_keys(req, db),
+            AuthMessage::GetKeyFileInfo => {
+                self.req_get_keyfile_info(req, db)
+            }
+            AuthMessage::ChangeKeyFileIf => {
+                self.req_change_keyfile_if(req, db, quota)
+            }
+            AuthMessage::ListRevisions => self.req_list_revisions(req, db),
+            AuthMessage::GetRevision => self.req_get_revision(req, db),
+            AuthMessage::RestoreRevision => {
+                self.req_restore_revision(req, db, quota)
+            }
+            AuthMessage::ReplicationStatus => {
+                self.req_replication_status(req, db)
+            }
+            AuthMessage::CacheStatus => self.req_cache_status(req, db),
+
+            // Only handled here when the server has no namespaces
+            AuthMessage::CreateNamespace |
+            AuthMessage::ListNamespaces |
+            AuthMessage::DropNamespace => {
+                Ok(self._error_response(&req, KeyFileError::Other))
+            }
+
+            // Only handled here when the server can't send notifications
+            AuthMessage::Watch => {
+                Ok(self._error_response(&req, KeyFileError::Other))
+            }
+
+            // Only handled here when the server has no backup directory
+            AuthMessage::Backup => {
+                Ok(self._error_response(&req, KeyFileError::Other))
+            }
         }
     }
 
+    // Arguments are copied into buffers that are zeroized once the request
+    // has been handled
     fn _check_message(&self, req: &AuthRequest, numargs: usize)
-        -> StateResult<Vec<Vec<u8>>>
+        -> StateResult<Vec<Zeroizing>>
     {
         // Get message arguments
         let args = req.message_args();
@@ -123,18 +283,112 @@ impl ProcessAuthRequest {
         }
 
         // All arguments must be binary data
-        let mut ret: Vec<Vec<u8>> = Vec::new();
+        let mut ret: Vec<Zeroizing> = Vec::new();
         for i in 0..numargs {
             let val = &args[i];
             if !val.is_bin() {
                 return Err(ProtocolError::InvalidRequest);
             }
             let a: Vec<u8> = Vec::from(val.as_slice().unwrap());
-            ret.push(a);
+            ret.push(Zeroizing::new(a));
         }
         Ok(ret)
     }
 
+    // Get the key and revision arguments of a revision request
+    fn _check_revision_message(&self, req: &AuthRequest)
+        -> StateResult<(Vec<u8>, u64)>
+    {
+        let args = req.message_args();
+        if args.len() != 2 {
+            return Err(ProtocolError::InvalidRequestArgs);
+        }
+        if !args[0].is_bin() {
+            return Err(ProtocolError::InvalidRequest);
+        }
+        let key = Vec::from(args[0].as_slice().unwrap());
+        match args[1].as_u64() {
+            Some(rev) => Ok((key, rev)),
+            None => Err(ProtocolError::InvalidRequest),
+        }
+    }
+
+    // Get the key and keyfile, and the expiry time from an optional trailing
+    // time to live in seconds. A nil time to live means the keyfile never
+    // expires.
+    fn _check_expiry_message(&self, req: &AuthRequest)
+        -> StateResult<(Vec<u8>, Zeroizing, Option<u64>)>
+    {
+        let args = req.message_args();
+        if args.len() != 2 && args.len() != 3 {
+            return Err(ProtocolError::InvalidRequestArgs);
+        }
+        if !args[0].is_bin() || !args[1].is_bin() {
+            return Err(ProtocolError::InvalidRequest);
+        }
+        let key = Vec::from(args[0].as_slice().unwrap());
+        let keyfile = Vec::from(args[1].as_slice().unwrap());
+        let keyfile = Zeroizing::new(keyfile);
+        let expires = match args.get(2) {
+            None | Some(&Value::Nil) => None,
+            Some(ttl) => {
+                match ttl.as_u64() {
+                    Some(ttl) => Some(timestamp().saturating_add(ttl)),
+                    None => return Err(ProtocolError::InvalidRequest),
+                }
+            }
+        };
+        Ok((key, keyfile, expires))
+    }
+
+    fn _error_response(&self, req: &AuthRequest, err: KeyFileError)
+        -> AuthResponse
+    {
+        let (code, result) = match err {
+            KeyFileError::Key(k) => {
+                (AuthError::KeyFileNotFound, Value::from(k))
+            }
+            KeyFileError::MapFull => {
+                (AuthError::DatabaseFull, Value::Boolean(false))
+            }
+            KeyFileError::ReadersFull => {
+                (AuthError::DatabaseReadersFull, Value::Boolean(false))
+            }
+            KeyFileError::Corrupted => {
+                (AuthError::DatabaseCorrupted, Value::Boolean(false))
+            }
+            KeyFileError::Io => (AuthError::DatabaseIo, Value::Boolean(false)),
+            KeyFileError::ValueTooLarge => {
+                (AuthError::ValueTooLarge, Value::Boolean(false))
+            }
+            KeyFileError::Mismatch(k) => {
+                (AuthError::RevisionMismatch, Value::from(k))
+            }
+            KeyFileError::Revision(k, rev) => {
+                let result = vec![Value::from(k), Value::from(rev)];
+                (AuthError::RevisionNotFound, Value::Array(result))
+            }
+            KeyFileError::Namespace(name) => {
+                (AuthError::NamespaceNotFound, Value::from(name))
+            }
+            KeyFileError::NamespaceExists(name) => {
+                (AuthError::NamespaceExists, Value::from(name))
+            }
+            KeyFileError::Quota(limit, max) => {
+                let name = Value::from(limit.name());
+                let result = vec![name, Value::from(max)];
+                (AuthError::QuotaExceeded, Value::Array(result))
+            }
+            KeyFileError::Schema(_) |
+            KeyFileError::KeySalt |
+            KeyFileError::Trimmed |
+            KeyFileError::Other => {
+                (AuthError::DatabaseError, Value::Boolean(false))
+            }
+        };
+        AuthResponse::new(req.message_id(), code, result)
+    }
+
     fn req_key_exists(&self, req: AuthRequest, db: KeyFileDB)
         -> StateResult<AuthResponse>
     {
@@ -143,7 +397,7 @@ impl ProcessAuthRequest {
 
         // Get result, dropping the db lock as soon as possible
         let result = {
-            let db = db.read().unwrap();
+            let db = db.read().unpoison();
             Value::Boolean(db.exists(key))
         };
 
@@ -161,7 +415,7 @@ impl ProcessAuthRequest {
 
         // Get keyfile, dropping the db lock as soon as possible
         let keyfile = {
-            let db = db.read().unwrap();
+            let db = db.read().unpoison();
             db.get(key)
         };
 
@@ -177,33 +431,50 @@ impl ProcessAuthRequest {
             }
 
             // Create error response
-            Err(KeyFileError::Key(k)) => {
+            Err(e) => Ok(self._error_response(&req, e)),
+        }
+    }
+
+    fn req_get_keyfile_info(&self, req: AuthRequest, db: KeyFileDB)
+        -> StateResult<AuthResponse>
+    {
+        // Get key
+        let key = &self._check_message(&req, 1)?[0];
+
+        // Get metadata, dropping the db lock as soon as possible
+        let info = {
+            let db = db.read().unpoison();
+            db.info(key)
+        };
+
+        match info {
+            // Create response
+            Ok(i) => {
                 let response = AuthResponse::new(
                     req.message_id(),
-                    AuthError::KeyFileNotFound,
-                    Value::from(k),
+                    AuthError::Nil,
+                    Value::from(i),
                 );
                 Ok(response)
             }
 
-            // TODO: handle other errors that may be raised (eg from lmdb backend)
-            Err(KeyFileError::Other) => unimplemented!(),
+            // Create error response
+            Err(e) => Ok(self._error_response(&req, e)),
         }
     }
 
-    fn req_create_keyfile(&self, req: AuthRequest, db: KeyFileDB)
-        -> StateResult<AuthResponse>
+    fn req_create_keyfile(
+        &self, req: AuthRequest, db: KeyFileDB, quota: &Quota
+    ) -> StateResult<AuthResponse>
     {
         // Get args
-        let args = self._check_message(&req, 2)?;
-        let key = &args[0];
-        let keyfile = &args[1];
+        let (key, keyfile, expires) = self._check_expiry_message(&req)?;
 
         {
-            let mut db = db.write().unwrap();
+            let mut db = db.write().unpoison();
 
             // Return an error if keyfile exists
-            if db.exists(key) {
+            if db.exists(&key) {
                 let response = AuthResponse::new(
                     req.message_id(),
                     AuthError::KeyFileExists,
@@ -212,8 +483,11 @@ impl ProcessAuthRequest {
                 return Ok(response);
             }
 
-            // Create keyfile
-            match db.set(key, keyfile) {
+            // Create keyfile if it fits in the quota
+            let created = quota
+                .check(&*db, &key, &keyfile, None)
+                .and_then(|_| set_keyfile(&mut *db, &key, &keyfile, expires));
+            match created {
                 Ok(_) => {
                     let response = AuthResponse::new(
                         req.message_id(),
@@ -223,32 +497,23 @@ impl ProcessAuthRequest {
                     Ok(response)
                 }
                 // Create error response
-                Err(KeyFileError::Other) => {
-                    let response = AuthResponse::new(
-                        req.message_id(),
-                        AuthError::DatabaseError,
-                        Value::Boolean(false),
-                    );
-                    Ok(response)
-                }
-                Err(_) => unreachable!(),
+                Err(e) => Ok(self._error_response(&req, e)),
             }
         }
     }
 
-    fn req_change_keyfile(&self, req: AuthRequest, db: KeyFileDB)
-        -> StateResult<AuthResponse>
+    fn req_change_keyfile(
+        &self, req: AuthRequest, db: KeyFileDB, quota: &Quota
+    ) -> StateResult<AuthResponse>
     {
         // Get args
-        let args = self._check_message(&req, 2)?;
-        let key = &args[0];
-        let new_keyfile = &args[1];
+        let (key, new_keyfile, expires) = self._check_expiry_message(&req)?;
 
         {
-            let mut db = db.write().unwrap();
+            let mut db = db.write().unpoison();
 
             // Return an error if key does not exist
-            if !db.exists(key) {
+            if !db.exists(&key) {
                 let response = AuthResponse::new(
                     req.message_id(),
                     AuthError::KeyFileNotFound,
@@ -257,8 +522,13 @@ impl ProcessAuthRequest {
                 return Ok(response);
             }
 
-            // Change keyfile
-            match db.set(key, new_keyfile) {
+            // Change keyfile if the new keyfile fits in the quota
+            let changed = quota
+                .check(&*db, &key, &new_keyfile, Some(&key))
+                .and_then(|_| {
+                    set_keyfile(&mut *db, &key, &new_keyfile, expires)
+                });
+            match changed {
                 Ok(_) => {
                     let response = AuthResponse::new(
                         req.message_id(),
@@ -268,16 +538,64 @@ impl ProcessAuthRequest {
                     Ok(response)
                 }
                 // Create error response
-                Err(KeyFileError::Other) => {
-                    let response = AuthResponse::new(
-                        req.message_id(),
-                        AuthError::DatabaseError,
-                        Value::Boolean(false),
-                    );
-                    Ok(response)
+                Err(e) => Ok(self._error_response(&req, e)),
+            }
+        }
+    }
+
+    fn req_change_keyfile_if(
+        &self, req: AuthRequest, db: KeyFileDB, quota: &Quota
+    ) -> StateResult<AuthResponse>
+    {
+        // Get args
+        let args = req.message_args();
+        if args.len() != 3 {
+            return Err(ProtocolError::InvalidRequestArgs);
+        }
+        if !args[0].is_bin() || !args[2].is_bin() {
+            return Err(ProtocolError::InvalidRequest);
+        }
+        let key = args[0].as_slice().unwrap();
+        let new_keyfile = args[2].as_slice().unwrap();
+        let check = match args[1] {
+            Value::Binary(ref h) if h.len() == 8 => {
+                let hash = h.iter().fold(0, |n, &b| (n << 8) | u64::from(b));
+                KeyFileCheck::Hash(hash)
+            }
+            ref v => {
+                match v.as_u64() {
+                    Some(rev) => KeyFileCheck::Revision(rev),
+                    None => return Err(ProtocolError::InvalidRequest),
+                }
+            }
+        };
+
+        // Check the keyfile and change it in a single transaction, returning
+        // an error response if the new keyfile doesn't fit in the quota, or
+        // the keyfile doesn't exist or doesn't match
+        let result = {
+            let mut db = db.write().unpoison();
+            match quota.check(&*db, key, new_keyfile, Some(&key.to_vec())) {
+                Ok(()) => {
+                    let mut txn = db.begin();
+                    txn.check(key, check).set(key, new_keyfile);
+                    db.commit(txn)
                 }
-                Err(_) => unreachable!(),
+                Err(e) => Err(e),
+            }
+        };
+
+        match result {
+            Ok(()) => {
+                let response = AuthResponse::new(
+                    req.message_id(),
+                    AuthError::Nil,
+                    Value::Boolean(true),
+                );
+                Ok(response)
             }
+            // Create error response
+            Err(e) => Ok(self._error_response(&req, e)),
         }
     }
 
@@ -289,7 +607,7 @@ impl ProcessAuthRequest {
         let key = &args[0];
 
         {
-            let mut db = db.write().unwrap();
+            let mut db = db.write().unpoison();
 
             // Return an error if key does not exist
             if !db.exists(key) {
@@ -312,21 +630,14 @@ impl ProcessAuthRequest {
                     Ok(response)
                 }
                 // Create error response
-                Err(KeyFileError::Other) => {
-                    let response = AuthResponse::new(
-                        req.message_id(),
-                        AuthError::DatabaseError,
-                        Value::Boolean(false),
-                    );
-                    Ok(response)
-                }
-                Err(_) => unreachable!(),
+                Err(e) => Ok(self._error_response(&req, e)),
             }
         }
     }
 
-    fn req_change_key(&self, req: AuthRequest, db: KeyFileDB)
-        -> StateResult<AuthResponse>
+    fn req_change_key(
+        &self, req: AuthRequest, db: KeyFileDB, quota: &Quota
+    ) -> StateResult<AuthResponse>
     {
         // Get args
         let args = self._check_message(&req, 2)?;
@@ -338,7 +649,7 @@ impl ProcessAuthRequest {
         };
 
         // Get exclusive lock to database
-        let mut db = db.write().unwrap();
+        let mut db = db.write().unpoison();
 
         // Return error response if newkey already exists
         if db.exists(newkey) {
@@ -348,50 +659,41 @@ impl ProcessAuthRequest {
             );
         }
 
-        let keyfile: Vec<u8> = match db.get(oldkey) {
+        let keyfile = match db.get(oldkey) {
             // Get keyfile for oldkey
-            Ok(kf) => kf,
-
-            // Return an error response if oldkey does not exist
-            Err(KeyFileError::Key(k)) => {
-                return mkresponse(AuthError::KeyFileNotFound, Value::from(k))
-            }
+            Ok(kf) => Zeroizing::new(kf),
 
-            // Any other error is a db error response
-            Err(KeyFileError::Other) => {
-                return mkresponse(
-                    AuthError::DatabaseError,
-                    Value::Boolean(false),
-                )
-            }
+            // Return an error response if oldkey does not exist or on any
+            // other db error
+            Err(e) => return Ok(self._error_response(&req, e)),
         };
 
-        // Delete the oldkey, send error response on db error
-        let result = db.delete(oldkey);
-        if let Err(KeyFileError::Other) = result {
-            return mkresponse(
-                AuthError::DatabaseError,
-                Value::Boolean(false),
-            );
-        } else if result.is_err() {
-            unreachable!()
+        // Return error response if newkey doesn't fit in the quota
+        if let Err(e) = quota.check(&*db, newkey, &keyfile, Some(oldkey)) {
+            return Ok(self._error_response(&req, e));
         }
 
-        // Re-add the keyfile with the new key
-        match db.set(newkey, &keyfile) {
+        // Move the keyfile from oldkey to newkey in a single transaction so
+        // that the keyfile is never lost if either step fails. The keyfile
+        // keeps its expiry time.
+        let expires = expiry(&*db, oldkey);
+        let mut txn = db.begin();
+        txn.delete(oldkey).set(newkey, &keyfile);
+        if expires.is_some() {
+            txn.expire(newkey, expires);
+        }
+        match db.commit(txn) {
             Ok(()) => {
                 mkresponse(AuthError::Nil, Value::Boolean(true))
             }
             // Create error response
-            Err(KeyFileError::Other) => {
-                mkresponse(AuthError::DatabaseError, Value::Boolean(false))
-            }
-            Err(_) => unreachable!(),
+            Err(e) => Ok(self._error_response(&req, e)),
         }
     }
 
-    fn req_replace_keyfile(&self, req: AuthRequest, db: KeyFileDB)
-        -> StateResult<AuthResponse>
+    fn req_replace_keyfile(
+        &self, req: AuthRequest, db: KeyFileDB, quota: &Quota
+    ) -> StateResult<AuthResponse>
     {
         // Get args
         let args = self._check_message(&req, 3)?;
@@ -404,7 +706,7 @@ impl ProcessAuthRequest {
         };
 
         // Get exclusive lock to database
-        let mut db = db.write().unwrap();
+        let mut db = db.write().unpoison();
 
         // Return error response if newkey already exists
         if db.exists(newkey) {
@@ -414,113 +716,583 @@ impl ProcessAuthRequest {
             );
         }
 
-        // Delete oldkey, return error response if oldkey doesn't exist
-        match db.delete(oldkey) {
-            Err(KeyFileError::Other) => {
-                return mkresponse(
-                    AuthError::DatabaseError,
-                    Value::Boolean(false),
-                )
-            }
-            Err(KeyFileError::Key(k)) => {
-                return mkresponse(AuthError::KeyFileNotFound, Value::from(k))
-            }
-            Ok(()) => {}
+        // Return error response if the new keyfile doesn't fit in the quota
+        if let Err(e) = quota.check(&*db, newkey, newkeyfile, Some(oldkey)) {
+            return Ok(self._error_response(&req, e));
         }
 
-        // Add the new keyfile with the new key
-        match db.set(newkey, &newkeyfile) {
+        // Delete oldkey and add the new keyfile with the new key in a single
+        // transaction, returning an error response if oldkey doesn't exist.
+        // The new keyfile expires when the old one would have.
+        let expires = expiry(&*db, oldkey);
+        let mut txn = db.begin();
+        txn.delete(oldkey).set(newkey, newkeyfile);
+        if expires.is_some() {
+            txn.expire(newkey, expires);
+        }
+        match db.commit(txn) {
             Ok(()) => {
                 mkresponse(AuthError::Nil, Value::Boolean(true))
             }
             // Create error response
-            Err(KeyFileError::Other) => {
-                mkresponse(AuthError::DatabaseError, Value::Boolean(false))
-            }
-            Err(_) => unreachable!(),
+            Err(e) => Ok(self._error_response(&req, e)),
         }
     }
-}
 
+    fn req_list_keys(&self, req: AuthRequest, db: KeyFileDB)
+        -> StateResult<AuthResponse>
+    {
+        // Get args
+        let args = req.message_args();
+        if args.len() != 3 {
+            return Err(ProtocolError::InvalidRequestArgs);
+        }
+        if !args[0].is_bin() {
+            return Err(ProtocolError::InvalidRequest);
+        }
+        let prefix = args[0].as_slice().unwrap();
+        let pagesize = match args[1].as_u64() {
+            Some(n) if n > 0 => n as usize,
+            _ => return Err(ProtocolError::InvalidRequest),
+        };
+        let token = match args[2] {
+            Value::Nil => None,
+            Value::Binary(ref t) => Some(&t[..]),
+            _ => return Err(ProtocolError::InvalidRequest),
+        };
 
-// ===========================================================================
-// Tests
-// ===========================================================================
+        // Get one more key than requested to find out if there is another
+        // page, dropping the db lock as soon as possible
+        let keys = {
+            let db = db.read().unpoison();
+            db.iter_prefix(prefix, token, pagesize.saturating_add(1))
+        };
+        let mut keys = match keys {
+            Ok(k) => k,
 
-#[cfg(test)]
-mod tests {
+            // Create error response
+            Err(e) => return Ok(self._error_response(&req, e)),
+        };
 
-    // Stdlib imports
+        // The last key of a full page is used as the resume token
+        let token = if keys.len() > pagesize {
+            keys.truncate(pagesize);
+            Value::from(&keys[pagesize - 1][..])
+        } else {
+            Value::Nil
+        };
+        let keys = keys.into_iter().map(Value::from).collect();
+        let response = AuthResponse::new(
+            req.message_id(),
+            AuthError::Nil,
+            Value::Array(vec![Value::Array(keys), token]),
+        );
+        Ok(response)
+    }
 
-    use std::rc::Rc;
-    use std::sync::RwLock;
+    fn req_list_revisions(&self, req: AuthRequest, db: KeyFileDB)
+        -> StateResult<AuthResponse>
+    {
+        // Get key
+        let key = &self._check_message(&req, 1)?[0];
 
-    // Third-party imports
+        // Get revisions, dropping the db lock as soon as possible
+        let revisions = {
+            let db = db.read().unpoison();
+            db.revisions(key)
+        };
 
-    use quickcheck::TestResult;
-    use rmpv::Value;
+        match revisions {
+            // Create response
+            Ok(r) => {
+                let result = r.into_iter().map(Value::from).collect();
+                let response = AuthResponse::new(
+                    req.message_id(),
+                    AuthError::Nil,
+                    Value::Array(result),
+                );
+                Ok(response)
+            }
 
-    // Local imports
+            // Create error response
+            Err(e) => Ok(self._error_response(&req, e)),
+        }
+    }
 
-    use super::{AuthInfo, AuthRequest, AuthResponse, ProcessAuthMessage,
-                ProcessAuthRequest};
-    use error::{Error, GeneralError, Result};
-    use network::rpc::{CodeConvert, Message, NotificationMessage,
-                       RpcResponse};
-    use protocol::message::{AuthError, AuthMessage, AuthNotice,
-                            ProtocolError};
-    use service::state::{SessionState, State};
-    use storage::{KeyFileError, KeyFileResult, KeyFileStore};
+    fn req_get_revision(&self, req: AuthRequest, db: KeyFileDB)
+        -> StateResult<AuthResponse>
+    {
+        // Get args
+        let (key, rev) = self._check_revision_message(&req)?;
 
-    // --------------------
-    // ProcessAuthMessage
-    // --------------------
+        // Get keyfile, dropping the db lock as soon as possible
+        let keyfile = {
+            let db = db.read().unpoison();
+            db.get_revision(&key, rev)
+        };
 
-    #[test]
-    fn processauthmessage_request_error()
-    {
-        // --------------------------------------------------------------------
-        // GIVEN
-        // A fake KeyFileDB and
-        // a Request message with 2 arguments and
-        // the request code is AuthMessage::KeyExists and
-        // the first message argument is a key that does not exist and
-        // the second message argument is Nil and
-        // a ProcessAuthMessage instance initialized with the fake KeyFileDB
-        // --------------------------------------------------------------------
-        struct FakeDB;
-        impl KeyFileStore for FakeDB {
-            fn exists(&self, k: &Vec<u8>) -> bool
-            {
-                let expected = "ANSWER".to_string().into_bytes();
-                &expected == k
-            }
-            fn get(&self, k: &Vec<u8>) -> KeyFileResult<Vec<u8>>
-            {
-                let expected = "ANSWER".to_string().into_bytes();
-                if &expected == k {
-                    Ok("42".to_string().into_bytes())
-                } else {
-                    unreachable!()
-                }
-            }
-            fn set(&mut self, _k: &Vec<u8>, _file: &Vec<u8>)
-                -> KeyFileResult<()>
-            {
-                unimplemented!()
-            }
-            fn delete(&mut self, _k: &Vec<u8>) -> KeyFileResult<()>
-            {
-                unimplemented!()
+        match keyfile {
+            // Create response
+            Ok(f) => {
+                let response = AuthResponse::new(
+                    req.message_id(),
+                    AuthError::Nil,
+                    Value::from(f),
+                );
+                Ok(response)
             }
-        }
-        let db = Rc::new(RwLock::new(FakeDB));
 
-        let key = "noanswer".to_string().into_bytes();
-        let args = vec![Value::from(key), Value::Nil];
-        let req = AuthRequest::new(42, AuthMessage::GetKeyFile, args);
-        let msg: Message = req.into();
-        let process_msg = Box::new(ProcessAuthMessage::new(db));
+            // Create error response
+            Err(e) => Ok(self._error_response(&req, e)),
+        }
+    }
+
+    fn req_restore_revision(
+        &self, req: AuthRequest, db: KeyFileDB, quota: &Quota
+    ) -> StateResult<AuthResponse>
+    {
+        // Get args
+        let (key, rev) = self._check_revision_message(&req)?;
+
+        // Get exclusive lock to database so the revision can't be dropped
+        // from the history before it is restored
+        let mut db = db.write().unpoison();
+
+        // Replace the current keyfile, if any, with the revision's keyfile
+        // if it fits in the quota
+        let result = match db.get_revision(&key, rev) {
+            Ok(keyfile) => quota
+                .check(&*db, &key, &keyfile, Some(&key))
+                .and_then(|_| db.set(&key, &keyfile)),
+            Err(e) => Err(e),
+        };
+
+        match result {
+            Ok(()) => {
+                let response = AuthResponse::new(
+                    req.message_id(),
+                    AuthError::Nil,
+                    Value::Boolean(true),
+                );
+                Ok(response)
+            }
+            // Create error response
+            Err(e) => Ok(self._error_response(&req, e)),
+        }
+    }
+
+    // Followers answer this request in ProcessAuthMessage, so the status
+    // here is always that of a primary
+    fn req_replication_status(&self, req: AuthRequest, db: KeyFileDB)
+        -> StateResult<AuthResponse>
+    {
+        if !req.message_args().is_empty() {
+            return Err(ProtocolError::InvalidRequestArgs);
+        }
+
+        // Get the latest change, dropping the db lock as soon as possible
+        let latest = {
+            let db = db.read().unpoison();
+            db.changes(0, 0)
+        };
+
+        match latest {
+            Ok((latest, _)) => {
+                let status = ReplicaStatus::primary(latest);
+                let response = AuthResponse::new(
+                    req.message_id(),
+                    AuthError::Nil,
+                    Value::from(status),
+                );
+                Ok(response)
+            }
+            // Create error response
+            Err(e) => Ok(self._error_response(&req, e)),
+        }
+    }
+
+    fn req_cache_status(&self, req: AuthRequest, db: KeyFileDB)
+        -> StateResult<AuthResponse>
+    {
+        if !req.message_args().is_empty() {
+            return Err(ProtocolError::InvalidRequestArgs);
+        }
+
+        let stats = db.read().unpoison().cache_stats();
+        let response = AuthResponse::new(
+            req.message_id(),
+            AuthError::Nil,
+            stats.map_or(Value::Nil, Value::from),
+        );
+        Ok(response)
+    }
+}
+
+
+// Get the code of a request by reference, so that neither the message nor
+// any keyfile it carries is copied
+fn request_code(m: &Message) -> Option<AuthMessage>
+{
+    let array = m.as_vec();
+    if array.len() != 4 {
+        return None;
+    }
+    array[2]
+        .as_u64()
+        .and_then(|code| AuthMessage::from_number(code as u8).ok())
+}
+
+
+// Return true if the request is for the replication status
+fn is_status_request(code: &Option<AuthMessage>) -> bool
+{
+    match *code {
+        Some(AuthMessage::ReplicationStatus) => true,
+        _ => false,
+    }
+}
+
+
+// Requests that change a keyfile or namespace
+fn is_write_request(code: &Option<AuthMessage>) -> bool
+{
+    match *code {
+        Some(AuthMessage::CreateKeyFile) |
+        Some(AuthMessage::ChangeKeyFile) |
+        Some(AuthMessage::ChangeKey) |
+        Some(AuthMessage::ReplaceKeyFile) |
+        Some(AuthMessage::DeleteKeyFile) |
+        Some(AuthMessage::ChangeKeyFileIf) |
+        Some(AuthMessage::RestoreRevision) |
+        Some(AuthMessage::CreateNamespace) |
+        Some(AuthMessage::DropNamespace) => true,
+        _ => false,
+    }
+}
+
+
+// Return true if the message is a request to back up the store
+fn is_backup_request(code: &Option<AuthMessage>) -> bool
+{
+    match *code {
+        Some(AuthMessage::Backup) => true,
+        _ => false,
+    }
+}
+
+
+// Return true if the message is a request to watch keys
+fn is_watch_request(code: &Option<AuthMessage>) -> bool
+{
+    match *code {
+        Some(AuthMessage::Watch) => true,
+        _ => false,
+    }
+}
+
+
+// Get the keys that a request changes if it succeeds, along with the
+// notification sent to sessions watching them
+fn changed_keys(code: &Option<AuthMessage>, m: &Message)
+    -> Vec<(AuthNotice, Vec<u8>)>
+{
+    let args = match m.as_vec().get(3).and_then(|v| v.as_array()) {
+        Some(args) => args,
+        None => return Vec::new(),
+    };
+    let key = |i: usize| args.get(i).and_then(|v| v.as_slice());
+    let changes = match *code {
+        Some(AuthMessage::CreateKeyFile) => {
+            vec![(AuthNotice::KeyFileCreated, key(0))]
+        }
+        Some(AuthMessage::ChangeKeyFile) |
+        Some(AuthMessage::ChangeKeyFileIf) |
+        Some(AuthMessage::RestoreRevision) => {
+            vec![(AuthNotice::KeyFileChanged, key(0))]
+        }
+        Some(AuthMessage::DeleteKeyFile) => {
+            vec![(AuthNotice::KeyFileDeleted, key(0))]
+        }
+        Some(AuthMessage::ChangeKey) |
+        Some(AuthMessage::ReplaceKeyFile) => {
+            vec![
+                (AuthNotice::KeyFileDeleted, key(0)),
+                (AuthNotice::KeyFileCreated, key(1)),
+            ]
+        }
+        _ => Vec::new(),
+    };
+    changes
+        .into_iter()
+        .filter_map(|(notice, key)| key.map(|k| (notice, k.to_vec())))
+        .collect()
+}
+
+
+// Return true if the message is a request that manages namespaces
+fn is_namespace_request(code: &Option<AuthMessage>) -> bool
+{
+    match *code {
+        Some(AuthMessage::CreateNamespace) |
+        Some(AuthMessage::ListNamespaces) |
+        Some(AuthMessage::DropNamespace) => true,
+        _ => false,
+    }
+}
+
+
+struct ProcessNamespaceRequest;
+
+
+impl ProcessNamespaceRequest {
+    fn run(&self, namespaces: NamespaceDB, m: Message)
+        -> StateResult<AuthResponse>
+    {
+        let req = AuthRequest::from(m).unwrap();
+        let result = match req.message_code() {
+            AuthMessage::CreateNamespace => {
+                let name = self._check_name(&req)?;
+                let mut namespaces = namespaces.write().unpoison();
+                namespaces
+                    .create_namespace(&name)
+                    .map(|_| Value::Boolean(true))
+            }
+            AuthMessage::DropNamespace => {
+                let name = self._check_name(&req)?;
+                let mut namespaces = namespaces.write().unpoison();
+                namespaces
+                    .drop_namespace(&name)
+                    .map(|_| Value::Boolean(true))
+            }
+            AuthMessage::ListNamespaces => {
+                if !req.message_args().is_empty() {
+                    return Err(ProtocolError::InvalidRequestArgs);
+                }
+                let namespaces = namespaces.read().unpoison();
+                namespaces.list_namespaces().map(|names| {
+                    Value::Array(names.into_iter().map(Value::from).collect())
+                })
+            }
+            _ => unreachable!(),
+        };
+
+        match result {
+            Ok(result) => {
+                let code = AuthError::Nil;
+                Ok(AuthResponse::new(req.message_id(), code, result))
+            }
+            // Create error response
+            Err(e) => Ok(ProcessAuthRequest._error_response(&req, e)),
+        }
+    }
+
+    fn _check_name(&self, req: &AuthRequest) -> StateResult<String>
+    {
+        let args = req.message_args();
+        if args.len() != 1 {
+            return Err(ProtocolError::InvalidRequestArgs);
+        }
+        namespace_arg(&args[0]).ok_or(ProtocolError::InvalidRequest)
+    }
+}
+
+
+struct ProcessWatchRequest;
+
+
+impl ProcessWatchRequest {
+    fn run(&self, watcher: &Watcher, m: Message)
+        -> StateResult<AuthResponse>
+    {
+        let req = AuthRequest::from(m).unwrap();
+        let args = req.message_args();
+        if args.len() != 2 {
+            return Err(ProtocolError::InvalidRequestArgs);
+        }
+        let (key, prefix) = match (args[0].as_slice(), args[1].as_bool()) {
+            (Some(key), Some(prefix)) => (key.to_vec(), prefix),
+            _ => return Err(ProtocolError::InvalidRequest),
+        };
+        watcher.watch(key, prefix);
+        let result = Value::Boolean(true);
+        Ok(AuthResponse::new(req.message_id(), AuthError::Nil, result))
+    }
+}
+
+
+struct ProcessBackupRequest;
+
+
+impl ProcessBackupRequest {
+    // The copy is written into the request's directory under root
+    fn run(&self, root: &Path, db: KeyFileDB, m: Message)
+        -> StateResult<AuthResponse>
+    {
+        let req = AuthRequest::from(m).unwrap();
+
+        // Get args
+        let args = req.message_args();
+        if args.len() != 2 {
+            return Err(ProtocolError::InvalidRequestArgs);
+        }
+        let dir = match args[0].as_slice().map(str::from_utf8) {
+            Some(Ok(dir)) => backup_path(root, dir),
+            _ => None,
+        };
+        let (dir, compact) = match (dir, args[1].as_bool()) {
+            (Some(dir), Some(compact)) => (dir, compact),
+            _ => return Err(ProtocolError::InvalidRequest),
+        };
+
+        let result = {
+            let db = db.read().unpoison();
+            db.backup(&dir, compact)
+        };
+
+        match result {
+            Ok(()) => {
+                let response = AuthResponse::new(
+                    req.message_id(),
+                    AuthError::Nil,
+                    Value::Boolean(true),
+                );
+                Ok(response)
+            }
+            // Create error response
+            Err(e) => Ok(ProcessAuthRequest._error_response(&req, e)),
+        }
+    }
+}
+
+
+// Get the directory under root named by a backup request. Only relative
+// paths made of plain names are allowed, so that the copy can't be written
+// anywhere else.
+fn backup_path(root: &Path, dir: &str) -> Option<PathBuf>
+{
+    let dir = Path::new(dir);
+    let plain = dir.components().all(|c| match c {
+        Component::Normal(_) => true,
+        _ => false,
+    });
+    if dir.as_os_str().is_empty() || !plain {
+        return None;
+    }
+    Some(root.join(dir))
+}
+
+
+// Get when the keyfile expires. Keyfiles in stores that don't keep info
+// never expire.
+fn expiry(db: &KeyFileStore, key: &Vec<u8>) -> Option<u64>
+{
+    db.info(key).ok().and_then(|info| info.expires)
+}
+
+
+// Set the keyfile, along with its expiry time if it has one
+fn set_keyfile(
+    db: &mut KeyFileStore, key: &Vec<u8>, keyfile: &Vec<u8>,
+    expires: Option<u64>
+) -> KeyFileResult<()>
+{
+    match expires {
+        None => db.set(key, keyfile),
+        Some(_) => {
+            let mut txn = db.begin();
+            txn.set(key, keyfile).expire(key, expires);
+            db.commit(txn)
+        }
+    }
+}
+
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+#[cfg(test)]
+mod tests {
+
+    // Stdlib imports
+
+    use std::path::PathBuf;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+
+    // Third-party imports
+
+    use futures::{Future, Stream};
+    use futures::sync::mpsc;
+    use quickcheck::TestResult;
+    use rmpv::Value;
+
+    // Local imports
+
+    use super::{AuthInfo, AuthRequest, AuthResponse, ProcessAuthMessage,
+                ProcessAuthRequest};
+    use error::{Error, GeneralError, Result};
+    use network::rpc::{CodeConvert, Message, NotificationMessage,
+                       RpcNotice, RpcResponse};
+    use protocol::message::{AuthError, AuthMessage, AuthNotice,
+                            ProtocolError};
+    use service::state::{SessionState, State};
+    use service::watch::{Watcher, Watchers};
+    use storage::{timestamp, KeyFileBuilder, KeyFileError, KeyFileInfo,
+                  KeyFileOp, KeyFileResult, KeyFileStore, KeyFileTransaction,
+                  Quota};
+    use storage::cache::CachedStore;
+    use storage::memory::{MemoryKeyFile, MemoryNamespaces};
+
+    // --------------------
+    // ProcessAuthMessage
+    // --------------------
+
+    #[test]
+    fn processauthmessage_request_error()
+    {
+        // --------------------------------------------------------------------
+        // GIVEN
+        // A fake KeyFileDB and
+        // a Request message with 2 arguments and
+        // the request code is AuthMessage::KeyExists and
+        // the first message argument is a key that does not exist and
+        // the second message argument is Nil and
+        // a ProcessAuthMessage instance initialized with the fake KeyFileDB
+        // --------------------------------------------------------------------
+        struct FakeDB;
+        impl KeyFileStore for FakeDB {
+            fn exists(&self, k: &Vec<u8>) -> bool
+            {
+                let expected = "ANSWER".to_string().into_bytes();
+                &expected == k
+            }
+            fn get(&self, k: &Vec<u8>) -> KeyFileResult<Vec<u8>>
+            {
+                let expected = "ANSWER".to_string().into_bytes();
+                if &expected == k {
+                    Ok("42".to_string().into_bytes())
+                } else {
+                    unreachable!()
+                }
+            }
+            fn set(&mut self, _k: &Vec<u8>, _file: &Vec<u8>)
+                -> KeyFileResult<()>
+            {
+                unimplemented!()
+            }
+            fn delete(&mut self, _k: &Vec<u8>) -> KeyFileResult<()>
+            {
+                unimplemented!()
+            }
+        }
+        let db = Arc::new(RwLock::new(FakeDB));
+
+        let key = "noanswer".to_string().into_bytes();
+        let args = vec![Value::from(key), Value::Nil];
+        let req = AuthRequest::new(42, AuthMessage::GetKeyFile, args);
+        let msg: Message = req.into();
+        let process_msg = Box::new(ProcessAuthMessage::new(db));
 
         // ----------------------------------------------------------
         // WHEN
@@ -568,7 +1340,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let info = AuthResponse::new(42, AuthError::Nil, Value::Nil);
         let msg: Message = info.into();
@@ -623,7 +1395,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "ANSWER".to_string().into_bytes();
         let args = vec![Value::from(key)];
@@ -685,7 +1457,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let args: Vec<Value> = Vec::new();
         let info = AuthInfo::new(AuthNotice::Done, args);
@@ -746,7 +1518,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let args: Vec<Value> = Vec::new();
         let info = FakeInfo::new(FakeCode::Bad, args);
@@ -803,7 +1575,7 @@ mod tests {
                     unimplemented!()
                 }
             }
-            let db = Rc::new(RwLock::new(FakeDB));
+            let db = Arc::new(RwLock::new(FakeDB));
 
             let args: Vec<Value> =
                 args.iter().map(|v| Value::from(v.clone())).collect();
@@ -814,7 +1586,7 @@ mod tests {
             // WHEN
             // Calling ProcessAuthRequest.run() w/ any KeyfileDB
             // -------------------------------------------------
-            let result = ProcessAuthRequest.run(db, msg);
+            let result = ProcessAuthRequest.run(db, &Quota::new(), msg);
 
             // -------------------------------------------------------
             // THEN
@@ -857,7 +1629,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let args = vec![Value::Nil];
         let req = AuthRequest::new(42, AuthMessage::KeyExists, args);
@@ -868,7 +1640,7 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let result = match ProcessAuthRequest.run(db, msg) {
+        let result = match ProcessAuthRequest.run(db, &Quota::new(), msg) {
             Err(ProtocolError::InvalidRequest) => true,
             _ => false,
         };
@@ -911,7 +1683,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "ANSWER".to_string().into_bytes();
         let args = vec![Value::from(key)];
@@ -923,7 +1695,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -968,7 +1741,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "42".to_string().into_bytes();
         let args = vec![Value::from(key)];
@@ -980,7 +1753,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -1030,7 +1804,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "42".to_string().into_bytes();
         let args = vec![Value::from(key)];
@@ -1042,7 +1816,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -1094,7 +1869,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "ANSWER".to_string().into_bytes();
         let args = vec![Value::from(key)];
@@ -1106,7 +1881,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -1123,52 +1899,121 @@ mod tests {
     }
 
     #[test]
-    fn processauthrequest_run_createkeyfile_keyexists()
+    fn processauthrequest_run_getkey_dberror()
     {
         // --------------------------------------------------------------------
         // GIVEN
-        // A fake KeyFileDB and
-        // a Request message with two arguments and
-        // the request code is AuthMessage::CreateKeyFile and
-        // the first message arg is a key that exists in the keyfilestore and
-        // the second message arg is the keyfile key references
+        // A fake KeyFileDB that returns a given error for every get and
+        // a Request message with a single argument and
+        // the request code is AuthMessage::GetKeyFile
         // --------------------------------------------------------------------
-        struct FakeDB;
+        struct FakeDB {
+            err: KeyFileError,
+        }
         impl KeyFileStore for FakeDB {
-            fn exists(&self, k: &Vec<u8>) -> bool
+            fn exists(&self, _k: &Vec<u8>) -> bool
             {
-                let expected = "ANSWER".to_string().into_bytes();
-                &expected == k
+                unimplemented!()
             }
             fn get(&self, _k: &Vec<u8>) -> KeyFileResult<Vec<u8>>
             {
-                let keyfile = "42".to_string().into_bytes();
-                Ok(keyfile)
+                Err(self.err.clone())
             }
             fn set(&mut self, _k: &Vec<u8>, _file: &Vec<u8>)
                 -> KeyFileResult<()>
             {
-                Ok(())
+                unimplemented!()
             }
             fn delete(&mut self, _k: &Vec<u8>) -> KeyFileResult<()>
             {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
 
-        let key = "ANSWER".to_string().into_bytes();
-        let keyfile = "42".to_string().into_bytes();
-        let args = vec![Value::from(&key[..]), Value::from(&keyfile[..])];
-        let req = AuthRequest::new(42, AuthMessage::CreateKeyFile, args);
-        let msg: Message = req.into();
+        let errors = vec![
+            (KeyFileError::MapFull, AuthError::DatabaseFull),
+            (KeyFileError::ReadersFull, AuthError::DatabaseReadersFull),
+            (KeyFileError::Corrupted, AuthError::DatabaseCorrupted),
+            (KeyFileError::Io, AuthError::DatabaseIo),
+            (KeyFileError::ValueTooLarge, AuthError::ValueTooLarge),
+            (KeyFileError::Other, AuthError::DatabaseError),
+        ];
 
-        // ----------------------------------------------------------
-        // WHEN
-        // Calling ProcessAuthRequest.run() with a FakeDB object and
-        // the request message
-        // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        for (err, expected) in errors {
+            let db = Arc::new(RwLock::new(FakeDB { err: err }));
+            let key = "42".to_string().into_bytes();
+            let args = vec![Value::from(key)];
+            let req = AuthRequest::new(42, AuthMessage::GetKeyFile, args);
+            let msg: Message = req.into();
+
+            // ----------------------------------------------------------
+            // WHEN
+            // Calling ProcessAuthRequest.run() with a FakeDB object and
+            // the request message
+            // ----------------------------------------------------------
+            let response =
+                ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
+
+            // --------------------------------------------------------------
+            // THEN
+            // A AuthResponse message is returned and
+            // the message's error code matches the db error and
+            // the message's result is the false boolean value
+            // --------------------------------------------------------------
+            assert_eq!(response.message_id(), 42);
+            assert_eq!(response.error_code(), expected);
+            assert_eq!(response.result(), &Value::Boolean(false));
+        }
+    }
+
+    #[test]
+    fn processauthrequest_run_createkeyfile_keyexists()
+    {
+        // --------------------------------------------------------------------
+        // GIVEN
+        // A fake KeyFileDB and
+        // a Request message with two arguments and
+        // the request code is AuthMessage::CreateKeyFile and
+        // the first message arg is a key that exists in the keyfilestore and
+        // the second message arg is the keyfile key references
+        // --------------------------------------------------------------------
+        struct FakeDB;
+        impl KeyFileStore for FakeDB {
+            fn exists(&self, k: &Vec<u8>) -> bool
+            {
+                let expected = "ANSWER".to_string().into_bytes();
+                &expected == k
+            }
+            fn get(&self, _k: &Vec<u8>) -> KeyFileResult<Vec<u8>>
+            {
+                let keyfile = "42".to_string().into_bytes();
+                Ok(keyfile)
+            }
+            fn set(&mut self, _k: &Vec<u8>, _file: &Vec<u8>)
+                -> KeyFileResult<()>
+            {
+                Ok(())
+            }
+            fn delete(&mut self, _k: &Vec<u8>) -> KeyFileResult<()>
+            {
+                unimplemented!()
+            }
+        }
+        let db = Arc::new(RwLock::new(FakeDB));
+
+        let key = "ANSWER".to_string().into_bytes();
+        let keyfile = "42".to_string().into_bytes();
+        let args = vec![Value::from(&key[..]), Value::from(&keyfile[..])];
+        let req = AuthRequest::new(42, AuthMessage::CreateKeyFile, args);
+        let msg: Message = req.into();
+
+        // ----------------------------------------------------------
+        // WHEN
+        // Calling ProcessAuthRequest.run() with a FakeDB object and
+        // the request message
+        // ----------------------------------------------------------
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -1216,7 +2061,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "ANSWER".to_string().into_bytes();
         let keyfile = "42".to_string().into_bytes();
@@ -1229,7 +2074,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -1278,7 +2124,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "ANSWER".to_string().into_bytes();
         let keyfile = "42".to_string().into_bytes();
@@ -1292,7 +2138,8 @@ mod tests {
         // the request message and
         // the KeyFileStore.set() method returns an error
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -1341,7 +2188,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "ANSWER".to_string().into_bytes();
         let keyfile = "42".to_string().into_bytes();
@@ -1354,7 +2201,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -1401,7 +2249,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "ANSWER".to_string().into_bytes();
         let keyfile = "42".to_string().into_bytes();
@@ -1414,7 +2262,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -1462,7 +2311,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "ANSWER".to_string().into_bytes();
         let keyfile = "42".to_string().into_bytes();
@@ -1476,7 +2325,8 @@ mod tests {
         // the request message and
         // the KeyFileStore.set() method returns an error
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -1523,7 +2373,7 @@ mod tests {
                 Ok(())
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "ANSWER".to_string().into_bytes();
         let args = vec![Value::from(&key[..])];
@@ -1535,7 +2385,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -1581,7 +2432,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "ANSWER".to_string().into_bytes();
         let args = vec![Value::from(&key[..])];
@@ -1593,7 +2444,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -1640,7 +2492,7 @@ mod tests {
                 Err(KeyFileError::Other)
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "ANSWER".to_string().into_bytes();
         let args = vec![Value::from(&key[..])];
@@ -1653,7 +2505,8 @@ mod tests {
         // the request message and
         // the KeyFileStore.set() method returns an error
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -1703,7 +2556,7 @@ mod tests {
                 Ok(())
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let oldkey = "ANSWER".to_string().into_bytes();
         let newkey = "UNIVERSE".to_string().into_bytes();
@@ -1716,7 +2569,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -1765,7 +2619,7 @@ mod tests {
                 unreachable!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let oldkey = "ANSWER".to_string().into_bytes();
         let newkey = "UNIVERSE".to_string().into_bytes();
@@ -1778,7 +2632,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -1824,7 +2679,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let oldkey = "ANSWER".to_string().into_bytes();
         let newkey = "UNIVERSE".to_string().into_bytes();
@@ -1837,7 +2692,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -1885,7 +2741,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let oldkey = "ANSWER".to_string().into_bytes();
         let newkey = "UNIVERSE".to_string().into_bytes();
@@ -1898,7 +2754,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -1947,7 +2804,7 @@ mod tests {
                 Err(KeyFileError::Other)
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let oldkey = "ANSWER".to_string().into_bytes();
         let newkey = "UNIVERSE".to_string().into_bytes();
@@ -1960,7 +2817,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -2009,7 +2867,7 @@ mod tests {
                 Ok(())
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let oldkey = "ANSWER".to_string().into_bytes();
         let newkey = "UNIVERSE".to_string().into_bytes();
@@ -2022,7 +2880,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -2070,7 +2929,7 @@ mod tests {
                 Ok(())
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let oldkey = "ANSWER".to_string().into_bytes();
         let newkey = "UNIVERSE".to_string().into_bytes();
@@ -2083,7 +2942,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -2099,6 +2959,81 @@ mod tests {
         assert_eq!(response.result(), &expected);
     }
 
+    #[test]
+    fn processauthrequest_run_changekey_single_transaction()
+    {
+        // --------------------------------------------------------------------
+        // GIVEN
+        // A fake KeyFileDB and
+        // a Request message with 2 arguments and
+        // the request code is AuthMessage::ChangeKey and
+        // the first message arg is a key that exists in the db and
+        // the second message arg is a key that doesn't exist in the db and
+        // the db only accepts writes via a transaction
+        // --------------------------------------------------------------------
+        struct FakeDB;
+        impl KeyFileStore for FakeDB {
+            fn exists(&self, k: &Vec<u8>) -> bool
+            {
+                let oldkey = "ANSWER".to_string().into_bytes();
+                &oldkey == k
+            }
+            fn get(&self, _k: &Vec<u8>) -> KeyFileResult<Vec<u8>>
+            {
+                Ok("42".to_string().into_bytes())
+            }
+            fn set(&mut self, _k: &Vec<u8>, _file: &Vec<u8>)
+                -> KeyFileResult<()>
+            {
+                unreachable!()
+            }
+            fn delete(&mut self, _k: &Vec<u8>) -> KeyFileResult<()>
+            {
+                unreachable!()
+            }
+            fn commit(&mut self, txn: KeyFileTransaction)
+                -> KeyFileResult<()>
+            {
+                let oldkey = "ANSWER".to_string().into_bytes();
+                let newkey = "UNIVERSE".to_string().into_bytes();
+                let keyfile = "42".to_string().into_bytes();
+                let expected = [
+                    KeyFileOp::Delete(oldkey),
+                    KeyFileOp::Set(newkey, keyfile),
+                ];
+                assert_eq!(txn.ops(), &expected[..]);
+                Ok(())
+            }
+        }
+        let db = Arc::new(RwLock::new(FakeDB));
+
+        let oldkey = "ANSWER".to_string().into_bytes();
+        let newkey = "UNIVERSE".to_string().into_bytes();
+        let args = vec![Value::from(&oldkey[..]), Value::from(&newkey[..])];
+        let req = AuthRequest::new(42, AuthMessage::ChangeKey, args);
+        let msg: Message = req.into();
+
+        // ----------------------------------------------------------
+        // WHEN
+        // Calling ProcessAuthRequest.run() with a FakeDB object and
+        // the request message
+        // ----------------------------------------------------------
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
+
+        // ------------------------------------------------------------------
+        // THEN
+        // The delete and set operations are committed together and
+        // the message's error code is AuthError::Nil and
+        // the message's result is the true boolean value
+        // ------------------------------------------------------------------
+        assert_eq!(response.message_id(), 42);
+        assert_eq!(response.error_code(), AuthError::Nil);
+
+        let expected = Value::Boolean(true);
+        assert_eq!(response.result(), &expected);
+    }
+
     #[test]
     fn processauthrequest_run_replacekeyfile_newkey_exists()
     {
@@ -2130,7 +3065,7 @@ mod tests {
                 unreachable!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let oldkey = "ANSWER".to_string().into_bytes();
         let newkey = "UNIVERSE".to_string().into_bytes();
@@ -2148,7 +3083,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -2197,7 +3133,7 @@ mod tests {
                 Err(KeyFileError::Other)
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let oldkey = "ANSWER".to_string().into_bytes();
         let newkey = "UNIVERSE".to_string().into_bytes();
@@ -2215,7 +3151,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -2264,7 +3201,7 @@ mod tests {
                 Err(KeyFileError::Key(k.clone()))
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let oldkey = "ANSWER".to_string().into_bytes();
         let newkey = "UNIVERSE".to_string().into_bytes();
@@ -2282,7 +3219,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -2331,7 +3269,7 @@ mod tests {
                 Ok(())
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let oldkey = "ANSWER".to_string().into_bytes();
         let newkey = "UNIVERSE".to_string().into_bytes();
@@ -2349,7 +3287,8 @@ mod tests {
         // Calling ProcessAuthRequest.run() with a FakeDB object and
         // the request message
         // ----------------------------------------------------------
-        let response = ProcessAuthRequest.run(db, msg).unwrap();
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
 
         // ------------------------------------------------------------------
         // THEN
@@ -2365,6 +3304,1097 @@ mod tests {
         assert_eq!(response.result(), &expected);
     }
 
+    #[test]
+    fn processauthrequest_run_replacekeyfile_single_transaction()
+    {
+        // --------------------------------------------------------------------
+        // GIVEN
+        // A fake KeyFileDB and
+        // a Request message with 3 arguments and
+        // the request code is AuthMessage::ReplaceKeyFile and
+        // the first message arg is a key that exists in the db and
+        // the second message arg is a key that doesn't exist in the db and
+        // the db only accepts writes via a transaction
+        // --------------------------------------------------------------------
+        struct FakeDB;
+        impl KeyFileStore for FakeDB {
+            fn exists(&self, k: &Vec<u8>) -> bool
+            {
+                let indb = "UNIVERSE".to_string().into_bytes();
+                &indb != k
+            }
+            fn get(&self, _k: &Vec<u8>) -> KeyFileResult<Vec<u8>>
+            {
+                unreachable!()
+            }
+            fn set(&mut self, _k: &Vec<u8>, _file: &Vec<u8>)
+                -> KeyFileResult<()>
+            {
+                unreachable!()
+            }
+            fn delete(&mut self, _k: &Vec<u8>) -> KeyFileResult<()>
+            {
+                unreachable!()
+            }
+            fn commit(&mut self, txn: KeyFileTransaction)
+                -> KeyFileResult<()>
+            {
+                let oldkey = "ANSWER".to_string().into_bytes();
+                let newkey = "UNIVERSE".to_string().into_bytes();
+                let keyfile = "42".to_string().into_bytes();
+                let expected = [
+                    KeyFileOp::Delete(oldkey),
+                    KeyFileOp::Set(newkey, keyfile),
+                ];
+                assert_eq!(txn.ops(), &expected[..]);
+                Ok(())
+            }
+        }
+        let db = Arc::new(RwLock::new(FakeDB));
+
+        let oldkey = "ANSWER".to_string().into_bytes();
+        let newkey = "UNIVERSE".to_string().into_bytes();
+        let keyfile = "42".to_string().into_bytes();
+        let args = vec![
+            Value::from(&oldkey[..]),
+            Value::from(&newkey[..]),
+            Value::from(&keyfile[..]),
+        ];
+        let req = AuthRequest::new(42, AuthMessage::ReplaceKeyFile, args);
+        let msg: Message = req.into();
+
+        // ----------------------------------------------------------
+        // WHEN
+        // Calling ProcessAuthRequest.run() with a FakeDB object and
+        // the request message
+        // ----------------------------------------------------------
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), msg).unwrap();
+
+        // ------------------------------------------------------------------
+        // THEN
+        // The delete and set operations are committed together and
+        // the message's error code is AuthError::Nil and
+        // the message's result is the true boolean value
+        // ------------------------------------------------------------------
+        assert_eq!(response.message_id(), 42);
+        assert_eq!(response.error_code(), AuthError::Nil);
+
+        let expected = Value::Boolean(true);
+        assert_eq!(response.result(), &expected);
+    }
+
+    #[test]
+    fn processauthrequest_run_getkeyfileinfo_notexists()
+    {
+        // ---------------------------------------------
+        // GIVEN
+        // An empty MemoryKeyFile and
+        // a GetKeyFileInfo request for a key
+        // ---------------------------------------------
+        let db = Arc::new(RwLock::new(MemoryKeyFile::new("temp", None)));
+        let key = "ANSWER".to_string().into_bytes();
+        let args = vec![Value::from(&key[..])];
+        let req = AuthRequest::new(42, AuthMessage::GetKeyFileInfo, args);
+
+        // ----------------------------------------------------------
+        // WHEN
+        // Calling ProcessAuthRequest.run() with the request message
+        // ----------------------------------------------------------
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), req.into()).unwrap();
+
+        // ------------------------------------------------------------
+        // THEN
+        // The message's error code is AuthError::KeyFileNotFound and
+        // the message's result is the key
+        // ------------------------------------------------------------
+        assert_eq!(response.message_id(), 42);
+        assert_eq!(response.error_code(), AuthError::KeyFileNotFound);
+        assert_eq!(response.result(), &Value::from(key));
+    }
+
+    #[test]
+    fn processauthrequest_run_changekeyfileif()
+    {
+        // ----------------------------------------------------------------
+        // GIVEN
+        // A MemoryKeyFile holding a keyfile at revision 1 and
+        // a ChangeKeyFileIf request expecting revision 1 and
+        // a second ChangeKeyFileIf request also expecting revision 1
+        // ----------------------------------------------------------------
+        let key = "ANSWER".to_string().into_bytes();
+        let mut store = MemoryKeyFile::new("temp", None);
+        store.set(&key, &"4".to_string().into_bytes()).unwrap();
+        let db = Arc::new(RwLock::new(store));
+
+        let mkrequest = |msgid: u32, keyfile: &str| {
+            let args = vec![
+                Value::from(&key[..]),
+                Value::from(1),
+                Value::from(keyfile.as_bytes()),
+            ];
+            AuthRequest::new(msgid, AuthMessage::ChangeKeyFileIf, args)
+        };
+        let first = mkrequest(42, "42");
+        let second = mkrequest(43, "24");
+
+        // ----------------------------------------------------------
+        // WHEN
+        // Calling ProcessAuthRequest.run() with both requests
+        // ----------------------------------------------------------
+        let quota = Quota::new();
+        let first = ProcessAuthRequest.run(db.clone(), &quota, first.into())
+            .unwrap();
+        let second = ProcessAuthRequest.run(db.clone(), &quota, second.into())
+            .unwrap();
+
+        // ------------------------------------------------------------
+        // THEN
+        // The first request succeeds and
+        // the second request fails with AuthError::RevisionMismatch and
+        // the keyfile has the value set by the first request
+        // ------------------------------------------------------------
+        assert_eq!(first.error_code(), AuthError::Nil);
+        assert_eq!(second.error_code(), AuthError::RevisionMismatch);
+        assert_eq!(second.result(), &Value::from(&key[..]));
+
+        let keyfile = db.read().unwrap().get(&key).unwrap();
+        assert_eq!(keyfile, "42".to_string().into_bytes());
+    }
+
+    #[test]
+    fn processauthrequest_run_createkeyfile_ttl()
+    {
+        // --------------------------------------------------------------
+        // GIVEN
+        // An empty MemoryKeyFile and
+        // a CreateKeyFile request for a keyfile that expires at once and
+        // a CreateKeyFile request for a keyfile that expires in an hour
+        // --------------------------------------------------------------
+        let db = Arc::new(RwLock::new(MemoryKeyFile::new("temp", None)));
+        let expired = "EXPIRED".to_string().into_bytes();
+        let live = "LIVE".to_string().into_bytes();
+        let mkrequest = |msgid: u32, key: &[u8], ttl: u64| {
+            let args =
+                vec![Value::from(key), Value::from(key), Value::from(ttl)];
+            AuthRequest::new(msgid, AuthMessage::CreateKeyFile, args)
+        };
+        let first = mkrequest(42, &expired, 0);
+        let second = mkrequest(43, &live, 3600);
+
+        // ----------------------------------------------------------
+        // WHEN
+        // Calling ProcessAuthRequest.run() with both requests
+        // ----------------------------------------------------------
+        let quota = Quota::new();
+        let first = ProcessAuthRequest.run(db.clone(), &quota, first.into())
+            .unwrap();
+        let second = ProcessAuthRequest.run(db.clone(), &quota, second.into())
+            .unwrap();
+
+        // ------------------------------------------------------------
+        // THEN
+        // Both requests succeed and
+        // the first keyfile can't be read and
+        // the second keyfile can be read and has an expiry time
+        // ------------------------------------------------------------
+        assert_eq!(first.error_code(), AuthError::Nil);
+        assert_eq!(second.error_code(), AuthError::Nil);
+
+        let db = db.read().unwrap();
+        assert_eq!(db.get(&expired), Err(KeyFileError::Key(expired.clone())));
+        assert_eq!(db.get(&live).unwrap(), live);
+        assert!(db.info(&live).unwrap().expires.is_some());
+    }
+
+    #[test]
+    fn processauthrequest_run_rename_keeps_expiry()
+    {
+        // -------------------------------------------------------------
+        // GIVEN
+        // A MemoryKeyFile with a keyfile that expires in an hour and
+        // a ChangeKey request renaming the keyfile and
+        // a ReplaceKeyFile request replacing the renamed keyfile
+        // -------------------------------------------------------------
+        let db = Arc::new(RwLock::new(MemoryKeyFile::new("temp", None)));
+        let expires = Some(timestamp() + 3600);
+        let (first, second, third) = (
+            "1".to_string().into_bytes(),
+            "2".to_string().into_bytes(),
+            "3".to_string().into_bytes(),
+        );
+        {
+            let mut db = db.write().unwrap();
+            let mut txn = db.begin();
+            txn.set(&first, &first).expire(&first, expires);
+            db.commit(txn).unwrap();
+        }
+        let change = AuthRequest::new(
+            42,
+            AuthMessage::ChangeKey,
+            vec![Value::from(&first[..]), Value::from(&second[..])],
+        );
+        let replace = AuthRequest::new(
+            43,
+            AuthMessage::ReplaceKeyFile,
+            vec![
+                Value::from(&second[..]),
+                Value::from(&third[..]),
+                Value::from(&third[..]),
+            ],
+        );
+
+        // ----------------------------------------------------------
+        // WHEN
+        // Calling ProcessAuthRequest.run() with both requests
+        // ----------------------------------------------------------
+        let quota = Quota::new();
+        let change = ProcessAuthRequest.run(db.clone(), &quota, change.into())
+            .unwrap();
+        let renamed = db.read().unwrap().info(&second).unwrap().expires;
+        let replace =
+            ProcessAuthRequest.run(db.clone(), &quota, replace.into())
+                .unwrap();
+
+        // ------------------------------------------------------------
+        // THEN
+        // Both requests succeed and
+        // the renamed and replaced keyfiles expire with the original
+        // ------------------------------------------------------------
+        assert_eq!(change.error_code(), AuthError::Nil);
+        assert_eq!(replace.error_code(), AuthError::Nil);
+        assert_eq!(renamed, expires);
+
+        let db = db.read().unwrap();
+        assert_eq!(db.get(&third).unwrap(), third);
+        assert_eq!(db.info(&third).unwrap().expires, expires);
+    }
+
+    #[test]
+    fn processauthrequest_run_poisoned_db()
+    {
+        // ------------------------------------------------------------
+        // GIVEN
+        // A MemoryKeyFile holding a key, whose lock was poisoned by a
+        // job that panicked while holding it, and
+        // a GetKeyFile request for the key
+        // ------------------------------------------------------------
+        let db = Arc::new(RwLock::new(MemoryKeyFile::new("temp", None)));
+        let key = "42".to_string().into_bytes();
+        db.write().unwrap().set(&key, &key).unwrap();
+        let job = db.clone();
+        let panicked = thread::spawn(move || {
+            let _db = job.write().unwrap();
+            panic!("job failed");
+        }).join();
+        assert!(panicked.is_err() && db.is_poisoned());
+        let args = vec![Value::from(&key[..])];
+        let req = AuthRequest::new(42, AuthMessage::GetKeyFile, args);
+
+        // ----------------------------------------------------------
+        // WHEN
+        // Calling ProcessAuthRequest.run() with the request
+        // ----------------------------------------------------------
+        let quota = Quota::new();
+        let response = ProcessAuthRequest.run(db.clone(), &quota, req.into())
+            .unwrap();
+
+        // ------------------------------------------------------------
+        // THEN
+        // The keyfile is returned
+        // ------------------------------------------------------------
+        assert_eq!(response.error_code(), AuthError::Nil);
+        assert_eq!(response.result(), &Value::from(&key[..]));
+    }
+
+    #[test]
+    fn processauthrequest_run_cachestatus()
+    {
+        // ------------------------------------------------------------
+        // GIVEN
+        // A CachedStore over a MemoryKeyFile holding a key and
+        // 2 GetKeyFile requests for the key and
+        // a CacheStatus request
+        // ------------------------------------------------------------
+        let key = "ANSWER".to_string().into_bytes();
+        let mut store = MemoryKeyFile::new("temp", None);
+        store.set(&key, &"42".to_string().into_bytes()).unwrap();
+        let db = Arc::new(RwLock::new(CachedStore::new(store, 10)));
+        let get = |msgid: u32| {
+            let args = vec![Value::from(&key[..])];
+            AuthRequest::new(msgid, AuthMessage::GetKeyFile, args)
+        };
+        let status = AuthRequest::new(42, AuthMessage::CacheStatus, vec![]);
+
+        // ----------------------------------------------------------
+        // WHEN
+        // Calling ProcessAuthRequest.run() with each request message
+        // ----------------------------------------------------------
+        for msgid in 1..3 {
+            let msg = get(msgid).into();
+            ProcessAuthRequest.run(db.clone(), &Quota::new(), msg).unwrap();
+        }
+        let response = ProcessAuthRequest
+            .run(db, &Quota::new(), status.into())
+            .unwrap();
+
+        // ------------------------------------------------------------
+        // THEN
+        // The first read missed the cache and the second hit it
+        // ------------------------------------------------------------
+        let expected = Value::Map(vec![
+            (Value::from("hits"), Value::from(1)),
+            (Value::from("misses"), Value::from(1)),
+        ]);
+        assert_eq!(response.message_id(), 42);
+        assert_eq!(response.error_code(), AuthError::Nil);
+        assert_eq!(response.result(), &expected);
+    }
+
+    #[test]
+    fn processauthrequest_run_cachestatus_uncached()
+    {
+        // ------------------------------------------------------------
+        // GIVEN
+        // A MemoryKeyFile that isn't cached and
+        // a CacheStatus request
+        // ------------------------------------------------------------
+        let db = Arc::new(RwLock::new(MemoryKeyFile::new("temp", None)));
+        let req = AuthRequest::new(42, AuthMessage::CacheStatus, vec![]);
+
+        // ----------------------------------------------------------
+        // WHEN
+        // Calling ProcessAuthRequest.run() with the request message
+        // ----------------------------------------------------------
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), req.into()).unwrap();
+
+        // ------------------------------------------------------------
+        // THEN
+        // The message's result is nil
+        // ------------------------------------------------------------
+        assert_eq!(response.error_code(), AuthError::Nil);
+        assert_eq!(response.result(), &Value::Nil);
+    }
+
+    #[test]
+    fn processauthmessage_namespace_requests()
+    {
+        // ---------------------------------------------------------------
+        // GIVEN
+        // A ProcessAuthMessage state with MemoryNamespaces and
+        // a CreateNamespace request and
+        // a second CreateNamespace request for the same namespace and
+        // a ListNamespaces request and
+        // a DropNamespace request and
+        // a CreateNamespace request with an invalid name
+        // ---------------------------------------------------------------
+        let db = Arc::new(RwLock::new(MemoryKeyFile::new("temp", None)));
+        let namespaces = Arc::new(RwLock::new(MemoryNamespaces::new()));
+        let name = || vec![Value::from("team-a")];
+        let requests = vec![
+            AuthRequest::new(1, AuthMessage::CreateNamespace, name()),
+            AuthRequest::new(2, AuthMessage::CreateNamespace, name()),
+            AuthRequest::new(3, AuthMessage::ListNamespaces, vec![]),
+            AuthRequest::new(4, AuthMessage::DropNamespace, name()),
+            AuthRequest::new(5, AuthMessage::ListNamespaces, vec![]),
+        ];
+        let badname = vec![Value::from("team.a")];
+        let badreq =
+            AuthRequest::new(6, AuthMessage::CreateNamespace, badname);
+
+        // -------------------------------------------------------
+        // WHEN
+        // Sending each request to a new ProcessAuthMessage state
+        // -------------------------------------------------------
+        let state = || {
+            let state = ProcessAuthMessage::new(db.clone())
+                .namespaces(namespaces.clone());
+            Box::new(state)
+        };
+        let responses: Vec<AuthResponse> = requests
+            .into_iter()
+            .map(|req| match state().change(req.into()) {
+                Ok(State::ProcessAuthMessage(_, Some(r))) => r,
+                _ => panic!("Expected a response"),
+            })
+            .collect();
+        let badresult = state().change(badreq.into());
+
+        // ------------------------------------------------------------
+        // THEN
+        // The namespace is created, listed and dropped and
+        // creating it twice fails with AuthError::NamespaceExists and
+        // the invalid name fails with ProtocolError::InvalidRequest
+        // ------------------------------------------------------------
+        let codes: Vec<AuthError> =
+            responses.iter().map(|r| r.error_code()).collect();
+        assert_eq!(codes, vec![
+            AuthError::Nil,
+            AuthError::NamespaceExists,
+            AuthError::Nil,
+            AuthError::Nil,
+            AuthError::Nil,
+        ]);
+        let listed = Value::Array(vec![Value::from("team-a")]);
+        assert_eq!(responses[2].result(), &listed);
+        assert_eq!(responses[4].result(), &Value::Array(vec![]));
+        match badresult {
+            Err(ProtocolError::InvalidRequest) => {}
+            _ => panic!("Expected InvalidRequest"),
+        }
+    }
+
+    #[test]
+    fn processauthmessage_quota_exceeded()
+    {
+        // -----------------------------------------------------------
+        // GIVEN
+        // A ProcessAuthMessage state with a quota of 1 keyfile of at
+        // most 4 bytes and
+        // a store holding a single keyfile and
+        // requests to create a second keyfile, to change the keyfile
+        // to 5 bytes, and to replace the keyfile with a 4 byte one
+        // -----------------------------------------------------------
+        let mut store = MemoryKeyFile::new("temp", None);
+        store.set(&b"a".to_vec(), &b"1".to_vec()).unwrap();
+        let db = Arc::new(RwLock::new(store));
+        let quota = Quota::new().max_entries(1).max_keyfile_size(4);
+        let bin = |v: &[u8]| Value::from(v);
+        let requests = vec![
+            AuthRequest::new(
+                1,
+                AuthMessage::CreateKeyFile,
+                vec![bin(b"b"), bin(b"2")],
+            ),
+            AuthRequest::new(
+                2,
+                AuthMessage::ChangeKeyFile,
+                vec![bin(b"a"), bin(b"12345")],
+            ),
+            AuthRequest::new(
+                3,
+                AuthMessage::ReplaceKeyFile,
+                vec![bin(b"a"), bin(b"b"), bin(b"1234")],
+            ),
+        ];
+
+        // -------------------------------------------------------
+        // WHEN
+        // Sending each request to a new ProcessAuthMessage state
+        // -------------------------------------------------------
+        let responses: Vec<AuthResponse> = requests
+            .into_iter()
+            .map(|req| {
+                let state = ProcessAuthMessage::new(db.clone()).quota(quota);
+                match Box::new(state).change(req.into()) {
+                    Ok(State::ProcessAuthMessage(_, Some(r))) => r,
+                    _ => panic!("Expected a response"),
+                }
+            })
+            .collect();
+
+        // -------------------------------------------------------------
+        // THEN
+        // Creating and changing the keyfile fail with
+        // AuthError::QuotaExceeded naming the violated limit and
+        // replacing the keyfile succeeds
+        // -------------------------------------------------------------
+        let exceeded = |name: &str, max: u64| {
+            Value::Array(vec![Value::from(name), Value::from(max)])
+        };
+        assert_eq!(responses[0].error_code(), AuthError::QuotaExceeded);
+        assert_eq!(responses[0].result(), &exceeded("entries", 1));
+        assert_eq!(responses[1].error_code(), AuthError::QuotaExceeded);
+        assert_eq!(responses[1].result(), &exceeded("keyfile_size", 4));
+        assert_eq!(responses[2].error_code(), AuthError::Nil);
+        let db = db.read().unwrap();
+        assert_eq!(db.get(&b"b".to_vec()).unwrap(), b"1234".to_vec());
+        assert!(!db.exists(&b"a".to_vec()));
+    }
+
+    #[test]
+    fn processauthmessage_quota_change_keyfile_if()
+    {
+        // -----------------------------------------------------------
+        // GIVEN
+        // A ProcessAuthMessage state with a quota of keyfiles of at
+        // most 4 bytes and
+        // a store holding a single keyfile at revision 1 and
+        // a request to change the keyfile to 5 bytes if it is at
+        // revision 1
+        // -----------------------------------------------------------
+        let mut store = MemoryKeyFile::new("temp", None);
+        store.set(&b"a".to_vec(), &b"1".to_vec()).unwrap();
+        let db = Arc::new(RwLock::new(store));
+        let state = ProcessAuthMessage::new(db.clone())
+            .quota(Quota::new().max_keyfile_size(4));
+        let args = vec![
+            Value::from(&b"a"[..]),
+            Value::from(1),
+            Value::from(&b"12345"[..]),
+        ];
+        let req = AuthRequest::new(42, AuthMessage::ChangeKeyFileIf, args);
+
+        // -------------------------------------------------------
+        // WHEN
+        // Sending the request to the state
+        // -------------------------------------------------------
+        let response = match Box::new(state).change(req.into()) {
+            Ok(State::ProcessAuthMessage(_, Some(r))) => r,
+            _ => panic!("Expected a response"),
+        };
+
+        // -------------------------------------------------------------
+        // THEN
+        // The request fails with AuthError::QuotaExceeded naming the
+        // keyfile size limit and
+        // the keyfile is unchanged
+        // -------------------------------------------------------------
+        let exceeded =
+            Value::Array(vec![Value::from("keyfile_size"), Value::from(4)]);
+        assert_eq!(response.error_code(), AuthError::QuotaExceeded);
+        assert_eq!(response.result(), &exceeded);
+        let db = db.read().unwrap();
+        assert_eq!(db.get(&b"a".to_vec()).unwrap(), b"1".to_vec());
+    }
+
+    #[test]
+    fn processauthmessage_quota_change_key()
+    {
+        // -----------------------------------------------------------
+        // GIVEN
+        // A ProcessAuthMessage state with a quota of keys of at most
+        // 2 bytes and
+        // a store holding a single keyfile and
+        // a request to change its key to a 3 byte key
+        // -----------------------------------------------------------
+        let mut store = MemoryKeyFile::new("temp", None);
+        store.set(&b"a".to_vec(), &b"1".to_vec()).unwrap();
+        let db = Arc::new(RwLock::new(store));
+        let state = ProcessAuthMessage::new(db.clone())
+            .quota(Quota::new().max_key_length(2));
+        let args = vec![Value::from(&b"a"[..]), Value::from(&b"abc"[..])];
+        let req = AuthRequest::new(42, AuthMessage::ChangeKey, args);
+
+        // -------------------------------------------------------
+        // WHEN
+        // Sending the request to the state
+        // -------------------------------------------------------
+        let response = match Box::new(state).change(req.into()) {
+            Ok(State::ProcessAuthMessage(_, Some(r))) => r,
+            _ => panic!("Expected a response"),
+        };
+
+        // -------------------------------------------------------------
+        // THEN
+        // The request fails with AuthError::QuotaExceeded naming the
+        // key length limit and
+        // the keyfile keeps its key
+        // -------------------------------------------------------------
+        let exceeded =
+            Value::Array(vec![Value::from("key_length"), Value::from(2)]);
+        assert_eq!(response.error_code(), AuthError::QuotaExceeded);
+        assert_eq!(response.result(), &exceeded);
+        let db = db.read().unwrap();
+        assert!(db.exists(&b"a".to_vec()));
+        assert!(!db.exists(&b"abc".to_vec()));
+    }
+
+    #[test]
+    fn processauthmessage_quota_restore_revision()
+    {
+        // -----------------------------------------------------------
+        // GIVEN
+        // A ProcessAuthMessage state with a quota of at most 4 bytes
+        // in the store and
+        // a store keeping 1 previous revision, holding a keyfile
+        // changed from 5 bytes to 1 byte and
+        // a request to restore revision 1
+        // -----------------------------------------------------------
+        let key = b"a".to_vec();
+        let mut store = MemoryKeyFile::new("temp", None).history(1);
+        store.set(&key, &b"12345".to_vec()).unwrap();
+        store.set(&key, &b"1".to_vec()).unwrap();
+        let db = Arc::new(RwLock::new(store));
+        let state = ProcessAuthMessage::new(db.clone())
+            .quota(Quota::new().max_bytes(4));
+        let args = vec![Value::from(&key[..]), Value::from(1)];
+        let req = AuthRequest::new(42, AuthMessage::RestoreRevision, args);
+
+        // -------------------------------------------------------
+        // WHEN
+        // Sending the request to the state
+        // -------------------------------------------------------
+        let response = match Box::new(state).change(req.into()) {
+            Ok(State::ProcessAuthMessage(_, Some(r))) => r,
+            _ => panic!("Expected a response"),
+        };
+
+        // -------------------------------------------------------------
+        // THEN
+        // The request fails with AuthError::QuotaExceeded naming the
+        // byte limit and
+        // the keyfile is unchanged
+        // -------------------------------------------------------------
+        let exceeded =
+            Value::Array(vec![Value::from("bytes"), Value::from(4)]);
+        assert_eq!(response.error_code(), AuthError::QuotaExceeded);
+        assert_eq!(response.result(), &exceeded);
+        let db = db.read().unwrap();
+        assert_eq!(db.get(&key).unwrap(), b"1".to_vec());
+    }
+
+    #[test]
+    fn processauthmessage_quota_replaced_over_usage()
+    {
+        // -----------------------------------------------------------
+        // GIVEN
+        // A fake KeyFileDB that reports less usage than the length of
+        // its only keyfile, as a store with stale usage counts would,
+        // and
+        // a ProcessAuthMessage state with a quota of 10 bytes and
+        // a request to change the keyfile to 4 bytes
+        // -----------------------------------------------------------
+        struct FakeDB;
+        impl KeyFileStore for FakeDB {
+            fn exists(&self, _k: &Vec<u8>) -> bool
+            {
+                true
+            }
+            fn get(&self, _k: &Vec<u8>) -> KeyFileResult<Vec<u8>>
+            {
+                unimplemented!()
+            }
+            fn set(&mut self, _k: &Vec<u8>, _file: &Vec<u8>)
+                -> KeyFileResult<()>
+            {
+                Ok(())
+            }
+            fn delete(&mut self, _k: &Vec<u8>) -> KeyFileResult<()>
+            {
+                unimplemented!()
+            }
+            fn info(&self, _k: &Vec<u8>) -> KeyFileResult<KeyFileInfo>
+            {
+                Ok(KeyFileInfo::new(8))
+            }
+            fn usage(&self) -> KeyFileResult<(usize, u64)>
+            {
+                Ok((1, 2))
+            }
+        }
+        let db = Arc::new(RwLock::new(FakeDB));
+        let state = ProcessAuthMessage::new(db)
+            .quota(Quota::new().max_bytes(10));
+        let args = vec![Value::from(&b"a"[..]), Value::from(&b"1234"[..])];
+        let req = AuthRequest::new(42, AuthMessage::ChangeKeyFile, args);
+
+        // -------------------------------------------------------
+        // WHEN
+        // Sending the request to the state
+        // -------------------------------------------------------
+        let response = match Box::new(state).change(req.into()) {
+            Ok(State::ProcessAuthMessage(_, Some(r))) => r,
+            _ => panic!("Expected a response"),
+        };
+
+        // -------------------------------------------------------------
+        // THEN
+        // The keyfile is changed
+        // -------------------------------------------------------------
+        assert_eq!(response.error_code(), AuthError::Nil);
+    }
+
+    #[test]
+    fn processauthmessage_read_only()
+    {
+        // -----------------------------------------------------------
+        // GIVEN
+        // A read-only ProcessAuthMessage state and
+        // a store holding a single keyfile and
+        // requests to get the keyfile, create a second keyfile and
+        // delete the keyfile
+        // -----------------------------------------------------------
+        let mut store = MemoryKeyFile::new("temp", None);
+        store.set(&b"a".to_vec(), &b"1".to_vec()).unwrap();
+        let db = Arc::new(RwLock::new(store));
+        let bin = |v: &[u8]| Value::from(v);
+        let requests = vec![
+            AuthRequest::new(1, AuthMessage::GetKeyFile, vec![bin(b"a")]),
+            AuthRequest::new(
+                2,
+                AuthMessage::CreateKeyFile,
+                vec![bin(b"b"), bin(b"2")],
+            ),
+            AuthRequest::new(3, AuthMessage::DeleteKeyFile, vec![bin(b"a")]),
+        ];
+
+        // -------------------------------------------------------
+        // WHEN
+        // Sending each request to a new ProcessAuthMessage state
+        // -------------------------------------------------------
+        let responses: Vec<AuthResponse> = requests
+            .into_iter()
+            .map(|req| {
+                let state =
+                    ProcessAuthMessage::new(db.clone()).read_only(true);
+                match Box::new(state).change(req.into()) {
+                    Ok(State::ProcessAuthMessage(_, Some(r))) => r,
+                    _ => panic!("Expected a response"),
+                }
+            })
+            .collect();
+
+        // --------------------------------------------------------
+        // THEN
+        // The keyfile is returned and
+        // creating and deleting fail with AuthError::ReadOnly and
+        // the store is unchanged
+        // --------------------------------------------------------
+        assert_eq!(responses[0].error_code(), AuthError::Nil);
+        assert_eq!(responses[0].result(), &bin(b"1"));
+        assert_eq!(responses[1].message_id(), 2);
+        assert_eq!(responses[1].error_code(), AuthError::ReadOnly);
+        assert_eq!(responses[2].error_code(), AuthError::ReadOnly);
+        let db = db.read().unwrap();
+        assert!(db.exists(&b"a".to_vec()));
+        assert!(!db.exists(&b"b".to_vec()));
+    }
+
+    #[test]
+    fn processauthmessage_watch()
+    {
+        // ------------------------------------------------------------
+        // GIVEN
+        // An empty store and
+        // a session watching every key starting with "a" and
+        // a second session that creates "ab", changes its key to "x",
+        // then fails to delete "ab"
+        // ------------------------------------------------------------
+        let db = Arc::new(RwLock::new(MemoryKeyFile::new("temp", None)));
+        let watchers = Arc::new(RwLock::new(Watchers::new()));
+        let (tx, rx) = mpsc::channel(10);
+        let watching = ProcessAuthMessage::new(db.clone())
+            .watcher(Watcher::new(watchers.clone(), tx));
+        let args = vec![Value::from(&b"a"[..]), Value::from(true)];
+        let watch = AuthRequest::new(1, AuthMessage::Watch, args);
+        let (tx, _writer_rx) = mpsc::channel(10);
+        let writing = ProcessAuthMessage::new(db.clone())
+            .watcher(Watcher::new(watchers.clone(), tx));
+        let bin = |v: &[u8]| Value::from(v);
+        let requests = vec![
+            AuthRequest::new(
+                2,
+                AuthMessage::CreateKeyFile,
+                vec![bin(b"ab"), bin(b"1")],
+            ),
+            AuthRequest::new(
+                3,
+                AuthMessage::ChangeKey,
+                vec![bin(b"ab"), bin(b"x")],
+            ),
+            AuthRequest::new(4, AuthMessage::DeleteKeyFile, vec![bin(b"ab")]),
+        ];
+
+        // -------------------------------------------------------
+        // WHEN
+        // Sending the watch request to the first session and
+        // sending each request to the second session and
+        // ending both sessions
+        // -------------------------------------------------------
+        let watching = match Box::new(watching).change(watch.into()) {
+            Ok(State::ProcessAuthMessage(s, Some(r))) => {
+                assert_eq!(r.error_code(), AuthError::Nil);
+                assert_eq!(r.result(), &Value::Boolean(true));
+                s
+            }
+            _ => panic!("Expected a response"),
+        };
+        let mut state: Box<SessionState> = Box::new(writing);
+        for req in requests {
+            state = match state.change(req.into()) {
+                Ok(State::ProcessAuthMessage(s, Some(_))) => s,
+                _ => panic!("Expected a response"),
+            };
+        }
+        drop((watching, state));
+
+        // -------------------------------------------------------
+        // THEN
+        // The first session is told that "ab" was created, then
+        // deleted when its key changed
+        // -------------------------------------------------------
+        let notices: Vec<_> = rx.collect()
+            .wait()
+            .unwrap()
+            .into_iter()
+            .map(|val| {
+                let msg = Message::from(val.unwrap()).unwrap();
+                let info = AuthInfo::from(msg).unwrap();
+                (info.message_code(), info.message_args().clone())
+            })
+            .collect();
+        assert_eq!(
+            notices,
+            vec![
+                (AuthNotice::KeyFileCreated, vec![bin(b"ab")]),
+                (AuthNotice::KeyFileDeleted, vec![bin(b"ab")]),
+            ]
+        );
+    }
+
+    #[test]
+    fn processauthmessage_backup_disabled()
+    {
+        // --------------------------------------------------
+        // GIVEN
+        // A ProcessAuthMessage state without a backup dir and
+        // a Backup request
+        // --------------------------------------------------
+        let db = Arc::new(RwLock::new(MemoryKeyFile::new("temp", None)));
+        let state = ProcessAuthMessage::new(db);
+        let args = vec![Value::from("backup"), Value::from(true)];
+        let req = AuthRequest::new(42, AuthMessage::Backup, args);
+
+        // ----------------------------------------------------------
+        // WHEN
+        // Sending the request to the state
+        // ----------------------------------------------------------
+        let response = match Box::new(state).change(req.into()) {
+            Ok(State::ProcessAuthMessage(_, Some(r))) => r,
+            _ => panic!("Expected a response"),
+        };
+
+        // -------------------------------------------------------------
+        // THEN
+        // The message's error code is AuthError::DatabaseError
+        // -------------------------------------------------------------
+        assert_eq!(response.message_id(), 42);
+        assert_eq!(response.error_code(), AuthError::DatabaseError);
+    }
+
+    #[test]
+    fn processauthmessage_backup_outside_dir()
+    {
+        // --------------------------------------------------------
+        // GIVEN
+        // A MemoryKeyFile, which can't be backed up, and
+        // Backup requests naming directories outside of the
+        // backup dir and
+        // a Backup request without the compact argument and
+        // a Backup request naming a directory inside the backup dir
+        // --------------------------------------------------------
+        let db = Arc::new(RwLock::new(MemoryKeyFile::new("temp", None)));
+        let request = |dir: &str| {
+            let args = vec![Value::from(dir), Value::from(true)];
+            AuthRequest::new(42, AuthMessage::Backup, args)
+        };
+        let outside: Vec<AuthRequest> = ["", "/tmp/x", "../x", "a/../../x"]
+            .iter()
+            .map(|dir| request(dir))
+            .collect();
+        let args = vec![Value::from("backup")];
+        let badreq = AuthRequest::new(43, AuthMessage::Backup, args);
+        let inside = request("daily/1");
+
+        // ----------------------------------------------------------
+        // WHEN
+        // Sending each request to a ProcessAuthMessage state with a
+        // backup dir
+        // ----------------------------------------------------------
+        let send = |req: AuthRequest| {
+            let state = ProcessAuthMessage::new(db.clone())
+                .backup_dir(PathBuf::from("/nonexistent/backups"));
+            Box::new(state).change(req.into())
+        };
+        let results: Vec<_> = outside.into_iter().map(&send).collect();
+        let badresult = send(badreq);
+        let result = send(inside);
+
+        // -------------------------------------------------------------
+        // THEN
+        // Requests outside the backup dir fail with
+        // ProtocolError::InvalidRequest and
+        // the request without compact fails with
+        // ProtocolError::InvalidRequestArgs and
+        // the request inside the backup dir reaches the store, which
+        // fails with AuthError::DatabaseError
+        // -------------------------------------------------------------
+        for result in results {
+            match result {
+                Err(ProtocolError::InvalidRequest) => {}
+                _ => panic!("Expected InvalidRequest"),
+            }
+        }
+        match badresult {
+            Err(ProtocolError::InvalidRequestArgs) => {}
+            _ => panic!("Expected InvalidRequestArgs"),
+        }
+        match result {
+            Ok(State::ProcessAuthMessage(_, Some(r))) => {
+                assert_eq!(r.error_code(), AuthError::DatabaseError);
+            }
+            _ => panic!("Expected a response"),
+        }
+    }
+
+    #[test]
+    fn processauthrequest_run_restorerevision()
+    {
+        // ----------------------------------------------------------------
+        // GIVEN
+        // A MemoryKeyFile keeping 1 previous revision and
+        // a keyfile that has been changed once and
+        // a RestoreRevision request for revision 1
+        // ----------------------------------------------------------------
+        let key = "ANSWER".to_string().into_bytes();
+        let mut store = MemoryKeyFile::new("temp", None).history(1);
+        store.set(&key, &"42".to_string().into_bytes()).unwrap();
+        store.set(&key, &"24".to_string().into_bytes()).unwrap();
+        let db = Arc::new(RwLock::new(store));
+
+        let args = vec![Value::from(&key[..]), Value::from(1)];
+        let req = AuthRequest::new(42, AuthMessage::RestoreRevision, args);
+
+        // ----------------------------------------------------------
+        // WHEN
+        // Calling ProcessAuthRequest.run() with the request message
+        // ----------------------------------------------------------
+        let quota = Quota::new();
+        let response = ProcessAuthRequest.run(db.clone(), &quota, req.into())
+            .unwrap();
+
+        // ------------------------------------------------------------
+        // THEN
+        // The message's error code is AuthError::Nil and
+        // the keyfile has the contents of revision 1 and
+        // the keyfile is at revision 3
+        // ------------------------------------------------------------
+        assert_eq!(response.error_code(), AuthError::Nil);
+        let db = db.read().unwrap();
+        assert_eq!(db.get(&key).unwrap(), "42".to_string().into_bytes());
+        assert_eq!(db.info(&key).unwrap().revision, 3);
+    }
+
+    #[test]
+    fn processauthrequest_run_getrevision_notfound()
+    {
+        // ----------------------------------------------------------------
+        // GIVEN
+        // A MemoryKeyFile that doesn't keep any history and
+        // a keyfile that has been changed once and
+        // a GetRevision request for revision 1
+        // ----------------------------------------------------------------
+        let key = "ANSWER".to_string().into_bytes();
+        let mut store = MemoryKeyFile::new("temp", None);
+        store.set(&key, &"42".to_string().into_bytes()).unwrap();
+        store.set(&key, &"24".to_string().into_bytes()).unwrap();
+        let db = Arc::new(RwLock::new(store));
+
+        let args = vec![Value::from(&key[..]), Value::from(1)];
+        let req = AuthRequest::new(42, AuthMessage::GetRevision, args);
+
+        // ----------------------------------------------------------
+        // WHEN
+        // Calling ProcessAuthRequest.run() with the request message
+        // ----------------------------------------------------------
+        let response =
+            ProcessAuthRequest.run(db, &Quota::new(), req.into()).unwrap();
+
+        // ------------------------------------------------------------
+        // THEN
+        // The message's error code is AuthError::RevisionNotFound and
+        // the message's result is the key and revision
+        // ------------------------------------------------------------
+        assert_eq!(response.error_code(), AuthError::RevisionNotFound);
+        let expected = vec![Value::from(&key[..]), Value::from(1)];
+        assert_eq!(response.result(), &Value::Array(expected));
+    }
+
+    #[test]
+    fn processauthrequest_run_listkeys_pages()
+    {
+        // ----------------------------------------------------------------
+        // GIVEN
+        // A MemoryKeyFile holding 3 keys that start with "a" and
+        // 1 key that starts with "b" and
+        // a ListKeys request for prefix "a" with a page size of 2 and
+        // no resume token
+        // ----------------------------------------------------------------
+        let mut store = MemoryKeyFile::new("temp", None);
+        for k in &["a1", "a2", "a3", "b1"] {
+            let key = k.to_string().into_bytes();
+            store.set(&key, &key).unwrap();
+        }
+        let db = Arc::new(RwLock::new(store));
+        let args = vec![Value::from(&b"a"[..]), Value::from(2), Value::Nil];
+        let req = AuthRequest::new(42, AuthMessage::ListKeys, args);
+
+        // -------------------------------------------------------------
+        // WHEN
+        // Calling ProcessAuthRequest.run() with the first page request
+        // and then with the returned resume token
+        // -------------------------------------------------------------
+        let quota = Quota::new();
+        let first =
+            ProcessAuthRequest.run(db.clone(), &quota, req.into()).unwrap();
+        let token = first.result().as_array().unwrap()[1].clone();
+        let args = vec![Value::from(&b"a"[..]), Value::from(2), token];
+        let req = AuthRequest::new(43, AuthMessage::ListKeys, args);
+        let second = ProcessAuthRequest.run(db, &quota, req.into()).unwrap();
+
+        // ------------------------------------------------------------
+        // THEN
+        // The first page has the first 2 keys and a resume token and
+        // the second page has the remaining key and a nil token
+        // ------------------------------------------------------------
+        assert_eq!(first.error_code(), AuthError::Nil);
+        let expected = Value::Array(vec![
+            Value::Array(vec![
+                Value::from(&b"a1"[..]),
+                Value::from(&b"a2"[..]),
+            ]),
+            Value::from(&b"a2"[..]),
+        ]);
+        assert_eq!(first.result(), &expected);
+
+        assert_eq!(second.error_code(), AuthError::Nil);
+        let expected = Value::Array(vec![
+            Value::Array(vec![Value::from(&b"a3"[..])]),
+            Value::Nil,
+        ]);
+        assert_eq!(second.result(), &expected);
+    }
+
+    #[test]
+    fn processauthrequest_run_listkeys_bad_pagesize()
+    {
+        // ---------------------------------------------------
+        // GIVEN
+        // An empty MemoryKeyFile and
+        // a ListKeys request with a page size of 0
+        // ---------------------------------------------------
+        let db = Arc::new(RwLock::new(MemoryKeyFile::new("temp", None)));
+        let args = vec![Value::from(&b"a"[..]), Value::from(0), Value::Nil];
+        let req = AuthRequest::new(42, AuthMessage::ListKeys, args);
+
+        // -------------------------------------------------------
+        // WHEN
+        // Calling ProcessAuthRequest.run() with the request
+        // -------------------------------------------------------
+        let result = ProcessAuthRequest.run(db, &Quota::new(), req.into());
+
+        // ---------------------------------------------------
+        // THEN
+        // The ProtocolError::InvalidRequest error is returned
+        // ---------------------------------------------------
+        match result {
+            Err(ProtocolError::InvalidRequest) => {}
+            _ => panic!("Expected error did not occur"),
+        }
+    }
 }
 
 
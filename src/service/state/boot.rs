@@ -26,6 +26,7 @@ use network::rpc::{Message, MessageType, NotificationMessage,
 use protocol::message::{BootError, BootMessage, BootNotice, ProtocolError};
 use rmpv::Value;
 use storage::KeyFileError;
+use util::Unpoison;
 
 
 // ===========================================================================
@@ -102,6 +103,9 @@ impl ProcessBootRequest {
         match req.message_code() {
             BootMessage::KeyExists => return self.req_key_exists(req, db),
             BootMessage::GetKeyFile => return self.req_get_keyfile(req, db),
+            BootMessage::GetKeyFileInfo => {
+                return self.req_get_keyfile_info(req, db)
+            }
         }
     }
 
@@ -123,6 +127,43 @@ impl ProcessBootRequest {
         Ok(key)
     }
 
+    fn _error_response(&self, req: &BootRequest, err: KeyFileError)
+        -> BootResponse
+    {
+        let (code, result) = match err {
+            KeyFileError::Key(k) => {
+                (BootError::KeyFileNotFound, Value::from(k))
+            }
+            KeyFileError::MapFull => {
+                (BootError::DatabaseFull, Value::Boolean(false))
+            }
+            KeyFileError::ReadersFull => {
+                (BootError::DatabaseReadersFull, Value::Boolean(false))
+            }
+            KeyFileError::Corrupted => {
+                (BootError::DatabaseCorrupted, Value::Boolean(false))
+            }
+            KeyFileError::Io => (BootError::DatabaseIo, Value::Boolean(false)),
+            KeyFileError::ValueTooLarge => {
+                (BootError::ValueTooLarge, Value::Boolean(false))
+            }
+            // Boot sessions never make conditional changes, use the
+            // history or manage namespaces
+            KeyFileError::Mismatch(_) |
+            KeyFileError::Revision(_, _) |
+            KeyFileError::Namespace(_) |
+            KeyFileError::NamespaceExists(_) |
+            KeyFileError::Quota(_, _) |
+            KeyFileError::Schema(_) |
+            KeyFileError::KeySalt |
+            KeyFileError::Trimmed |
+            KeyFileError::Other => {
+                (BootError::DatabaseError, Value::Boolean(false))
+            }
+        };
+        BootResponse::new(req.message_id(), code, result)
+    }
+
     fn req_key_exists(&self, req: BootRequest, db: KeyFileDB)
         -> StateResult<BootResponse>
     {
@@ -131,7 +172,7 @@ impl ProcessBootRequest {
 
         // Get result, dropping the db lock as soon as possible
         let result = {
-            let db = db.read().unwrap();
+            let db = db.read().unpoison();
             Value::Boolean(db.exists(&key))
         };
 
@@ -149,7 +190,7 @@ impl ProcessBootRequest {
 
         // Get keyfile, dropping the db lock as soon as possible
         let keyfile = {
-            let db = db.read().unwrap();
+            let db = db.read().unpoison();
             db.get(&key)
         };
 
@@ -165,17 +206,35 @@ impl ProcessBootRequest {
             }
 
             // Create error response
-            Err(KeyFileError::Key(k)) => {
+            Err(e) => Ok(self._error_response(&req, e)),
+        }
+    }
+
+    fn req_get_keyfile_info(&self, req: BootRequest, db: KeyFileDB)
+        -> StateResult<BootResponse>
+    {
+        // Get key
+        let key = self._check_message(&req)?;
+
+        // Get metadata, dropping the db lock as soon as possible
+        let info = {
+            let db = db.read().unpoison();
+            db.info(&key)
+        };
+
+        match info {
+            // Create response
+            Ok(i) => {
                 let response = BootResponse::new(
                     req.message_id(),
-                    BootError::KeyFileNotFound,
-                    Value::from(k),
+                    BootError::Nil,
+                    Value::from(i),
                 );
                 Ok(response)
             }
 
-            // TODO: handle other errors that may be raised (eg from lmdb backend)
-            Err(KeyFileError::Other) => unimplemented!(),
+            // Create error response
+            Err(e) => Ok(self._error_response(&req, e)),
         }
     }
 }
@@ -191,8 +250,7 @@ mod tests {
 
     // Stdlib imports
 
-    use std::rc::Rc;
-    use std::sync::RwLock;
+    use std::sync::{Arc, RwLock};
 
     // Third-party imports
 
@@ -209,7 +267,9 @@ mod tests {
     use protocol::message::{BootError, BootMessage, BootNotice,
                             ProtocolError};
     use service::state::{SessionState, State};
-    use storage::{KeyFileError, KeyFileResult, KeyFileStore};
+    use storage::{KeyFileBuilder, KeyFileError, KeyFileResult,
+                  KeyFileStore};
+    use storage::memory::MemoryKeyFile;
 
     // --------------------
     // ProcessBootRequest
@@ -244,7 +304,7 @@ mod tests {
                     unimplemented!()
                 }
             }
-            let db = Rc::new(RwLock::new(FakeDB));
+            let db = Arc::new(RwLock::new(FakeDB));
 
             let args: Vec<Value> =
                 args.iter().map(|v| Value::from(v.clone())).collect();
@@ -298,7 +358,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let args = vec![Value::Nil];
         let req = BootRequest::new(42, BootMessage::KeyExists, args);
@@ -358,7 +418,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "ANSWER".to_string().into_bytes();
         let args = vec![Value::from(key)];
@@ -415,7 +475,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "42".to_string().into_bytes();
         let args = vec![Value::from(key)];
@@ -477,7 +537,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "42".to_string().into_bytes();
         let args = vec![Value::from(key)];
@@ -541,7 +601,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "ANSWER".to_string().into_bytes();
         let args = vec![Value::from(key)];
@@ -569,6 +629,109 @@ mod tests {
         assert_eq!(response.result(), &expected);
     }
 
+    #[test]
+    fn processbootrequest_run_getkey_dberror()
+    {
+        // --------------------------------------------------------------------
+        // GIVEN
+        // A fake KeyFileDB that returns a given error for every get and
+        // a Request message with a single argument and
+        // the request code is BootMessage::GetKeyFile
+        // --------------------------------------------------------------------
+        struct FakeDB {
+            err: KeyFileError,
+        }
+        impl KeyFileStore for FakeDB {
+            fn exists(&self, _k: &Vec<u8>) -> bool
+            {
+                unimplemented!()
+            }
+            fn get(&self, _k: &Vec<u8>) -> KeyFileResult<Vec<u8>>
+            {
+                Err(self.err.clone())
+            }
+            fn set(&mut self, _k: &Vec<u8>, _file: &Vec<u8>)
+                -> KeyFileResult<()>
+            {
+                unimplemented!()
+            }
+            fn delete(&mut self, _k: &Vec<u8>) -> KeyFileResult<()>
+            {
+                unimplemented!()
+            }
+        }
+
+        let errors = vec![
+            (KeyFileError::MapFull, BootError::DatabaseFull),
+            (KeyFileError::ReadersFull, BootError::DatabaseReadersFull),
+            (KeyFileError::Corrupted, BootError::DatabaseCorrupted),
+            (KeyFileError::Io, BootError::DatabaseIo),
+            (KeyFileError::ValueTooLarge, BootError::ValueTooLarge),
+            (KeyFileError::Other, BootError::DatabaseError),
+        ];
+
+        for (err, expected) in errors {
+            let db = Arc::new(RwLock::new(FakeDB { err: err }));
+            let key = "42".to_string().into_bytes();
+            let args = vec![Value::from(key)];
+            let req = BootRequest::new(42, BootMessage::GetKeyFile, args);
+            let msg: Message = req.into();
+
+            // ----------------------------------------------------------
+            // WHEN
+            // Calling ProcessBootRequest.run() with a FakeDB object and
+            // the request message
+            // ----------------------------------------------------------
+            let response = ProcessBootRequest.run(db, msg).unwrap();
+
+            // --------------------------------------------------------------
+            // THEN
+            // A BootResponse message is returned and
+            // the message's error code matches the db error and
+            // the message's result is the false boolean value
+            // --------------------------------------------------------------
+            assert_eq!(response.message_id(), 42);
+            assert_eq!(response.error_code(), expected);
+            assert_eq!(response.result(), &Value::Boolean(false));
+        }
+    }
+
+    #[test]
+    fn processbootrequest_run_getkeyfileinfo()
+    {
+        // ------------------------------------------------------------------
+        // GIVEN
+        // A MemoryKeyFile holding a keyfile that has been changed once and
+        // a GetKeyFileInfo request for the keyfile's key
+        // ------------------------------------------------------------------
+        let key = "ANSWER".to_string().into_bytes();
+        let mut store = MemoryKeyFile::new("temp", None);
+        store.set(&key, &"4".to_string().into_bytes()).unwrap();
+        store.set(&key, &"42".to_string().into_bytes()).unwrap();
+        let info = store.info(&key).unwrap();
+        let db = Arc::new(RwLock::new(store));
+
+        let args = vec![Value::from(&key[..])];
+        let req = BootRequest::new(42, BootMessage::GetKeyFileInfo, args);
+
+        // ----------------------------------------------------------
+        // WHEN
+        // Calling ProcessBootRequest.run() with the request message
+        // ----------------------------------------------------------
+        let response = ProcessBootRequest.run(db, req.into()).unwrap();
+
+        // ------------------------------------------------------------
+        // THEN
+        // The message's error code is BootError::Nil and
+        // the message's result is a map of the keyfile's metadata
+        // ------------------------------------------------------------
+        assert_eq!(response.message_id(), 42);
+        assert_eq!(response.error_code(), BootError::Nil);
+        assert_eq!(info.length, 2);
+        assert_eq!(info.revision, 2);
+        assert_eq!(response.result(), &Value::from(info));
+    }
+
     // --------------------
     // ProcessBootMessage
     // --------------------
@@ -610,7 +773,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "noanswer".to_string().into_bytes();
         let args = vec![Value::from(key), Value::Nil];
@@ -667,7 +830,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let key = "ANSWER".to_string().into_bytes();
         let args = vec![Value::from(key)];
@@ -729,7 +892,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let args: Vec<Value> = Vec::new();
         let info = BootInfo::new(BootNotice::Done, args);
@@ -790,7 +953,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let args: Vec<Value> = Vec::new();
         let info = FakeInfo::new(FakeCode::Bad, args);
@@ -843,7 +1006,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let info = BootResponse::new(42, BootError::Nil, Value::Nil);
         let msg: Message = info.into();
@@ -22,6 +22,7 @@
 
 pub mod auth;
 pub mod boot;
+pub mod replica;
 
 
 // ===========================================================================
@@ -31,17 +32,22 @@ pub mod boot;
 
 // Stdlib imports
 
-use std::rc::Rc;
-use std::sync::RwLock;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
 
 
 // Third-party imports
 
+use rmpv::Value;
+
 // Local imports
 
 use network::rpc::{Message, NotificationMessage, RpcNotice};
 use protocol::message::{ProtocolError, SessionType};
-use storage::KeyFileStore;
+use service::replication::ReplicaStatusDB;
+use service::watch::Watcher;
+use storage::{valid_namespace, KeyFileNamespaces, KeyFileStore, Quota};
+use util::Unpoison;
 
 
 // ===========================================================================
@@ -64,6 +70,11 @@ pub enum State {
     BootEnd,
     ProcessAuthMessage(Box<SessionState>, Option<auth::AuthResponse>),
     AuthEnd,
+    ProcessReplicaMessage(
+        Box<SessionState>,
+        Option<replica::ReplicaResponse>,
+    ),
+    ReplicaEnd,
 }
 
 
@@ -72,7 +83,8 @@ pub enum State {
 // ===========================================================================
 
 
-pub trait SessionState {
+// States are changed on the worker threads that run storage requests
+pub trait SessionState: Send {
     fn change(self: Box<Self>, Message) -> StateResult<State>;
 }
 
@@ -82,21 +94,122 @@ pub trait SessionState {
 // ===========================================================================
 
 
-pub type KeyFileDB = Rc<RwLock<KeyFileStore>>;
+pub type KeyFileDB = Arc<RwLock<KeyFileStore>>;
+
+
+pub type NamespaceDB = Arc<RwLock<KeyFileNamespaces>>;
 
 
 pub type SessionInfo = NotificationMessage<SessionType>;
 
 
+// Get a namespace name from a string or binary message argument
+fn namespace_arg(arg: &Value) -> Option<String>
+{
+    let name = match *arg {
+        Value::String(ref s) => s.as_str()?.to_string(),
+        Value::Binary(ref b) => String::from_utf8(b.clone()).ok()?,
+        _ => return None,
+    };
+    if valid_namespace(&name) {
+        Some(name)
+    } else {
+        None
+    }
+}
+
+
 pub struct Start {
+    backup_dir: Option<PathBuf>,
     db: KeyFileDB,
+    namespaces: Option<NamespaceDB>,
+    quota: Quota,
+    read_only: bool,
+    replica: Option<ReplicaStatusDB>,
+    watcher: Option<Watcher>,
 }
 
 
 impl Start {
     pub fn new(db: KeyFileDB) -> Self
     {
-        Self { db: db }
+        Self {
+            backup_dir: None,
+            db: db,
+            namespaces: None,
+            quota: Quota::new(),
+            read_only: false,
+            replica: None,
+            watcher: None,
+        }
+    }
+
+    /// Let auth sessions using the default namespace back up the store,
+    /// along with every namespace, into directories under dir.
+    pub fn backup_dir(mut self, dir: PathBuf) -> Self
+    {
+        self.backup_dir = Some(dir);
+        self
+    }
+
+    /// Let sessions choose a namespace other than the default one.
+    pub fn namespaces(mut self, namespaces: NamespaceDB) -> Self
+    {
+        self.namespaces = Some(namespaces);
+        self
+    }
+
+    /// Limit what auth sessions can write to the store.
+    pub fn quota(mut self, quota: Quota) -> Self
+    {
+        self.quota = quota;
+        self
+    }
+
+    /// Only serve auth requests that leave the store unchanged.
+    pub fn read_only(mut self, val: bool) -> Self
+    {
+        self.read_only = val;
+        self
+    }
+
+    /// Report the given progress to replication status requests, rather
+    /// than that of a primary.
+    pub fn replica(mut self, status: ReplicaStatusDB) -> Self
+    {
+        self.replica = Some(status);
+        self
+    }
+
+    /// Let auth sessions watch keys using the given watcher.
+    pub fn watcher(mut self, watcher: Watcher) -> Self
+    {
+        self.watcher = Some(watcher);
+        self
+    }
+
+    // Get the name and store of the namespace named in the notification.
+    // The default namespace has no name.
+    fn store(&self, notice: &SessionInfo)
+        -> StateResult<(Option<String>, KeyFileDB)>
+    {
+        let args = notice.message_args();
+        let name = match args.len() {
+            0 => return Ok((None, self.db.clone())),
+            1 if args[0].is_nil() => return Ok((None, self.db.clone())),
+            1 => namespace_arg(&args[0]),
+            _ => None,
+        };
+        match (name, self.namespaces.as_ref()) {
+            (Some(name), Some(namespaces)) => {
+                let mut namespaces = namespaces.write().unpoison();
+                let db = namespaces.namespace(&name).map_err(|_| {
+                    ProtocolError::InvalidNotificationArgs
+                })?;
+                Ok((Some(name), db))
+            }
+            _ => Err(ProtocolError::InvalidNotificationArgs),
+        }
     }
 }
 
@@ -108,19 +221,38 @@ impl SessionState for Start {
         let notice = SessionInfo::from(m).map_err(|_| {
             ProtocolError::InvalidNotification
         })?;
+        let (name, db) = self.store(&notice)?;
 
-        // Determine if should use boot or auth processing
+        // Determine if should use boot, auth or replica processing
         match notice.message_code() {
             SessionType::Boot => Ok(State::ProcessBootMessage(
-                Box::new(boot::ProcessBootMessage::new(
-                    self.db.clone(),
-                )),
+                Box::new(boot::ProcessBootMessage::new(db)),
                 None,
             )),
-            SessionType::Auth => Ok(State::ProcessAuthMessage(
-                Box::new(auth::ProcessAuthMessage::new(
-                    self.db.clone(),
-                )),
+            SessionType::Auth => {
+                let mut state = auth::ProcessAuthMessage::new(db)
+                    .quota(self.quota)
+                    .read_only(self.read_only);
+                if let Some(namespaces) = self.namespaces {
+                    state = state.namespaces(namespaces);
+                }
+                if let Some(status) = self.replica {
+                    state = state.replica(status);
+                }
+                // Backups copy every namespace, so sessions limited to one
+                // can't make them
+                if let (Some(dir), None) = (self.backup_dir, name.as_ref()) {
+                    state = state.backup_dir(dir);
+                }
+                if let Some(watcher) = self.watcher {
+                    state = state.watcher(watcher.namespace(name));
+                }
+                Ok(State::ProcessAuthMessage(Box::new(state), None))
+            }
+
+            // The change log covers every namespace
+            SessionType::Replica => Ok(State::ProcessReplicaMessage(
+                Box::new(replica::ProcessReplicaMessage::new(self.db)),
                 None,
             )),
         }
@@ -138,8 +270,8 @@ mod tests {
 
     // Stdlib imports
 
-    use std::rc::Rc;
-    use std::sync::RwLock;
+    use std::path::{Path, PathBuf};
+    use std::sync::{Arc, RwLock};
 
     // Third-party imports
 
@@ -147,11 +279,16 @@ mod tests {
 
     // Local imports
 
-    use super::{SessionInfo, Start, State};
+    use super::{KeyFileDB, SessionInfo, SessionState, Start, State};
     use network::rpc::Message;
     use protocol::message::{BootError, ProtocolError, SessionType};
+    use network::rpc::RpcResponse;
+    use protocol::message::{AuthError, AuthMessage};
+    use service::state::auth::AuthRequest;
     use service::state::boot::BootResponse;
-    use storage::{KeyFileResult, KeyFileStore};
+    use storage::{KeyFileBuilder, KeyFileNamespaces, KeyFileResult,
+                  KeyFileStore};
+    use storage::memory::{MemoryKeyFile, MemoryNamespaces};
 
     // --------------------
     // Start
@@ -185,7 +322,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let info = BootResponse::new(42, BootError::Nil, Value::Nil);
         let msg: Message = info.into();
@@ -240,7 +377,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let args = vec![Value::Nil];
         let info = SessionInfo::new(SessionType::Boot, args);
@@ -299,7 +436,7 @@ mod tests {
                 unimplemented!()
             }
         }
-        let db = Rc::new(RwLock::new(FakeDB));
+        let db = Arc::new(RwLock::new(FakeDB));
 
         let args = vec![Value::Nil];
         let info = SessionInfo::new(SessionType::Auth, args);
@@ -328,6 +465,158 @@ mod tests {
         };
         assert!(val);
     }
+
+    #[test]
+    fn start_auth_namespace()
+    {
+        // ----------------------------------------------------------------
+        // GIVEN
+        // An empty default MemoryKeyFile and
+        // a "team-a" namespace holding a key and
+        // an Auth notification naming the "team-a" namespace and
+        // an Auth notification naming a namespace that doesn't exist and
+        // a KeyExists request for the key
+        // ----------------------------------------------------------------
+        let key = "ANSWER".to_string().into_bytes();
+        let mut namespaces = MemoryNamespaces::new();
+        namespaces.create_namespace("team-a").unwrap();
+        let store = namespaces.namespace("team-a").unwrap();
+        store.write().unwrap().set(&key, &key).unwrap();
+        let namespaces = Arc::new(RwLock::new(namespaces));
+        let db = Arc::new(RwLock::new(MemoryKeyFile::new("temp", None)));
+        let start = || {
+            let start = Start::new(db.clone()).namespaces(namespaces.clone());
+            Box::new(start)
+        };
+
+        let info = SessionInfo::new(SessionType::Auth, vec![
+            Value::from("team-a"),
+        ]);
+        let missing = SessionInfo::new(SessionType::Auth, vec![
+            Value::from("team-b"),
+        ]);
+        let args = vec![Value::from(&key[..])];
+        let req = AuthRequest::new(42, AuthMessage::KeyExists, args);
+
+        // ---------------------------------------------------------
+        // WHEN
+        // Calling Start.change() with each notification and
+        // sending the request to the state chosen for "team-a"
+        // ---------------------------------------------------------
+        let result = start().change(info.into());
+        let missing = start().change(missing.into());
+        let response = match result {
+            Ok(State::ProcessAuthMessage(s, _)) => s.change(req.into()),
+            _ => unreachable!(),
+        };
+
+        // -----------------------------------------------------------
+        // THEN
+        // The request is answered from the "team-a" namespace and
+        // the missing namespace gives an InvalidNotificationArgs error
+        // -----------------------------------------------------------
+        match response {
+            Ok(State::ProcessAuthMessage(_, Some(r))) => {
+                assert_eq!(r.result(), &Value::Boolean(true));
+            }
+            _ => panic!("Expected a response"),
+        }
+        match missing {
+            Err(ProtocolError::InvalidNotificationArgs) => {}
+            _ => panic!("Expected InvalidNotificationArgs"),
+        }
+    }
+
+    #[test]
+    fn start_auth_namespace_backup()
+    {
+        // ----------------------------------------------------------------
+        // GIVEN
+        // A fake KeyFileDB that can always be backed up and
+        // fake namespaces sharing it, as lmdb namespaces share an
+        // environment, and
+        // a Start state with a backup dir and
+        // Auth notifications for the default and "team-a" namespaces and
+        // a Backup request
+        // ----------------------------------------------------------------
+        struct FakeDB;
+        impl KeyFileStore for FakeDB {
+            fn exists(&self, _k: &Vec<u8>) -> bool
+            {
+                unimplemented!()
+            }
+            fn get(&self, _k: &Vec<u8>) -> KeyFileResult<Vec<u8>>
+            {
+                unimplemented!()
+            }
+            fn set(&mut self, _k: &Vec<u8>, _file: &Vec<u8>)
+                -> KeyFileResult<()>
+            {
+                unimplemented!()
+            }
+            fn delete(&mut self, _k: &Vec<u8>) -> KeyFileResult<()>
+            {
+                unimplemented!()
+            }
+            fn backup(&self, _dir: &Path, _compact: bool)
+                -> KeyFileResult<()>
+            {
+                Ok(())
+            }
+        }
+        struct FakeNamespaces(KeyFileDB);
+        impl KeyFileNamespaces for FakeNamespaces {
+            fn namespace(&mut self, _name: &str) -> KeyFileResult<KeyFileDB>
+            {
+                Ok(self.0.clone())
+            }
+            fn create_namespace(&mut self, _name: &str)
+                -> KeyFileResult<()>
+            {
+                unimplemented!()
+            }
+            fn list_namespaces(&self) -> KeyFileResult<Vec<String>>
+            {
+                unimplemented!()
+            }
+            fn drop_namespace(&mut self, _name: &str) -> KeyFileResult<()>
+            {
+                unimplemented!()
+            }
+        }
+        let db: KeyFileDB = Arc::new(RwLock::new(FakeDB));
+        let namespaces = Arc::new(RwLock::new(FakeNamespaces(db.clone())));
+        let backup = |args: Vec<Value>| {
+            let start = Start::new(db.clone())
+                .namespaces(namespaces.clone())
+                .backup_dir(PathBuf::from("/nonexistent/backups"));
+            let info = SessionInfo::new(SessionType::Auth, args);
+            let state = match Box::new(start).change(info.into()) {
+                Ok(State::ProcessAuthMessage(s, _)) => s,
+                _ => panic!("Expected ProcessAuthMessage"),
+            };
+            let args = vec![Value::from("daily"), Value::from(true)];
+            let req = AuthRequest::new(42, AuthMessage::Backup, args);
+            match state.change(req.into()) {
+                Ok(State::ProcessAuthMessage(_, Some(r))) => r.error_code(),
+                _ => panic!("Expected a response"),
+            }
+        };
+
+        // ---------------------------------------------------------
+        // WHEN
+        // Sending the request from a session on each namespace
+        // ---------------------------------------------------------
+        let default = backup(vec![]);
+        let namespaced = backup(vec![Value::from("team-a")]);
+
+        // -----------------------------------------------------------
+        // THEN
+        // Only the session on the default namespace makes the backup
+        // -----------------------------------------------------------
+        assert_eq!(default, AuthError::Nil);
+        assert_eq!(namespaced, AuthError::DatabaseError);
+    }
 }
 
 
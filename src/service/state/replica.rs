@@ -0,0 +1,305 @@
+// src/service/state/replica.rs
+// Copyright (C) 2017 authors and contributors (see AUTHORS file)
+//
+// This file is released under the MIT License.
+
+// ===========================================================================
+// Externs
+// ===========================================================================
+
+
+// ===========================================================================
+// Imports
+// ===========================================================================
+
+
+// Stdlib imports
+
+use std::cmp;
+
+// Third-party imports
+
+use rmpv::Value;
+
+// Local imports
+
+use super::{KeyFileDB, SessionState, State, StateResult};
+use network::rpc::{Message, MessageType, NotificationMessage,
+                   RequestMessage, ResponseMessage, RpcMessage, RpcNotice,
+                   RpcRequest};
+use protocol::message::{ProtocolError, ReplicaError, ReplicaMessage,
+                        ReplicaNotice};
+use storage::KeyFileError;
+use util::Unpoison;
+
+
+// ===========================================================================
+// Replica messages
+// ===========================================================================
+
+
+pub type ReplicaRequest = RequestMessage<ReplicaMessage>;
+
+
+pub type ReplicaResponse = ResponseMessage<ReplicaError>;
+
+
+pub type ReplicaInfo = NotificationMessage<ReplicaNotice>;
+
+
+// Most changes sent in a single response, whatever limit is asked for
+pub const MAX_CHANGES: usize = 1024;
+
+
+// ===========================================================================
+// ProcessReplicaMessage
+// ===========================================================================
+
+
+pub struct ProcessReplicaMessage {
+    db: KeyFileDB,
+}
+
+
+impl ProcessReplicaMessage {
+    pub fn new(db: KeyFileDB) -> Self
+    {
+        Self { db: db }
+    }
+}
+
+
+impl SessionState for ProcessReplicaMessage {
+    fn change(self: Box<Self>, m: Message) -> StateResult<State>
+    {
+        match m.message_type().unwrap() {
+
+            // If the message is a request, process it and change state back
+            // to ProcessReplicaMessage
+            MessageType::Request => {
+                let db = self.db.clone();
+                let response = ProcessReplicaRequest.run(db, m)?;
+                Ok(State::ProcessReplicaMessage(self, Some(response)))
+            }
+
+            // If the message is a done notification, change state to
+            // ReplicaEnd
+            MessageType::Notification => {
+                let notice = ReplicaInfo::from(m).map_err(|_| {
+                    ProtocolError::InvalidNotification
+                })?;
+                match notice.message_code() {
+                    ReplicaNotice::Done => Ok(State::ReplicaEnd),
+                }
+            }
+
+            // If the message is a response, return an error
+            MessageType::Response => Err(ProtocolError::UnexpectedMessage),
+        }
+    }
+}
+
+
+struct ProcessReplicaRequest;
+
+
+impl ProcessReplicaRequest {
+    fn run(&self, db: KeyFileDB, m: Message) -> StateResult<ReplicaResponse>
+    {
+        let req = ReplicaRequest::from(m).unwrap();
+        match req.message_code() {
+            ReplicaMessage::GetChanges => self.req_get_changes(req, db),
+        }
+    }
+
+    fn _error_response(&self, req: &ReplicaRequest, err: KeyFileError)
+        -> ReplicaResponse
+    {
+        let code = match err {
+            KeyFileError::ReadersFull => ReplicaError::DatabaseReadersFull,
+            KeyFileError::Corrupted => ReplicaError::DatabaseCorrupted,
+            KeyFileError::Io => ReplicaError::DatabaseIo,
+            KeyFileError::Trimmed => ReplicaError::ChangesTrimmed,
+            _ => ReplicaError::DatabaseError,
+        };
+        ReplicaResponse::new(req.message_id(), code, Value::Boolean(false))
+    }
+
+    fn req_get_changes(&self, req: ReplicaRequest, db: KeyFileDB)
+        -> StateResult<ReplicaResponse>
+    {
+        // Get args
+        let args = req.message_args();
+        if args.len() != 2 {
+            return Err(ProtocolError::InvalidRequestArgs);
+        }
+        let (after, limit) = match (args[0].as_u64(), args[1].as_u64()) {
+            (Some(after), Some(limit)) => (after, limit),
+            _ => return Err(ProtocolError::InvalidRequest),
+        };
+        let limit = cmp::min(limit, MAX_CHANGES as u64) as usize;
+
+        // Get changes, dropping the db lock as soon as possible
+        let changes = {
+            let db = db.read().unpoison();
+            db.changes(after, limit)
+        };
+        let (latest, changes) = match changes {
+            Ok(c) => c,
+
+            // Create error response
+            Err(e) => return Ok(self._error_response(&req, e)),
+        };
+
+        let changes = changes
+            .into_iter()
+            .map(|(seq, change)| {
+                Value::Array(vec![Value::from(seq), Value::from(change)])
+            })
+            .collect();
+        let result =
+            Value::Array(vec![Value::from(latest), Value::Array(changes)]);
+        let response =
+            ReplicaResponse::new(req.message_id(), ReplicaError::Nil, result);
+        Ok(response)
+    }
+}
+
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+
+#[cfg(test)]
+mod tests {
+
+    // Stdlib imports
+
+    use std::sync::{Arc, RwLock};
+
+    // Third-party imports
+
+    use rmpv::Value;
+
+    // Local imports
+
+    use super::{ProcessReplicaMessage, ReplicaRequest};
+    use network::rpc::RpcResponse;
+    use protocol::message::{ProtocolError, ReplicaError, ReplicaMessage};
+    use service::state::{SessionState, State};
+    use storage::{Change, Changes, KeyFileBuilder, KeyFileResult,
+                  KeyFileStore};
+    use storage::memory::MemoryKeyFile;
+
+    #[test]
+    fn processreplicamessage_get_changes()
+    {
+        // --------------------------------------------------------
+        // GIVEN
+        // A fake KeyFileDB holding a change log of 3 changes and
+        // a GetChanges request for up to 1 change after change 1
+        // --------------------------------------------------------
+        struct FakeDB;
+        impl KeyFileStore for FakeDB {
+            fn exists(&self, _k: &Vec<u8>) -> bool
+            {
+                unreachable!()
+            }
+            fn get(&self, _k: &Vec<u8>) -> KeyFileResult<Vec<u8>>
+            {
+                unreachable!()
+            }
+            fn set(&mut self, _k: &Vec<u8>, _file: &Vec<u8>)
+                -> KeyFileResult<()>
+            {
+                unreachable!()
+            }
+            fn delete(&mut self, _k: &Vec<u8>) -> KeyFileResult<()>
+            {
+                unreachable!()
+            }
+            fn changes(&self, after: u64, limit: usize)
+                -> KeyFileResult<(u64, Changes)>
+            {
+                assert_eq!((after, limit), (1, 1));
+                let change = Change::Delete(None, b"42".to_vec());
+                Ok((3, vec![(2, change)]))
+            }
+        }
+        let db = Arc::new(RwLock::new(FakeDB));
+        let args = vec![Value::from(1), Value::from(1)];
+        let req = ReplicaRequest::new(42, ReplicaMessage::GetChanges, args);
+
+        // ------------------------------------------------------
+        // WHEN
+        // Sending the request to a ProcessReplicaMessage state
+        // ------------------------------------------------------
+        let state = Box::new(ProcessReplicaMessage::new(db));
+        let response = match state.change(req.into()) {
+            Ok(State::ProcessReplicaMessage(_, Some(r))) => r,
+            _ => panic!("Expected a response"),
+        };
+
+        // ------------------------------------------------------------
+        // THEN
+        // The response holds the latest sequence number and change 2
+        // ------------------------------------------------------------
+        let change = Value::from(Change::Delete(None, b"42".to_vec()));
+        let changes = vec![Value::Array(vec![Value::from(2), change])];
+        let expected =
+            Value::Array(vec![Value::from(3), Value::Array(changes)]);
+        assert_eq!(response.message_id(), 42);
+        assert_eq!(response.error_code(), ReplicaError::Nil);
+        assert_eq!(response.result(), &expected);
+    }
+
+    #[test]
+    fn processreplicamessage_no_change_log()
+    {
+        // ------------------------------------------------------
+        // GIVEN
+        // A MemoryKeyFile, which doesn't keep a change log, and
+        // a GetChanges request and
+        // another one with a missing argument
+        // ------------------------------------------------------
+        let mut store = MemoryKeyFile::new("temp", None);
+        store.set(&b"42".to_vec(), &b"answer".to_vec()).unwrap();
+        let db = Arc::new(RwLock::new(store));
+        let args = vec![Value::from(0), Value::from(10)];
+        let req = ReplicaRequest::new(42, ReplicaMessage::GetChanges, args);
+        let args = vec![Value::from(0)];
+        let badreq =
+            ReplicaRequest::new(43, ReplicaMessage::GetChanges, args);
+
+        // --------------------------------------------------------
+        // WHEN
+        // Sending each request to a new ProcessReplicaMessage state
+        // --------------------------------------------------------
+        let state = Box::new(ProcessReplicaMessage::new(db.clone()));
+        let result = state.change(req.into());
+        let state = Box::new(ProcessReplicaMessage::new(db));
+        let badresult = state.change(badreq.into());
+
+        // ------------------------------------------------------------
+        // THEN
+        // The first request fails with ReplicaError::DatabaseError and
+        // the second request is rejected
+        // ------------------------------------------------------------
+        match result {
+            Ok(State::ProcessReplicaMessage(_, Some(r))) => {
+                assert_eq!(r.error_code(), ReplicaError::DatabaseError)
+            }
+            _ => panic!("Expected a response"),
+        }
+        match badresult {
+            Err(e) => assert_eq!(e, ProtocolError::InvalidRequestArgs),
+            _ => panic!("Expected an error"),
+        }
+    }
+}
+
+
+// ===========================================================================
+//
+// ===========================================================================
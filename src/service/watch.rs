@@ -0,0 +1,344 @@
+// src/service/watch.rs
+// Copyright (C) 2017 authors and contributors (see AUTHORS file)
+//
+// This file is released under the MIT License.
+
+// ===========================================================================
+// Imports
+// ===========================================================================
+
+
+// Stdlib imports
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+// Third-party imports
+
+use futures::sync::mpsc;
+use rmpv::Value;
+
+// Local imports
+
+use network::rpc::Message;
+use protocol::message::AuthNotice;
+use service::state::auth::AuthInfo;
+use util::Unpoison;
+
+
+// ===========================================================================
+// Watchers
+// ===========================================================================
+
+
+/// Number of notifications a connection can fall behind by before it is
+/// closed.
+pub const NOTIFY_BUFFER: usize = 64;
+
+
+/// Sends messages that the server starts to a connection, or None to close
+/// the connection.
+pub type Notifier = mpsc::Sender<Option<Value>>;
+
+
+// Keys watched by a single session
+struct WatchSession {
+    namespace: Option<String>,
+    notifier: Notifier,
+
+    // Watched keys, and whether every key they prefix is also watched
+    keys: Vec<(Vec<u8>, bool)>,
+}
+
+
+impl WatchSession {
+    fn watches(&self, namespace: &Option<String>, key: &[u8]) -> bool
+    {
+        if self.namespace != *namespace {
+            return false;
+        }
+        self.keys.iter().any(|watched| {
+            let (ref k, prefix) = *watched;
+            if prefix {
+                key.starts_with(k)
+            } else {
+                key == &k[..]
+            }
+        })
+    }
+}
+
+
+/// Every watch made by the sessions of a server.
+pub struct Watchers {
+    next_id: u64,
+    sessions: HashMap<u64, WatchSession>,
+}
+
+
+impl Watchers {
+    pub fn new() -> Self
+    {
+        Self {
+            next_id: 0,
+            sessions: HashMap::new(),
+        }
+    }
+
+    /// Send a notification to every session watching key in namespace.
+    ///
+    /// Sessions whose connection has closed are forgotten. So are sessions
+    /// that have fallen too far behind, and their connection is closed.
+    pub fn notify(
+        &mut self, namespace: &Option<String>, notice: AuthNotice,
+        key: &[u8]
+    )
+    {
+        let msg: Message =
+            AuthInfo::new(notice, vec![Value::from(key.to_vec())]).into();
+        let val: Value = msg.into();
+        self.sessions.retain(|_, session| {
+            if !session.watches(namespace, key) {
+                return true;
+            }
+            match session.notifier.try_send(Some(val.clone())) {
+                Ok(()) => true,
+
+                // A new sender can always send one message, however full
+                // the channel is
+                Err(ref e) if e.is_full() => {
+                    let _ = session.notifier.clone().try_send(None);
+                    false
+                }
+                Err(_) => false,
+            }
+        });
+    }
+
+    fn watch(
+        &mut self, id: u64, watcher: &Watcher, key: Vec<u8>, prefix: bool
+    )
+    {
+        let session = self.sessions.entry(id).or_insert_with(|| {
+            WatchSession {
+                namespace: watcher.namespace.clone(),
+                notifier: watcher.notifier.clone(),
+                keys: Vec::new(),
+            }
+        });
+        session.keys.push((key, prefix));
+    }
+}
+
+
+impl Default for Watchers {
+    fn default() -> Self
+    {
+        Self::new()
+    }
+}
+
+
+pub type WatchDB = Arc<RwLock<Watchers>>;
+
+
+// ===========================================================================
+// Watcher
+// ===========================================================================
+
+
+/// A session's access to the watchers of its server.
+///
+/// The session's watches are dropped along with the watcher.
+pub struct Watcher {
+    id: u64,
+    watchers: WatchDB,
+    namespace: Option<String>,
+    notifier: Notifier,
+}
+
+
+impl Watcher {
+    /// Create a watcher whose notifications are sent with notifier.
+    pub fn new(watchers: WatchDB, notifier: Notifier) -> Self
+    {
+        let id = {
+            let mut watchers = watchers.write().unpoison();
+            watchers.next_id += 1;
+            watchers.next_id
+        };
+        Self {
+            id: id,
+            watchers: watchers,
+            namespace: None,
+            notifier: notifier,
+        }
+    }
+
+    /// Watch and report changes to the given namespace rather than the
+    /// default one.
+    pub fn namespace(mut self, name: Option<String>) -> Self
+    {
+        self.namespace = name;
+        self
+    }
+
+    /// Notify the session of changes to key, or of changes to every key
+    /// starting with key if prefix is true.
+    pub fn watch(&self, key: Vec<u8>, prefix: bool)
+    {
+        let mut watchers = self.watchers.write().unpoison();
+        watchers.watch(self.id, self, key, prefix);
+    }
+
+    /// Notify every session watching key of a change made by this session.
+    pub fn notify(&self, notice: AuthNotice, key: &[u8])
+    {
+        let mut watchers = self.watchers.write().unpoison();
+        watchers.notify(&self.namespace, notice, key);
+    }
+}
+
+
+impl Drop for Watcher {
+    fn drop(&mut self)
+    {
+        let mut watchers = self.watchers.write().unpoison();
+        watchers.sessions.remove(&self.id);
+    }
+}
+
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+
+#[cfg(test)]
+mod tests {
+
+    // Stdlib imports
+
+    use std::sync::{Arc, RwLock};
+
+    // Third-party imports
+
+    use futures::{Future, Stream};
+    use futures::sync::mpsc;
+    use rmpv::Value;
+
+    // Local imports
+
+    use super::{NOTIFY_BUFFER, Watcher, Watchers};
+    use network::rpc::{Message, RpcNotice};
+    use protocol::message::AuthNotice;
+    use service::state::auth::AuthInfo;
+
+    #[test]
+    fn watchers_notify()
+    {
+        // --------------------------------------------------------
+        // GIVEN
+        // A session watching the key "4" and every key starting
+        // with "team/" and
+        // a session watching the key "4" in namespace "team-a"
+        // --------------------------------------------------------
+        let watchers = Arc::new(RwLock::new(Watchers::new()));
+        let (tx, rx) = mpsc::channel(NOTIFY_BUFFER);
+        let watcher = Watcher::new(watchers.clone(), tx);
+        watcher.watch(b"4".to_vec(), false);
+        watcher.watch(b"team/".to_vec(), true);
+        let (tx, other_rx) = mpsc::channel(NOTIFY_BUFFER);
+        let other = Watcher::new(watchers.clone(), tx)
+            .namespace(Some("team-a".to_string()));
+        other.watch(b"4".to_vec(), false);
+
+        // ---------------------------------------------------------
+        // WHEN
+        // Changes to "4", "42" and "team/a" in the default namespace
+        // are reported and
+        // both sessions end
+        // ---------------------------------------------------------
+        other.notify(AuthNotice::KeyFileChanged, b"4");
+        let mut watchers = watchers.write().unwrap();
+        let namespace = None;
+        watchers.notify(&namespace, AuthNotice::KeyFileCreated, b"4");
+        watchers.notify(&namespace, AuthNotice::KeyFileCreated, b"42");
+        watchers.notify(&namespace, AuthNotice::KeyFileDeleted, b"team/a");
+        drop(watchers);
+        drop((watcher, other));
+
+        // ----------------------------------------------------------
+        // THEN
+        // The first session is told about "4" and "team/a" and
+        // the second session is told about the change it made itself
+        // ----------------------------------------------------------
+        let notices = |rx: mpsc::Receiver<Option<Value>>| {
+            rx.collect()
+                .wait()
+                .unwrap()
+                .into_iter()
+                .map(|val| {
+                    let msg = Message::from(val.unwrap()).unwrap();
+                    let info = AuthInfo::from(msg).unwrap();
+                    (info.message_code(), info.message_args().clone())
+                })
+                .collect::<Vec<_>>()
+        };
+        let key = |k: &[u8]| vec![Value::from(k.to_vec())];
+        assert_eq!(
+            notices(rx),
+            vec![
+                (AuthNotice::KeyFileCreated, key(b"4")),
+                (AuthNotice::KeyFileDeleted, key(b"team/a")),
+            ]
+        );
+        assert_eq!(
+            notices(other_rx),
+            vec![(AuthNotice::KeyFileChanged, key(b"4"))]
+        );
+    }
+
+    #[test]
+    fn watchers_close_lagging_session()
+    {
+        // --------------------------------------------------------
+        // GIVEN
+        // A session watching the key "4" whose connection can fall
+        // behind by one notification
+        // --------------------------------------------------------
+        let watchers = Arc::new(RwLock::new(Watchers::new()));
+        let (tx, rx) = mpsc::channel(1);
+        let watcher = Watcher::new(watchers.clone(), tx);
+        watcher.watch(b"4".to_vec(), false);
+
+        // --------------------------------------------------------
+        // WHEN
+        // 4 changes to "4" are reported before the connection reads
+        // any notification and
+        // the session ends
+        // --------------------------------------------------------
+        for _ in 0..4 {
+            watcher.notify(AuthNotice::KeyFileChanged, b"4");
+        }
+        drop(watcher);
+
+        // --------------------------------------------------------
+        // THEN
+        // The connection is sent the notifications it had room for
+        // followed by None, which closes it
+        // --------------------------------------------------------
+        let sent: Vec<bool> = rx.collect()
+            .wait()
+            .unwrap()
+            .iter()
+            .map(|val| val.is_some())
+            .collect();
+        assert_eq!(sent, vec![true, true, false]);
+    }
+}
+
+
+// ===========================================================================
+//
+// ===========================================================================
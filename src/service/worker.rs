@@ -0,0 +1,221 @@
+// src/service/worker.rs
+// Copyright (C) 2017 authors and contributors (see AUTHORS file)
+//
+// This file is released under the MIT License.
+
+// ===========================================================================
+// Imports
+// ===========================================================================
+
+
+// Stdlib imports
+
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+
+// Third-party imports
+
+use futures::{Future, future};
+use futures::sync::oneshot;
+
+// Local imports
+
+use util::Unpoison;
+
+
+// ===========================================================================
+// WorkerPool
+// ===========================================================================
+
+
+type Job = Box<FnOnce() + Send>;
+
+
+/// Number of jobs that can be queued for each worker before more are
+/// refused.
+pub const QUEUE_SIZE: usize = 64;
+
+
+/// Future resolving to the value returned by a job run on a worker thread.
+pub type FutureJob<T> = Box<Future<Item = T, Error = io::Error>>;
+
+
+/// A fixed number of threads that run jobs off the event loop.
+///
+/// Jobs are queued until a worker is free, and run in the order they were
+/// given. Clones of a pool share its workers and queue, and the workers
+/// stop once every clone has been dropped and the queue is empty.
+#[derive(Clone)]
+pub struct WorkerPool {
+    jobs: mpsc::SyncSender<Job>,
+}
+
+
+impl WorkerPool {
+    /// Start a pool of the given number of worker threads.
+    ///
+    /// A pool always has at least 1 worker.
+    pub fn new(threads: usize) -> io::Result<Self>
+    {
+        let threads = threads.max(1);
+        let (tx, rx) = mpsc::sync_channel::<Job>(threads * QUEUE_SIZE);
+        let rx = Arc::new(Mutex::new(rx));
+        for i in 0..threads {
+            let rx = rx.clone();
+            thread::Builder::new()
+                .name(format!("safesec-worker-{}", i))
+                .spawn(move || loop {
+                    // Hold the lock only while waiting for the next job
+                    let job = match rx.lock().unpoison().recv() {
+                        Ok(job) => job,
+                        Err(_) => break,
+                    };
+
+                    // A job that panics fails its own future, but leaves
+                    // the worker running
+                    let _ = panic::catch_unwind(AssertUnwindSafe(job));
+                })?;
+        }
+        Ok(Self { jobs: tx })
+    }
+
+    /// Run job on a worker thread, returning a future that resolves to
+    /// the job's return value.
+    ///
+    /// The future fails if the job panics, or at once if the queue is full.
+    pub fn run<F, T>(&self, job: F) -> FutureJob<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        let job: Job = Box::new(move || {
+            let _ = tx.send(job());
+        });
+
+        // Workers only stop once every clone of the pool is gone, so this
+        // only fails when the queue is full. Jobs are given from the event
+        // loop, which mustn't wait for room.
+        if self.jobs.try_send(job).is_err() {
+            let errmsg = "too many storage jobs queued";
+            let err = io::Error::new(io::ErrorKind::Other, errmsg);
+            return Box::new(future::err(err));
+        }
+        Box::new(rx.map_err(|_| {
+            io::Error::new(io::ErrorKind::Other, "storage job failed")
+        }))
+    }
+}
+
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+
+#[cfg(test)]
+mod tests {
+
+    // Stdlib imports
+
+    use std::sync::{Arc, Barrier, mpsc};
+    use std::thread;
+
+    // Third-party imports
+
+    use futures::Future;
+
+    // Local imports
+
+    use super::{QUEUE_SIZE, WorkerPool};
+
+    #[test]
+    fn workerpool_run()
+    {
+        // --------------------------------------------------------
+        // GIVEN
+        // A pool of 2 workers and
+        // 2 jobs that wait for each other before returning their
+        // thread's name and
+        // a job that panics
+        // --------------------------------------------------------
+        let pool = WorkerPool::new(2).unwrap();
+        let barrier = Arc::new(Barrier::new(2));
+        let job = |barrier: Arc<Barrier>| {
+            move || {
+                barrier.wait();
+                thread::current().name().map(String::from)
+            }
+        };
+
+        // --------------------------------------------------------
+        // WHEN
+        // Running every job and waiting for its result
+        // --------------------------------------------------------
+        let first = pool.run(job(barrier.clone()));
+        let second = pool.run(job(barrier));
+        let names = first.join(second).wait().unwrap();
+        let panicked = pool.run(|| panic!("job failed")).wait();
+        let after = pool.run(|| 42).wait();
+
+        // --------------------------------------------------------
+        // THEN
+        // The first 2 jobs ran at the same time on different
+        // workers and
+        // the panicking job's future fails and
+        // the pool carries on running jobs
+        // --------------------------------------------------------
+        let (first, second) = (names.0.unwrap(), names.1.unwrap());
+        assert!(first.starts_with("safesec-worker-"));
+        assert!(second.starts_with("safesec-worker-"));
+        assert_ne!(first, second);
+        assert!(panicked.is_err());
+        assert_eq!(after.unwrap(), 42);
+    }
+
+    #[test]
+    fn workerpool_run_queue_full()
+    {
+        // --------------------------------------------------------
+        // GIVEN
+        // A pool of 1 worker busy with a job that waits to be
+        // released
+        // --------------------------------------------------------
+        let pool = WorkerPool::new(1).unwrap();
+        let (started_tx, started) = mpsc::channel();
+        let (release, release_rx) = mpsc::channel::<()>();
+        let busy = pool.run(move || {
+            started_tx.send(()).unwrap();
+            release_rx.recv().unwrap();
+        });
+        started.recv().unwrap();
+
+        // --------------------------------------------------------
+        // WHEN
+        // Filling the queue and running one more job and
+        // releasing the busy worker
+        // --------------------------------------------------------
+        let queued: Vec<_> = (0..QUEUE_SIZE).map(|i| pool.run(move || i))
+            .collect();
+        let refused = pool.run(|| QUEUE_SIZE).wait();
+        release.send(()).unwrap();
+
+        // --------------------------------------------------------
+        // THEN
+        // The extra job is refused and
+        // every queued job runs
+        // --------------------------------------------------------
+        assert!(refused.is_err());
+        busy.wait().unwrap();
+        for (i, job) in queued.into_iter().enumerate() {
+            assert_eq!(job.wait().unwrap(), i);
+        }
+    }
+}
+
+
+// ===========================================================================
+//
+// ===========================================================================
@@ -0,0 +1,385 @@
+// src/storage/archive.rs
+// Copyright (C) 2017 authors and contributors (see AUTHORS file)
+//
+// This file is released under the MIT License.
+
+// ===========================================================================
+// Imports
+// ===========================================================================
+
+
+// Stdlib imports
+
+use std::io::{self, Read, Write};
+
+// Third-party imports
+
+use bytes::BytesMut;
+use rmpv::Value;
+use tokio_io::codec::{Decoder, Encoder};
+
+// Local imports
+
+use network::codec::MsgPackCodec;
+use storage::{hash_update, KeyFileError, KeyFileInfo, KeyFileNamespaces,
+              KeyFileStore, KeyFileTransaction, HASH_INIT};
+use util::Unpoison;
+
+
+// ===========================================================================
+// Format
+// ===========================================================================
+
+
+// An archive is a stream of MessagePack values:
+//
+//     header:    ["safesec-archive", version]
+//     record:    [key, keyfile, metadata]
+//     namespace: [name]
+//     trailer:   [number of records, checksum]
+//
+// There is one record for each keyfile, in key order. The metadata is the
+// map returned by a GetKeyFileInfo request, or nil if the store doesn't keep
+// metadata. Records belong to the default namespace until a namespace entry
+// is reached, and to that namespace after it. Version 1 archives have no
+// namespace entries. The checksum is the FNV-1a hash of the encoded header,
+// records and namespace entries.
+const MAGIC: &str = "safesec-archive";
+
+
+/// Version of the archive format written by `export()`.
+pub const VERSION: u64 = 2;
+
+
+// Number of keys listed from the store at a time
+const PAGE_SIZE: usize = 256;
+
+
+fn invalid(errmsg: &str) -> io::Error
+{
+    io::Error::new(io::ErrorKind::InvalidData, errmsg)
+}
+
+
+fn store_error(err: KeyFileError) -> io::Error
+{
+    io::Error::new(io::ErrorKind::Other, err.to_string())
+}
+
+
+// Encodes values onto a writer, keeping the checksum of everything written
+struct ArchiveWriter<W> {
+    out: W,
+    buf: BytesMut,
+    hash: u64,
+}
+
+
+impl<W: Write> ArchiveWriter<W> {
+    fn new(out: W) -> Self
+    {
+        Self {
+            out: out,
+            buf: BytesMut::new(),
+            hash: HASH_INIT,
+        }
+    }
+
+    fn write(&mut self, value: Value) -> io::Result<()>
+    {
+        self.buf.clear();
+        MsgPackCodec.encode(value, &mut self.buf)?;
+        self.hash = hash_update(self.hash, &self.buf);
+        self.out.write_all(&self.buf)
+    }
+
+    fn finish(mut self, count: usize) -> io::Result<()>
+    {
+        let trailer = vec![Value::from(count as u64), Value::from(self.hash)];
+        self.write(Value::Array(trailer))?;
+        self.out.flush()
+    }
+}
+
+
+// Decodes values from a reader as they arrive
+struct ArchiveReader<R> {
+    input: R,
+    buf: BytesMut,
+    hash: u64,
+}
+
+
+impl<R: Read> ArchiveReader<R> {
+    fn new(input: R) -> Self
+    {
+        Self {
+            input: input,
+            buf: BytesMut::new(),
+            hash: HASH_INIT,
+        }
+    }
+
+    // Read up to 8KiB more data, returning false once there is no more
+    fn fill(&mut self) -> io::Result<bool>
+    {
+        let mut chunk = [0; 8192];
+        loop {
+            match self.input.read(&mut chunk) {
+                Ok(0) => return Ok(false),
+                Ok(n) => {
+                    self.buf.extend_from_slice(&chunk[..n]);
+                    return Ok(true);
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn read(&mut self) -> io::Result<Value>
+    {
+        loop {
+            if let Some(value) = MsgPackCodec.decode(&mut self.buf)? {
+                return Ok(value);
+            }
+            if !self.fill()? {
+                return Err(invalid("Archive is truncated"));
+            }
+        }
+    }
+
+    // Add a value that was read to the checksum. Values are encoded the same
+    // way every time, so this matches the checksum of the written value.
+    fn add(&mut self, value: &Value) -> io::Result<()>
+    {
+        let mut buf = BytesMut::new();
+        MsgPackCodec.encode(value.clone(), &mut buf)?;
+        self.hash = hash_update(self.hash, &buf);
+        Ok(())
+    }
+
+    // Check that nothing comes after the trailer
+    fn finish(mut self) -> io::Result<()>
+    {
+        if !self.buf.is_empty() || self.fill()? {
+            return Err(invalid("Unexpected data after archive trailer"));
+        }
+        Ok(())
+    }
+}
+
+
+// Read metadata saved by export(). Keyfiles saved without metadata get new
+// metadata.
+fn read_info(value: &Value, keyfile: &[u8]) -> Option<KeyFileInfo>
+{
+    if value.is_nil() {
+        return Some(KeyFileInfo::new(keyfile.len()));
+    }
+    let map = value.as_map()?;
+    let field = |name: &str| {
+        map.iter()
+            .find(|entry| entry.0.as_str() == Some(name))
+            .map(|entry| &entry.1)
+    };
+    let expires = match *field("expires")? {
+        Value::Nil => None,
+        ref t => Some(t.as_u64()?),
+    };
+    Some(KeyFileInfo {
+        created: field("created")?.as_u64()?,
+        modified: field("modified")?.as_u64()?,
+        length: keyfile.len() as u64,
+        revision: field("revision")?.as_u64()?,
+        expires: expires,
+    })
+}
+
+
+// ===========================================================================
+// Export and import
+// ===========================================================================
+
+
+// Write a record for every keyfile in the store, returning the number of
+// records written
+fn write_records<W: Write>(
+    writer: &mut ArchiveWriter<W>, store: &KeyFileStore
+) -> io::Result<usize>
+{
+    let mut count = 0;
+    let mut after: Option<Vec<u8>> = None;
+    loop {
+        let mut keys = store
+            .iter_prefix(&[], after.as_ref().map(|a| &a[..]), PAGE_SIZE)
+            .map_err(store_error)?;
+        for k in &keys {
+            // Skip keyfiles deleted since the keys were listed
+            let keyfile = match store.get(k) {
+                Ok(keyfile) => keyfile,
+                Err(KeyFileError::Key(_)) => continue,
+                Err(e) => return Err(store_error(e)),
+            };
+            let info = match store.info(k) {
+                Ok(info) => Value::from(info),
+                Err(KeyFileError::Other) => Value::Nil,
+                Err(KeyFileError::Key(_)) => continue,
+                Err(e) => return Err(store_error(e)),
+            };
+            let record =
+                vec![Value::from(&k[..]), Value::from(keyfile), info];
+            writer.write(Value::Array(record))?;
+            count += 1;
+        }
+        if keys.len() < PAGE_SIZE {
+            break;
+        }
+        after = keys.pop();
+    }
+    Ok(count)
+}
+
+
+/// Write every keyfile in the store, and in every namespace if namespaces
+/// are given, to out, returning the number of keyfiles written.
+///
+/// Keys are read from the store a page at a time, so keyfiles changed while
+/// the export runs may or may not be included.
+pub fn export<W: Write>(
+    store: &KeyFileStore, namespaces: Option<&mut KeyFileNamespaces>, out: W
+) -> io::Result<usize>
+{
+    let mut writer = ArchiveWriter::new(out);
+    let header = vec![Value::from(MAGIC), Value::from(VERSION)];
+    writer.write(Value::Array(header))?;
+
+    let mut count = write_records(&mut writer, store)?;
+    if let Some(namespaces) = namespaces {
+        for name in namespaces.list_namespaces().map_err(store_error)? {
+            let ns = namespaces.namespace(&name).map_err(store_error)?;
+            writer.write(Value::Array(vec![Value::from(name)]))?;
+            count += write_records(&mut writer, &*ns.read().unpoison())?;
+        }
+    }
+
+    writer.finish(count)?;
+    Ok(count)
+}
+
+
+/// Load every keyfile in an archive written by `export()` into the store,
+/// returning the number of keyfiles loaded.
+///
+/// Keyfiles keep the metadata saved in the archive, and replace any keyfile
+/// in the store with the same key. Keyfiles from other namespaces are loaded
+/// into namespaces of the same name, which are created if needed. Archives
+/// with namespaces can't be loaded unless namespaces are given.
+///
+/// Nothing is written unless the whole archive is read and its checksum
+/// matches. The keyfiles of each namespace are then written together, one
+/// namespace at a time.
+pub fn import<R: Read>(
+    store: &mut KeyFileStore, mut namespaces: Option<&mut KeyFileNamespaces>,
+    input: R
+) -> io::Result<usize>
+{
+    let mut reader = ArchiveReader::new(input);
+
+    // Check header
+    let header = reader.read()?;
+    {
+        let fields = match header.as_array() {
+            Some(fields) if fields.len() == 2 => fields,
+            _ => return Err(invalid("Not a safesec archive")),
+        };
+        if fields[0].as_str() != Some(MAGIC) {
+            return Err(invalid("Not a safesec archive"));
+        }
+        match fields[1].as_u64() {
+            Some(version) if (1..=VERSION).contains(&version) => {}
+            _ => {
+                let errmsg =
+                    format!("Unsupported archive version: {}", fields[1]);
+                return Err(invalid(&errmsg));
+            }
+        }
+    }
+    reader.add(&header)?;
+
+    // Stage every record until the trailer is reached, along with the
+    // namespace it belongs to
+    let mut txns = vec![(None, store.begin())];
+    let mut count = 0;
+    loop {
+        let value = reader.read()?;
+        let fields = match value.as_array() {
+            Some(fields) => fields,
+            None => return Err(invalid("Invalid archive record")),
+        };
+
+        // Trailer
+        if fields.len() == 2 {
+            if fields[0].as_u64() != Some(count as u64) {
+                return Err(invalid("Archive is missing records"));
+            }
+            if fields[1].as_u64() != Some(reader.hash) {
+                return Err(invalid("Archive checksum does not match"));
+            }
+            break;
+        }
+
+        // Namespace
+        if fields.len() == 1 {
+            let name = match fields[0].as_str() {
+                Some(name) => name.to_string(),
+                None => return Err(invalid("Invalid archive namespace")),
+            };
+            if namespaces.is_none() {
+                let errmsg = "Archive has namespaces, which can't be loaded";
+                return Err(invalid(errmsg));
+            }
+            txns.push((Some(name), KeyFileTransaction::new()));
+            reader.add(&value)?;
+            continue;
+        }
+
+        // Record
+        if fields.len() != 3 || !fields[0].is_bin() || !fields[1].is_bin() {
+            return Err(invalid("Invalid archive record"));
+        }
+        let k = fields[0].as_slice().unwrap();
+        let keyfile = fields[1].as_slice().unwrap();
+        let info = match read_info(&fields[2], keyfile) {
+            Some(info) => info,
+            None => return Err(invalid("Invalid keyfile metadata")),
+        };
+        txns.last_mut().unwrap().1.load(k, keyfile, info);
+        count += 1;
+        reader.add(&value)?;
+    }
+    reader.finish()?;
+
+    for (name, txn) in txns {
+        let name = match name {
+            Some(name) => name,
+            None => {
+                store.commit(txn).map_err(store_error)?;
+                continue;
+            }
+        };
+        let namespaces = namespaces.as_mut().unwrap();
+        match namespaces.create_namespace(&name) {
+            Ok(()) | Err(KeyFileError::NamespaceExists(_)) => {}
+            Err(e) => return Err(store_error(e)),
+        }
+        let ns = namespaces.namespace(&name).map_err(store_error)?;
+        ns.write().unpoison().commit(txn).map_err(store_error)?;
+    }
+    Ok(count)
+}
+
+
+// ===========================================================================
+//
+// ===========================================================================
@@ -0,0 +1,436 @@
+// src/storage/cache.rs
+// Copyright (C) 2017 authors and contributors (see AUTHORS file)
+//
+// This file is released under the MIT License.
+
+// ===========================================================================
+// Imports
+// ===========================================================================
+
+
+// Stdlib imports
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
+use std::sync::{Mutex, MutexGuard};
+
+// Third-party imports
+
+use rmpv::Value;
+
+// Local imports
+
+use storage::{timestamp, Change, Changes, KeyFileError, KeyFileInfo,
+              KeyFileResult, KeyFileStore, KeyFileTransaction};
+use util::{Unpoison, Zeroizing};
+
+
+// ===========================================================================
+// Lru
+// ===========================================================================
+
+
+// What is known about a key
+#[derive(Debug, Clone)]
+enum Cached {
+    Missing,
+
+    // The key exists, but its keyfile hasn't been read
+    Exists,
+
+    // Overwritten with zeros once the key is dropped from the cache
+    Keyfile(Zeroizing),
+}
+
+
+#[derive(Debug)]
+struct Entry {
+    // When the entry was last used
+    used: u64,
+
+    // When the keyfile expires, in seconds since the unix epoch
+    expires: Option<u64>,
+
+    value: Cached,
+}
+
+
+// Keys ordered by when they were last used, so that the least recently
+// used key is dropped first once the cache is full
+#[derive(Debug)]
+struct Lru {
+    capacity: usize,
+    clock: u64,
+    entries: HashMap<Vec<u8>, Entry>,
+    order: BTreeMap<u64, Vec<u8>>,
+    stats: CacheStats,
+}
+
+
+impl Lru {
+    fn new(capacity: usize) -> Self
+    {
+        Self {
+            capacity: capacity,
+            clock: 0,
+            entries: HashMap::new(),
+            order: BTreeMap::new(),
+            stats: CacheStats::default(),
+        }
+    }
+
+    // Return what is known about k, marking it as the most recently used
+    // key. Expired keyfiles are forgotten.
+    fn lookup(&mut self, k: &[u8]) -> Option<Cached>
+    {
+        let expired = match self.entries.get(k).map(|entry| entry.expires) {
+            Some(Some(t)) => t <= timestamp(),
+            Some(None) => false,
+            None => return None,
+        };
+        if expired {
+            self.remove(k);
+            return None;
+        }
+
+        self.clock += 1;
+        let entry = self.entries.get_mut(k).unwrap();
+        self.order.remove(&entry.used);
+        self.order.insert(self.clock, k.to_vec());
+        entry.used = self.clock;
+        Some(entry.value.clone())
+    }
+
+    fn insert(&mut self, k: &[u8], value: Cached, expires: Option<u64>)
+    {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(k);
+        if self.entries.len() >= self.capacity {
+            let oldest = self.order.keys().next().cloned();
+            if let Some(used) = oldest {
+                let key = self.order.remove(&used).unwrap();
+                self.entries.remove(&key);
+            }
+        }
+
+        self.clock += 1;
+        self.order.insert(self.clock, k.to_vec());
+        let entry = Entry {
+            used: self.clock,
+            expires: expires,
+            value: value,
+        };
+        self.entries.insert(k.to_vec(), entry);
+    }
+
+    fn remove(&mut self, k: &[u8])
+    {
+        if let Some(entry) = self.entries.remove(k) {
+            self.order.remove(&entry.used);
+        }
+    }
+
+    fn clear(&mut self)
+    {
+        self.entries.clear();
+        self.order.clear();
+    }
+}
+
+
+// ===========================================================================
+// CachedStore
+// ===========================================================================
+
+
+/// Number of reads a `CachedStore` answered with and without its store.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+}
+
+
+// Encoded as the map returned by a CacheStatus request
+impl From<CacheStats> for Value {
+    fn from(stats: CacheStats) -> Value
+    {
+        Value::Map(vec![
+            (Value::from("hits"), Value::from(stats.hits)),
+            (Value::from("misses"), Value::from(stats.misses)),
+        ])
+    }
+}
+
+
+/// A keyfile store that keeps the most recently read keyfiles in memory.
+///
+/// `get()` and `exists()` are answered from the cache when they can be,
+/// including for keys that don't exist. Any write made through the cache
+/// drops what is known about the keys it touches, and once the cache holds
+/// its capacity of keys, the least recently used one is dropped.
+///
+/// Writes made to the store by anything other than the cache aren't seen.
+pub struct CachedStore<S: KeyFileStore> {
+    store: S,
+    cache: Mutex<Lru>,
+}
+
+
+impl<S: KeyFileStore> CachedStore<S> {
+    /// Cache reads of store, keeping at most capacity keys.
+    pub fn new(store: S, capacity: usize) -> Self
+    {
+        Self {
+            store: store,
+            cache: Mutex::new(Lru::new(capacity)),
+        }
+    }
+
+    /// Return the store being cached.
+    pub fn store(&self) -> &S
+    {
+        &self.store
+    }
+
+    /// Number of keys held by the cache.
+    pub fn len(&self) -> usize
+    {
+        self.cache().entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.len() == 0
+    }
+
+    /// Number of reads answered so far, with and without the store.
+    pub fn stats(&self) -> CacheStats
+    {
+        self.cache().stats
+    }
+
+    fn cache<'a>(&'a self) -> MutexGuard<'a, Lru>
+    {
+        self.cache.lock().unpoison()
+    }
+
+    // Stores that don't keep metadata have keyfiles that never expire
+    fn expires(&self, k: &Vec<u8>) -> Option<u64>
+    {
+        self.store.info(k).ok().and_then(|info| info.expires)
+    }
+
+    // Look up k, counting a hit if the cache knows enough to answer
+    fn lookup<F>(&self, k: &[u8], answers: F) -> Option<Cached>
+    where
+        F: FnOnce(&Cached) -> bool,
+    {
+        let mut cache = self.cache();
+        match cache.lookup(k) {
+            Some(ref value) if answers(value) => {
+                cache.stats.hits += 1;
+                Some(value.clone())
+            }
+            _ => {
+                cache.stats.misses += 1;
+                None
+            }
+        }
+    }
+
+    // Drop keys written to the store, whether or not the write succeeded
+    fn forget<'a, I>(&self, keys: I)
+    where
+        I: IntoIterator<Item = &'a [u8]>,
+    {
+        let mut cache = self.cache();
+        for k in keys {
+            cache.remove(k);
+        }
+    }
+}
+
+
+impl<S: KeyFileStore> KeyFileStore for CachedStore<S> {
+    fn exists(&self, k: &Vec<u8>) -> bool
+    {
+        match self.lookup(k, |_| true) {
+            Some(Cached::Missing) => return false,
+            Some(_) => return true,
+            None => {}
+        }
+
+        if self.store.exists(k) {
+            let expires = self.expires(k);
+            self.cache().insert(k, Cached::Exists, expires);
+            true
+        } else {
+            self.cache().insert(k, Cached::Missing, None);
+            false
+        }
+    }
+
+    fn get(&self, k: &Vec<u8>) -> KeyFileResult<Vec<u8>>
+    {
+        let answers = |value: &Cached| match *value {
+            Cached::Exists => false,
+            Cached::Missing | Cached::Keyfile(_) => true,
+        };
+        // As with the backing store, the copy returned is zeroized along
+        // with the response it is sent in
+        match self.lookup(k, answers) {
+            Some(Cached::Keyfile(file)) => return Ok(file.to_vec()),
+            Some(_) => return Err(KeyFileError::Key(k.clone())),
+            None => {}
+        }
+
+        match self.store.get(k) {
+            Ok(file) => {
+                let expires = self.expires(k);
+                let value = Cached::Keyfile(Zeroizing::new(file.clone()));
+                self.cache().insert(k, value, expires);
+                Ok(file)
+            }
+            Err(KeyFileError::Key(key)) => {
+                self.cache().insert(k, Cached::Missing, None);
+                Err(KeyFileError::Key(key))
+            }
+
+            // Other errors may not happen on the next read
+            Err(e) => Err(e),
+        }
+    }
+
+    fn set(&mut self, k: &Vec<u8>, file: &Vec<u8>) -> KeyFileResult<()>
+    {
+        let result = self.store.set(k, file);
+        self.forget(vec![&k[..]]);
+        result
+    }
+
+    fn delete(&mut self, k: &Vec<u8>) -> KeyFileResult<()>
+    {
+        let result = self.store.delete(k);
+        self.forget(vec![&k[..]]);
+        result
+    }
+
+    fn iter_prefix(&self, prefix: &[u8], after: Option<&[u8]>, limit: usize)
+        -> KeyFileResult<Vec<Vec<u8>>>
+    {
+        self.store.iter_prefix(prefix, after, limit)
+    }
+
+    fn info(&self, k: &Vec<u8>) -> KeyFileResult<KeyFileInfo>
+    {
+        self.store.info(k)
+    }
+
+    fn revisions(&self, k: &Vec<u8>) -> KeyFileResult<Vec<KeyFileInfo>>
+    {
+        self.store.revisions(k)
+    }
+
+    fn get_revision(&self, k: &Vec<u8>, rev: u64) -> KeyFileResult<Vec<u8>>
+    {
+        self.store.get_revision(k, rev)
+    }
+
+    fn expire(&mut self, k: &Vec<u8>, expires: Option<u64>)
+        -> KeyFileResult<()>
+    {
+        let result = self.store.expire(k, expires);
+        self.forget(vec![&k[..]]);
+        result
+    }
+
+    fn purge_expired(&mut self) -> KeyFileResult<Vec<Vec<u8>>>
+    {
+        // Expired keyfiles are already treated as missing by the cache
+        self.store.purge_expired()
+    }
+
+    fn usage(&self) -> KeyFileResult<(usize, u64)>
+    {
+        self.store.usage()
+    }
+
+    fn backup(&self, dir: &Path, compact: bool) -> KeyFileResult<()>
+    {
+        self.store.backup(dir, compact)
+    }
+
+    fn scrub(&self) -> KeyFileResult<Vec<(Vec<u8>, KeyFileError)>>
+    {
+        self.store.scrub()
+    }
+
+    fn changes(&self, after: u64, limit: usize)
+        -> KeyFileResult<(u64, Changes)>
+    {
+        self.store.changes(after, limit)
+    }
+
+    // Changes name hashed keys if the store hashes them, and those can't be
+    // matched with the keys held by the cache, so every key is dropped
+    fn replicate(&mut self, changes: &[(u64, Change)]) -> KeyFileResult<()>
+    {
+        if self.store.hashes_keys() {
+            let result = self.store.replicate(changes);
+            if !changes.is_empty() {
+                self.cache().clear();
+            }
+            return result;
+        }
+
+        // Only changes to the default namespace are made to this store
+        let keys: Vec<&[u8]> = changes
+            .iter()
+            .filter_map(|entry| match entry.1 {
+                Change::Put(None, ref k, _, _) |
+                Change::Delete(None, ref k) => Some(&k[..]),
+                _ => None,
+            })
+            .collect();
+        let result = self.store.replicate(changes);
+        self.forget(keys);
+        result
+    }
+
+    fn replicated(&self) -> KeyFileResult<u64>
+    {
+        self.store.replicated()
+    }
+
+    fn hashes_keys(&self) -> bool
+    {
+        self.store.hashes_keys()
+    }
+
+    fn cache_stats(&self) -> Option<CacheStats>
+    {
+        Some(self.stats())
+    }
+
+    fn begin(&self) -> KeyFileTransaction
+    {
+        self.store.begin()
+    }
+
+    fn commit(&mut self, txn: KeyFileTransaction) -> KeyFileResult<()>
+    {
+        let keys: Vec<Vec<u8>> =
+            txn.ops().iter().map(|op| op.key().to_vec()).collect();
+        let result = self.store.commit(txn);
+        self.forget(keys.iter().map(|k| &k[..]));
+        result
+    }
+}
+
+
+// ===========================================================================
+//
+// ===========================================================================
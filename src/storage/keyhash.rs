@@ -0,0 +1,127 @@
+// src/storage/keyhash.rs
+// Copyright (C) 2017 authors and contributors (see AUTHORS file)
+//
+// This file is released under the MIT License.
+
+// ===========================================================================
+// Imports
+// ===========================================================================
+
+
+// Stdlib imports
+
+use std::fmt;
+use std::fs::{File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+// Third-party imports
+
+use hmac::{Hmac, Mac};
+use rand::{OsRng, Rng};
+use sha2::Sha256;
+
+// Local imports
+
+use util::Zeroizing;
+
+
+// ===========================================================================
+// KeyHasher
+// ===========================================================================
+
+
+// Number of random bytes written to a new salt file
+const SALT_SIZE: usize = 32;
+
+
+// Salts shorter than this are refused
+const MIN_SALT_SIZE: usize = 16;
+
+
+/// Hashes keys with HMAC-SHA256, using a secret salt as the HMAC key.
+///
+/// Stores that hash their keys only ever hold the hash of a key, so the
+/// keys can't be read back from the database files. The same salt has to
+/// be used every time a store is opened.
+#[derive(Clone)]
+pub struct KeyHasher {
+    salt: Zeroizing,
+}
+
+
+impl KeyHasher {
+    /// Create a hasher using the given salt.
+    pub fn new(salt: Vec<u8>) -> Self
+    {
+        Self {
+            salt: Zeroizing::new(salt),
+        }
+    }
+
+    /// Create a hasher using the salt kept in the file at path.
+    ///
+    /// If the file doesn't exist, it is created holding a new random salt
+    /// that only the current user can read.
+    pub fn open(path: &Path) -> io::Result<Self>
+    {
+        let created = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(0o600)
+            .open(path);
+        match created {
+            Ok(mut file) => {
+                let mut salt = vec![0; SALT_SIZE];
+                OsRng::new()?.fill_bytes(&mut salt);
+                let hasher = Self::new(salt);
+                file.write_all(&hasher.salt)?;
+                file.sync_all()?;
+                return Ok(hasher);
+            }
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {}
+            Err(e) => return Err(e),
+        }
+
+        // The buffer is sized up front so that reading never leaves copies
+        // of the salt behind
+        let mut file = File::open(path)?;
+        let size = file.metadata()?.len() as usize;
+        let mut salt = Vec::with_capacity(size + 1);
+        file.read_to_end(&mut salt)?;
+        let hasher = Self::new(salt);
+        if hasher.salt.len() < MIN_SALT_SIZE {
+            let errmsg = format!(
+                "Salt file {} holds fewer than {} bytes",
+                path.display(),
+                MIN_SALT_SIZE
+            );
+            return Err(io::Error::new(io::ErrorKind::InvalidData, errmsg));
+        }
+        Ok(hasher)
+    }
+
+    /// Return the 32 byte hash of key.
+    pub fn hash(&self, key: &[u8]) -> Vec<u8>
+    {
+        // HMAC accepts keys of any length
+        let mut mac = Hmac::<Sha256>::new_varkey(&self.salt).unwrap();
+        mac.input(key);
+        mac.result().code().to_vec()
+    }
+}
+
+
+// The salt is secret, so it is left out
+impl fmt::Debug for KeyHasher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "KeyHasher {{ .. }}")
+    }
+}
+
+
+// ===========================================================================
+//
+// ===========================================================================
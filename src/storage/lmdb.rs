@@ -10,19 +10,38 @@
 
 // Stdlib imports
 
+use std::borrow::Cow;
+use std::cmp;
+use std::collections::HashMap;
 use std::env;
+use std::fs;
 use std::io;
+use std::mem;
+use std::os::unix::fs::{DirBuilderExt, OpenOptionsExt,
+                        PermissionsExt};
+use std::os::unix::io::AsRawFd;
 use std::path::{Path, PathBuf};
+use std::sync::{Arc, RwLock};
 
 // Third-party imports
 
-use lmdb::{Database, DatabaseFlags, Environment, Error as LmdbError,
-           Result as LmdbResult, Transaction, WriteFlags};
+use bytes::BytesMut;
+use lmdb::{APPEND, Cursor, Database, DatabaseFlags, Environment,
+           EnvironmentFlags, Error as LmdbError, NO_LOCK, NO_META_SYNC,
+           NO_SYNC, READ_ONLY, Result as LmdbResult, RoTransaction,
+           RwTransaction, Transaction, WRITE_MAP, WriteFlags};
+use lmdb_sys as ffi;
 use lmdb_sys::mode_t;
+use tokio_io::codec::{Decoder, Encoder};
 
 // Local imports
 
-use storage::{KeyFileBuilder, KeyFileError, KeyFileResult, KeyFileStore};
+use network::codec::MsgPackCodec;
+use storage::{keyfile_hash, valid_namespace, Change, Changes,
+              KeyFileBuilder, KeyFileError, KeyFileInfo, KeyFileNamespaces,
+              KeyFileOp, KeyFileResult, KeyFileStore, KeyFileTransaction};
+use storage::keyhash::KeyHasher;
+use util::{Unpoison, Zeroizing};
 
 
 // ===========================================================================
@@ -38,51 +57,279 @@ fn default_db_path() -> io::Result<PathBuf>
 }
 
 
+// Map an LMDB error onto a KeyFileError. The key is only used if the error
+// is LmdbError::NotFound.
+//
+// Only LmdbError::MapFull becomes KeyFileError::MapFull, since that is what
+// makes a write grow the map. A transaction with too many changes can't be
+// helped by a bigger map, and LmdbError::MapResized is dealt with when a
+// transaction is started.
+fn keyfile_error(err: LmdbError, k: &[u8]) -> KeyFileError
+{
+    match err {
+        LmdbError::NotFound => KeyFileError::Key(k.to_vec()),
+        LmdbError::MapFull => KeyFileError::MapFull,
+        LmdbError::TxnFull => KeyFileError::ValueTooLarge,
+        LmdbError::ReadersFull => KeyFileError::ReadersFull,
+        LmdbError::PageNotFound |
+        LmdbError::Corrupted |
+        LmdbError::Panic |
+        LmdbError::VersionMismatch |
+        LmdbError::Invalid => KeyFileError::Corrupted,
+        LmdbError::BadValSize => KeyFileError::ValueTooLarge,
+
+        // Any error code not defined by LMDB is an errno value returned by
+        // the OS (eg EACCES, EIO, ENOSPC)
+        LmdbError::Other(_) => KeyFileError::Io,
+        _ => KeyFileError::Other,
+    }
+}
+
+
+// Name k in place of its stored key in an error naming the stored key
+fn original_key(err: KeyFileError, stored: &[u8], k: &[u8]) -> KeyFileError
+{
+    match err {
+        KeyFileError::Key(ref s) if s[..] == *stored => {
+            KeyFileError::Key(k.to_vec())
+        }
+        KeyFileError::Mismatch(ref s) if s[..] == *stored => {
+            KeyFileError::Mismatch(k.to_vec())
+        }
+        KeyFileError::Revision(ref s, rev) if s[..] == *stored => {
+            KeyFileError::Revision(k.to_vec(), rev)
+        }
+        err => err,
+    }
+}
+
+
 // ===========================================================================
 // DB Init
 // ===========================================================================
 
 
+/// Settings used to open the LMDB environment.
+///
+/// Any setting that is not given keeps LMDB's own default.
+#[derive(Debug, Clone)]
 pub struct Init {
     maxdb: u32,
+    maxreaders: Option<u32>,
+    mapsize: Option<usize>,
+    maxmapsize: Option<usize>,
+    growth: f64,
+    history: usize,
     mode: mode_t,
+    flags: EnvironmentFlags,
+    hasher: Option<KeyHasher>,
+    wipe: bool,
+    changes: usize,
     pub path: PathBuf,
 }
 
 
 impl Init {
-    fn new() -> Init
+    pub fn new() -> Init
     {
         Init {
             maxdb: 128,
+            maxreaders: None,
+            mapsize: None,
+            maxmapsize: None,
+            growth: 2.0,
+            history: 0,
             // mode: 0b111101101 as u32,
             mode: 0o600,
+            flags: EnvironmentFlags::empty(),
+            hasher: None,
+            wipe: false,
+            changes: 0,
             path: default_db_path().expect("Error with db path"),
         }
     }
 
-    // pub fn max_dbs(mut self, maxdbs: usize) -> Self {
-    //     self.maxdb = maxdbs;
-    //     self
-    // }
+    /// Maximum size of the database in bytes.
+    pub fn map_size(mut self, size: usize) -> Self
+    {
+        self.mapsize = Some(size);
+        self
+    }
+
+    /// Largest size in bytes the database may grow to.
+    ///
+    /// When a write fails because the database is full, the map size is
+    /// multiplied by the growth factor, up to this size, and the write is
+    /// retried. If not given, the database never grows.
+    pub fn max_map_size(mut self, size: usize) -> Self
+    {
+        self.maxmapsize = Some(size);
+        self
+    }
+
+    /// Factor the map size is multiplied by each time the database grows.
+    ///
+    /// Values not greater than 1.0 are ignored.
+    pub fn map_growth(mut self, factor: f64) -> Self
+    {
+        if factor > 1.0 {
+            self.growth = factor;
+        }
+        self
+    }
+
+    /// Number of previous revisions kept for each key.
+    ///
+    /// Keys longer than 499 bytes can't be stored while history is kept.
+    pub fn history(mut self, revisions: usize) -> Self
+    {
+        self.history = revisions;
+        self
+    }
+
+    /// Maximum number of concurrent read transactions.
+    pub fn max_readers(mut self, readers: u32) -> Self
+    {
+        self.maxreaders = Some(readers);
+        self
+    }
+
+    /// Maximum number of named databases in the environment.
+    pub fn max_dbs(mut self, maxdbs: u32) -> Self
+    {
+        self.maxdb = maxdbs;
+        self
+    }
+
+    /// Unix permissions used when creating the database files.
+    pub fn mode(mut self, val: u32) -> Self
+    {
+        self.mode = val as mode_t;
+        self
+    }
+
+    /// Don't fsync after each commit.
+    ///
+    /// Faster, but the last transactions may be lost on a system crash.
+    pub fn no_sync(self, val: bool) -> Self
+    {
+        self.flag(NO_SYNC, val)
+    }
+
+    /// Don't fsync the meta page after each commit.
+    ///
+    /// The last transaction may be lost on a system crash.
+    pub fn no_meta_sync(self, val: bool) -> Self
+    {
+        self.flag(NO_META_SYNC, val)
+    }
+
+    /// Use a writeable memory map.
+    ///
+    /// Faster, but a buggy process can corrupt the database.
+    pub fn write_map(self, val: bool) -> Self
+    {
+        self.flag(WRITE_MAP, val)
+    }
+
+    /// Open the database without allowing any writes.
+    ///
+    /// Every database must already exist, so a store has to be opened for
+    /// writing at least once before it can be opened read-only.
+    pub fn read_only(self, val: bool) -> Self
+    {
+        self.flag(READ_ONLY, val)
+    }
+
+    /// Store a keyed hash of every key rather than the key itself.
+    ///
+    /// Keys can't be listed while they are hashed. Hashing can only be
+    /// turned on before anything has been written to the environment, and
+    /// the environment must be opened with the same salt from then on.
+    /// Followers must use the salt of their primary.
+    pub fn hash_keys(mut self, hasher: KeyHasher) -> Self
+    {
+        self.hasher = Some(hasher);
+        self
+    }
+
+    /// Overwrite keyfiles with zeros before they are deleted or replaced.
+    ///
+    /// This is best effort and doesn't remove the keyfile from the data
+    /// file. LMDB copies a page before changing it, so the keyfile is still
+    /// held by the page it was copied from until that page is reused. Only
+    /// the copy is overwritten, which keeps the keyfile out of the unused
+    /// space of pages still in use. The change log also keeps the keyfiles
+    /// written by the changes it holds. Only `compact()` rewrites the
+    /// environment without either, and it is never run by the server.
+    ///
+    /// Revisions kept in the history are only overwritten once they are
+    /// dropped from it.
+    pub fn secure_delete(mut self, val: bool) -> Self
+    {
+        self.wipe = val;
+        self
+    }
 
-    // pub fn mode(mut self, val: u32) -> Self {
-    //     self.mode = val;
-    //     self
-    // }
+    /// Keep the latest changes made to the environment in its change log,
+    /// for followers to copy. No changes are kept by default.
+    ///
+    /// Older changes are dropped from the log as new ones are made, so a
+    /// follower that falls further behind can't catch up and has to be
+    /// started again from a backup of its primary.
+    pub fn change_log(mut self, changes: usize) -> Self
+    {
+        self.changes = changes;
+        self
+    }
 
-    fn path(&mut self, val: &Path) -> &Self
+    pub fn path(mut self, val: &Path) -> Self
     {
         self.path = PathBuf::from(val);
         self
     }
 
-    fn create(&self) -> Environment
+    /// Number of previous revisions kept for each key.
+    pub fn history_len(&self) -> usize
     {
-        Environment::new()
-            .set_max_dbs(self.maxdb)
-            .open_with_permissions(self.path.as_path(), self.mode)
-            .expect("Error opening db file")
+        self.history
+    }
+
+    /// Whether the database is opened without allowing any writes.
+    pub fn is_read_only(&self) -> bool
+    {
+        self.flags.contains(READ_ONLY)
+    }
+
+    fn flag(mut self, flag: EnvironmentFlags, val: bool) -> Self
+    {
+        if val {
+            self.flags.insert(flag);
+        } else {
+            self.flags.remove(flag);
+        }
+        self
+    }
+
+    fn create(&self) -> LmdbResult<Environment>
+    {
+        let mut builder = Environment::new();
+        builder.set_max_dbs(self.maxdb).set_flags(self.flags);
+        if let Some(readers) = self.maxreaders {
+            builder.set_max_readers(readers);
+        }
+        if let Some(size) = self.mapsize {
+            builder.set_map_size(size);
+        }
+        builder.open_with_permissions(self.path.as_path(), self.mode)
+    }
+}
+
+
+impl Default for Init {
+    fn default() -> Init
+    {
+        Init::new()
     }
 }
 
@@ -92,119 +339,2122 @@ impl Init {
 // ===========================================================================
 
 
-pub struct KeyFile {
-    pub dbinit: Init,
-    env: Environment,
+// The databases that make up a keyfile store
+#[derive(Debug, Clone)]
+struct Tables {
     db: Database,
+
+    // Sidecar database holding the KeyFileInfo of every key in db, followed
+    // by a checksum of the key's keyfile
+    infodb: Database,
+
+    // Previous revisions of every key. Each entry's key is the key's length
+    // as a big-endian u32, the key, and the revision as a big-endian u64.
+    // Each value is the revision's KeyFileInfo followed by its keyfile.
+    histdb: Database,
+
+    // Change log shared by every store in the environment
+    log: Option<ChangeLog>,
+
+    // Meta db of the environment, which holds the usage of every store
+    meta: Option<Database>,
+
+    // Key in the meta db holding the usage of this store
+    usage_key: Vec<u8>,
+
+    // Number of previous revisions kept for each key
+    keep: usize,
+
+    // Whether keyfiles are overwritten with zeros before being deleted or
+    // replaced
+    wipe: bool,
 }
 
 
-impl KeyFile {
-    fn create(env: &Environment, dbname: &str, dbflags: DatabaseFlags)
-        -> LmdbResult<Database>
+impl Tables {
+    // Open the named databases of a keyfile store, creating them if they
+    // don't exist
+    fn open(env: &Environment, name: &str, init: &Init) -> LmdbResult<Self>
     {
-        let db = env.open_db(Some(dbname));
-        match db {
-            Ok(db) => Ok(db),
-            Err(_) => env.create_db(Some(dbname), dbflags),
+        let dbflags = DatabaseFlags::empty();
+        let db = KeyFile::create(env, name, dbflags)?;
+        let infoname = format!("{}.info", name);
+        let infodb = KeyFile::create(env, &infoname, dbflags)?;
+        let histname = format!("{}.history", name);
+        let histdb = KeyFile::create(env, &histname, dbflags)?;
+        Ok(Self {
+            db: db,
+            infodb: infodb,
+            histdb: histdb,
+            log: change_log(env, init.changes)?,
+            meta: meta_db(env)?,
+            usage_key: usage_key(name),
+            keep: init.history,
+            wipe: init.wipe,
+        })
+    }
+
+    // Open the named databases of a keyfile store from inside a write
+    // transaction, creating them if they don't exist
+    fn open_in(
+        session: &RwTransaction, name: &str, keep: usize, wipe: bool,
+        log: Option<ChangeLog>
+    ) -> LmdbResult<Self>
+    {
+        let open = |name: &str| unsafe {
+            session.create_db(Some(name), DatabaseFlags::empty())
+        };
+        let meta = match unsafe { session.open_db(Some(META_DB)) } {
+            Ok(meta) => Some(meta),
+            Err(LmdbError::NotFound) => None,
+            Err(e) => return Err(e),
+        };
+        Ok(Self {
+            db: open(name)?,
+            infodb: open(&format!("{}.info", name))?,
+            histdb: open(&format!("{}.history", name))?,
+            log: log,
+            meta: meta,
+            usage_key: usage_key(name),
+            keep: keep,
+            wipe: wipe,
+        })
+    }
+
+    // Delete every keyfile, its metadata and its history
+    fn clear(&self, session: &mut RwTransaction) -> LmdbResult<()>
+    {
+        session.clear_db(self.db)?;
+        session.clear_db(self.infodb)?;
+        session.clear_db(self.histdb)?;
+        self.set_usage(session, 0, 0)
+    }
+
+    // Number of keyfiles in the store and their total length in bytes.
+    // Stores written before usage was kept have theirs counted.
+    fn usage<T>(&self, session: &T) -> LmdbResult<(u64, u64)>
+    where
+        T: Transaction,
+    {
+        if let Some(meta) = self.meta {
+            match session.get(meta, &self.usage_key) {
+                Ok(buf) if buf.len() == 16 => {
+                    let entries = parse_sequence(&buf[..8]);
+                    return Ok((entries, parse_sequence(&buf[8..])));
+                }
+                Ok(_) => return Err(LmdbError::Corrupted),
+                Err(LmdbError::NotFound) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        let (mut entries, mut bytes) = (0, 0);
+        scan(session, self.db, &[], |_, keyfile| {
+            entries += 1;
+            bytes += keyfile.len() as u64;
+            true
+        })?;
+        Ok((entries, bytes))
+    }
+
+    // Keep the usage of the store in the meta db, if the environment has
+    // one
+    fn set_usage(
+        &self, session: &mut RwTransaction, entries: u64, bytes: u64
+    ) -> LmdbResult<()>
+    {
+        let meta = match self.meta {
+            Some(meta) => meta,
+            None => return Ok(()),
+        };
+        let mut buf = sequence_key(entries);
+        buf.extend_from_slice(&sequence_key(bytes));
+        session.put(meta, &self.usage_key, &buf, WriteFlags::empty())
+    }
+
+    // Update the usage of the store for a keyfile of length removed being
+    // replaced by one of length added, where None means there is no
+    // keyfile. Must be called before the keyfile is written, so that the
+    // usage of a store that hasn't kept it yet is counted correctly.
+    fn count(
+        &self, session: &mut RwTransaction, removed: Option<usize>,
+        added: Option<usize>
+    ) -> LmdbResult<()>
+    {
+        if self.meta.is_none() {
+            return Ok(());
+        }
+        let (mut entries, mut bytes) = self.usage(session)?;
+        if let Some(len) = removed {
+            entries = entries.saturating_sub(1);
+            bytes = bytes.saturating_sub(len as u64);
+        }
+        if let Some(len) = added {
+            entries += 1;
+            bytes += len as u64;
+        }
+        self.set_usage(session, entries, bytes)
+    }
+
+    fn stage(&self, session: &mut RwTransaction, op: &KeyFileOp)
+        -> KeyFileResult<()>
+    {
+        let k = op.key();
+        match *op {
+            KeyFileOp::Set(_, ref v) => {
+                let info = match self.info(session, k) {
+                    Ok(info) => {
+                        self.archive(session, k, &info)?;
+                        info.update(v.len())
+                    }
+
+                    // Revisions carry on from a deleted keyfile's history
+                    Err(KeyFileError::Key(_)) => {
+                        let mut info = KeyFileInfo::new(v.len());
+                        if let Some(last) = self.history(session, k)?.pop() {
+                            info.revision = last.revision + 1;
+                        }
+                        info
+                    }
+                    Err(e) => return Err(e),
+                };
+                self.put(session, k, v, &info)
+            }
+            KeyFileOp::Delete(_) => {
+                let info = self.info(session, k)?;
+                self.archive(session, k, &info)?;
+                session.get(self.db, &k)
+                    .map(|old| old.len())
+                    .and_then(|len| self.count(session, Some(len), None))
+                    .and_then(|_| self.wipe(session, self.db, k))
+                    .and_then(|_| session.del(self.db, &k, None))
+                    .map_err(|e| keyfile_error(e, k))?;
+
+                // Keys stored before metadata was kept have no info
+                match session.del(self.infodb, &k, None) {
+                    Ok(()) | Err(LmdbError::NotFound) => Ok(()),
+                    Err(e) => Err(keyfile_error(e, k)),
+                }
+            }
+            KeyFileOp::Check(_, ref check) => {
+                let keyfile = self.get(session, k)?;
+                let info = self.info(session, k)?;
+                if check.matches(&info, &keyfile) {
+                    Ok(())
+                } else {
+                    Err(KeyFileError::Mismatch(k.to_vec()))
+                }
+            }
+            KeyFileOp::Load(_, ref v, ref info) => {
+                self.put(session, k, v, info)
+            }
+            KeyFileOp::Expire(_, expires) => {
+                let (mut info, checksum) = self.record(session, k)?;
+                if info.is_expired() {
+                    return Err(KeyFileError::Key(k.to_vec()));
+                }
+                info.expires = expires;
+                let buf = info_record(&info, checksum);
+                session
+                    .put(self.infodb, &k, &buf, WriteFlags::empty())
+                    .map_err(|e| keyfile_error(e, k))
+            }
         }
     }
 
-    fn dbget<K>(&self, key: &K) -> LmdbResult<Vec<u8>>
+    // Write a keyfile along with its metadata and checksum
+    fn put(
+        &self, session: &mut RwTransaction, k: &[u8], keyfile: &[u8],
+        info: &KeyFileInfo
+    ) -> KeyFileResult<()>
+    {
+        let flags = WriteFlags::empty();
+        let record = info_record(info, Some(keyfile_hash(keyfile)));
+        let old = match session.get(self.db, &k) {
+            Ok(old) => Some(old.len()),
+            Err(LmdbError::NotFound) => None,
+            Err(e) => return Err(keyfile_error(e, k)),
+        };
+        self.count(session, old, Some(keyfile.len()))
+            .and_then(|_| self.wipe(session, self.db, k))
+            .and_then(|_| session.put(self.db, &k, &keyfile, flags))
+            .and_then(|_| session.put(self.infodb, &k, &record, flags))
+            .map_err(|e| keyfile_error(e, k))
+    }
+
+    // Add the keyfile and metadata a key was left with to the change log,
+    // or its deletion if it no longer exists
+    fn log_key(
+        &self, session: &mut RwTransaction, namespace: &Option<String>,
+        k: &[u8]
+    ) -> KeyFileResult<()>
+    {
+        let ns = namespace.clone();
+        let change = match session.get(self.db, &k) {
+            Ok(keyfile) => {
+                let info = self.info(session, k)?;
+                Change::Put(ns, k.to_vec(), keyfile.to_vec(), info)
+            }
+            Err(LmdbError::NotFound) => Change::Delete(ns, k.to_vec()),
+            Err(e) => return Err(keyfile_error(e, k)),
+        };
+        log_change(session, self.log, change)
+            .map_err(|e| keyfile_error(e, k))
+    }
+
+    // Get the keyfile of a key that hasn't expired, checking that it
+    // matches its checksum
+    fn get<T>(&self, session: &T, k: &[u8]) -> KeyFileResult<Zeroizing>
     where
-        K: AsRef<[u8]>,
+        T: Transaction,
     {
-        let session = self.env.begin_ro_txn()?;
-        let value = Vec::from(session.get(self.db.clone(), key)?);
-        session.commit()?;
-        Ok(value)
+        let (info, checksum) = self.record(session, k)?;
+        if info.is_expired() {
+            return Err(KeyFileError::Key(k.to_vec()));
+        }
+        let keyfile = session.get(self.db, &k).map_err(
+            |e| keyfile_error(e, k),
+        )?;
+        verify_keyfile(keyfile, checksum)?;
+        Ok(Zeroizing::new(keyfile.to_vec()))
     }
 
-    fn dbset<K, V>(&mut self, key: &K, val: &V, flags: Option<WriteFlags>)
-        -> LmdbResult<()>
+    // Get the metadata of a key, treating an expired key as if it doesn't
+    // exist
+    fn live_info<T>(&self, session: &T, k: &[u8])
+        -> KeyFileResult<KeyFileInfo>
     where
-        K: AsRef<[u8]>,
-        V: AsRef<[u8]>,
+        T: Transaction,
     {
-        let flags = match flags {
-            None => WriteFlags::empty(),
-            Some(f) => f,
-        };
-        let mut session = self.env.begin_rw_txn()?;
-        session.put(self.db.clone(), key, val, flags)?;
-        session.commit()?;
-        Ok(())
+        let info = self.info(session, k)?;
+        if info.is_expired() {
+            return Err(KeyFileError::Key(k.to_vec()));
+        }
+        Ok(info)
     }
 
-    fn dbdel<K>(&mut self, key: &K) -> LmdbResult<()>
+    // Get the metadata of a key
+    fn info<T>(&self, session: &T, k: &[u8]) -> KeyFileResult<KeyFileInfo>
     where
-        K: AsRef<[u8]>,
+        T: Transaction,
     {
-        let mut session = self.env.begin_rw_txn()?;
-        session.del(self.db.clone(), key, None)?;
-        session.commit()?;
-        Ok(())
+        self.record(session, k).map(|(info, _)| info)
     }
-}
 
+    // Get the metadata of a key along with its keyfile's checksum, if it
+    // has one
+    fn record<T>(&self, session: &T, k: &[u8])
+        -> KeyFileResult<(KeyFileInfo, Option<u64>)>
+    where
+        T: Transaction,
+    {
+        match session.get(self.infodb, &k) {
+            Ok(buf) => parse_record(buf),
 
-impl KeyFileBuilder for KeyFile {
-    fn new(name: &str, envpath: Option<&Path>) -> KeyFile
+            // Keys stored before metadata was kept only have a known length
+            Err(LmdbError::NotFound) => {
+                let keyfile = session.get(self.db, &k).map_err(
+                    |e| keyfile_error(e, k),
+                )?;
+                let info = KeyFileInfo {
+                    created: 0,
+                    modified: 0,
+                    length: keyfile.len() as u64,
+                    revision: 0,
+                    expires: None,
+                };
+                Ok((info, None))
+            }
+            Err(e) => Err(keyfile_error(e, k)),
+        }
+    }
+
+    // Copy the current keyfile into the history, dropping the oldest
+    // revisions so that no more than keep revisions remain
+    fn archive(
+        &self, session: &mut RwTransaction, k: &[u8], info: &KeyFileInfo
+    ) -> KeyFileResult<()>
     {
-        let mut init = Init::new();
-        let env = match envpath {
-            Some(p) => init.path(p).create(),
-            None => init.create(),
-        };
+        if self.keep == 0 {
+            return Ok(());
+        }
+        let mut entry = info.to_bytes();
+        entry.extend_from_slice(
+            session.get(self.db, &k).map_err(|e| keyfile_error(e, k))?,
+        );
+        let histkey = history_key(k, info.revision);
+        session
+            .put(self.histdb, &histkey, &entry, WriteFlags::empty())
+            .map_err(|e| keyfile_error(e, k))?;
 
-        // Create DB
-        let dbflags = DatabaseFlags::empty();
-        let db =
-            KeyFile::create(&env, name, dbflags).expect("Error creating DB");
-        KeyFile {
-            dbinit: init,
-            env: env,
-            db: db,
+        let history = self.history(session, k)?;
+        let extra = history.len().saturating_sub(self.keep);
+        for old in &history[..extra] {
+            let histkey = history_key(k, old.revision);
+            self.wipe(session, self.histdb, &histkey)
+                .and_then(|_| session.del(self.histdb, &histkey, None))
+                .map_err(|e| keyfile_error(e, k))?;
         }
+        Ok(())
     }
-}
 
+    // Overwrite the value of k in db with zeros if keyfiles are wiped.
+    // Deleting a value from a page leaves its bytes in the page's unused
+    // space, so it is overwritten first. The zeros are written to the copy
+    // of the page made by this transaction, so the page it was copied from
+    // still holds the value until LMDB reuses it.
+    fn wipe(&self, session: &mut RwTransaction, db: Database, k: &[u8])
+        -> LmdbResult<()>
+    {
+        if !self.wipe {
+            return Ok(());
+        }
+        let len = match session.get(db, &k) {
+            Ok(value) => value.len(),
+            Err(LmdbError::NotFound) => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        let buf = session.reserve(db, &k, len, WriteFlags::empty())?;
+        for b in buf.iter_mut() {
+            *b = 0;
+        }
+        Ok(())
+    }
 
-// TODO: handle all LmdbError variants
-impl KeyFileStore for KeyFile {
-    fn exists(&self, k: &Vec<u8>) -> bool
+    // Get the metadata of every revision of a key in the history, oldest
+    // first
+    fn history<T>(&self, session: &T, k: &[u8])
+        -> KeyFileResult<Vec<KeyFileInfo>>
+    where
+        T: Transaction,
     {
-        match self.dbget(k) {
-            Ok(_) => true,
-            Err(_) => false,
+        let prefix = history_key(k, 0);
+        let prefix = &prefix[..prefix.len() - 8];
+        let mut history = Vec::new();
+        let mut error = None;
+        scan(session, self.histdb, prefix, |histkey, entry| {
+            if !histkey.starts_with(prefix) {
+                return false;
+            }
+            if entry.len() < KeyFileInfo::SIZE {
+                error = Some(KeyFileError::Corrupted);
+                return false;
+            }
+            match KeyFileInfo::from_bytes(&entry[..KeyFileInfo::SIZE]) {
+                Ok(info) => history.push(info),
+                Err(e) => error = Some(e),
+            }
+            error.is_none()
+        }).map_err(|e| keyfile_error(e, k))?;
+        match error {
+            Some(e) => Err(e),
+            None => Ok(history),
         }
     }
 
-    fn get(&self, k: &Vec<u8>) -> KeyFileResult<Vec<u8>>
+    // Get the keyfile of a revision in the history
+    fn revision<T>(&self, session: &T, k: &[u8], rev: u64)
+        -> KeyFileResult<Vec<u8>>
+    where
+        T: Transaction,
     {
-        match self.dbget(k) {
-            Ok(v) => Ok(v),
-            Err(LmdbError::NotFound) => Err(KeyFileError::Key(k.clone())),
-            _ => Err(KeyFileError::Other),
+        match session.get(self.histdb, &history_key(k, rev)) {
+            Ok(entry) if entry.len() >= KeyFileInfo::SIZE => {
+                Ok(entry[KeyFileInfo::SIZE..].to_vec())
+            }
+            Ok(_) => Err(KeyFileError::Corrupted),
+            Err(LmdbError::NotFound) => {
+                Err(KeyFileError::Revision(k.to_vec(), rev))
+            }
+            Err(e) => Err(keyfile_error(e, k)),
         }
     }
 
-    fn set(&mut self, k: &Vec<u8>, file: &Vec<u8>) -> KeyFileResult<()>
+    // Check every keyfile against its metadata and checksum, along with
+    // every revision in the history, returning the key of each damaged
+    // entry and the error found
+    fn scrub<T>(&self, session: &T)
+        -> LmdbResult<Vec<(Vec<u8>, KeyFileError)>>
+    where
+        T: Transaction,
     {
-        match self.dbset(k, file, None) {
-            Ok(_) => Ok(()),
-            _ => Err(KeyFileError::Other),
+        let mut damaged = Vec::new();
+        scan(session, self.db, &[], |k, keyfile| {
+            let checked = self.record(session, k).and_then(|record| {
+                check_entry(keyfile, &record.0, record.1)
+            });
+            if let Err(e) = checked {
+                damaged.push((k.to_vec(), e));
+            }
+            true
+        })?;
+
+        // Metadata left behind without a keyfile
+        scan(session, self.infodb, &[], |k, _| {
+            match session.get(self.db, &k) {
+                Ok(_) => {}
+                Err(LmdbError::NotFound) => {
+                    damaged.push((k.to_vec(), KeyFileError::Corrupted))
+                }
+                Err(e) => damaged.push((k.to_vec(), keyfile_error(e, k))),
+            }
+            true
+        })?;
+
+        // History entries hold their revision's metadata and keyfile
+        scan(session, self.histdb, &[], |histkey, entry| {
+            let size = KeyFileInfo::SIZE;
+            let checked = if entry.len() < size {
+                Err(KeyFileError::Corrupted)
+            } else {
+                KeyFileInfo::from_bytes(&entry[..size]).and_then(|info| {
+                    check_entry(&entry[size..], &info, None)
+                })
+            };
+            if let Err(e) = checked {
+                damaged.push((history_entry_key(histkey).to_vec(), e));
+            }
+            true
+        })?;
+        Ok(damaged)
+    }
+}
+
+
+fn history_key(k: &[u8], rev: u64) -> Vec<u8>
+{
+    let len = k.len() as u32;
+    let mut histkey = Vec::with_capacity(k.len() + 12);
+    for i in (0..4).rev() {
+        histkey.push((len >> (i * 8)) as u8);
+    }
+    histkey.extend_from_slice(k);
+    for i in (0..8).rev() {
+        histkey.push((rev >> (i * 8)) as u8);
+    }
+    histkey
+}
+
+
+// Get the key a history entry belongs to
+fn history_entry_key(histkey: &[u8]) -> &[u8]
+{
+    if histkey.len() < 12 {
+        return histkey;
+    }
+    &histkey[4..histkey.len() - 8]
+}
+
+
+// Size of an infodb record: the key's KeyFileInfo followed by the checksum
+// of its keyfile as a big-endian u64. Records written before checksums were
+// kept only hold the KeyFileInfo.
+const RECORD_SIZE: usize = KeyFileInfo::SIZE + 8;
+
+
+fn info_record(info: &KeyFileInfo, checksum: Option<u64>) -> Vec<u8>
+{
+    let mut record = info.to_bytes();
+    if let Some(checksum) = checksum {
+        for i in (0..8).rev() {
+            record.push((checksum >> (i * 8)) as u8);
         }
     }
+    record
+}
 
-    fn delete(&mut self, k: &Vec<u8>) -> KeyFileResult<()>
+
+// Decode an infodb record into the metadata and checksum it holds
+fn parse_record(record: &[u8]) -> KeyFileResult<(KeyFileInfo, Option<u64>)>
+{
+    match record.len() {
+        KeyFileInfo::SIZE => Ok((KeyFileInfo::from_bytes(record)?, None)),
+        RECORD_SIZE => {
+            let (info, checksum) = record.split_at(KeyFileInfo::SIZE);
+            let checksum =
+                checksum.iter().fold(0, |n, &b| (n << 8) | u64::from(b));
+            Ok((KeyFileInfo::from_bytes(info)?, Some(checksum)))
+        }
+        _ => Err(KeyFileError::Corrupted),
+    }
+}
+
+
+// Return KeyFileError::Corrupted if the keyfile doesn't match its checksum
+fn verify_keyfile(keyfile: &[u8], checksum: Option<u64>) -> KeyFileResult<()>
+{
+    match checksum {
+        Some(checksum) if checksum != keyfile_hash(keyfile) => {
+            Err(KeyFileError::Corrupted)
+        }
+        _ => Ok(()),
+    }
+}
+
+
+// Return KeyFileError::Corrupted if the keyfile doesn't match its metadata
+// or checksum
+fn check_entry(keyfile: &[u8], info: &KeyFileInfo, checksum: Option<u64>)
+    -> KeyFileResult<()>
+{
+    if info.length != keyfile.len() as u64 {
+        return Err(KeyFileError::Corrupted);
+    }
+    verify_keyfile(keyfile, checksum)
+}
+
+
+// Visit entries in key order using a read-only cursor, starting at the first
+// key that is not less than start. Each key and value is passed to visit,
+// which returns false once no more entries are wanted.
+fn scan<T, F>(session: &T, db: Database, start: &[u8], mut visit: F)
+    -> LmdbResult<()>
+where
+    T: Transaction,
+    F: FnMut(&[u8], &[u8]) -> bool,
+{
+    let mut cursor = session.open_ro_cursor(db)?;
+
+    // Position the cursor on the first key. This is done by hand since
+    // Cursor::iter_from() panics if there is no such key. LMDB rejects an
+    // empty key, so an empty start begins at the first key in the db.
+    let position = if start.is_empty() {
+        cursor.get(None, None, ffi::MDB_FIRST)
+    } else {
+        cursor.get(Some(start), None, ffi::MDB_SET_RANGE)
+    };
+    let (first, value) = match position {
+        Ok((Some(k), v)) => (k, v),
+        Ok((None, v)) => (start, v),
+        Err(LmdbError::NotFound) => return Ok(()),
+        Err(e) => return Err(e),
+    };
+    if visit(first, value) {
+        for (k, v) in cursor.iter() {
+            if !visit(k, v) {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+
+pub struct KeyFile {
+    pub dbinit: Init,
+
+    // Shared with every namespace kept in the same environment
+    env: Arc<Environment>,
+    tables: Tables,
+
+    // Held for reading by every transaction in the environment, and for
+    // writing while the map is resized
+    resizing: Arc<RwLock<()>>,
+
+    // Name of the namespace the store holds, or None for the default
+    // namespace
+    namespace: Option<String>,
+}
+
+
+impl KeyFile {
+    /// Open the named database using the given environment settings,
+    /// creating it if it doesn't exist.
+    ///
+    /// Panics if the database can't be opened.
+    pub fn with_init(name: &str, init: Init) -> KeyFile
+    {
+        KeyFile::open(name, init).expect("Error opening DB")
+    }
+
+    /// Open the named database using the given environment settings,
+    /// creating it if it doesn't exist.
+    ///
+    /// An environment written with an older schema is upgraded first. One
+    /// written with a newer schema is refused with `KeyFileError::Schema`,
+    /// and one whose keys aren't hashed with the salt given in init is
+    /// refused with `KeyFileError::KeySalt`.
+    pub fn open(name: &str, init: Init) -> KeyFileResult<KeyFile>
+    {
+        let env = init.create().map_err(|e| keyfile_error(e, &[]))?;
+
+        // Create DB
+        let tables = Tables::open(&env, name, &init)
+            .map_err(|e| keyfile_error(e, &[]))?;
+        let mut keyfile = KeyFile {
+            dbinit: init,
+            env: Arc::new(env),
+            tables: tables,
+            resizing: Arc::new(RwLock::new(())),
+            namespace: None,
+        };
+        keyfile.migrate(name)?;
+
+        // The change log and meta db may have been added by the upgrade
+        let lmdb_error = |e| keyfile_error(e, &[]);
+        let changes = keyfile.dbinit.changes;
+        keyfile.tables.log =
+            change_log(&keyfile.env, changes).map_err(lmdb_error)?;
+        keyfile.tables.meta = meta_db(&keyfile.env).map_err(lmdb_error)?;
+        keyfile.check_key_salt()?;
+        keyfile.trim_changes()?;
+        Ok(keyfile)
+    }
+
+    /// Open the namespaces kept in the same environment as this store.
+    ///
+    /// Panics if the namespaces can't be opened.
+    pub fn namespaces(&self) -> Namespaces
+    {
+        self.open_namespaces().expect("Error opening namespace DB")
+    }
+
+    /// Open the namespaces kept in the same environment as this store,
+    /// creating the db listing them if it doesn't exist.
+    pub fn open_namespaces(&self) -> KeyFileResult<Namespaces>
+    {
+        let dbflags = DatabaseFlags::empty();
+        let registry = KeyFile::create(&self.env, NAMESPACE_DB, dbflags)
+            .map_err(|e| keyfile_error(e, &[]))?;
+        Ok(Namespaces {
+            init: self.dbinit.clone(),
+            env: self.env.clone(),
+            resizing: self.resizing.clone(),
+            registry: registry,
+            stores: HashMap::new(),
+        })
+    }
+
+    fn create(env: &Environment, dbname: &str, dbflags: DatabaseFlags)
+        -> LmdbResult<Database>
     {
-        match self.dbdel(k) {
-            Ok(()) => Ok(()),
-            Err(LmdbError::NotFound) => Err(KeyFileError::Key(k.clone())),
-            Err(_) => Err(KeyFileError::Other),
+        let db = env.open_db(Some(dbname));
+        match db {
+            Ok(db) => Ok(db),
+            Err(_) => env.create_db(Some(dbname), dbflags),
         }
     }
+
+    // Key stored in the db in place of k
+    fn stored_key<'a>(&self, k: &'a [u8]) -> Cow<'a, [u8]>
+    {
+        match self.dbinit.hasher {
+            Some(ref hasher) => Cow::Owned(hasher.hash(k)),
+            None => Cow::Borrowed(k),
+        }
+    }
+
+    // Run op on the stored key of k inside a read-only transaction. Errors
+    // name k rather than its stored key.
+    fn keyread<F, V>(&self, k: &[u8], op: F) -> KeyFileResult<V>
+    where
+        F: FnOnce(&Tables, &RoTransaction, &[u8]) -> KeyFileResult<V>,
+    {
+        let stored = self.stored_key(k);
+        self.dbread(&stored, |tables, session| op(tables, session, &stored))
+            .map_err(|e| original_key(e, &stored, k))
+    }
+
+    // Apply every operation to the stored key of its key. Errors name the
+    // key given in the operation rather than its stored key.
+    fn keyapply(&mut self, ops: &[KeyFileOp]) -> KeyFileResult<()>
+    {
+        if self.dbinit.hasher.is_none() {
+            return self.dbapply(ops);
+        }
+        let stored: Vec<KeyFileOp> = ops.iter()
+            .map(|op| {
+                let k = self.stored_key(op.key()).into_owned();
+                match *op {
+                    KeyFileOp::Set(_, ref file) => {
+                        KeyFileOp::Set(k, file.clone())
+                    }
+                    KeyFileOp::Delete(_) => KeyFileOp::Delete(k),
+                    KeyFileOp::Check(_, ref check) => {
+                        KeyFileOp::Check(k, check.clone())
+                    }
+                    KeyFileOp::Expire(_, expires) => {
+                        KeyFileOp::Expire(k, expires)
+                    }
+                    KeyFileOp::Load(_, ref file, ref info) => {
+                        KeyFileOp::Load(k, file.clone(), info.clone())
+                    }
+                }
+            })
+            .collect();
+        self.dbapply(&stored).map_err(|e| {
+            ops.iter().zip(&stored).fold(e, |e, (op, stored)| {
+                original_key(e, stored.key(), op.key())
+            })
+        })
+    }
+
+    // Run op inside a read-only transaction
+    fn dbread<F, V>(&self, k: &[u8], op: F) -> KeyFileResult<V>
+    where
+        F: FnOnce(&Tables, &RoTransaction) -> KeyFileResult<V>,
+    {
+        let tables = &self.tables;
+        read_txn(&self.env, &self.resizing, k, |session| {
+            op(tables, session)
+        })
+    }
+
+    // Apply every operation in a single write transaction, keeping the
+    // metadata and history of each changed key in step with its keyfile. If
+    // any operation fails, the transaction is dropped and LMDB discards
+    // every change made so far.
+    //
+    // Every key changed by an operation is added to the change log in the
+    // same transaction.
+    fn dbapply(&mut self, ops: &[KeyFileOp]) -> KeyFileResult<()>
+    {
+        let tables = self.tables.clone();
+        let namespace = self.namespace.clone();
+        self.dbwrite(|session| {
+            for op in ops {
+                tables.stage(session, op)?;
+                match *op {
+                    // Checks don't change anything
+                    KeyFileOp::Check(_, _) => {}
+                    _ => tables.log_key(session, &namespace, op.key())?,
+                }
+            }
+            Ok(())
+        })
+    }
+
+    // Run op inside a write transaction and commit it. If the database is
+    // full, grow the map and run op again in a new transaction. If another
+    // process has grown the map, adopt its size before starting over.
+    fn dbwrite<F>(&mut self, mut op: F) -> KeyFileResult<()>
+    where
+        F: FnMut(&mut RwTransaction) -> KeyFileResult<()>,
+    {
+        loop {
+            let result = {
+                let _resizing = self.resizing.read().unpoison();
+                match self.env.begin_rw_txn() {
+                    Ok(mut session) => Some(op(&mut session).and_then(|_| {
+                        session.commit().map_err(|e| keyfile_error(e, &[]))
+                    })),
+                    Err(LmdbError::MapResized) => None,
+                    Err(e) => Some(Err(keyfile_error(e, &[]))),
+                }
+            };
+            match result {
+                None => adopt_map_size(&self.env, &self.resizing)
+                    .map_err(|e| keyfile_error(e, &[]))?,
+                Some(Err(KeyFileError::MapFull)) => {
+                    self.grow().map_err(|e| keyfile_error(e, &[]))?
+                }
+                Some(result) => return result,
+            }
+        }
+    }
+
+    // Grow the map by the configured growth factor, up to the configured
+    // maximum map size. Returns LmdbError::MapFull if the map can't grow.
+    //
+    // LMDB requires that no transactions are active in this process when
+    // the map size is changed. Stores in the same environment may be used
+    // from other threads, so the map is only resized once every
+    // transaction has let go of the resizing lock.
+    fn grow(&mut self) -> LmdbResult<()>
+    {
+        let _resizing = self.resizing.write().unpoison();
+        let maxsize = match self.dbinit.maxmapsize {
+            Some(size) => size,
+            None => return Err(LmdbError::MapFull),
+        };
+        let cursize = self.map_size()?;
+        if cursize >= maxsize {
+            return Err(LmdbError::MapFull);
+        }
+        let newsize = (cursize as f64 * self.dbinit.growth) as usize;
+        let newsize = cmp::min(cmp::max(newsize, cursize + 1), maxsize);
+        let ret = unsafe { ffi::mdb_env_set_mapsize(self.env.env(), newsize) };
+        match ret {
+            0 => Ok(()),
+            e => Err(LmdbError::from_err_code(e)),
+        }
+    }
+
+    /// Current size of the memory map in bytes.
+    pub fn map_size(&self) -> LmdbResult<usize>
+    {
+        env_map_size(&self.env)
+    }
+}
+
+
+// Run op inside a read-only transaction, holding the resizing lock until
+// the transaction ends. If another process has grown the map, adopt its
+// size and start the transaction again.
+fn read_txn<F, V>(
+    env: &Environment, resizing: &RwLock<()>, k: &[u8], op: F
+) -> KeyFileResult<V>
+where
+    F: FnOnce(&RoTransaction) -> KeyFileResult<V>,
+{
+    loop {
+        let guard = resizing.read().unpoison();
+        match env.begin_ro_txn() {
+            Ok(session) => {
+                let value = op(&session)?;
+                session.commit().map_err(|e| keyfile_error(e, k))?;
+                return Ok(value);
+            }
+            Err(LmdbError::MapResized) => {
+                drop(guard);
+                adopt_map_size(env, resizing)
+                    .map_err(|e| keyfile_error(e, k))?;
+            }
+            Err(e) => return Err(keyfile_error(e, k)),
+        }
+    }
+}
+
+
+// Take on the map size set by another process using the same environment,
+// which LMDB reports as LmdbError::MapResized when a transaction starts.
+// As when the map grows, no transaction may be active in this process.
+fn adopt_map_size(env: &Environment, resizing: &RwLock<()>) -> LmdbResult<()>
+{
+    let _resizing = resizing.write().unpoison();
+    let ret = unsafe { ffi::mdb_env_set_mapsize(env.env(), 0) };
+    match ret {
+        0 => Ok(()),
+        e => Err(LmdbError::from_err_code(e)),
+    }
+}
+
+
+// Current size of the memory map of an environment in bytes
+fn env_map_size(env: &Environment) -> LmdbResult<usize>
+{
+    let mut info: ffi::MDB_envinfo = unsafe { mem::zeroed() };
+    let ret = unsafe { ffi::mdb_env_info(env.env(), &mut info) };
+    match ret {
+        0 => Ok(info.me_mapsize as usize),
+        e => Err(LmdbError::from_err_code(e)),
+    }
+}
+
+
+impl KeyFileBuilder for KeyFile {
+    fn new(name: &str, envpath: Option<&Path>) -> KeyFile
+    {
+        let init = match envpath {
+            Some(p) => Init::new().path(p),
+            None => Init::new(),
+        };
+        KeyFile::with_init(name, init)
+    }
+}
+
+
+impl KeyFileStore for KeyFile {
+    fn exists(&self, k: &Vec<u8>) -> bool
+    {
+        self.keyread(k, |tables, session, k| tables.get(session, k))
+            .is_ok()
+    }
+
+    // The keyfile is handed on to the response sent for it, which is
+    // zeroized by MsgPackCodec once encoded
+    fn get(&self, k: &Vec<u8>) -> KeyFileResult<Vec<u8>>
+    {
+        self.keyread(k, |tables, session, k| tables.get(session, k))
+            .map(Zeroizing::into_inner)
+    }
+
+    fn set(&mut self, k: &Vec<u8>, file: &Vec<u8>) -> KeyFileResult<()>
+    {
+        self.keyapply(&[KeyFileOp::Set(k.clone(), file.clone())])
+    }
+
+    fn delete(&mut self, k: &Vec<u8>) -> KeyFileResult<()>
+    {
+        self.keyapply(&[KeyFileOp::Delete(k.clone())])
+    }
+
+    fn info(&self, k: &Vec<u8>) -> KeyFileResult<KeyFileInfo>
+    {
+        self.keyread(k, |tables, session, k| tables.live_info(session, k))
+    }
+
+    fn expire(&mut self, k: &Vec<u8>, expires: Option<u64>)
+        -> KeyFileResult<()>
+    {
+        self.keyapply(&[KeyFileOp::Expire(k.clone(), expires)])
+    }
+
+    fn purge_expired(&mut self) -> KeyFileResult<Vec<Vec<u8>>>
+    {
+        let mut expired = Vec::new();
+        self.dbread(&[], |tables, session| {
+            scan(session, tables.infodb, &[], |k, buf| {
+                match parse_record(buf) {
+                    Ok((ref info, _)) if info.is_expired() => {
+                        expired.push(k.to_vec())
+                    }
+                    _ => {}
+                }
+                true
+            }).map_err(|e| keyfile_error(e, &[]))
+        })?;
+        let ops: Vec<KeyFileOp> = expired
+            .iter()
+            .map(|k| KeyFileOp::Delete(k.clone()))
+            .collect();
+        self.dbapply(&ops)?;
+        Ok(expired)
+    }
+
+    // Usage is kept up to date by every write, so it isn't counted here
+    fn usage(&self) -> KeyFileResult<(usize, u64)>
+    {
+        let (entries, bytes) = self.dbread(&[], |tables, session| {
+            tables.usage(session).map_err(|e| keyfile_error(e, &[]))
+        })?;
+        Ok((entries as usize, bytes))
+    }
+
+    fn revisions(&self, k: &Vec<u8>) -> KeyFileResult<Vec<KeyFileInfo>>
+    {
+        self.keyread(k, |tables, session, k| tables.history(session, k))
+    }
+
+    fn get_revision(&self, k: &Vec<u8>, rev: u64) -> KeyFileResult<Vec<u8>>
+    {
+        self.keyread(k, |tables, session, k| tables.revision(session, k, rev))
+    }
+
+    // Hashed keys can't be turned back into the keys they were made from,
+    // so they can't be listed
+    fn iter_prefix(&self, prefix: &[u8], after: Option<&[u8]>, limit: usize)
+        -> KeyFileResult<Vec<Vec<u8>>>
+    {
+        if self.dbinit.hasher.is_some() {
+            return Err(KeyFileError::Other);
+        }
+        let mut keys: Vec<Vec<u8>> = Vec::new();
+        if limit == 0 {
+            return Ok(keys);
+        }
+        let start = match after {
+            Some(a) if a > prefix => a,
+            _ => prefix,
+        };
+        self.dbread(prefix, |tables, session| {
+            scan(session, tables.db, start, |k, _| {
+                if !k.starts_with(prefix) {
+                    return false;
+                }
+                let expired = match tables.info(session, k) {
+                    Ok(info) => info.is_expired(),
+                    Err(_) => false,
+                };
+                match after {
+                    Some(a) if k <= a => {}
+                    _ if expired => {}
+                    _ => keys.push(k.to_vec()),
+                }
+                keys.len() < limit
+            }).map_err(|e| keyfile_error(e, prefix))
+        })?;
+        Ok(keys)
+    }
+
+    // The copy is only readable by the owner of the directory, and its
+    // data file gets the same permissions as the store's
+    fn backup(&self, dir: &Path, compact: bool) -> KeyFileResult<()>
+    {
+        fs::DirBuilder::new()
+            .recursive(true)
+            .mode(0o700)
+            .create(dir)
+            .map_err(|_| KeyFileError::Io)?;
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .mode(self.dbinit.mode)
+            .open(dir.join(DATA_FILE))
+            .map_err(|_| KeyFileError::Io)?;
+        let flags = if compact { ffi::MDB_CP_COMPACT } else { 0 };
+
+        // The copy is made inside a read-only transaction, so writes can
+        // carry on while it runs. Nothing is written before the transaction
+        // starts, so the copy can start over if another process has grown
+        // the map.
+        loop {
+            let ret = {
+                let _resizing = self.resizing.read().unpoison();
+                unsafe {
+                    ffi::mdb_env_copyfd2(
+                        self.env.env(),
+                        file.as_raw_fd(),
+                        flags,
+                    )
+                }
+            };
+            match ret {
+                0 => return Ok(()),
+                e => match LmdbError::from_err_code(e) {
+                    LmdbError::MapResized => {
+                        adopt_map_size(&self.env, &self.resizing)
+                            .map_err(|e| keyfile_error(e, &[]))?
+                    }
+                    e => return Err(keyfile_error(e, &[])),
+                },
+            }
+        }
+    }
+
+    fn scrub(&self) -> KeyFileResult<Vec<(Vec<u8>, KeyFileError)>>
+    {
+        self.dbread(&[], |tables, session| {
+            tables.scrub(session).map_err(|e| keyfile_error(e, &[]))
+        })
+    }
+
+    // Environments written before the change log was kept and opened
+    // read-only have an empty change log. Changes that have been dropped
+    // from the log are refused with KeyFileError::Trimmed.
+    fn changes(&self, after: u64, limit: usize)
+        -> KeyFileResult<(u64, Changes)>
+    {
+        let log = match self.tables.log {
+            Some(log) => log,
+            None => return Ok((0, Vec::new())),
+        };
+        self.dbread(&[], |_, session| {
+            let lmdb_error = |e| keyfile_error(e, &[]);
+            let latest = latest_sequence(session, log).map_err(lmdb_error)?;
+            let mut changes = Vec::new();
+            if limit == 0 || after >= latest {
+                return Ok((latest, changes));
+            }
+            let first = first_sequence(session, log).map_err(lmdb_error)?;
+            if after + 1 < first.unwrap_or(latest + 1) {
+                return Err(KeyFileError::Trimmed);
+            }
+            let mut error = None;
+            scan(session, log.db, &sequence_key(after + 1), |k, buf| {
+                match decode_change(buf) {
+                    Ok(change) => changes.push((parse_sequence(k), change)),
+                    Err(e) => error = Some(e),
+                }
+                error.is_none() && changes.len() < limit
+            }).map_err(|e| keyfile_error(e, &[]))?;
+            match error {
+                Some(e) => Err(e),
+                None => Ok((latest, changes)),
+            }
+        })
+    }
+
+    // Replicated keyfiles replace the current keyfile without adding it to
+    // the history, so the history of a replica only holds the changes made
+    // to it directly.
+    //
+    // Changes name their namespace, so only the store of the default
+    // namespace can apply them.
+    fn replicate(&mut self, changes: &[(u64, Change)]) -> KeyFileResult<()>
+    {
+        let last = match changes.last() {
+            Some(&(seq, _)) => seq,
+            None => return Ok(()),
+        };
+        if self.namespace.is_some() {
+            return Err(KeyFileError::Other);
+        }
+        let meta = KeyFile::create(&self.env, META_DB, DatabaseFlags::empty())
+            .map_err(|e| keyfile_error(e, &[]))?;
+        let tables = self.tables.clone();
+        self.dbwrite(|session| {
+            for (_, change) in changes {
+                apply_change(session, &tables, change)?;
+            }
+            let buf = sequence_key(last);
+            session
+                .put(meta, &REPLICATED_KEY, &buf, WriteFlags::empty())
+                .map_err(|e| keyfile_error(e, &[]))
+        })
+    }
+
+    // An environment restored from a backup of its primary carries on from
+    // the latest change in the backup
+    fn replicated(&self) -> KeyFileResult<u64>
+    {
+        let meta = match self.env.open_db(Some(META_DB)) {
+            Ok(meta) => meta,
+            Err(LmdbError::NotFound) => return Ok(0),
+            Err(e) => return Err(keyfile_error(e, &[])),
+        };
+        let log = self.tables.log;
+        self.dbread(&[], |_, session| {
+            match (session.get(meta, &REPLICATED_KEY), log) {
+                (Ok(buf), _) if buf.len() == 8 => Ok(parse_sequence(buf)),
+                (Ok(_), _) => Err(KeyFileError::Corrupted),
+                (Err(LmdbError::NotFound), Some(log)) => {
+                    latest_sequence(session, log)
+                        .map_err(|e| keyfile_error(e, &[]))
+                }
+                (Err(LmdbError::NotFound), None) => Ok(0),
+                (Err(e), _) => Err(keyfile_error(e, &[])),
+            }
+        })
+    }
+
+    fn hashes_keys(&self) -> bool
+    {
+        self.dbinit.hasher.is_some()
+    }
+
+    fn commit(&mut self, txn: KeyFileTransaction) -> KeyFileResult<()>
+    {
+        self.keyapply(txn.ops())
+    }
+}
+
+
+// ===========================================================================
+// Schema
+// ===========================================================================
+
+
+/// Version of the layout used for every store in an LMDB environment.
+pub const SCHEMA_VERSION: u32 = 3;
+
+
+// Named db holding details about the environment itself
+const META_DB: &str = "meta";
+
+
+// Key in the meta db holding the schema version as a big-endian u32. An
+// environment without it was written before versions were kept, which is
+// version 0.
+const SCHEMA_KEY: &str = "schema";
+
+
+// Prefix of the key in the meta db holding the usage of each store, which is
+// the number of keyfiles in the store followed by their total length in
+// bytes, each as a big-endian u64
+const USAGE_KEY: &str = "usage";
+
+
+// Each migration upgrades every store in the environment from the version
+// matching its index to the next version. It is given the name of the store
+// being opened, which holds the default namespace.
+type Migration = fn(&mut RwTransaction, &str) -> LmdbResult<()>;
+
+
+const MIGRATIONS: [Migration; SCHEMA_VERSION as usize] =
+    [add_checksums, add_change_log, add_usage];
+
+
+impl KeyFile {
+    /// Schema version of the environment.
+    pub fn schema_version(&self) -> KeyFileResult<u32>
+    {
+        let meta = match self.env.open_db(Some(META_DB)) {
+            Ok(meta) => meta,
+            Err(LmdbError::NotFound) => return Ok(0),
+            Err(e) => return Err(keyfile_error(e, &[])),
+        };
+        self.dbread(&[], |_, session| read_version(session, meta))
+    }
+
+    // Drop the changes the change log no longer keeps, in case fewer are
+    // kept than when the environment was last written
+    fn trim_changes(&mut self) -> KeyFileResult<()>
+    {
+        let log = match self.tables.log {
+            Some(log) if !self.dbinit.flags.contains(READ_ONLY) => log,
+            _ => return Ok(()),
+        };
+        self.dbwrite(|session| {
+            latest_sequence(session, log)
+                .and_then(|latest| trim_log(session, log, latest))
+                .map_err(|e| keyfile_error(e, &[]))
+        })
+    }
+
+    // Run every migration the environment hasn't had yet in a single
+    // transaction, so that a failed upgrade leaves the environment as it
+    // was.
+    //
+    // A read-only environment can't be upgraded, so an older one is used as
+    // it is. Every older layout can still be read.
+    fn migrate(&mut self, name: &str) -> KeyFileResult<()>
+    {
+        let version = self.schema_version()?;
+        if version > SCHEMA_VERSION {
+            return Err(KeyFileError::Schema(version));
+        }
+        if version == SCHEMA_VERSION || self.dbinit.flags.contains(READ_ONLY)
+        {
+            return Ok(());
+        }
+
+        let meta = KeyFile::create(&self.env, META_DB, DatabaseFlags::empty())
+            .map_err(|e| keyfile_error(e, &[]))?;
+        self.dbwrite(|session| {
+            // Another process may have upgraded the environment already
+            let version = read_version(session, meta)?;
+            if version >= SCHEMA_VERSION {
+                return Ok(());
+            }
+            for migration in &MIGRATIONS[version as usize..] {
+                migration(session, name)
+                    .map_err(|e| keyfile_error(e, &[]))?;
+            }
+            let buf = [
+                (SCHEMA_VERSION >> 24) as u8,
+                (SCHEMA_VERSION >> 16) as u8,
+                (SCHEMA_VERSION >> 8) as u8,
+                SCHEMA_VERSION as u8,
+            ];
+            session
+                .put(meta, &SCHEMA_KEY, &buf, WriteFlags::empty())
+                .map_err(|e| keyfile_error(e, &[]))
+        })
+    }
+}
+
+
+// Open the meta db. Environments written before versions were kept have
+// none until they are upgraded.
+fn meta_db(env: &Environment) -> LmdbResult<Option<Database>>
+{
+    match env.open_db(Some(META_DB)) {
+        Ok(meta) => Ok(Some(meta)),
+        Err(LmdbError::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+
+// Key in the meta db holding the usage of the named store
+fn usage_key(name: &str) -> Vec<u8>
+{
+    format!("{}.{}", USAGE_KEY, name).into_bytes()
+}
+
+
+fn read_version<T>(session: &T, meta: Database) -> KeyFileResult<u32>
+where
+    T: Transaction,
+{
+    match session.get(meta, &SCHEMA_KEY) {
+        Ok(buf) if buf.len() == 4 => {
+            Ok(buf.iter().fold(0, |n, &b| (n << 8) | u32::from(b)))
+        }
+        Ok(_) => Err(KeyFileError::Corrupted),
+        Err(LmdbError::NotFound) => Ok(0),
+        Err(e) => Err(keyfile_error(e, &[])),
+    }
+}
+
+
+// Names of every keyfile store in the environment. The names of named dbs
+// are the keys of the unnamed db, and every store has an info db.
+fn store_names(session: &RwTransaction) -> LmdbResult<Vec<String>>
+{
+    let main = unsafe { session.open_db(None)? };
+    let mut names = Vec::new();
+    scan(session, main, &[], |k, _| {
+        names.push(String::from_utf8_lossy(k).into_owned());
+        true
+    })?;
+    let stores = names
+        .iter()
+        .filter(|name| names.contains(&format!("{}.info", name)))
+        .cloned()
+        .collect();
+    Ok(stores)
+}
+
+
+// Version 1 keeps a checksum of every keyfile after its metadata. Keys
+// stored before metadata was kept are given metadata as well.
+fn add_checksums(session: &mut RwTransaction, _name: &str) -> LmdbResult<()>
+{
+    for name in store_names(session)? {
+        let db = unsafe { session.open_db(Some(&name))? };
+        let infoname = format!("{}.info", name);
+        let infodb = unsafe { session.open_db(Some(&infoname))? };
+
+        let mut records = Vec::new();
+        let mut error = None;
+        scan(session, db, &[], |k, keyfile| {
+            let info = match session.get(infodb, &k) {
+                Ok(buf) if buf.len() == KeyFileInfo::SIZE => {
+                    KeyFileInfo::from_bytes(buf).ok()
+                }
+
+                // Damaged records are left for a scrub to find
+                Ok(_) => None,
+                Err(LmdbError::NotFound) => Some(KeyFileInfo {
+                    created: 0,
+                    modified: 0,
+                    length: keyfile.len() as u64,
+                    revision: 0,
+                    expires: None,
+                }),
+                Err(e) => {
+                    error = Some(e);
+                    return false;
+                }
+            };
+            if let Some(info) = info {
+                let checksum = Some(keyfile_hash(keyfile));
+                records.push((k.to_vec(), info_record(&info, checksum)));
+            }
+            true
+        })?;
+        if let Some(e) = error {
+            return Err(e);
+        }
+
+        for (k, record) in records {
+            session.put(infodb, &k, &record, WriteFlags::empty())?;
+        }
+    }
+    Ok(())
+}
+
+
+// Version 2 keeps a log of every change made to the environment. The log
+// starts out holding every keyfile of the store being opened, and every
+// namespace along with its keyfiles.
+fn add_change_log(session: &mut RwTransaction, name: &str) -> LmdbResult<()>
+{
+    let open = |name| unsafe {
+        session.create_db(Some(name), DatabaseFlags::empty())
+    };
+    let log = ChangeLog {
+        db: open(CHANGES_DB)?,
+        meta: open(META_DB)?,
+        keep: usize::max_value(),
+    };
+    if latest_sequence(session, log)? > 0 {
+        return Ok(());
+    }
+
+    let mut stores = vec![(None, name.to_string())];
+    match unsafe { session.open_db(Some(NAMESPACE_DB)) } {
+        Ok(registry) => scan(session, registry, &[], |k, _| {
+            let name = String::from_utf8_lossy(k).into_owned();
+            let dbname = format!("ns.{}", name);
+            stores.push((Some(name), dbname));
+            true
+        })?,
+        Err(LmdbError::NotFound) => {}
+        Err(e) => return Err(e),
+    }
+
+    let mut changes = Vec::new();
+    for (namespace, dbname) in stores {
+        if let Some(ref name) = namespace {
+            changes.push(Change::CreateNamespace(name.clone()));
+        }
+        let tables = Tables::open_in(session, &dbname, 0, false, None)?;
+        scan(session, tables.db, &[], |k, keyfile| {
+            // Damaged records are left for a scrub to find
+            if let Ok(info) = tables.info(session, k) {
+                let ns = namespace.clone();
+                let (k, keyfile) = (k.to_vec(), keyfile.to_vec());
+                changes.push(Change::Put(ns, k, keyfile, info));
+            }
+            true
+        })?;
+    }
+    for change in changes {
+        log_change(session, Some(log), change)?;
+    }
+    Ok(())
+}
+
+
+// Version 3 keeps the usage of every store in the meta db, so that it
+// doesn't have to be counted each time a quota is checked
+fn add_usage(session: &mut RwTransaction, _name: &str) -> LmdbResult<()>
+{
+    for name in store_names(session)? {
+        let tables = Tables::open_in(session, &name, 0, false, None)?;
+        let (entries, bytes) = tables.usage(session)?;
+        tables.set_usage(session, entries, bytes)?;
+    }
+    Ok(())
+}
+
+
+// ===========================================================================
+// Hashed keys
+// ===========================================================================
+
+
+// Key in the meta db holding the hash of no bytes, made with the salt that
+// every key in the environment is hashed with. Environments whose keys
+// aren't hashed don't have it.
+const KEY_SALT_KEY: &str = "key_salt";
+
+
+impl KeyFile {
+    // Refuse an environment whose keys are hashed with a different salt,
+    // or aren't hashed the same way as the keys given to the store. Keys
+    // can only start being hashed before anything has been written to the
+    // environment.
+    fn check_key_salt(&mut self) -> KeyFileResult<()>
+    {
+        let check = self.dbinit.hasher.as_ref().map(|h| h.hash(&[]));
+        let stored = match self.env.open_db(Some(META_DB)) {
+            Ok(meta) => self.dbread(&[], |_, session| {
+                match session.get(meta, &KEY_SALT_KEY) {
+                    Ok(buf) => Ok(Some(buf.to_vec())),
+                    Err(LmdbError::NotFound) => Ok(None),
+                    Err(e) => Err(keyfile_error(e, &[])),
+                }
+            })?,
+            Err(LmdbError::NotFound) => None,
+            Err(e) => return Err(keyfile_error(e, &[])),
+        };
+        let check = match (stored, check) {
+            (None, None) => return Ok(()),
+            (Some(ref s), Some(ref c)) if s == c => return Ok(()),
+            (None, Some(c)) => c,
+            _ => return Err(KeyFileError::KeySalt),
+        };
+        if self.dbinit.flags.contains(READ_ONLY) {
+            return Err(KeyFileError::KeySalt);
+        }
+
+        let log = self.tables.log;
+        let meta = KeyFile::create(&self.env, META_DB, DatabaseFlags::empty())
+            .map_err(|e| keyfile_error(e, &[]))?;
+        self.dbwrite(|session| {
+            let written = match log {
+                Some(log) => {
+                    latest_sequence(session, log)
+                        .map_err(|e| keyfile_error(e, &[]))? > 0
+                }
+                None => true,
+            };
+            if written {
+                return Err(KeyFileError::KeySalt);
+            }
+            session
+                .put(meta, &KEY_SALT_KEY, &check, WriteFlags::empty())
+                .map_err(|e| keyfile_error(e, &[]))
+        })
+    }
+}
+
+
+// ===========================================================================
+// Change log
+// ===========================================================================
+
+
+// Named db holding the latest changes made to the environment. Each entry's
+// key is the change's sequence number as a big-endian u64, starting at 1,
+// and each value is the change encoded as MessagePack.
+const CHANGES_DB: &str = "changes";
+
+
+// Key in the meta db holding the sequence number of the last change applied
+// from another environment's change log, as a big-endian u64
+const REPLICATED_KEY: &str = "replicated";
+
+
+// Key in the meta db holding the sequence number of the latest change made
+// to the environment, as a big-endian u64, whether or not the change is
+// still in the change log. Environments written before it was kept take the
+// sequence number of the last change in the log.
+const LATEST_KEY: &str = "latest";
+
+
+// Change log of an environment, along with the number of changes it keeps
+#[derive(Debug, Clone, Copy)]
+struct ChangeLog {
+    db: Database,
+    meta: Database,
+    keep: usize,
+}
+
+
+// Open the change log. Environments written before the change log was kept
+// have none until they are upgraded.
+fn change_log(env: &Environment, keep: usize)
+    -> LmdbResult<Option<ChangeLog>>
+{
+    let open = |name| match env.open_db(Some(name)) {
+        Ok(db) => Ok(Some(db)),
+        Err(LmdbError::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    };
+    match (open(CHANGES_DB)?, open(META_DB)?) {
+        (Some(db), Some(meta)) => Ok(Some(ChangeLog {
+            db: db,
+            meta: meta,
+            keep: keep,
+        })),
+        _ => Ok(None),
+    }
+}
+
+
+fn sequence_key(seq: u64) -> Vec<u8>
+{
+    (0..8).rev().map(|i| (seq >> (i * 8)) as u8).collect()
+}
+
+
+fn parse_sequence(buf: &[u8]) -> u64
+{
+    buf.iter().fold(0, |n, &b| (n << 8) | u64::from(b))
+}
+
+
+// Sequence number of the first or last change in the log, or None if it is
+// empty
+fn end_sequence<T>(session: &T, log: Database, op: u32)
+    -> LmdbResult<Option<u64>>
+where
+    T: Transaction,
+{
+    let cursor = session.open_ro_cursor(log)?;
+    match cursor.get(None, None, op) {
+        Ok((Some(k), _)) => Ok(Some(parse_sequence(k))),
+        Ok((None, _)) | Err(LmdbError::NotFound) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+
+// Sequence number of the oldest change still in the log
+fn first_sequence<T>(session: &T, log: ChangeLog) -> LmdbResult<Option<u64>>
+where
+    T: Transaction,
+{
+    end_sequence(session, log.db, ffi::MDB_FIRST)
+}
+
+
+// Sequence number of the latest change made to the environment, or 0 if
+// none has been
+fn latest_sequence<T>(session: &T, log: ChangeLog) -> LmdbResult<u64>
+where
+    T: Transaction,
+{
+    let last = end_sequence(session, log.db, ffi::MDB_LAST)?.unwrap_or(0);
+    match session.get(log.meta, &LATEST_KEY) {
+        Ok(buf) if buf.len() == 8 => Ok(cmp::max(parse_sequence(buf), last)),
+        Ok(_) => Err(LmdbError::Corrupted),
+        Err(LmdbError::NotFound) => Ok(last),
+        Err(e) => Err(e),
+    }
+}
+
+
+fn encode_change(change: Change) -> Vec<u8>
+{
+    let mut buf = BytesMut::new();
+    MsgPackCodec
+        .encode(change.into(), &mut buf)
+        .expect("Error encoding change");
+    buf.to_vec()
+}
+
+
+fn decode_change(buf: &[u8]) -> KeyFileResult<Change>
+{
+    let mut buf = BytesMut::from(buf);
+    match MsgPackCodec.decode(&mut buf) {
+        Ok(Some(ref value)) if buf.is_empty() => Change::from_value(value),
+        _ => Err(KeyFileError::Corrupted),
+    }
+}
+
+
+// Give a change the next sequence number and add it to the end of the log,
+// dropping the changes that no longer fit
+fn log_change(
+    session: &mut RwTransaction, log: Option<ChangeLog>, change: Change
+) -> LmdbResult<()>
+{
+    let log = match log {
+        Some(log) => log,
+        None => return Ok(()),
+    };
+    let seq = latest_sequence(session, log)? + 1;
+    let seqkey = sequence_key(seq);
+    session.put(log.meta, &LATEST_KEY, &seqkey, WriteFlags::empty())?;
+    if log.keep > 0 {
+        let buf = encode_change(change);
+        session.put(log.db, &seqkey, &buf, APPEND)?;
+    }
+    trim_log(session, log, seq)
+}
+
+
+// Drop every change from the log except the latest ones it keeps
+fn trim_log(session: &mut RwTransaction, log: ChangeLog, latest: u64)
+    -> LmdbResult<()>
+{
+    let last = latest.saturating_sub(log.keep as u64);
+    let mut dropped = Vec::new();
+    scan(session, log.db, &[], |k, _| {
+        let old = parse_sequence(k) <= last;
+        if old {
+            dropped.push(k.to_vec());
+        }
+        old
+    })?;
+    for k in dropped {
+        session.del(log.db, &k, None)?;
+    }
+    Ok(())
+}
+
+
+// Apply a change read from another environment's change log to the stores
+// of this environment, adding it to this environment's change log. Tables
+// are those of the default namespace.
+fn apply_change(session: &mut RwTransaction, tables: &Tables, change: &Change)
+    -> KeyFileResult<()>
+{
+    let lmdb_error = |e| keyfile_error(e, &[]);
+    let namespace_tables = |session: &RwTransaction, ns: &Option<String>| {
+        match *ns {
+            Some(ref name) if valid_namespace(name) => {
+                let dbname = format!("ns.{}", name);
+                Tables::open_in(
+                    session,
+                    &dbname,
+                    tables.keep,
+                    tables.wipe,
+                    tables.log,
+                ).map_err(lmdb_error)
+            }
+            Some(_) => Err(KeyFileError::Corrupted),
+            None => Ok(tables.clone()),
+        }
+    };
+    match *change {
+        Change::Put(ref ns, ref k, ref keyfile, ref info) => {
+            let tables = namespace_tables(session, ns)?;
+            tables.put(session, k, keyfile, info)?;
+        }
+        Change::Delete(ref ns, ref k) => {
+            let tables = namespace_tables(session, ns)?;
+            let op = KeyFileOp::Delete(k.clone());
+            match tables.stage(session, &op) {
+                Ok(()) | Err(KeyFileError::Key(_)) => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Change::CreateNamespace(ref name) |
+        Change::DropNamespace(ref name) => {
+            let ns = Some(name.clone());
+            let nstables = namespace_tables(session, &ns)?;
+            let registry = unsafe {
+                session.create_db(Some(NAMESPACE_DB), DatabaseFlags::empty())
+            }.map_err(lmdb_error)?;
+            nstables.clear(session).map_err(lmdb_error)?;
+            let result = match *change {
+                Change::CreateNamespace(_) => {
+                    session.put(registry, &name, &[], WriteFlags::empty())
+                }
+                _ => session.del(registry, &name, None),
+            };
+            match result {
+                Ok(()) | Err(LmdbError::NotFound) => {}
+                Err(e) => return Err(lmdb_error(e)),
+            }
+        }
+    }
+    log_change(session, tables.log, change.clone()).map_err(lmdb_error)
+}
+
+
+// ===========================================================================
+// Namespaces
+// ===========================================================================
+
+
+// Named db holding the name of every namespace
+const NAMESPACE_DB: &str = "namespaces";
+
+
+/// Keyfile stores kept in the same LMDB environment as a `KeyFile`, each
+/// under its own name.
+///
+/// Every namespace uses 3 of the environment's named dbs, so `max_dbs`
+/// limits how many namespaces can be created. Dropping a namespace empties
+/// its dbs rather than deleting them, since sessions may still be using
+/// them.
+pub struct Namespaces {
+    init: Init,
+    env: Arc<Environment>,
+    resizing: Arc<RwLock<()>>,
+    registry: Database,
+
+    // Stores of every namespace that has been opened
+    stores: HashMap<String, Arc<RwLock<KeyFileStore>>>,
+}
+
+
+impl Namespaces {
+    // Namespace names can't contain a dot, so they never clash with the
+    // dbs of the main store
+    fn open(&self, name: &str) -> KeyFileResult<KeyFile>
+    {
+        let dbname = format!("ns.{}", name);
+        let tables = Tables::open(&self.env, &dbname, &self.init)
+            .map_err(|e| keyfile_error(e, &[]))?;
+        Ok(KeyFile {
+            dbinit: self.init.clone(),
+            env: self.env.clone(),
+            tables: tables,
+            resizing: self.resizing.clone(),
+            namespace: Some(name.to_string()),
+        })
+    }
+
+    fn exists(&self, name: &str) -> KeyFileResult<bool>
+    {
+        let registry = self.registry;
+        read_txn(&self.env, &self.resizing, &[], |session| {
+            match session.get(registry, &name) {
+                Ok(_) => Ok(true),
+                Err(LmdbError::NotFound) => Ok(false),
+                Err(e) => Err(keyfile_error(e, &[])),
+            }
+        })
+    }
+}
+
+
+impl KeyFileNamespaces for Namespaces {
+    fn namespace(&mut self, name: &str)
+        -> KeyFileResult<Arc<RwLock<KeyFileStore>>>
+    {
+        if let Some(store) = self.stores.get(name) {
+            return Ok(store.clone());
+        }
+        if !self.exists(name)? {
+            return Err(KeyFileError::Namespace(name.to_string()));
+        }
+        let store: Arc<RwLock<KeyFileStore>> =
+            Arc::new(RwLock::new(self.open(name)?));
+        self.stores.insert(name.to_string(), store.clone());
+        Ok(store)
+    }
+
+    fn create_namespace(&mut self, name: &str) -> KeyFileResult<()>
+    {
+        if !valid_namespace(name) {
+            return Err(KeyFileError::Other);
+        }
+        if self.exists(name)? {
+            return Err(KeyFileError::NamespaceExists(name.to_string()));
+        }
+
+        // The dbs are emptied in case a dropped namespace left them behind
+        let mut store = self.open(name)?;
+        let (tables, registry) = (store.tables.clone(), self.registry);
+        store.dbwrite(|session| {
+            let change = Change::CreateNamespace(name.to_string());
+            tables.clear(session)
+                .and_then(|_| {
+                    session.put(registry, &name, &[], WriteFlags::empty())
+                })
+                .and_then(|_| log_change(session, tables.log, change))
+                .map_err(|e| keyfile_error(e, &[]))
+        })?;
+        let store: Arc<RwLock<KeyFileStore>> = Arc::new(RwLock::new(store));
+        self.stores.insert(name.to_string(), store);
+        Ok(())
+    }
+
+    fn list_namespaces(&self) -> KeyFileResult<Vec<String>>
+    {
+        let registry = self.registry;
+        read_txn(&self.env, &self.resizing, &[], |session| {
+            let mut names = Vec::new();
+            scan(session, registry, &[], |k, _| {
+                names.push(String::from_utf8_lossy(k).into_owned());
+                true
+            }).map_err(|e| keyfile_error(e, &[]))?;
+            Ok(names)
+        })
+    }
+
+    fn drop_namespace(&mut self, name: &str) -> KeyFileResult<()>
+    {
+        if !self.exists(name)? {
+            return Err(KeyFileError::Namespace(name.to_string()));
+        }
+        let mut store = self.open(name)?;
+        let (tables, registry) = (store.tables.clone(), self.registry);
+        store.dbwrite(|session| {
+            let change = Change::DropNamespace(name.to_string());
+            tables.clear(session)
+                .and_then(|_| session.del(registry, &name, None))
+                .and_then(|_| log_change(session, tables.log, change))
+                .map_err(|e| keyfile_error(e, &[]))
+        })?;
+        self.stores.remove(name);
+        Ok(())
+    }
+}
+
+
+// ===========================================================================
+// Backup
+// ===========================================================================
+
+
+// Name of the file LMDB keeps all data in
+const DATA_FILE: &str = "data.mdb";
+
+
+/// Check that dir holds a readable copy of the named keyfile store,
+/// returning the number of keyfiles in it.
+///
+/// The copy is opened read-only and without a lock file, so dir is left
+/// untouched.
+pub fn verify(dir: &Path, name: &str) -> KeyFileResult<usize>
+{
+    // A missing db means the copy isn't a keyfile store
+    let invalid = |e| match e {
+        LmdbError::NotFound => KeyFileError::Corrupted,
+        e => keyfile_error(e, &[]),
+    };
+    let env = Environment::new()
+        .set_max_dbs(3)
+        .set_flags(READ_ONLY | NO_LOCK)
+        .open(dir)
+        .map_err(&invalid)?;
+    let db = env.open_db(Some(name)).map_err(&invalid)?;
+    let infodb = env.open_db(Some(&format!("{}.info", name)))
+        .map_err(&invalid)?;
+    let meta = match env.open_db(Some(META_DB)) {
+        Ok(meta) => Some(meta),
+        Err(LmdbError::NotFound) => None,
+        Err(e) => return Err(keyfile_error(e, &[])),
+    };
+    let session = env.begin_ro_txn().map_err(&invalid)?;
+
+    // Copies made by a newer version can't be used
+    if let Some(meta) = meta {
+        let version = read_version(&session, meta)?;
+        if version > SCHEMA_VERSION {
+            return Err(KeyFileError::Schema(version));
+        }
+    }
+
+    // Every keyfile must be readable and match its metadata and checksum,
+    // if it has any
+    let mut count = 0;
+    let mut result = Ok(());
+    scan(&session, db, &[], |k, keyfile| {
+        count += 1;
+        result = match session.get(infodb, &k) {
+            Ok(buf) => parse_record(buf).and_then(|(info, checksum)| {
+                check_entry(keyfile, &info, checksum)
+            }),
+            Err(LmdbError::NotFound) => Ok(()),
+            Err(e) => Err(keyfile_error(e, k)),
+        };
+        result.is_ok()
+    }).map_err(&invalid)?;
+    result.map(|_| count)
+}
+
+
+/// Replace the named keyfile store in dbdir with the backup in backupdir,
+/// returning the number of keyfiles restored.
+///
+/// The backup is copied next to the store and verified before it is
+/// renamed over the store's data file, so the store is left as it was if
+/// anything fails. The server must not be running while restoring.
+pub fn restore(backupdir: &Path, dbdir: &Path, name: &str)
+    -> KeyFileResult<usize>
+{
+    replace_data(dbdir, name, "restore", |staging| {
+        fs::copy(backupdir.join(DATA_FILE), staging.join(DATA_FILE))
+            .map(|_| ())
+            .map_err(|_| KeyFileError::Io)
+    })
+}
+
+
+/// Rewrite the environment in dbdir so that it only holds what is still
+/// in use, returning the number of keyfiles in the named store.
+///
+/// LMDB leaves deleted and replaced keyfiles in free pages, and in the
+/// unused space of pages, until the space is reused. Every db is copied
+/// entry by entry into a new environment, which only ever holds what is
+/// copied into it. The change log holds every keyfile ever written, so
+/// changes to a key that has since been changed again, or whose namespace
+/// has been dropped, are copied as the key's deletion. Followers replaying
+/// the log still end up with the same keyfiles.
+///
+/// The copy is verified before it is renamed over the data file. The server
+/// must not be running while compacting, and never compacts on its own, so
+/// this has to be run by hand or on a schedule while the server is
+/// stopped.
+pub fn compact(dbdir: &Path, name: &str) -> KeyFileResult<usize>
+{
+    replace_data(dbdir, name, "compact", |staging| copy_live(dbdir, staging))
+}
+
+
+// Copy every db in the environment in dbdir into a new environment in
+// staging
+fn copy_live(dbdir: &Path, staging: &Path) -> KeyFileResult<()>
+{
+    let lmdb_error = |e| keyfile_error(e, &[]);
+    let open = |maxdbs: usize| {
+        Environment::new()
+            .set_max_dbs(maxdbs as u32)
+            .set_flags(READ_ONLY)
+            .open(dbdir)
+            .map_err(lmdb_error)
+    };
+    let names = db_names(&open(0)?).map_err(lmdb_error)?;
+    let source = open(names.len())?;
+    let replaced = replaced_changes(&source)?;
+
+    // The copy gets the same map size and permissions
+    let mode = fs::metadata(dbdir.join(DATA_FILE))
+        .map_err(|_| KeyFileError::Io)?
+        .permissions()
+        .mode() & 0o777;
+    let copy = Environment::new()
+        .set_max_dbs(names.len() as u32)
+        .set_map_size(env_map_size(&source).map_err(lmdb_error)?)
+        .open_with_permissions(staging, mode as mode_t)
+        .map_err(lmdb_error)?;
+
+    let reader = source.begin_ro_txn().map_err(lmdb_error)?;
+    let mut writer = copy.begin_rw_txn().map_err(lmdb_error)?;
+    for name in &names {
+        let from =
+            unsafe { reader.open_db(Some(name)) }.map_err(lmdb_error)?;
+        let flags = reader.db_flags(from).map_err(lmdb_error)?;
+        let to = unsafe { writer.create_db(Some(name), flags) }
+            .map_err(lmdb_error)?;
+        let mut result = Ok(());
+        let scanned = scan(&reader, from, &[], |k, value| {
+            let value = match replaced.get(k) {
+                Some(deletion) if name == CHANGES_DB => &deletion[..],
+                _ => value,
+            };
+            result = writer.put(to, &k, &value, APPEND);
+            result.is_ok()
+        });
+        scanned.and(result).map_err(lmdb_error)?;
+    }
+    writer.commit().map_err(lmdb_error)
+}
+
+
+// Names of every db in an environment, which are the keys of its unnamed
+// db
+fn db_names(env: &Environment) -> LmdbResult<Vec<String>>
+{
+    let main = env.open_db(None)?;
+    let session = env.begin_ro_txn()?;
+    let mut names = Vec::new();
+    scan(&session, main, &[], |k, _| {
+        names.push(String::from_utf8_lossy(k).into_owned());
+        true
+    })?;
+    Ok(names)
+}
+
+
+// Map the sequence key of every change in the change log that has been
+// replaced by a later change to its key, or by its namespace being emptied,
+// to the key's deletion
+fn replaced_changes(env: &Environment)
+    -> KeyFileResult<HashMap<Vec<u8>, Vec<u8>>>
+{
+    let mut replaced = HashMap::new();
+    let log = match change_log(env, 0).map_err(|e| keyfile_error(e, &[]))? {
+        Some(log) => log.db,
+        None => return Ok(replaced),
+    };
+    let session = env.begin_ro_txn().map_err(|e| keyfile_error(e, &[]))?;
+
+    // Sequence of the last change made to every key, and of the last time
+    // every namespace was emptied
+    let mut last = HashMap::new();
+    let mut emptied = HashMap::new();
+    let mut puts = Vec::new();
+    let mut error = None;
+    scan(&session, log, &[], |seqkey, buf| {
+        let seq = parse_sequence(seqkey);
+        match decode_change(buf) {
+            Ok(Change::Put(ns, k, _, _)) => {
+                last.insert((ns.clone(), k.clone()), seq);
+                puts.push((seq, ns, k));
+            }
+            Ok(Change::Delete(ns, k)) => {
+                last.insert((ns, k), seq);
+            }
+            Ok(Change::CreateNamespace(name)) |
+            Ok(Change::DropNamespace(name)) => {
+                emptied.insert(name, seq);
+            }
+            Err(e) => error = Some(e),
+        }
+        error.is_none()
+    }).map_err(|e| keyfile_error(e, &[]))?;
+    if let Some(e) = error {
+        return Err(e);
+    }
+
+    for (seq, ns, k) in puts {
+        let dropped = match ns {
+            Some(ref name) => emptied.get(name).cloned().unwrap_or(0) > seq,
+            None => false,
+        };
+        let id = (ns, k);
+        if dropped || last[&id] > seq {
+            let deletion = encode_change(Change::Delete(id.0, id.1));
+            replaced.insert(sequence_key(seq), deletion);
+        }
+    }
+    Ok(replaced)
+}
+
+
+// Have fill write a new data file into a staging directory next to the
+// named store, then rename it over the store's data file once it has been
+// verified. The store is left as it was if anything fails.
+fn replace_data<F>(dbdir: &Path, name: &str, staging: &str, fill: F)
+    -> KeyFileResult<usize>
+where
+    F: FnOnce(&Path) -> KeyFileResult<()>,
+{
+    let staging = dbdir.join(staging);
+    let io_error = |_| KeyFileError::Io;
+    fs::create_dir_all(&staging).map_err(io_error)?;
+    let result = fill(&staging)
+        .and_then(|_| verify(&staging, name))
+        .and_then(|count| {
+            fs::rename(staging.join(DATA_FILE), dbdir.join(DATA_FILE))
+                .map(|_| count)
+                .map_err(io_error)
+        });
+    let _ = fs::remove_dir_all(&staging);
+    result
 }
 
 
@@ -0,0 +1,357 @@
+// src/storage/memory.rs
+// Copyright (C) 2017 authors and contributors (see AUTHORS file)
+//
+// This file is released under the MIT License.
+
+// ===========================================================================
+// Imports
+// ===========================================================================
+
+
+// Stdlib imports
+
+use std::collections::BTreeMap;
+use std::collections::Bound::{Excluded, Included, Unbounded};
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+
+// Third-party imports
+
+// Local imports
+
+use storage::{valid_namespace, KeyFileBuilder, KeyFileError, KeyFileInfo,
+              KeyFileNamespaces, KeyFileOp, KeyFileResult, KeyFileStore,
+              KeyFileTransaction};
+
+
+// ===========================================================================
+// MemoryKeyFile
+// ===========================================================================
+
+
+// Every key maps to its keyfile and the keyfile's metadata
+type Entries = BTreeMap<Vec<u8>, (Vec<u8>, KeyFileInfo)>;
+
+
+/// A keyfile store that only lives in memory.
+///
+/// Nothing is ever written to disk, so every keyfile is lost once the store
+/// is dropped. Useful for tests and throwaway servers.
+#[derive(Debug, Default, Clone)]
+pub struct MemoryKeyFile {
+    pub name: String,
+    db: Entries,
+
+    // Previous revisions of every key, oldest first
+    history: BTreeMap<Vec<u8>, Vec<(Vec<u8>, KeyFileInfo)>>,
+    keep: usize,
+}
+
+
+impl MemoryKeyFile {
+    /// Number of keys in the store.
+    pub fn len(&self) -> usize
+    {
+        self.db.len()
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.db.is_empty()
+    }
+
+    /// Keep the given number of previous revisions of each key.
+    pub fn history(mut self, revisions: usize) -> Self
+    {
+        self.keep = revisions;
+        self
+    }
+
+    fn apply(&mut self, op: &KeyFileOp) -> KeyFileResult<()>
+    {
+        match *op {
+            KeyFileOp::Set(ref k, ref v) => {
+                let info = match self.db.remove(k) {
+                    Some(old) => {
+                        let info = old.1.update(v.len());
+                        self.archive(k, old);
+                        info
+                    }
+
+                    // Revisions carry on from a deleted keyfile's history
+                    None => {
+                        let mut info = KeyFileInfo::new(v.len());
+                        let last = self.history.get(k).and_then(|h| h.last());
+                        if let Some(last) = last {
+                            info.revision = last.1.revision + 1;
+                        }
+                        info
+                    }
+                };
+                self.db.insert(k.clone(), (v.clone(), info));
+                Ok(())
+            }
+            KeyFileOp::Delete(ref k) => {
+                match self.db.remove(k) {
+                    Some(old) => {
+                        self.archive(k, old);
+                        Ok(())
+                    }
+                    None => Err(KeyFileError::Key(k.clone())),
+                }
+            }
+            KeyFileOp::Check(ref k, ref check) => {
+                let entry = self.entry(k)?;
+                if check.matches(&entry.1, &entry.0) {
+                    Ok(())
+                } else {
+                    Err(KeyFileError::Mismatch(k.clone()))
+                }
+            }
+            KeyFileOp::Load(ref k, ref v, ref info) => {
+                self.db.insert(k.clone(), (v.clone(), info.clone()));
+                Ok(())
+            }
+            KeyFileOp::Expire(ref k, expires) => {
+                match self.db.get_mut(k) {
+                    Some(ref mut entry) if !entry.1.is_expired() => {
+                        entry.1.expires = expires;
+                        Ok(())
+                    }
+                    _ => Err(KeyFileError::Key(k.clone())),
+                }
+            }
+        }
+    }
+
+    // Return the keyfile and metadata of a key that hasn't expired
+    fn entry(&self, k: &[u8]) -> KeyFileResult<&(Vec<u8>, KeyFileInfo)>
+    {
+        match self.db.get(k) {
+            Some(entry) if !entry.1.is_expired() => Ok(entry),
+            _ => Err(KeyFileError::Key(k.to_vec())),
+        }
+    }
+
+    // Add a replaced or deleted keyfile to the history, dropping the oldest
+    // revisions so that no more than keep revisions remain
+    fn archive(&mut self, k: &[u8], entry: (Vec<u8>, KeyFileInfo))
+    {
+        if self.keep == 0 {
+            return;
+        }
+        let history = self.history.entry(k.to_vec()).or_default();
+        history.push(entry);
+        let extra = history.len().saturating_sub(self.keep);
+        history.drain(..extra);
+    }
+}
+
+
+impl KeyFileBuilder for MemoryKeyFile {
+    // There is no environment, so envpath is ignored
+    fn new(name: &str, _envpath: Option<&Path>) -> MemoryKeyFile
+    {
+        MemoryKeyFile {
+            name: name.to_string(),
+            db: BTreeMap::new(),
+            history: BTreeMap::new(),
+            keep: 0,
+        }
+    }
+}
+
+
+impl KeyFileStore for MemoryKeyFile {
+    fn exists(&self, k: &Vec<u8>) -> bool
+    {
+        self.entry(k).is_ok()
+    }
+
+    fn get(&self, k: &Vec<u8>) -> KeyFileResult<Vec<u8>>
+    {
+        self.entry(k).map(|entry| entry.0.clone())
+    }
+
+    fn set(&mut self, k: &Vec<u8>, file: &Vec<u8>) -> KeyFileResult<()>
+    {
+        self.apply(&KeyFileOp::Set(k.clone(), file.clone()))
+    }
+
+    fn delete(&mut self, k: &Vec<u8>) -> KeyFileResult<()>
+    {
+        self.apply(&KeyFileOp::Delete(k.clone()))
+    }
+
+    fn info(&self, k: &Vec<u8>) -> KeyFileResult<KeyFileInfo>
+    {
+        self.entry(k).map(|entry| entry.1.clone())
+    }
+
+    fn revisions(&self, k: &Vec<u8>) -> KeyFileResult<Vec<KeyFileInfo>>
+    {
+        let history = match self.history.get(k) {
+            Some(h) => h.iter().map(|entry| entry.1.clone()).collect(),
+            None => Vec::new(),
+        };
+        Ok(history)
+    }
+
+    fn get_revision(&self, k: &Vec<u8>, rev: u64) -> KeyFileResult<Vec<u8>>
+    {
+        self.history
+            .get(k)
+            .and_then(|h| h.iter().find(|entry| entry.1.revision == rev))
+            .map(|entry| entry.0.clone())
+            .ok_or_else(|| KeyFileError::Revision(k.clone(), rev))
+    }
+
+    fn iter_prefix(&self, prefix: &[u8], after: Option<&[u8]>, limit: usize)
+        -> KeyFileResult<Vec<Vec<u8>>>
+    {
+        let start = match after {
+            Some(a) if a >= prefix => Excluded(a),
+            _ => Included(prefix),
+        };
+        let keys = self.db
+            .range::<[u8], _>((start, Unbounded))
+            .take_while(|&(k, _)| k.starts_with(prefix))
+            .filter(|&(_, entry)| !entry.1.is_expired())
+            .map(|(k, _)| k)
+            .take(limit)
+            .cloned()
+            .collect();
+        Ok(keys)
+    }
+
+    fn expire(&mut self, k: &Vec<u8>, expires: Option<u64>)
+        -> KeyFileResult<()>
+    {
+        self.apply(&KeyFileOp::Expire(k.clone(), expires))
+    }
+
+    fn purge_expired(&mut self) -> KeyFileResult<Vec<Vec<u8>>>
+    {
+        let expired: Vec<Vec<u8>> = self.db
+            .iter()
+            .filter(|&(_, entry)| entry.1.is_expired())
+            .map(|(k, _)| k.clone())
+            .collect();
+        for k in &expired {
+            self.apply(&KeyFileOp::Delete(k.clone()))?;
+        }
+        Ok(expired)
+    }
+
+    fn usage(&self) -> KeyFileResult<(usize, u64)>
+    {
+        let usage = self.db.values().fold((0, 0), |(entries, bytes), entry| {
+            (entries + 1, bytes + entry.1.length)
+        });
+        Ok(usage)
+    }
+
+    // Operations are applied in place. The entry and history of each key
+    // are saved before an operation first touches the key, and are put back
+    // if any operation fails.
+    fn commit(&mut self, txn: KeyFileTransaction) -> KeyFileResult<()>
+    {
+        let mut saved = BTreeMap::new();
+        for op in txn.ops() {
+            let k = op.key();
+            if !saved.contains_key(k) {
+                let entry = self.db.get(k).cloned();
+                let history = self.history.get(k).cloned();
+                saved.insert(k.to_vec(), (entry, history));
+            }
+            if let Err(e) = self.apply(op) {
+                for (k, (entry, history)) in saved {
+                    match entry {
+                        Some(entry) => self.db.insert(k.clone(), entry),
+                        None => self.db.remove(&k),
+                    };
+                    match history {
+                        Some(history) => self.history.insert(k, history),
+                        None => self.history.remove(&k),
+                    };
+                }
+                return Err(e);
+            }
+        }
+        Ok(())
+    }
+}
+
+
+// ===========================================================================
+// MemoryNamespaces
+// ===========================================================================
+
+
+/// Separately named `MemoryKeyFile` stores.
+#[derive(Default)]
+pub struct MemoryNamespaces {
+    stores: BTreeMap<String, Arc<RwLock<KeyFileStore>>>,
+    keep: usize,
+}
+
+
+impl MemoryNamespaces {
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    /// Keep the given number of previous revisions of each key in every
+    /// namespace.
+    pub fn history(mut self, revisions: usize) -> Self
+    {
+        self.keep = revisions;
+        self
+    }
+}
+
+
+impl KeyFileNamespaces for MemoryNamespaces {
+    fn namespace(&mut self, name: &str)
+        -> KeyFileResult<Arc<RwLock<KeyFileStore>>>
+    {
+        match self.stores.get(name) {
+            Some(store) => Ok(store.clone()),
+            None => Err(KeyFileError::Namespace(name.to_string())),
+        }
+    }
+
+    fn create_namespace(&mut self, name: &str) -> KeyFileResult<()>
+    {
+        if !valid_namespace(name) {
+            return Err(KeyFileError::Other);
+        }
+        if self.stores.contains_key(name) {
+            return Err(KeyFileError::NamespaceExists(name.to_string()));
+        }
+        let store = MemoryKeyFile::new(name, None).history(self.keep);
+        self.stores
+            .insert(name.to_string(), Arc::new(RwLock::new(store)));
+        Ok(())
+    }
+
+    fn list_namespaces(&self) -> KeyFileResult<Vec<String>>
+    {
+        Ok(self.stores.keys().cloned().collect())
+    }
+
+    // Sessions still using the namespace keep its keyfiles until they end
+    fn drop_namespace(&mut self, name: &str) -> KeyFileResult<()>
+    {
+        match self.stores.remove(name) {
+            Some(_) => Ok(()),
+            None => Err(KeyFileError::Namespace(name.to_string())),
+        }
+    }
+}
+
+
+// ===========================================================================
+//
+// ===========================================================================
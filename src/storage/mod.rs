@@ -10,34 +10,503 @@
 
 // Stdlib imports
 
+use std::fmt;
 use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{SystemTime, UNIX_EPOCH};
 
 // Third-party imports
 
+use rmpv::Value;
+
 // Local imports
 
+use error::ErrorMessage;
+use storage::cache::CacheStats;
+
 
 // ===========================================================================
 // Types
 // ===========================================================================
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone, PartialEq)]
 pub enum KeyFileError {
+    // Key does not exist
     Key(Vec<u8>),
+
+    // No space left in the database
+    MapFull,
+
+    // Too many concurrent readers
+    ReadersFull,
+
+    // Database files are damaged or not a valid database, or a keyfile
+    // doesn't match its checksum
+    Corrupted,
+
+    // Permission or other OS-level IO error
+    Io,
+
+    // Key or keyfile exceeds the size supported by the database, or a
+    // transaction changes more than the database can hold in one
+    ValueTooLarge,
+
+    // Keyfile does not have the expected revision or content hash
+    Mismatch(Vec<u8>),
+
+    // Revision of the key is not kept in the history
+    Revision(Vec<u8>, u64),
+
+    // Namespace does not exist
+    Namespace(String),
+
+    // Namespace already exists
+    NamespaceExists(String),
+
+    // Write would go over a quota limit, which has the given maximum
+    Quota(QuotaLimit, u64),
+
+    // Database was written with a newer schema version than is supported
+    Schema(u32),
+
+    // Keys were hashed with a different salt than the one given, or hashed
+    // when no salt was given, or not hashed when one was
+    KeySalt,
+
+    // Changes asked for have been dropped from the change log
+    Trimmed,
+
     Other,
 }
 
 
+impl ErrorMessage for KeyFileError {
+    fn message(&self) -> &'static str
+    {
+        match *self {
+            KeyFileError::Key(_) => "Key does not exist",
+            KeyFileError::MapFull => "Database is full",
+            KeyFileError::ReadersFull => "Too many database readers",
+            KeyFileError::Corrupted => "Database is corrupted",
+            KeyFileError::Io => "Database IO error",
+            KeyFileError::ValueTooLarge => "Key or keyfile is too large",
+            KeyFileError::Mismatch(_) => "Keyfile has changed",
+            KeyFileError::Revision(_, _) => "Revision does not exist",
+            KeyFileError::Namespace(_) => "Namespace does not exist",
+            KeyFileError::NamespaceExists(_) => "Namespace already exists",
+            KeyFileError::Quota(_, _) => "Quota exceeded",
+            KeyFileError::Schema(_) => "Database schema is too new",
+            KeyFileError::KeySalt => "Database keys use a different salt",
+            KeyFileError::Trimmed => "Changes are no longer kept",
+            KeyFileError::Other => "Database error",
+        }
+    }
+}
+
+
+impl fmt::Display for KeyFileError {
+    fn fmt(&self, fmt: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(fmt, "{}", self.message().to_string())
+    }
+}
+
+
 pub type KeyFileResult<V> = Result<V, KeyFileError>;
 
 
+// ===========================================================================
+// Metadata
+// ===========================================================================
+
+
+/// Metadata kept for every stored keyfile.
+///
+/// Timestamps are seconds since the unix epoch. The revision starts at 1
+/// when the keyfile is created and goes up by 1 every time it is changed.
+/// Once the expiry time has passed, the keyfile is treated as if it doesn't
+/// exist.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyFileInfo {
+    pub created: u64,
+    pub modified: u64,
+    pub length: u64,
+    pub revision: u64,
+    pub expires: Option<u64>,
+}
+
+
+impl KeyFileInfo {
+    // Size in bytes of the value returned by to_bytes()
+    const SIZE: usize = 40;
+
+    /// Metadata for a newly created keyfile.
+    pub fn new(length: usize) -> Self
+    {
+        let now = timestamp();
+        Self {
+            created: now,
+            modified: now,
+            length: length as u64,
+            revision: 1,
+            expires: None,
+        }
+    }
+
+    /// Metadata for the next revision of this keyfile.
+    ///
+    /// The new revision never expires.
+    pub fn update(&self, length: usize) -> Self
+    {
+        Self {
+            created: self.created,
+            modified: timestamp(),
+            length: length as u64,
+            revision: self.revision + 1,
+            expires: None,
+        }
+    }
+
+    pub fn is_expired(&self) -> bool
+    {
+        match self.expires {
+            Some(t) => t <= timestamp(),
+            None => false,
+        }
+    }
+
+    /// Encode as 5 big-endian u64 values. An expiry of 0 means the keyfile
+    /// never expires.
+    pub fn to_bytes(&self) -> Vec<u8>
+    {
+        let fields = [
+            self.created,
+            self.modified,
+            self.length,
+            self.revision,
+            self.expires.unwrap_or(0),
+        ];
+        let mut buf = Vec::with_capacity(KeyFileInfo::SIZE);
+        for field in &fields {
+            for i in (0..8).rev() {
+                buf.push((field >> (i * 8)) as u8);
+            }
+        }
+        buf
+    }
+
+    /// Decode a value created by to_bytes().
+    pub fn from_bytes(buf: &[u8]) -> KeyFileResult<Self>
+    {
+        if buf.len() != KeyFileInfo::SIZE {
+            return Err(KeyFileError::Corrupted);
+        }
+        let mut fields = [0u64; 5];
+        for (field, chunk) in fields.iter_mut().zip(buf.chunks(8)) {
+            *field = chunk.iter().fold(0, |n, &b| (n << 8) | u64::from(b));
+        }
+        Ok(Self {
+            created: fields[0],
+            modified: fields[1],
+            length: fields[2],
+            revision: fields[3],
+            expires: match fields[4] {
+                0 => None,
+                t => Some(t),
+            },
+        })
+    }
+}
+
+
+/// Current time in seconds since the unix epoch.
+pub fn timestamp() -> u64
+{
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(d) => d.as_secs(),
+        Err(_) => 0,
+    }
+}
+
+
+impl From<KeyFileInfo> for Value {
+    fn from(info: KeyFileInfo) -> Value
+    {
+        Value::Map(vec![
+            (Value::from("created"), Value::from(info.created)),
+            (Value::from("modified"), Value::from(info.modified)),
+            (Value::from("length"), Value::from(info.length)),
+            (Value::from("revision"), Value::from(info.revision)),
+            (
+                Value::from("expires"),
+                info.expires.map_or(Value::Nil, Value::from),
+            ),
+        ])
+    }
+}
+
+
+/// 64-bit FNV-1a hash of a keyfile.
+///
+/// Used by `KeyFileCheck::Hash` to compare keyfile contents. This is not a
+/// cryptographic hash.
+pub fn keyfile_hash(keyfile: &[u8]) -> u64
+{
+    hash_update(HASH_INIT, keyfile)
+}
+
+
+// FNV-1a hash of no bytes
+const HASH_INIT: u64 = 0xcbf2_9ce4_8422_2325;
+
+
+// Continue an FNV-1a hash with more bytes
+fn hash_update(hash: u64, bytes: &[u8]) -> u64
+{
+    bytes.iter().fold(hash, |hash, &b| {
+        (hash ^ u64::from(b)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+
+// ===========================================================================
+// Transactions
+// ===========================================================================
+
+
+/// Condition a keyfile must meet for a transaction to be committed.
+#[derive(Debug, PartialEq, Clone)]
+pub enum KeyFileCheck {
+    // The keyfile's current revision
+    Revision(u64),
+
+    // The keyfile_hash() of the keyfile's current contents
+    Hash(u64),
+}
+
+
+impl KeyFileCheck {
+    pub fn matches(&self, info: &KeyFileInfo, keyfile: &[u8]) -> bool
+    {
+        match *self {
+            KeyFileCheck::Revision(rev) => info.revision == rev,
+            KeyFileCheck::Hash(hash) => keyfile_hash(keyfile) == hash,
+        }
+    }
+}
+
+
+/// A single operation staged in a `KeyFileTransaction`.
+#[derive(Debug, PartialEq, Clone)]
+pub enum KeyFileOp {
+    Set(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+
+    // Fail the transaction with KeyFileError::Mismatch unless the check
+    // passes. Nothing is written.
+    Check(Vec<u8>, KeyFileCheck),
+
+    // Set the time, in seconds since the unix epoch, when an existing
+    // keyfile expires. None means the keyfile never expires.
+    Expire(Vec<u8>, Option<u64>),
+
+    // Write the keyfile along with the given metadata as is, replacing any
+    // existing keyfile without adding it to the history.
+    Load(Vec<u8>, Vec<u8>, KeyFileInfo),
+}
+
+
+impl KeyFileOp {
+    /// Return the key this operation acts on.
+    pub fn key(&self) -> &[u8]
+    {
+        match *self {
+            KeyFileOp::Set(ref k, _) |
+            KeyFileOp::Delete(ref k) |
+            KeyFileOp::Check(ref k, _) |
+            KeyFileOp::Expire(ref k, _) |
+            KeyFileOp::Load(ref k, _, _) => k,
+        }
+    }
+}
+
+
+/// A group of operations that are applied to a store all at once.
+///
+/// Operations are only staged until the transaction is passed to
+/// `KeyFileStore::commit()`. Dropping the transaction, or calling `abort()`,
+/// discards every staged operation without touching the store.
+#[derive(Debug, Default)]
+pub struct KeyFileTransaction {
+    ops: Vec<KeyFileOp>,
+}
+
+
+impl KeyFileTransaction {
+    pub fn new() -> Self
+    {
+        Self { ops: Vec::new() }
+    }
+
+    pub fn set(&mut self, k: &[u8], file: &[u8]) -> &mut Self
+    {
+        self.ops.push(KeyFileOp::Set(k.to_vec(), file.to_vec()));
+        self
+    }
+
+    pub fn delete(&mut self, k: &[u8]) -> &mut Self
+    {
+        self.ops.push(KeyFileOp::Delete(k.to_vec()));
+        self
+    }
+
+    pub fn check(&mut self, k: &[u8], check: KeyFileCheck) -> &mut Self
+    {
+        self.ops.push(KeyFileOp::Check(k.to_vec(), check));
+        self
+    }
+
+    pub fn expire(&mut self, k: &[u8], expires: Option<u64>) -> &mut Self
+    {
+        self.ops.push(KeyFileOp::Expire(k.to_vec(), expires));
+        self
+    }
+
+    pub fn load(&mut self, k: &[u8], file: &[u8], info: KeyFileInfo)
+        -> &mut Self
+    {
+        self.ops.push(KeyFileOp::Load(k.to_vec(), file.to_vec(), info));
+        self
+    }
+
+    pub fn abort(self)
+    {
+        // Staged operations are discarded when self is dropped
+    }
+
+    pub fn ops(&self) -> &[KeyFileOp]
+    {
+        &self.ops[..]
+    }
+
+    pub fn is_empty(&self) -> bool
+    {
+        self.ops.is_empty()
+    }
+}
+
+
+// ===========================================================================
+// Change log
+// ===========================================================================
+
+
+/// A committed change to a keyfile store, as kept in its change log.
+///
+/// Keyfile changes name the namespace they were made in, or `None` for the
+/// default namespace.
+#[derive(Debug, PartialEq, Clone)]
+pub enum Change {
+    // The keyfile and metadata of a key after it was created or changed
+    Put(Option<String>, Vec<u8>, Vec<u8>, KeyFileInfo),
+
+    // The key was deleted or purged
+    Delete(Option<String>, Vec<u8>),
+
+    CreateNamespace(String),
+    DropNamespace(String),
+}
+
+
+/// Changes along with their sequence numbers, oldest first.
+pub type Changes = Vec<(u64, Change)>;
+
+
+impl Change {
+    /// Decode a value created by `Value::from(change)`.
+    pub fn from_value(value: &Value) -> KeyFileResult<Self>
+    {
+        let fields = value.as_array().ok_or(KeyFileError::Corrupted)?;
+        let bin = |i: usize| {
+            fields
+                .get(i)
+                .and_then(|v| v.as_slice())
+                .map(|v| v.to_vec())
+                .ok_or(KeyFileError::Corrupted)
+        };
+        let string = |i: usize| {
+            fields
+                .get(i)
+                .and_then(|v| v.as_str())
+                .map(|v| v.to_string())
+                .ok_or(KeyFileError::Corrupted)
+        };
+        let namespace = || match fields.get(1) {
+            Some(&Value::Nil) => Ok(None),
+            _ => string(1).map(Some),
+        };
+        let change = match fields.first().and_then(|v| v.as_str()) {
+            Some("put") if fields.len() == 5 => {
+                let info = KeyFileInfo::from_bytes(&bin(4)?)?;
+                Change::Put(namespace()?, bin(2)?, bin(3)?, info)
+            }
+            Some("delete") if fields.len() == 3 => {
+                Change::Delete(namespace()?, bin(2)?)
+            }
+            Some("create_namespace") if fields.len() == 2 => {
+                Change::CreateNamespace(string(1)?)
+            }
+            Some("drop_namespace") if fields.len() == 2 => {
+                Change::DropNamespace(string(1)?)
+            }
+            _ => return Err(KeyFileError::Corrupted),
+        };
+        Ok(change)
+    }
+}
+
+
+// Encoded as an array of the kind of change followed by its fields, with
+// metadata encoded by KeyFileInfo::to_bytes()
+impl From<Change> for Value {
+    fn from(change: Change) -> Value
+    {
+        let namespace =
+            |ns: Option<String>| ns.map_or(Value::Nil, Value::from);
+        let fields = match change {
+            Change::Put(ns, k, keyfile, info) => vec![
+                Value::from("put"),
+                namespace(ns),
+                Value::from(k),
+                Value::from(keyfile),
+                Value::from(info.to_bytes()),
+            ],
+            Change::Delete(ns, k) => {
+                vec![Value::from("delete"), namespace(ns), Value::from(k)]
+            }
+            Change::CreateNamespace(name) => {
+                vec![Value::from("create_namespace"), Value::from(name)]
+            }
+            Change::DropNamespace(name) => {
+                vec![Value::from("drop_namespace"), Value::from(name)]
+            }
+        };
+        Value::Array(fields)
+    }
+}
+
+
 // ===========================================================================
 // Modules
 // ===========================================================================
 
 
+pub mod archive;
+pub mod cache;
+pub mod keyhash;
 pub mod lmdb;
+pub mod memory;
 
 
 // ===========================================================================
@@ -50,11 +519,335 @@ pub trait KeyFileBuilder {
 }
 
 
-pub trait KeyFileStore {
+// Stores are shared with the worker threads that run storage requests
+pub trait KeyFileStore: Send + Sync {
     fn exists(&self, k: &Vec<u8>) -> bool;
     fn get(&self, k: &Vec<u8>) -> KeyFileResult<Vec<u8>>;
     fn set(&mut self, k: &Vec<u8>, file: &Vec<u8>) -> KeyFileResult<()>;
     fn delete(&mut self, k: &Vec<u8>) -> KeyFileResult<()>;
+
+    // Return up to limit keys that start with prefix, in key order. If after
+    // is given, only keys that sort after it are returned.
+    //
+    // Stores that can't list their keys return KeyFileError::Other.
+    fn iter_prefix(
+        &self, _prefix: &[u8], _after: Option<&[u8]>, _limit: usize
+    ) -> KeyFileResult<Vec<Vec<u8>>>
+    {
+        Err(KeyFileError::Other)
+    }
+
+    // Stores that don't keep metadata return KeyFileError::Other.
+    fn info(&self, _k: &Vec<u8>) -> KeyFileResult<KeyFileInfo>
+    {
+        Err(KeyFileError::Other)
+    }
+
+    // Previous revisions of a keyfile, oldest first. Only the most recent
+    // revisions are kept, and they are kept after the keyfile is deleted.
+    //
+    // Stores that don't keep history return an empty list.
+    fn revisions(&self, _k: &Vec<u8>) -> KeyFileResult<Vec<KeyFileInfo>>
+    {
+        Ok(Vec::new())
+    }
+
+    fn get_revision(&self, k: &Vec<u8>, rev: u64) -> KeyFileResult<Vec<u8>>
+    {
+        Err(KeyFileError::Revision(k.clone(), rev))
+    }
+
+    // Set when an existing keyfile expires.
+    //
+    // Stores that don't support expiry return KeyFileError::Other.
+    fn expire(&mut self, _k: &Vec<u8>, _expires: Option<u64>)
+        -> KeyFileResult<()>
+    {
+        Err(KeyFileError::Other)
+    }
+
+    // Delete every expired keyfile, returning the keys deleted. Stores that
+    // hash their keys return the hashes.
+    fn purge_expired(&mut self) -> KeyFileResult<Vec<Vec<u8>>>
+    {
+        Ok(Vec::new())
+    }
+
+    // Number of keyfiles in the store and their total length in bytes.
+    // Expired keyfiles are counted until they are purged, so that stores
+    // can keep a running count rather than scanning every keyfile.
+    //
+    // Stores that don't keep metadata return KeyFileError::Other.
+    fn usage(&self) -> KeyFileResult<(usize, u64)>
+    {
+        Err(KeyFileError::Other)
+    }
+
+    // Write a consistent copy of the store into dir, creating dir if it
+    // doesn't exist. Compacting leaves free space out of the copy.
+    //
+    // Stores that can't be backed up return KeyFileError::Other.
+    fn backup(&self, _dir: &Path, _compact: bool) -> KeyFileResult<()>
+    {
+        Err(KeyFileError::Other)
+    }
+
+    // Check every keyfile for damage, returning each damaged key along with
+    // the error found.
+    //
+    // Stores that can't be checked return KeyFileError::Other.
+    fn scrub(&self) -> KeyFileResult<Vec<(Vec<u8>, KeyFileError)>>
+    {
+        Err(KeyFileError::Other)
+    }
+
+    // Return the sequence number of the latest change in the change log,
+    // along with up to limit changes made after the given sequence number.
+    // The change log covers every namespace kept by the store's backend.
+    //
+    // Stores that have dropped some of the changes asked for from their log
+    // return KeyFileError::Trimmed.
+    //
+    // Stores that don't keep a change log return KeyFileError::Other.
+    fn changes(&self, _after: u64, _limit: usize)
+        -> KeyFileResult<(u64, Changes)>
+    {
+        Err(KeyFileError::Other)
+    }
+
+    // Apply changes read from another store's change log in a single
+    // transaction, and remember the sequence number of the last one.
+    //
+    // Stores that can't replicate another store return KeyFileError::Other.
+    fn replicate(&mut self, _changes: &[(u64, Change)]) -> KeyFileResult<()>
+    {
+        Err(KeyFileError::Other)
+    }
+
+    // Sequence number of the last change applied by replicate(), or 0 if
+    // none has been.
+    fn replicated(&self) -> KeyFileResult<u64>
+    {
+        Err(KeyFileError::Other)
+    }
+
+    // Whether keys are hashed before they are stored, in which case the
+    // changes in the change log name hashed keys rather than the keys given.
+    fn hashes_keys(&self) -> bool
+    {
+        false
+    }
+
+    // Number of reads answered with and without the backend, for stores
+    // that cache their reads. Other stores return None.
+    fn cache_stats(&self) -> Option<CacheStats>
+    {
+        None
+    }
+
+    fn begin(&self) -> KeyFileTransaction
+    {
+        KeyFileTransaction::new()
+    }
+
+    // Stores that cannot group writes fall back to applying each staged
+    // operation in order, stopping at the first error. Stores that can
+    // should override this so that either all or none of the operations are
+    // applied.
+    fn commit(&mut self, txn: KeyFileTransaction) -> KeyFileResult<()>
+    {
+        for op in txn.ops() {
+            match *op {
+                KeyFileOp::Set(ref k, ref file) => self.set(k, file)?,
+                KeyFileOp::Delete(ref k) => self.delete(k)?,
+                KeyFileOp::Check(ref k, ref check) => {
+                    let info = self.info(k)?;
+                    if !check.matches(&info, &self.get(k)?) {
+                        return Err(KeyFileError::Mismatch(k.clone()));
+                    }
+                }
+                KeyFileOp::Expire(ref k, expires) => self.expire(k, expires)?,
+
+                // Only the expiry time can be kept
+                KeyFileOp::Load(ref k, ref file, ref info) => {
+                    self.set(k, file)?;
+                    if info.expires.is_some() {
+                        self.expire(k, info.expires)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+
+// ===========================================================================
+// Quotas
+// ===========================================================================
+
+
+/// A limit set by a `Quota`.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QuotaLimit {
+    // Length of a key in bytes
+    KeyLength,
+
+    // Length of a keyfile in bytes
+    KeyFileSize,
+
+    // Number of keyfiles in a store
+    Entries,
+
+    // Total length in bytes of the keyfiles in a store
+    Bytes,
+}
+
+
+impl QuotaLimit {
+    /// Name used for the limit in protocol messages.
+    pub fn name(&self) -> &'static str
+    {
+        match *self {
+            QuotaLimit::KeyLength => "key_length",
+            QuotaLimit::KeyFileSize => "keyfile_size",
+            QuotaLimit::Entries => "entries",
+            QuotaLimit::Bytes => "bytes",
+        }
+    }
+}
+
+
+/// Limits on what can be written to a keyfile store.
+///
+/// Every limit is unset by default. Entry and byte limits apply to each
+/// store, so every namespace gets its own allowance.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub struct Quota {
+    key_length: Option<u64>,
+    keyfile_size: Option<u64>,
+    entries: Option<u64>,
+    bytes: Option<u64>,
+}
+
+
+impl Quota {
+    pub fn new() -> Self
+    {
+        Self::default()
+    }
+
+    pub fn max_key_length(mut self, len: u64) -> Self
+    {
+        self.key_length = Some(len);
+        self
+    }
+
+    pub fn max_keyfile_size(mut self, size: u64) -> Self
+    {
+        self.keyfile_size = Some(size);
+        self
+    }
+
+    pub fn max_entries(mut self, entries: u64) -> Self
+    {
+        self.entries = Some(entries);
+        self
+    }
+
+    pub fn max_bytes(mut self, bytes: u64) -> Self
+    {
+        self.bytes = Some(bytes);
+        self
+    }
+
+    /// Check that writing keyfile under key keeps store within every limit.
+    ///
+    /// If replaced names an existing key, its keyfile is taken to be removed
+    /// by the same write. A store that is already over its entry or byte
+    /// limit, say because the limit was lowered, still accepts writes that
+    /// don't make it any larger. Expired keyfiles count towards the entry
+    /// and byte limits until they are purged.
+    pub fn check(
+        &self, store: &KeyFileStore, key: &[u8], keyfile: &[u8],
+        replaced: Option<&Vec<u8>>
+    ) -> KeyFileResult<()>
+    {
+        let (keylen, length) = (key.len() as u64, keyfile.len() as u64);
+        check_limit(QuotaLimit::KeyLength, self.key_length, keylen)?;
+        check_limit(QuotaLimit::KeyFileSize, self.keyfile_size, length)?;
+        if self.entries.is_none() && self.bytes.is_none() {
+            return Ok(());
+        }
+
+        // Work out how much of the store is used once the write is done
+        let (count, bytes) = store.usage()?;
+        let entries = count as u64;
+        let removed = match replaced.map(|k| store.info(k)) {
+            Some(Ok(info)) => Some(info.length),
+            None | Some(Err(KeyFileError::Key(_))) => None,
+            Some(Err(e)) => return Err(e),
+        };
+        let (new_entries, new_bytes) = match removed {
+            Some(old) => (entries, bytes.saturating_sub(old) + length),
+            None => (entries + 1, bytes + length),
+        };
+
+        if new_entries > entries {
+            check_limit(QuotaLimit::Entries, self.entries, new_entries)?;
+        }
+        if new_bytes > bytes {
+            check_limit(QuotaLimit::Bytes, self.bytes, new_bytes)?;
+        }
+        Ok(())
+    }
+}
+
+
+// Return a quota error if value is over the limit's maximum
+fn check_limit(limit: QuotaLimit, max: Option<u64>, value: u64)
+    -> KeyFileResult<()>
+{
+    match max {
+        Some(max) if value > max => Err(KeyFileError::Quota(limit, max)),
+        _ => Ok(()),
+    }
+}
+
+
+// ===========================================================================
+// Namespaces
+// ===========================================================================
+
+
+/// Return true if name can be used as a namespace.
+///
+/// Names are 1 to 64 ASCII letters, digits, underscores or dashes.
+pub fn valid_namespace(name: &str) -> bool
+{
+    let valid_char = |c: char| match c {
+        '_' | '-' => true,
+        c => c.is_ascii_alphanumeric(),
+    };
+    !name.is_empty() && name.len() <= 64 && name.chars().all(valid_char)
+}
+
+
+/// Separately named keyfile stores kept by a single backend.
+///
+/// Keys in one namespace never collide with keys in another.
+pub trait KeyFileNamespaces: Send + Sync {
+    // Return the store of an existing namespace
+    fn namespace(&mut self, name: &str)
+        -> KeyFileResult<Arc<RwLock<KeyFileStore>>>;
+
+    fn create_namespace(&mut self, name: &str) -> KeyFileResult<()>;
+
+    // Names of every namespace, in order
+    fn list_namespaces(&self) -> KeyFileResult<Vec<String>>;
+
+    // Delete a namespace along with every keyfile in it
+    fn drop_namespace(&mut self, name: &str) -> KeyFileResult<()>;
 }
 
 
@@ -1,6 +1,14 @@
 use std::env::{current_dir, set_current_dir};
+use std::fmt;
 use std::io;
+use std::mem;
+use std::ops::Deref;
 use std::path::Path;
+use std::ptr;
+use std::sync::{LockResult, PoisonError};
+use std::sync::atomic::{self, Ordering};
+
+use rmpv::Value;
 
 
 // Change directory, run closure, then change directory back to original directory
@@ -15,3 +23,102 @@ where
     set_current_dir(old.as_path())?;
     Ok(())
 }
+
+
+// Overwrite buf with zeros. The writes are volatile so that they aren't
+// optimised away when buf is about to be freed.
+pub fn zeroize(buf: &mut [u8])
+{
+    for b in buf.iter_mut() {
+        unsafe { ptr::write_volatile(b, 0) };
+    }
+    atomic::compiler_fence(Ordering::SeqCst);
+}
+
+
+// Overwrite every binary and extension held by value with zeros
+pub fn zeroize_value(value: &mut Value)
+{
+    match *value {
+        Value::Binary(ref mut buf) | Value::Ext(_, ref mut buf) => {
+            zeroize(buf)
+        }
+        Value::Array(ref mut items) => {
+            for item in items.iter_mut() {
+                zeroize_value(item);
+            }
+        }
+        Value::Map(ref mut entries) => {
+            for &mut (ref mut k, ref mut v) in entries.iter_mut() {
+                zeroize_value(k);
+                zeroize_value(v);
+            }
+        }
+        _ => {}
+    }
+}
+
+
+// Take a lock even if a thread panicked while holding it. Workers carry on
+// running jobs after one panics, and lmdb aborts the transaction of a job
+// that panics, so the store is left as it was rather than being unusable
+// by every later session.
+pub trait Unpoison<G> {
+    fn unpoison(self) -> G;
+}
+
+
+impl<G> Unpoison<G> for LockResult<G> {
+    fn unpoison(self) -> G
+    {
+        self.unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+
+// Bytes that are overwritten with zeros when dropped. Clones are
+// overwritten separately.
+#[derive(Clone)]
+pub struct Zeroizing(Vec<u8>);
+
+
+impl Zeroizing {
+    pub fn new(buf: Vec<u8>) -> Self
+    {
+        Zeroizing(buf)
+    }
+
+    // Take the bytes, which are then no longer overwritten when dropped.
+    // Whoever takes them becomes responsible for zeroizing them.
+    pub fn into_inner(mut self) -> Vec<u8>
+    {
+        mem::take(&mut self.0)
+    }
+}
+
+
+impl Deref for Zeroizing {
+    type Target = Vec<u8>;
+
+    fn deref(&self) -> &Vec<u8>
+    {
+        &self.0
+    }
+}
+
+
+// Only the length is shown, so that secrets don't end up in logs
+impl fmt::Debug for Zeroizing {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result
+    {
+        write!(f, "Zeroizing([{} bytes])", self.0.len())
+    }
+}
+
+
+impl Drop for Zeroizing {
+    fn drop(&mut self)
+    {
+        zeroize(&mut self.0);
+    }
+}
@@ -0,0 +1,176 @@
+// test_archive.rs
+// Copyright (C) 2017 authors and contributors (see AUTHORS file)
+//
+// This file is released under the MIT License.
+
+// ===========================================================================
+// Externs
+// ===========================================================================
+
+
+extern crate chrono;
+extern crate safesec;
+extern crate tempdir;
+
+
+// ===========================================================================
+// Imports
+// ===========================================================================
+
+
+// Stdlib imports
+
+use std::fs;
+
+// Third-party imports
+
+use chrono::prelude::*;
+use tempdir::TempDir;
+
+// Local imports
+
+use safesec::storage::*;
+use safesec::storage::archive::{export, import};
+use safesec::storage::lmdb::*;
+use safesec::storage::memory::*;
+
+
+// ===========================================================================
+// Helpers
+// ===========================================================================
+
+
+fn mktempdir() -> TempDir
+{
+    // Generate unique temp name
+    let dt = UTC::now();
+    let suffix = dt.format("%Y%m%d%H%M%S%.9f");
+    let name = format!("safesec_test_{}", suffix.to_string());
+    let tmpdir = TempDir::new(&name).unwrap();
+    let dbpath = tmpdir.path().join("sec.db");
+    fs::create_dir(&dbpath).unwrap();
+    tmpdir
+}
+
+
+// Create a store holding 300 keyfiles, one of which has been changed and
+// one of which expires in an hour
+fn mkstore() -> MemoryKeyFile
+{
+    let mut kf = MemoryKeyFile::new("temp", None);
+    for i in 0..300 {
+        let key = format!("{:03}", i).into_bytes();
+        kf.set(&key, &i.to_string().into_bytes()).unwrap();
+    }
+    let changed = b"000".to_vec();
+    kf.set(&changed, &b"changed".to_vec()).unwrap();
+    kf.expire(&b"001".to_vec(), Some(timestamp() + 3600)).unwrap();
+    kf
+}
+
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+
+#[test]
+fn export_import_between_stores()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Export a memory store
+    let source = mkstore();
+    let mut archive = Vec::new();
+    assert_eq!(export(&source, None, &mut archive).unwrap(), 300);
+
+    // Import the archive into an lmdb store
+    let mut dest = KeyFile::new("temp", Some(dbpath.as_path()));
+    assert_eq!(import(&mut dest, None, &archive[..]).unwrap(), 300);
+
+    // Test every keyfile and its metadata was copied
+    let keys = source.iter_prefix(&[], None, 1000).unwrap();
+    assert_eq!(dest.iter_prefix(&[], None, 1000).unwrap(), keys);
+    for k in &keys {
+        assert_eq!(dest.get(k), source.get(k));
+        assert_eq!(dest.info(k), source.info(k));
+    }
+    assert_eq!(dest.info(&b"000".to_vec()).unwrap().revision, 2);
+
+    // Test exporting the copy gives the same archive
+    let mut copy = Vec::new();
+    export(&dest, None, &mut copy).unwrap();
+    assert_eq!(copy, archive);
+}
+
+
+#[test]
+fn import_damaged_archive()
+{
+    // Export a store
+    let mut archive = Vec::new();
+    export(&mkstore(), None, &mut archive).unwrap();
+
+    // Damage a copy of the archive by changing one byte of a keyfile, and
+    // truncate another copy
+    let mut damaged = archive.clone();
+    let pos = damaged.windows(7).position(|w| w == b"changed").unwrap();
+    damaged[pos] = b'C';
+    let truncated = &archive[..archive.len() - 1];
+
+    // Test neither archive is imported
+    let mut kf = MemoryKeyFile::new("temp", None);
+    assert!(import(&mut kf, None, &damaged[..]).is_err());
+    assert!(import(&mut kf, None, truncated).is_err());
+    assert!(import(&mut kf, None, &b"not an archive"[..]).is_err());
+    assert!(kf.is_empty());
+}
+
+
+#[test]
+fn export_import_namespaces()
+{
+    // Create a store and a namespace each holding a keyfile
+    let key = b"42".to_vec();
+    let mut source = MemoryKeyFile::new("temp", None);
+    source.set(&key, &b"default".to_vec()).unwrap();
+    let mut namespaces = MemoryNamespaces::new();
+    namespaces.create_namespace("team-a").unwrap();
+    {
+        let ns = namespaces.namespace("team-a").unwrap();
+        ns.write().unwrap().set(&key, &b"team-a".to_vec()).unwrap();
+    }
+
+    // Export both
+    let mut archive = Vec::new();
+    let exported =
+        export(&source, Some(&mut namespaces), &mut archive).unwrap();
+    assert_eq!(exported, 2);
+
+    // Test the archive can't be imported without namespaces
+    let mut kf = MemoryKeyFile::new("temp", None);
+    assert!(import(&mut kf, None, &archive[..]).is_err());
+    assert!(kf.is_empty());
+
+    // Import the archive into a new store and namespaces
+    let mut dest_namespaces = MemoryNamespaces::new();
+    let imported =
+        import(&mut kf, Some(&mut dest_namespaces), &archive[..]).unwrap();
+    assert_eq!(imported, 2);
+
+    // Test each keyfile was loaded into its own namespace
+    assert_eq!(kf.get(&key).unwrap(), b"default".to_vec());
+    assert_eq!(
+        dest_namespaces.list_namespaces().unwrap(),
+        vec!["team-a".to_string()]
+    );
+    let ns = dest_namespaces.namespace("team-a").unwrap();
+    assert_eq!(ns.read().unwrap().get(&key).unwrap(), b"team-a".to_vec());
+}
+
+
+// ===========================================================================
+//
+// ===========================================================================
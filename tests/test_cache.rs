@@ -0,0 +1,266 @@
+// test_cache.rs
+// Copyright (C) 2017 authors and contributors (see AUTHORS file)
+//
+// This file is released under the MIT License.
+
+// ===========================================================================
+// Externs
+// ===========================================================================
+
+
+extern crate safesec;
+extern crate tempdir;
+
+
+// ===========================================================================
+// Imports
+// ===========================================================================
+
+
+// Stdlib imports
+
+use std::fs;
+use std::thread;
+use std::time::Duration;
+
+// Third-party imports
+
+use tempdir::TempDir;
+
+// Local imports
+
+use safesec::storage::*;
+use safesec::storage::cache::*;
+use safesec::storage::keyhash::KeyHasher;
+use safesec::storage::lmdb::{Init, KeyFile};
+use safesec::storage::memory::*;
+
+
+// ===========================================================================
+// Helpers
+// ===========================================================================
+
+
+fn stats(hits: u64, misses: u64) -> CacheStats
+{
+    CacheStats {
+        hits: hits,
+        misses: misses,
+    }
+}
+
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+
+#[test]
+fn repeated_reads_hit()
+{
+    // Create cached keyfile store
+    let mut store = MemoryKeyFile::new("temp", None);
+    let key = 42.to_string().into_bytes();
+    let value = 42.to_string().into_bytes();
+    store.set(&key, &value).unwrap();
+    let kf = CachedStore::new(store, 10);
+
+    // Read the keyfile several times
+    for _ in 0..3 {
+        assert_eq!(kf.get(&key).unwrap(), value);
+    }
+    assert!(kf.exists(&key));
+
+    // Test only the first read used the store
+    assert_eq!(kf.stats(), stats(3, 1));
+    assert_eq!(kf.len(), 1);
+}
+
+
+#[test]
+fn missing_key_cached()
+{
+    // Create cached keyfile store
+    let kf = CachedStore::new(MemoryKeyFile::new("temp", None), 10);
+    let key = 42.to_string().into_bytes();
+
+    // Test missing keys are remembered
+    assert!(!kf.exists(&key));
+    assert_eq!(kf.get(&key), Err(KeyFileError::Key(key.clone())));
+    assert_eq!(kf.stats(), stats(1, 1));
+}
+
+
+#[test]
+fn exists_then_get()
+{
+    // Create cached keyfile store
+    let mut store = MemoryKeyFile::new("temp", None);
+    let key = 42.to_string().into_bytes();
+    let value = 42.to_string().into_bytes();
+    store.set(&key, &value).unwrap();
+    let kf = CachedStore::new(store, 10);
+
+    // Test the keyfile is read from the store the first time it's needed
+    assert!(kf.exists(&key));
+    assert_eq!(kf.get(&key).unwrap(), value);
+    assert_eq!(kf.get(&key).unwrap(), value);
+    assert_eq!(kf.stats(), stats(1, 2));
+}
+
+
+#[test]
+fn writes_invalidate()
+{
+    // Create cached keyfile store with a cached keyfile and a cached
+    // missing key
+    let mut kf = CachedStore::new(MemoryKeyFile::new("temp", None), 10);
+    let key = 42.to_string().into_bytes();
+    let newkey = 24.to_string().into_bytes();
+    let value = 42.to_string().into_bytes();
+    let newvalue = 24.to_string().into_bytes();
+    kf.set(&key, &value).unwrap();
+    assert_eq!(kf.get(&key).unwrap(), value);
+    assert!(!kf.exists(&newkey));
+
+    // Test setting a keyfile replaces its cached value
+    kf.set(&key, &newvalue).unwrap();
+    assert_eq!(kf.get(&key).unwrap(), newvalue);
+
+    // Test creating a keyfile replaces its missing key
+    kf.set(&newkey, &value).unwrap();
+    assert!(kf.exists(&newkey));
+
+    // Test deleting a keyfile drops it from the cache
+    kf.delete(&key).unwrap();
+    assert!(!kf.exists(&key));
+    assert_eq!(kf.get(&key), Err(KeyFileError::Key(key.clone())));
+}
+
+
+#[test]
+fn transaction_commit_invalidates()
+{
+    // Create cached keyfile store with a cached keyfile
+    let mut kf = CachedStore::new(MemoryKeyFile::new("temp", None), 10);
+    let oldkey = 42.to_string().into_bytes();
+    let newkey = 24.to_string().into_bytes();
+    let value = 42.to_string().into_bytes();
+    kf.set(&oldkey, &value).unwrap();
+    assert_eq!(kf.get(&oldkey).unwrap(), value);
+    assert!(!kf.exists(&newkey));
+
+    // Move value to new key
+    let mut txn = kf.begin();
+    txn.delete(&oldkey).set(&newkey, &value);
+    kf.commit(txn).unwrap();
+
+    // Test
+    assert!(!kf.exists(&oldkey));
+    assert_eq!(kf.get(&newkey).unwrap(), value);
+}
+
+
+#[test]
+fn least_recently_used_dropped()
+{
+    // Create cached keyfile store that holds 2 keys
+    let mut store = MemoryKeyFile::new("temp", None);
+    let keys: Vec<Vec<u8>> =
+        (0..3).map(|i| i.to_string().into_bytes()).collect();
+    for k in &keys {
+        store.set(k, k).unwrap();
+    }
+    let kf = CachedStore::new(store, 2);
+
+    // Read the first 2 keys, then the first key again and the last key
+    for i in &[0, 1, 0, 2] {
+        kf.get(&keys[*i]).unwrap();
+    }
+    assert_eq!(kf.stats(), stats(1, 3));
+    assert_eq!(kf.len(), 2);
+
+    // Test the second key was dropped to make room for the last key
+    kf.get(&keys[0]).unwrap();
+    kf.get(&keys[2]).unwrap();
+    assert_eq!(kf.stats(), stats(3, 3));
+    kf.get(&keys[1]).unwrap();
+    assert_eq!(kf.stats(), stats(3, 4));
+}
+
+
+#[test]
+fn expired_keyfile_not_served()
+{
+    // Create cached keyfile store with a keyfile that expires in a second
+    let mut store = MemoryKeyFile::new("temp", None);
+    let key = 42.to_string().into_bytes();
+    let value = 42.to_string().into_bytes();
+    store.set(&key, &value).unwrap();
+    store.expire(&key, Some(timestamp() + 1)).unwrap();
+    let kf = CachedStore::new(store, 10);
+    assert_eq!(kf.get(&key).unwrap(), value);
+
+    // Test the cached keyfile is gone once it expires
+    thread::sleep(Duration::from_secs(2));
+    assert_eq!(kf.get(&key), Err(KeyFileError::Key(key.clone())));
+    assert_eq!(kf.stats(), stats(0, 2));
+}
+
+
+#[test]
+fn zero_capacity()
+{
+    // Create keyfile store whose cache holds nothing
+    let mut kf = CachedStore::new(MemoryKeyFile::new("temp", None), 0);
+    let key = 42.to_string().into_bytes();
+    let value = 42.to_string().into_bytes();
+    kf.set(&key, &value).unwrap();
+
+    // Test every read uses the store
+    assert_eq!(kf.get(&key).unwrap(), value);
+    assert_eq!(kf.get(&key).unwrap(), value);
+    assert!(kf.is_empty());
+    assert_eq!(kf.stats(), stats(0, 2));
+}
+
+
+#[test]
+fn replicated_hashed_keys_invalidate()
+{
+    // Create a primary and a cached follower that hash keys with the same
+    // salt
+    let tmpdir = TempDir::new("safesec_cache").unwrap();
+    let open = |name: &str| {
+        let path = tmpdir.path().join(name);
+        fs::create_dir(&path).unwrap();
+        let hasher = KeyHasher::new(vec![7; 32]);
+        let init = Init::new().path(&path).change_log(10).hash_keys(hasher);
+        KeyFile::open("temp", init).unwrap()
+    };
+    let mut primary = open("primary");
+    let mut follower = CachedStore::new(open("follower"), 10);
+    let key = 42.to_string().into_bytes();
+
+    // Copy a keyfile to the follower and read it through the cache
+    primary.set(&key, &b"1".to_vec()).unwrap();
+    follower.replicate(&primary.changes(0, 10).unwrap().1).unwrap();
+    assert_eq!(follower.get(&key).unwrap(), b"1");
+    assert_eq!(follower.len(), 1);
+
+    // Test a replicated change to the keyfile is read from the store
+    primary.set(&key, &b"2".to_vec()).unwrap();
+    follower.replicate(&primary.changes(1, 10).unwrap().1).unwrap();
+    assert!(follower.is_empty());
+    assert_eq!(follower.get(&key).unwrap(), b"2");
+
+    // Test a replicated deletion is seen
+    primary.delete(&key).unwrap();
+    follower.replicate(&primary.changes(2, 10).unwrap().1).unwrap();
+    assert!(!follower.exists(&key));
+}
+
+
+// ===========================================================================
+//
+// ===========================================================================
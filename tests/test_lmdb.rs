@@ -9,6 +9,7 @@
 
 
 extern crate chrono;
+extern crate lmdb;
 extern crate safesec;
 extern crate tempdir;
 
@@ -20,16 +21,21 @@ extern crate tempdir;
 
 // Stdlib imports
 
-use std::fs;
+use std::fs::{self, File};
+use std::io::{Read, Write};
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
 
 // Third-party imports
 
 use chrono::prelude::*;
+use lmdb::{Cursor, Environment, Transaction, WriteFlags};
 use tempdir::TempDir;
 
 // Local imports
 
 use safesec::storage::*;
+use safesec::storage::keyhash::KeyHasher;
 use safesec::storage::lmdb::*;
 
 
@@ -219,6 +225,1101 @@ fn multiple_keys()
 }
 
 
+#[test]
+fn transaction_commit()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Create keyfile store
+    let mut kf = KeyFile::new("temp", Some(dbpath.as_path()));
+
+    // Set value
+    let oldkey = 42.to_string().into_bytes();
+    let newkey = 24.to_string().into_bytes();
+    let value = "The Answer to Life, the Universe, and Everything";
+    let expected = String::from(value).into_bytes();
+    kf.set(&oldkey, &expected).unwrap();
+
+    // Move value to new key
+    let mut txn = kf.begin();
+    txn.delete(&oldkey).set(&newkey, &expected);
+    kf.commit(txn).unwrap();
+
+    // Test
+    assert!(!kf.exists(&oldkey));
+    assert_eq!(kf.get(&newkey).unwrap(), expected);
+}
+
+
+#[test]
+fn transaction_error_aborts_all()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Create keyfile store
+    let mut kf = KeyFile::new("temp", Some(dbpath.as_path()));
+
+    // Set value
+    let key = 42.to_string().into_bytes();
+    let newkey = 24.to_string().into_bytes();
+    let missing = 0.to_string().into_bytes();
+    let value = "The Answer to Life, the Universe, and Everything";
+    let expected = String::from(value).into_bytes();
+    kf.set(&key, &expected).unwrap();
+
+    // Stage changes followed by deleting a key that doesn't exist
+    let mut txn = kf.begin();
+    txn.delete(&key).set(&newkey, &expected).delete(&missing);
+    let result = kf.commit(txn);
+
+    // Test commit failed on the missing key
+    match result {
+        Err(KeyFileError::Key(k)) => assert_eq!(k, missing),
+        _ => panic!("Expected error did not occur"),
+    }
+
+    // Test no staged change was applied
+    assert_eq!(kf.get(&key).unwrap(), expected);
+    assert!(!kf.exists(&newkey));
+}
+
+
+#[test]
+fn transaction_abort()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Create keyfile store
+    let kf = KeyFile::new("temp", Some(dbpath.as_path()));
+
+    // Stage a value then abort
+    let key = 42.to_string().into_bytes();
+    let value = 42.to_string().into_bytes();
+    let mut txn = kf.begin();
+    txn.set(&key, &value);
+    txn.abort();
+
+    // Test
+    assert!(!kf.exists(&key));
+}
+
+
+#[test]
+fn init_map_size()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Create keyfile store with a 1 MiB map
+    let init = Init::new().path(dbpath.as_path()).map_size(1024 * 1024);
+    let mut kf = KeyFile::with_init("temp", init);
+
+    // Set a value larger than the map
+    let key = 42.to_string().into_bytes();
+    let value = vec![42; 2 * 1024 * 1024];
+    let result = kf.set(&key, &value);
+
+    // Test
+    match result {
+        Err(KeyFileError::MapFull) => {}
+        _ => panic!("Expected error did not occur"),
+    }
+    assert!(!kf.exists(&key));
+}
+
+
+#[test]
+fn map_grows_when_full()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Create keyfile store with a 1 MiB map that may grow to 8 MiB
+    let init = Init::new()
+        .path(dbpath.as_path())
+        .map_size(1024 * 1024)
+        .max_map_size(8 * 1024 * 1024);
+    let mut kf = KeyFile::with_init("temp", init);
+
+    // Set a value larger than the initial map
+    let key = 42.to_string().into_bytes();
+    let value = vec![42; 2 * 1024 * 1024];
+    kf.set(&key, &value).unwrap();
+
+    // Test
+    assert_eq!(kf.get(&key).unwrap(), value);
+    let size = kf.map_size().unwrap();
+    assert!(size > 1024 * 1024);
+    assert!(size <= 8 * 1024 * 1024);
+}
+
+
+#[test]
+fn map_growth_stops_at_max_size()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Create keyfile store with a 1 MiB map that may grow to 2 MiB
+    let init = Init::new()
+        .path(dbpath.as_path())
+        .map_size(1024 * 1024)
+        .max_map_size(2 * 1024 * 1024)
+        .map_growth(1.5);
+    let mut kf = KeyFile::with_init("temp", init);
+
+    // Set a value larger than the maximum map size
+    let key = 42.to_string().into_bytes();
+    let value = vec![42; 4 * 1024 * 1024];
+    let result = kf.set(&key, &value);
+
+    // Test
+    match result {
+        Err(KeyFileError::MapFull) => {}
+        _ => panic!("Expected error did not occur"),
+    }
+    assert!(!kf.exists(&key));
+    assert_eq!(kf.map_size().unwrap(), 2 * 1024 * 1024);
+}
+
+
+#[test]
+fn map_grown_elsewhere_is_adopted()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Open the same 1 MiB map twice, only the first of which may grow
+    let init = Init::new().path(dbpath.as_path()).map_size(1024 * 1024);
+    let growing = init.clone().max_map_size(8 * 1024 * 1024);
+    let mut grows = KeyFile::with_init("temp", growing);
+    let mut fixed = KeyFile::with_init("temp", init);
+
+    // Grow the map by setting a value larger than it through the first
+    let key = 42.to_string().into_bytes();
+    let value = vec![42; 2 * 1024 * 1024];
+    grows.set(&key, &value).unwrap();
+
+    // Read and write through the second, which can't grow the map itself
+    let other = 24.to_string().into_bytes();
+    fixed.set(&other, &other).unwrap();
+
+    // Test
+    assert_eq!(fixed.get(&key).unwrap(), value);
+    assert_eq!(grows.get(&other).unwrap(), other);
+    assert_eq!(fixed.map_size().unwrap(), grows.map_size().unwrap());
+}
+
+
+#[test]
+fn init_mode()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Create keyfile store
+    let init = Init::new().path(dbpath.as_path()).mode(0o640).no_sync(true);
+    let _kf = KeyFile::with_init("temp", init);
+
+    // Test
+    let meta = fs::metadata(dbpath.join("data.mdb")).unwrap();
+    assert_eq!(meta.permissions().mode() & 0o777, 0o640);
+}
+
+
+
+#[test]
+fn iter_prefix()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Create keyfile store
+    let mut kf = KeyFile::new("temp", Some(dbpath.as_path()));
+
+    // Set values
+    for k in &["a", "ab1", "ab2", "ab3", "b"] {
+        let key = k.to_string().into_bytes();
+        kf.set(&key, &key).unwrap();
+    }
+
+    // List keys
+    let all = kf.iter_prefix(b"ab", None, 10).unwrap();
+    let page = kf.iter_prefix(b"ab", None, 2).unwrap();
+    let rest = kf.iter_prefix(b"ab", Some(b"ab2"), 10).unwrap();
+    let none = kf.iter_prefix(b"c", None, 10).unwrap();
+
+    // Test
+    let expected: Vec<Vec<u8>> = vec![
+        b"ab1".to_vec(),
+        b"ab2".to_vec(),
+        b"ab3".to_vec(),
+    ];
+    assert_eq!(all, expected);
+    assert_eq!(page, &expected[..2]);
+    assert_eq!(rest, &expected[2..]);
+    assert!(none.is_empty());
+}
+
+
+#[test]
+fn keyfile_info()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Create keyfile store
+    let mut kf = KeyFile::new("temp", Some(dbpath.as_path()));
+
+    // Create then change value
+    let key = 42.to_string().into_bytes();
+    kf.set(&key, &4.to_string().into_bytes()).unwrap();
+    let created = kf.info(&key).unwrap();
+    kf.set(&key, &42.to_string().into_bytes()).unwrap();
+    let changed = kf.info(&key).unwrap();
+
+    // Test
+    assert_eq!(created.revision, 1);
+    assert_eq!(created.length, 1);
+    assert_eq!(created.created, created.modified);
+    assert_eq!(changed.revision, 2);
+    assert_eq!(changed.length, 2);
+    assert_eq!(changed.created, created.created);
+    assert!(changed.modified >= created.modified);
+
+    // Metadata is removed along with the value
+    kf.delete(&key).unwrap();
+    assert_eq!(kf.info(&key), Err(KeyFileError::Key(key.clone())));
+}
+
+
+#[test]
+fn transaction_check()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Create keyfile store
+    let mut kf = KeyFile::new("temp", Some(dbpath.as_path()));
+
+    // Set value
+    let key = 42.to_string().into_bytes();
+    let value = 42.to_string().into_bytes();
+    let first = 1.to_string().into_bytes();
+    let second = 2.to_string().into_bytes();
+    kf.set(&key, &value).unwrap();
+
+    // Change value if it is still at revision 1
+    let mut txn = kf.begin();
+    txn.check(&key, KeyFileCheck::Revision(1)).set(&key, &first);
+    kf.commit(txn).unwrap();
+
+    // Change value again, expecting revision 1
+    let mut txn = kf.begin();
+    txn.check(&key, KeyFileCheck::Revision(1)).set(&key, &second);
+    let result = kf.commit(txn);
+
+    // Test the stale change was rejected
+    assert_eq!(result, Err(KeyFileError::Mismatch(key.clone())));
+    assert_eq!(kf.get(&key).unwrap(), first);
+
+    // Change value if its contents are unchanged
+    let mut txn = kf.begin();
+    let hash = keyfile_hash(&first);
+    txn.check(&key, KeyFileCheck::Hash(hash)).set(&key, &second);
+    kf.commit(txn).unwrap();
+
+    // Test
+    assert_eq!(kf.get(&key).unwrap(), second);
+    assert_eq!(kf.info(&key).unwrap().revision, 3);
+}
+
+
+#[test]
+fn history()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Create keyfile store keeping 2 previous revisions
+    let init = Init::new().path(dbpath.as_path()).history(2);
+    let mut kf = KeyFile::with_init("temp", init);
+
+    // Set 4 revisions of a value
+    let key = 42.to_string().into_bytes();
+    for rev in 1..5 {
+        kf.set(&key, &rev.to_string().into_bytes()).unwrap();
+    }
+
+    // Test only the 2 revisions before the current one are kept
+    let revisions: Vec<u64> =
+        kf.revisions(&key).unwrap().iter().map(|i| i.revision).collect();
+    assert_eq!(revisions, vec![2, 3]);
+    assert_eq!(kf.get_revision(&key, 2).unwrap(), b"2".to_vec());
+    assert_eq!(
+        kf.get_revision(&key, 1),
+        Err(KeyFileError::Revision(key.clone(), 1))
+    );
+
+    // Delete value
+    kf.delete(&key).unwrap();
+
+    // Test the deleted value is kept and revisions carry on once the value
+    // is set again
+    let revisions: Vec<u64> =
+        kf.revisions(&key).unwrap().iter().map(|i| i.revision).collect();
+    assert_eq!(revisions, vec![3, 4]);
+    kf.set(&key, &4.to_string().into_bytes()).unwrap();
+    assert_eq!(kf.info(&key).unwrap().revision, 5);
+}
+
+
+#[test]
+fn truncated_history()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Create keyfile store with a revision in the history
+    let init = Init::new().path(dbpath.as_path()).history(2);
+    let mut kf = KeyFile::with_init("temp", init.clone());
+    let key = 42.to_string().into_bytes();
+    kf.set(&key, &key).unwrap();
+    kf.set(&key, &key).unwrap();
+    drop(kf);
+
+    // Cut every history entry short
+    let env = Environment::new().set_max_dbs(4).open(&dbpath).unwrap();
+    let histdb = env.open_db(Some("temp.history")).unwrap();
+    let mut txn = env.begin_rw_txn().unwrap();
+    let entries: Vec<(Vec<u8>, Vec<u8>)> = txn
+        .open_ro_cursor(histdb)
+        .unwrap()
+        .iter()
+        .map(|(k, v)| (k.to_vec(), v[..10].to_vec()))
+        .collect();
+    assert_eq!(entries.len(), 1);
+    for (k, v) in &entries {
+        txn.put(histdb, k, v, WriteFlags::empty()).unwrap();
+    }
+    txn.commit().unwrap();
+    drop(env);
+
+    // Test the history is reported as corrupted
+    let kf = KeyFile::with_init("temp", init);
+    assert_eq!(kf.revisions(&key), Err(KeyFileError::Corrupted));
+}
+
+
+#[test]
+fn expiry()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Create keyfile store
+    let mut kf = KeyFile::new("temp", Some(dbpath.as_path()));
+
+    // Set values, one of which expired a second ago
+    let expired = 1.to_string().into_bytes();
+    let live = 2.to_string().into_bytes();
+    let later = timestamp() + 3600;
+    let mut txn = kf.begin();
+    txn.set(&expired, &expired)
+        .expire(&expired, Some(timestamp() - 1))
+        .set(&live, &live)
+        .expire(&live, Some(later));
+    kf.commit(txn).unwrap();
+
+    // Test the expired keyfile can't be read
+    let notfound = KeyFileError::Key(expired.clone());
+    assert!(!kf.exists(&expired));
+    assert_eq!(kf.get(&expired), Err(notfound.clone()));
+    assert_eq!(kf.info(&expired), Err(notfound));
+    assert_eq!(kf.iter_prefix(&[], None, 10).unwrap(), vec![live.clone()]);
+    assert_eq!(kf.info(&live).unwrap().expires, Some(later));
+
+    // Test only the expired keyfile is purged
+    assert_eq!(kf.purge_expired().unwrap(), vec![expired.clone()]);
+    assert!(kf.purge_expired().unwrap().is_empty());
+    assert_eq!(kf.get(&live).unwrap(), live);
+
+    // Test changing a keyfile clears its expiry
+    kf.set(&live, &expired).unwrap();
+    assert_eq!(kf.info(&live).unwrap().expires, None);
+}
+
+
+#[test]
+fn backup_and_restore()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+    let backuppath = tmpdir.path().join("backup");
+
+    // Create keyfile store
+    let mut kf = KeyFile::new("temp", Some(dbpath.as_path()));
+
+    // Set values and back them up
+    let first = 1.to_string().into_bytes();
+    let second = 2.to_string().into_bytes();
+    kf.set(&first, &first).unwrap();
+    kf.set(&second, &second).unwrap();
+    kf.backup(&backuppath, true).unwrap();
+
+    // Change values after the backup, then close the store
+    kf.set(&first, &second).unwrap();
+    kf.delete(&second).unwrap();
+    drop(kf);
+
+    // Test the backup is valid and restores the backed up values
+    assert_eq!(verify(&backuppath, "temp"), Ok(2));
+    assert_eq!(restore(&backuppath, &dbpath, "temp"), Ok(2));
+    let kf = KeyFile::new("temp", Some(dbpath.as_path()));
+    assert_eq!(kf.get(&first).unwrap(), first);
+    assert_eq!(kf.get(&second).unwrap(), second);
+}
+
+
+#[test]
+fn backup_permissions()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+    let backuppath = tmpdir.path().join("backups").join("daily");
+
+    // Create keyfile store whose files are readable by the group
+    let dbinit = Init::new().path(dbpath.as_path()).mode(0o640);
+    let kf = KeyFile::open("temp", dbinit).unwrap();
+    kf.backup(&backuppath, false).unwrap();
+
+    // Test the backup directories are only usable by the owner, and the
+    // data file has the store's permissions
+    let mode = |path: &Path| {
+        fs::metadata(path).unwrap().permissions().mode() & 0o777
+    };
+    assert_eq!(mode(backuppath.parent().unwrap()), 0o700);
+    assert_eq!(mode(&backuppath), 0o700);
+    assert_eq!(mode(&backuppath.join("data.mdb")), 0o640);
+
+    // Test an existing backup isn't overwritten
+    assert_eq!(kf.backup(&backuppath, false), Err(KeyFileError::Io));
+}
+
+
+#[test]
+fn restore_invalid_backup()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+    let backuppath = tmpdir.path().join("backup");
+
+    // Create keyfile store
+    let mut kf = KeyFile::new("temp", Some(dbpath.as_path()));
+    let key = 42.to_string().into_bytes();
+    kf.set(&key, &key).unwrap();
+    drop(kf);
+
+    // Create a backup that isn't an LMDB file
+    fs::create_dir(&backuppath).unwrap();
+    let mut file = File::create(backuppath.join("data.mdb")).unwrap();
+    file.write_all(b"not a db").unwrap();
+    drop(file);
+
+    // Test the backup is rejected and the store is left as it was
+    let result = restore(&backuppath, &dbpath, "temp");
+    assert_eq!(result, Err(KeyFileError::Corrupted));
+    let kf = KeyFile::new("temp", Some(dbpath.as_path()));
+    assert_eq!(kf.get(&key).unwrap(), key);
+}
+
+#[test]
+fn secure_delete()
+{
+    // Create keyfile store that overwrites keyfiles before deleting or
+    // replacing them, keeping one previous revision
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+    let init = Init::new().path(&dbpath).history(1).secure_delete(true);
+    let mut kf = KeyFile::open("temp", init).unwrap();
+    let key = 42.to_string().into_bytes();
+    for i in 0..3 {
+        kf.set(&key, &i.to_string().into_bytes()).unwrap();
+    }
+
+    // Test keyfiles are replaced and revisions dropped as usual
+    assert_eq!(kf.get(&key).unwrap(), b"2");
+    let revisions = kf.revisions(&key).unwrap();
+    assert_eq!(revisions.len(), 1);
+    let rev = revisions[0].revision;
+    assert_eq!(kf.get_revision(&key, rev).unwrap(), b"1");
+
+    // Test deleted keyfiles are gone, leaving their last revision
+    kf.delete(&key).unwrap();
+    assert!(!kf.exists(&key));
+    let revisions = kf.revisions(&key).unwrap();
+    assert_eq!(revisions.len(), 1);
+    let rev = revisions[0].revision;
+    assert_eq!(kf.get_revision(&key, rev).unwrap(), b"2");
+}
+
+
+#[test]
+fn compact_drops_deleted_keyfiles()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Create keyfile store that overwrites keyfiles before deleting or
+    // replacing them
+    let init = Init::new().path(&dbpath).change_log(100);
+    let mut kf = KeyFile::open("temp", init.clone().secure_delete(true))
+        .unwrap();
+
+    // Keep one keyfile, replace another and delete the last
+    let secret = |name: &str| format!("{}-keyfile-0123456789", name);
+    let secret = |name: &str| secret(name).into_bytes();
+    let (kept, replaced) = (b"1".to_vec(), b"2".to_vec());
+    let deleted = b"3".to_vec();
+    kf.set(&kept, &secret("kept")).unwrap();
+    kf.set(&replaced, &secret("old")).unwrap();
+    kf.set(&replaced, &secret("new")).unwrap();
+    kf.set(&deleted, &secret("deleted")).unwrap();
+    kf.delete(&deleted).unwrap();
+    drop(kf);
+
+    // Test only the keyfiles still in use are left in the data file once
+    // it is compacted
+    assert_eq!(compact(&dbpath, "temp"), Ok(2));
+    let mut data = Vec::new();
+    let mut file = File::open(dbpath.join("data.mdb")).unwrap();
+    file.read_to_end(&mut data).unwrap();
+    let holds = |keyfile: Vec<u8>| {
+        data.windows(keyfile.len()).any(|w| w == &keyfile[..])
+    };
+    assert!(holds(secret("kept")));
+    assert!(holds(secret("new")));
+    assert!(!holds(secret("old")));
+    assert!(!holds(secret("deleted")));
+
+    // Test the store can still be used, with replaced keyfiles logged as
+    // deletions
+    let kf = KeyFile::open("temp", init).unwrap();
+    assert_eq!(kf.get(&kept).unwrap(), secret("kept"));
+    assert_eq!(kf.get(&replaced).unwrap(), secret("new"));
+    let (latest, changes) = kf.changes(0, 100).unwrap();
+    assert_eq!(latest, 5);
+    for &(i, k) in &[(1, &replaced), (3, &deleted)] {
+        match changes[i].1 {
+            Change::Delete(None, ref key) => assert_eq!(key, k),
+            ref c => panic!("Unexpected change {:?}", c),
+        }
+    }
+}
+
+
+#[test]
+fn namespaces()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Create keyfile store
+    let mut kf = KeyFile::new("temp", Some(dbpath.as_path()));
+    let key = 42.to_string().into_bytes();
+    kf.set(&key, &key).unwrap();
+    let mut namespaces = kf.namespaces();
+
+    // Create namespaces
+    namespaces.create_namespace("team-b").unwrap();
+    namespaces.create_namespace("team-a").unwrap();
+    let result = namespaces.create_namespace("team-a");
+    assert_eq!(result, Err(KeyFileError::NamespaceExists("team-a".into())));
+    let names = namespaces.list_namespaces().unwrap();
+    assert_eq!(names, vec!["team-a", "team-b"]);
+
+    // Test keys in a namespace don't collide with keys elsewhere
+    let store = namespaces.namespace("team-a").unwrap();
+    assert!(!store.read().unwrap().exists(&key));
+    store.write().unwrap().set(&key, &b"team-a".to_vec()).unwrap();
+    assert_eq!(kf.get(&key).unwrap(), key);
+    let other = namespaces.namespace("team-b").unwrap();
+    assert!(!other.read().unwrap().exists(&key));
+
+    // Test a dropped namespace can't be used, and is empty once it is
+    // created again
+    namespaces.drop_namespace("team-a").unwrap();
+    let result = namespaces.namespace("team-a").map(|_| ());
+    assert_eq!(result, Err(KeyFileError::Namespace("team-a".into())));
+    assert_eq!(namespaces.list_namespaces().unwrap(), vec!["team-b"]);
+    namespaces.create_namespace("team-a").unwrap();
+    let store = namespaces.namespace("team-a").unwrap();
+    assert!(!store.read().unwrap().exists(&key));
+}
+
+#[test]
+fn quota()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Create keyfile store
+    let mut kf = KeyFile::new("temp", Some(dbpath.as_path()));
+
+    // Set values, one of which has expired
+    let small = 1.to_string().into_bytes();
+    let large = vec![0; 10];
+    let expired = 3.to_string().into_bytes();
+    kf.set(&small, &small).unwrap();
+    kf.set(&large, &large).unwrap();
+    let mut txn = kf.begin();
+    txn.set(&expired, &expired).expire(&expired, Some(timestamp() - 1));
+    kf.commit(txn).unwrap();
+
+    // Test usage counts the expired keyfile until it is purged
+    assert_eq!(kf.usage().unwrap(), (3, 12));
+    kf.purge_expired().unwrap();
+    assert_eq!(kf.usage().unwrap(), (2, 11));
+
+    // Test key and keyfile size limits
+    let quota = Quota::new().max_key_length(4).max_keyfile_size(10);
+    let result = quota.check(&kf, &large, &small, None);
+    assert_eq!(result, Err(KeyFileError::Quota(QuotaLimit::KeyLength, 4)));
+    let result = quota.check(&kf, &small, &[0; 11], None);
+    assert_eq!(result, Err(KeyFileError::Quota(QuotaLimit::KeyFileSize, 10)));
+
+    // Test entry limit only stops new keys
+    let quota = Quota::new().max_entries(2);
+    let result = quota.check(&kf, &expired, &expired, None);
+    assert_eq!(result, Err(KeyFileError::Quota(QuotaLimit::Entries, 2)));
+    assert!(quota.check(&kf, &small, &expired, Some(&small)).is_ok());
+
+    // Test byte limit counts the replaced keyfile as removed
+    let quota = Quota::new().max_bytes(12);
+    let result = quota.check(&kf, &expired, &small, None);
+    assert!(result.is_ok());
+    let result = quota.check(&kf, &expired, &large, Some(&small));
+    assert_eq!(result, Err(KeyFileError::Quota(QuotaLimit::Bytes, 12)));
+    assert!(quota.check(&kf, &expired, &large, Some(&large)).is_ok());
+}
+
+#[test]
+fn usage()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Create keyfile store with a namespace
+    let mut kf = KeyFile::new("temp", Some(dbpath.as_path()));
+    let mut namespaces = kf.namespaces();
+    namespaces.create_namespace("team-a").unwrap();
+    let store = namespaces.namespace("team-a").unwrap();
+
+    // Create, replace and delete keyfiles in both stores
+    let (a, b) = (b"a".to_vec(), b"b".to_vec());
+    kf.set(&a, &vec![0; 10]).unwrap();
+    kf.set(&b, &vec![0; 5]).unwrap();
+    kf.set(&a, &vec![0; 3]).unwrap();
+    kf.delete(&b).unwrap();
+    store.write().unwrap().set(&a, &vec![0; 7]).unwrap();
+
+    // Test a failed transaction leaves the usage as it was
+    let mut txn = kf.begin();
+    txn.set(&b, &[0; 5]).check(&a, KeyFileCheck::Revision(1));
+    assert_eq!(kf.commit(txn), Err(KeyFileError::Mismatch(a.clone())));
+
+    // Test each store keeps its own usage
+    assert_eq!(kf.usage().unwrap(), (1, 3));
+    assert_eq!(store.read().unwrap().usage().unwrap(), (1, 7));
+
+    // Test a namespace that is dropped and created again is empty
+    drop(store);
+    namespaces.drop_namespace("team-a").unwrap();
+    namespaces.create_namespace("team-a").unwrap();
+    let store = namespaces.namespace("team-a").unwrap();
+    assert_eq!(store.read().unwrap().usage().unwrap(), (0, 0));
+    drop((store, namespaces, kf));
+
+    // Test the usage is kept in the meta db rather than counted
+    let env = Environment::new().set_max_dbs(16).open(&dbpath).unwrap();
+    let meta = env.open_db(Some("meta")).unwrap();
+    let txn = env.begin_ro_txn().unwrap();
+    let usage = [0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 3];
+    assert_eq!(txn.get(meta, &"usage.temp").unwrap(), &usage[..]);
+}
+
+#[test]
+fn checksums()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+
+    // Create keyfile store
+    let mut kf = KeyFile::new("temp", Some(dbpath.as_path()));
+    let good = 1.to_string().into_bytes();
+    let bad = 2.to_string().into_bytes();
+    let keyfile = b"keyfile that will be damaged".to_vec();
+    kf.set(&good, &good).unwrap();
+    kf.set(&bad, &keyfile).unwrap();
+    assert_eq!(kf.scrub().unwrap(), vec![]);
+    drop(kf);
+
+    // Flip a bit in every copy of the keyfile in the data file
+    let datapath = dbpath.join("data.mdb");
+    let mut data = Vec::new();
+    File::open(&datapath).unwrap().read_to_end(&mut data).unwrap();
+    let mut pos = 0;
+    while pos + keyfile.len() <= data.len() {
+        if data[pos..pos + keyfile.len()] == keyfile[..] {
+            data[pos] ^= 1;
+        }
+        pos += 1;
+    }
+    File::create(&datapath).unwrap().write_all(&data).unwrap();
+
+    // Test the damaged keyfile can't be read and is found by a scrub
+    let kf = KeyFile::new("temp", Some(dbpath.as_path()));
+    assert_eq!(kf.get(&bad), Err(KeyFileError::Corrupted));
+    assert_eq!(kf.get(&good).unwrap(), good);
+    assert_eq!(kf.scrub().unwrap(), vec![(bad, KeyFileError::Corrupted)]);
+}
+
+#[test]
+fn schema_migration()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+    let open_env = || {
+        Environment::new().set_max_dbs(4).open(&dbpath).unwrap()
+    };
+
+    // Create keyfile store
+    let mut kf = KeyFile::new("temp", Some(dbpath.as_path()));
+    let key = 42.to_string().into_bytes();
+    kf.set(&key, &key).unwrap();
+    assert_eq!(kf.schema_version().unwrap(), SCHEMA_VERSION);
+    drop(kf);
+
+    // Turn the store back into version 0, which has no schema version, no
+    // keyfile checksums, no change log, no latest change and no usage
+    let env = open_env();
+    let meta = env.open_db(Some("meta")).unwrap();
+    let infodb = env.open_db(Some("temp.info")).unwrap();
+    let changes = env.open_db(Some("changes")).unwrap();
+    let mut txn = env.begin_rw_txn().unwrap();
+    let info = txn.get(infodb, &key).unwrap()[..40].to_vec();
+    txn.put(infodb, &key, &info, WriteFlags::empty()).unwrap();
+    txn.del(meta, &"schema", None).unwrap();
+    txn.del(meta, &"usage.temp", None).unwrap();
+    txn.del(meta, &"latest", None).unwrap();
+    txn.clear_db(changes).unwrap();
+    txn.commit().unwrap();
+    drop(env);
+
+    // Test opening the store upgrades it
+    let init = Init::new().path(dbpath.as_path()).change_log(100);
+    let kf = KeyFile::open("temp", init).unwrap();
+    assert_eq!(kf.schema_version().unwrap(), SCHEMA_VERSION);
+    assert_eq!(kf.get(&key).unwrap(), key);
+    let (latest, changes) = kf.changes(0, 100).unwrap();
+    assert_eq!(latest, 1);
+    match changes[0].1 {
+        Change::Put(None, ref k, ref file, _) => {
+            assert_eq!((k, file), (&key, &key))
+        }
+        ref c => panic!("Unexpected change {:?}", c),
+    }
+    drop(kf);
+    let env = open_env();
+    let meta = env.open_db(Some("meta")).unwrap();
+    let infodb = env.open_db(Some("temp.info")).unwrap();
+    let txn = env.begin_ro_txn().unwrap();
+    assert_eq!(txn.get(infodb, &key).unwrap().len(), 48);
+    let usage = [0, 0, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0, 2];
+    assert_eq!(txn.get(meta, &"usage.temp").unwrap(), &usage[..]);
+    txn.abort();
+
+    // Mark the store as written by a newer version
+    let meta = env.open_db(Some("meta")).unwrap();
+    let mut txn = env.begin_rw_txn().unwrap();
+    let version = SCHEMA_VERSION + 1;
+    let buf = [0, 0, 0, version as u8];
+    txn.put(meta, &"schema", &buf, WriteFlags::empty()).unwrap();
+    txn.commit().unwrap();
+    drop(env);
+
+    // Test the store is refused
+    let init = Init::new().path(dbpath.as_path());
+    let result = KeyFile::open("temp", init);
+    assert_eq!(result.err(), Some(KeyFileError::Schema(version)));
+}
+
+#[test]
+fn read_only()
+{
+    // Create temp directory
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+    let init = || Init::new().path(dbpath.as_path()).read_only(true);
+
+    // Test a store that doesn't exist can't be opened read-only
+    assert!(KeyFile::open("temp", init()).is_err());
+
+    // Create keyfile store
+    let mut kf = KeyFile::new("temp", Some(dbpath.as_path()));
+    let key = 42.to_string().into_bytes();
+    kf.set(&key, &key).unwrap();
+    drop(kf);
+
+    // Test keyfiles can be read but not written
+    let mut kf = KeyFile::open("temp", init()).unwrap();
+    assert_eq!(kf.get(&key).unwrap(), key);
+    assert!(kf.set(&key, &b"changed".to_vec()).is_err());
+    assert!(kf.delete(&key).is_err());
+    assert_eq!(kf.get(&key).unwrap(), key);
+}
+
+#[test]
+fn change_log()
+{
+    // Create temp directories
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+    let replicadir = mktempdir();
+    let replicapath = replicadir.path().join("sec.db");
+
+    // Create keyfile store and make some changes
+    let init = Init::new().change_log(100);
+    let mut kf = KeyFile::with_init("temp", init.clone().path(&dbpath));
+    let (key, other) = (b"42".to_vec(), b"43".to_vec());
+    kf.set(&key, &key).unwrap();
+    kf.set(&other, &other).unwrap();
+    kf.delete(&other).unwrap();
+    let mut namespaces = kf.namespaces();
+    namespaces.create_namespace("team-a").unwrap();
+    let store = namespaces.namespace("team-a").unwrap();
+    store.write().unwrap().set(&key, &b"team-a".to_vec()).unwrap();
+
+    // Test every change is logged in order
+    let (latest, changes) = kf.changes(0, 100).unwrap();
+    assert_eq!(latest, 5);
+    let seqs: Vec<u64> = changes.iter().map(|&(seq, _)| seq).collect();
+    assert_eq!(seqs, vec![1, 2, 3, 4, 5]);
+    match changes[2].1 {
+        Change::Delete(None, ref k) => assert_eq!(k, &other),
+        ref c => panic!("Unexpected change {:?}", c),
+    }
+    match changes[3].1 {
+        Change::CreateNamespace(ref name) => assert_eq!(name, "team-a"),
+        ref c => panic!("Unexpected change {:?}", c),
+    }
+
+    // Test changes can be read a few at a time
+    let (latest, some) = kf.changes(3, 1).unwrap();
+    assert_eq!(latest, 5);
+    assert_eq!(some.len(), 1);
+    assert_eq!(some[0].0, 4);
+    assert!(kf.changes(5, 100).unwrap().1.is_empty());
+
+    // Test the changes can be applied to another store
+    let mut replica = KeyFile::with_init("temp", init.path(&replicapath));
+    assert_eq!(replica.replicated().unwrap(), 0);
+    replica.replicate(&changes[..2]).unwrap();
+    assert_eq!(replica.replicated().unwrap(), 2);
+    replica.replicate(&changes[2..]).unwrap();
+    assert_eq!(replica.replicated().unwrap(), 5);
+    assert_eq!(replica.get(&key).unwrap(), key);
+    assert!(!replica.exists(&other));
+    let store = replica.namespaces().namespace("team-a").unwrap();
+    assert_eq!(store.read().unwrap().get(&key).unwrap(), b"team-a");
+
+    // Test the replica logs the changes it applies, so it can be followed
+    // in turn
+    let (latest, replicated) = replica.changes(0, 100).unwrap();
+    assert_eq!(latest, 5);
+    assert_eq!(replicated.len(), changes.len());
+
+    // Test only the default store can apply changes
+    let result = store.write().unwrap().replicate(&changes);
+    assert_eq!(result, Err(KeyFileError::Other));
+}
+
+
+#[test]
+fn change_log_retention()
+{
+    // Create temp directories
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+    let backupdir = mktempdir();
+    let backuppath = backupdir.path().join("sec.db");
+
+    // Create keyfile store keeping the latest 2 changes
+    let init = Init::new().path(dbpath.as_path());
+    let mut kf = KeyFile::with_init("temp", init.clone().change_log(2));
+    for i in 1..5 {
+        let key = i.to_string().into_bytes();
+        kf.set(&key, &key).unwrap();
+    }
+
+    // Test only the latest changes can be read
+    assert_eq!(kf.changes(0, 100), Err(KeyFileError::Trimmed));
+    let (latest, changes) = kf.changes(2, 100).unwrap();
+    assert_eq!(latest, 4);
+    let seqs: Vec<u64> = changes.iter().map(|&(seq, _)| seq).collect();
+    assert_eq!(seqs, vec![3, 4]);
+    drop(kf);
+
+    // Test a store keeping no changes still counts them, but can't be
+    // followed
+    let mut kf = KeyFile::with_init("temp", init);
+    assert_eq!(kf.changes(4, 100).unwrap(), (4, vec![]));
+    kf.set(&b"5".to_vec(), &b"5".to_vec()).unwrap();
+    assert_eq!(kf.changes(4, 100), Err(KeyFileError::Trimmed));
+    assert_eq!(kf.changes(5, 100).unwrap(), (5, vec![]));
+
+    // Test a backup carries on replicating from the latest change
+    kf.backup(&backuppath, false).unwrap();
+    let replica = KeyFile::new("temp", Some(backuppath.as_path()));
+    assert_eq!(replica.replicated().unwrap(), 5);
+}
+
+
+#[test]
+fn hashed_keys()
+{
+    // Create temp directory and a salt file
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+    let saltpath = tmpdir.path().join("salt");
+    let hasher = KeyHasher::open(&saltpath).unwrap();
+    let mode = fs::metadata(&saltpath).unwrap().permissions().mode();
+    assert_eq!(mode & 0o777, 0o600);
+
+    // Create keyfile store that hashes its keys
+    let init =
+        Init::new().path(dbpath.as_path()).history(1).change_log(100);
+    let mut kf = KeyFile::open("temp", init.clone().hash_keys(hasher))
+        .unwrap();
+    let key = b"host-1.example.com".to_vec();
+    let missing = b"host-2.example.com".to_vec();
+    kf.set(&key, &b"first".to_vec()).unwrap();
+    kf.set(&key, &b"second".to_vec()).unwrap();
+
+    // Test keys are used as usual
+    assert!(kf.exists(&key));
+    assert_eq!(kf.get(&key).unwrap(), b"second");
+    assert_eq!(kf.info(&key).unwrap().revision, 2);
+    assert_eq!(kf.get_revision(&key, 1).unwrap(), b"first");
+
+    // Test errors name the key that was given
+    assert_eq!(kf.get(&missing), Err(KeyFileError::Key(missing.clone())));
+    assert_eq!(
+        kf.get_revision(&key, 5),
+        Err(KeyFileError::Revision(key.clone(), 5))
+    );
+    let mut txn = kf.begin();
+    txn.check(&key, KeyFileCheck::Revision(1)).delete(&key);
+    assert_eq!(kf.commit(txn), Err(KeyFileError::Mismatch(key.clone())));
+
+    // Test keys can't be listed
+    assert_eq!(kf.iter_prefix(&[], None, 10), Err(KeyFileError::Other));
+
+    // Test the key isn't kept in the db file or the change log
+    drop(kf);
+    let mut data = Vec::new();
+    let datapath = dbpath.join("data.mdb");
+    File::open(&datapath).unwrap().read_to_end(&mut data).unwrap();
+    assert!(!data.windows(key.len()).any(|w| w == &key[..]));
+
+    // Test the store opens again with the same salt
+    let hasher = KeyHasher::open(&saltpath).unwrap();
+    let kf = KeyFile::open("temp", init.clone().hash_keys(hasher)).unwrap();
+    assert_eq!(kf.get(&key).unwrap(), b"second");
+    let (_, changes) = kf.changes(0, 100).unwrap();
+    assert!(!changes.is_empty());
+    for change in &changes {
+        match change.1 {
+            Change::Put(_, ref k, _, _) => assert_ne!(k, &key),
+            ref c => panic!("Unexpected change {:?}", c),
+        }
+    }
+    drop(kf);
+
+    // Test the store is refused with another salt or without a salt
+    let other = KeyHasher::new(vec![0; 32]);
+    let result = KeyFile::open("temp", init.clone().hash_keys(other));
+    assert_eq!(result.err(), Some(KeyFileError::KeySalt));
+    let result = KeyFile::open("temp", init);
+    assert_eq!(result.err(), Some(KeyFileError::KeySalt));
+}
+
+
+#[test]
+fn key_hasher()
+{
+    // Test keys are hashed with HMAC-SHA256 (RFC 4231 test case 2)
+    let hasher = KeyHasher::new(b"Jefe".to_vec());
+    let hash: Vec<String> = hasher
+        .hash(b"what do ya want for nothing?")
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect();
+    assert_eq!(
+        hash.concat(),
+        "5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+    );
+}
+
+
+#[test]
+fn hashed_keys_need_empty_store()
+{
+    // Create keyfile store that doesn't hash its keys
+    let tmpdir = mktempdir();
+    let dbpath = tmpdir.path().join("sec.db");
+    let init = Init::new().path(dbpath.as_path());
+    let mut kf = KeyFile::open("temp", init.clone()).unwrap();
+    kf.set(&b"42".to_vec(), &b"42".to_vec()).unwrap();
+    drop(kf);
+
+    // Test keys can't start being hashed once written
+    let hasher = KeyHasher::new(vec![42; 32]);
+    let result = KeyFile::open("temp", init.hash_keys(hasher));
+    assert_eq!(result.err(), Some(KeyFileError::KeySalt));
+
+    // Test a salt file that is too short is refused
+    let saltpath = tmpdir.path().join("salt");
+    File::create(&saltpath).unwrap().write_all(b"short").unwrap();
+    assert!(KeyHasher::open(&saltpath).is_err());
+}
+
 // ===========================================================================
 //
 // ===========================================================================
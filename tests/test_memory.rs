@@ -0,0 +1,414 @@
+// test_memory.rs
+// Copyright (C) 2017 authors and contributors (see AUTHORS file)
+//
+// This file is released under the MIT License.
+
+// ===========================================================================
+// Externs
+// ===========================================================================
+
+
+extern crate safesec;
+
+
+// ===========================================================================
+// Imports
+// ===========================================================================
+
+
+// Stdlib imports
+
+// Third-party imports
+
+// Local imports
+
+use safesec::storage::*;
+use safesec::storage::memory::*;
+
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+
+#[test]
+fn get_set_value()
+{
+    // Create keyfile store
+    let mut kf = MemoryKeyFile::new("temp", None);
+
+    // Set value
+    let key = 42.to_string().into_bytes();
+    let value = "The Answer to Life, the Universe, and Everything";
+    let expected = String::from(value).into_bytes();
+    kf.set(&key, &expected).unwrap();
+
+    // Test
+    assert!(kf.exists(&key));
+    assert_eq!(kf.get(&key).unwrap(), expected);
+    assert_eq!(kf.len(), 1);
+}
+
+
+#[test]
+fn del_value()
+{
+    // Create keyfile store
+    let mut kf = MemoryKeyFile::new("temp", None);
+
+    // Set value
+    let key = 42.to_string().into_bytes();
+    let value = 42.to_string().into_bytes();
+    kf.set(&key, &value).unwrap();
+
+    // Delete value
+    kf.delete(&key).unwrap();
+
+    // Test
+    assert!(!kf.exists(&key));
+    assert!(kf.is_empty());
+}
+
+
+#[test]
+fn no_key()
+{
+    // Create keyfile store
+    let mut kf = MemoryKeyFile::new("temp", None);
+
+    // Non-existent value
+    let key = 42.to_string().into_bytes();
+
+    // Test
+    assert!(!kf.exists(&key));
+    assert_eq!(kf.get(&key), Err(KeyFileError::Key(key.clone())));
+    assert_eq!(kf.delete(&key), Err(KeyFileError::Key(key.clone())));
+}
+
+
+#[test]
+fn transaction_commit()
+{
+    // Create keyfile store
+    let mut kf = MemoryKeyFile::new("temp", None);
+
+    // Set value
+    let oldkey = 42.to_string().into_bytes();
+    let newkey = 24.to_string().into_bytes();
+    let value = 42.to_string().into_bytes();
+    kf.set(&oldkey, &value).unwrap();
+
+    // Move value to new key
+    let mut txn = kf.begin();
+    txn.delete(&oldkey).set(&newkey, &value);
+    kf.commit(txn).unwrap();
+
+    // Test
+    assert!(!kf.exists(&oldkey));
+    assert_eq!(kf.get(&newkey).unwrap(), value);
+}
+
+
+#[test]
+fn transaction_error_aborts_all()
+{
+    // Create keyfile store
+    let mut kf = MemoryKeyFile::new("temp", None);
+
+    // Set value
+    let key = 42.to_string().into_bytes();
+    let newkey = 24.to_string().into_bytes();
+    let missing = 0.to_string().into_bytes();
+    let value = 42.to_string().into_bytes();
+    kf.set(&key, &value).unwrap();
+
+    // Stage changes followed by deleting a key that doesn't exist
+    let mut txn = kf.begin();
+    txn.delete(&key).set(&newkey, &value).delete(&missing);
+    let result = kf.commit(txn);
+
+    // Test no staged change was applied
+    assert_eq!(result, Err(KeyFileError::Key(missing)));
+    assert_eq!(kf.get(&key).unwrap(), value);
+    assert!(!kf.exists(&newkey));
+}
+
+
+#[test]
+fn transaction_error_restores_history()
+{
+    // Create keyfile store keeping 2 previous revisions
+    let mut kf = MemoryKeyFile::new("temp", None).history(2);
+
+    // Set value
+    let key = 42.to_string().into_bytes();
+    let missing = 0.to_string().into_bytes();
+    let value = 42.to_string().into_bytes();
+    kf.set(&key, &value).unwrap();
+
+    // Change the value twice and then delete a key that doesn't exist
+    let mut txn = kf.begin();
+    txn.set(&key, &1.to_string().into_bytes())
+        .set(&key, &2.to_string().into_bytes())
+        .delete(&missing);
+    let result = kf.commit(txn);
+
+    // Test the value, its metadata and its history are unchanged
+    assert_eq!(result, Err(KeyFileError::Key(missing)));
+    assert_eq!(kf.get(&key).unwrap(), value);
+    assert_eq!(kf.info(&key).unwrap().revision, 1);
+    assert!(kf.revisions(&key).unwrap().is_empty());
+}
+
+
+
+#[test]
+fn iter_prefix()
+{
+    // Create keyfile store
+    let mut kf = MemoryKeyFile::new("temp", None);
+
+    // Set values
+    for k in &["a", "ab1", "ab2", "ab3", "b"] {
+        let key = k.to_string().into_bytes();
+        kf.set(&key, &key).unwrap();
+    }
+
+    // List keys
+    let all = kf.iter_prefix(b"ab", None, 10).unwrap();
+    let page = kf.iter_prefix(b"ab", None, 2).unwrap();
+    let rest = kf.iter_prefix(b"ab", Some(b"ab2"), 10).unwrap();
+    let none = kf.iter_prefix(b"c", None, 10).unwrap();
+
+    // Test
+    let expected: Vec<Vec<u8>> = vec![
+        b"ab1".to_vec(),
+        b"ab2".to_vec(),
+        b"ab3".to_vec(),
+    ];
+    assert_eq!(all, expected);
+    assert_eq!(page, &expected[..2]);
+    assert_eq!(rest, &expected[2..]);
+    assert!(none.is_empty());
+}
+
+
+#[test]
+fn keyfile_info()
+{
+    // Create keyfile store
+    let mut kf = MemoryKeyFile::new("temp", None);
+
+    // Create then change value
+    let key = 42.to_string().into_bytes();
+    kf.set(&key, &4.to_string().into_bytes()).unwrap();
+    let created = kf.info(&key).unwrap();
+    kf.set(&key, &42.to_string().into_bytes()).unwrap();
+    let changed = kf.info(&key).unwrap();
+
+    // Test
+    assert_eq!(created.revision, 1);
+    assert_eq!(created.length, 1);
+    assert_eq!(created.created, created.modified);
+    assert_eq!(changed.revision, 2);
+    assert_eq!(changed.length, 2);
+    assert_eq!(changed.created, created.created);
+    assert!(changed.modified >= created.modified);
+
+    // Metadata is removed along with the value
+    kf.delete(&key).unwrap();
+    assert_eq!(kf.info(&key), Err(KeyFileError::Key(key.clone())));
+}
+
+
+#[test]
+fn transaction_check()
+{
+    // Create keyfile store
+    let mut kf = MemoryKeyFile::new("temp", None);
+
+    // Set value
+    let key = 42.to_string().into_bytes();
+    let value = 42.to_string().into_bytes();
+    let first = 1.to_string().into_bytes();
+    let second = 2.to_string().into_bytes();
+    kf.set(&key, &value).unwrap();
+
+    // Change value if it is still at revision 1
+    let mut txn = kf.begin();
+    txn.check(&key, KeyFileCheck::Revision(1)).set(&key, &first);
+    kf.commit(txn).unwrap();
+
+    // Change value again, expecting revision 1
+    let mut txn = kf.begin();
+    txn.check(&key, KeyFileCheck::Revision(1)).set(&key, &second);
+    let result = kf.commit(txn);
+
+    // Test the stale change was rejected
+    assert_eq!(result, Err(KeyFileError::Mismatch(key.clone())));
+    assert_eq!(kf.get(&key).unwrap(), first);
+
+    // Change value if its contents are unchanged
+    let mut txn = kf.begin();
+    let hash = keyfile_hash(&first);
+    txn.check(&key, KeyFileCheck::Hash(hash)).set(&key, &second);
+    kf.commit(txn).unwrap();
+
+    // Test
+    assert_eq!(kf.get(&key).unwrap(), second);
+    assert_eq!(kf.info(&key).unwrap().revision, 3);
+}
+
+
+#[test]
+fn history()
+{
+    // Create keyfile store keeping 2 previous revisions
+    let mut kf = MemoryKeyFile::new("temp", None).history(2);
+
+    // Set 4 revisions of a value
+    let key = 42.to_string().into_bytes();
+    for rev in 1..5 {
+        kf.set(&key, &rev.to_string().into_bytes()).unwrap();
+    }
+
+    // Test only the 2 revisions before the current one are kept
+    let revisions: Vec<u64> =
+        kf.revisions(&key).unwrap().iter().map(|i| i.revision).collect();
+    assert_eq!(revisions, vec![2, 3]);
+    assert_eq!(kf.get_revision(&key, 2).unwrap(), b"2".to_vec());
+    assert_eq!(
+        kf.get_revision(&key, 1),
+        Err(KeyFileError::Revision(key.clone(), 1))
+    );
+
+    // Delete value
+    kf.delete(&key).unwrap();
+
+    // Test the deleted value is kept and revisions carry on once the value
+    // is set again
+    let revisions: Vec<u64> =
+        kf.revisions(&key).unwrap().iter().map(|i| i.revision).collect();
+    assert_eq!(revisions, vec![3, 4]);
+    kf.set(&key, &4.to_string().into_bytes()).unwrap();
+    assert_eq!(kf.info(&key).unwrap().revision, 5);
+}
+
+
+#[test]
+fn expiry()
+{
+    // Create keyfile store
+    let mut kf = MemoryKeyFile::new("temp", None);
+
+    // Set values, one of which expired a second ago
+    let expired = 1.to_string().into_bytes();
+    let live = 2.to_string().into_bytes();
+    let later = timestamp() + 3600;
+    let mut txn = kf.begin();
+    txn.set(&expired, &expired)
+        .expire(&expired, Some(timestamp() - 1))
+        .set(&live, &live)
+        .expire(&live, Some(later));
+    kf.commit(txn).unwrap();
+
+    // Test the expired keyfile can't be read
+    let notfound = KeyFileError::Key(expired.clone());
+    assert!(!kf.exists(&expired));
+    assert_eq!(kf.get(&expired), Err(notfound.clone()));
+    assert_eq!(kf.info(&expired), Err(notfound));
+    assert_eq!(kf.iter_prefix(&[], None, 10).unwrap(), vec![live.clone()]);
+    assert_eq!(kf.info(&live).unwrap().expires, Some(later));
+
+    // Test only the expired keyfile is purged
+    assert_eq!(kf.purge_expired().unwrap(), vec![expired.clone()]);
+    assert!(kf.purge_expired().unwrap().is_empty());
+    assert_eq!(kf.get(&live).unwrap(), live);
+
+    // Test changing a keyfile clears its expiry
+    kf.set(&live, &expired).unwrap();
+    assert_eq!(kf.info(&live).unwrap().expires, None);
+}
+
+#[test]
+fn namespaces()
+{
+    // Create keyfile store
+    let mut kf = MemoryKeyFile::new("temp", None);
+    let key = 42.to_string().into_bytes();
+    kf.set(&key, &key).unwrap();
+    let mut namespaces = MemoryNamespaces::new();
+
+    // Create namespaces
+    namespaces.create_namespace("team-b").unwrap();
+    namespaces.create_namespace("team-a").unwrap();
+    let result = namespaces.create_namespace("team-a");
+    assert_eq!(result, Err(KeyFileError::NamespaceExists("team-a".into())));
+    let names = namespaces.list_namespaces().unwrap();
+    assert_eq!(names, vec!["team-a", "team-b"]);
+
+    // Test keys in a namespace don't collide with keys elsewhere
+    let store = namespaces.namespace("team-a").unwrap();
+    assert!(!store.read().unwrap().exists(&key));
+    store.write().unwrap().set(&key, &b"team-a".to_vec()).unwrap();
+    assert_eq!(kf.get(&key).unwrap(), key);
+    let other = namespaces.namespace("team-b").unwrap();
+    assert!(!other.read().unwrap().exists(&key));
+
+    // Test a dropped namespace can't be used, and is empty once it is
+    // created again
+    namespaces.drop_namespace("team-a").unwrap();
+    let result = namespaces.namespace("team-a").map(|_| ());
+    assert_eq!(result, Err(KeyFileError::Namespace("team-a".into())));
+    assert_eq!(namespaces.list_namespaces().unwrap(), vec!["team-b"]);
+    namespaces.create_namespace("team-a").unwrap();
+    let store = namespaces.namespace("team-a").unwrap();
+    assert!(!store.read().unwrap().exists(&key));
+}
+
+#[test]
+fn quota()
+{
+    // Create keyfile store
+    let mut kf = MemoryKeyFile::new("temp", None);
+
+    // Set values, one of which has expired
+    let small = 1.to_string().into_bytes();
+    let large = vec![0; 10];
+    let expired = 3.to_string().into_bytes();
+    kf.set(&small, &small).unwrap();
+    kf.set(&large, &large).unwrap();
+    let mut txn = kf.begin();
+    txn.set(&expired, &expired).expire(&expired, Some(timestamp() - 1));
+    kf.commit(txn).unwrap();
+
+    // Test usage counts the expired keyfile until it is purged
+    assert_eq!(kf.usage().unwrap(), (3, 12));
+    kf.purge_expired().unwrap();
+    assert_eq!(kf.usage().unwrap(), (2, 11));
+
+    // Test key and keyfile size limits
+    let quota = Quota::new().max_key_length(4).max_keyfile_size(10);
+    let result = quota.check(&kf, &large, &small, None);
+    assert_eq!(result, Err(KeyFileError::Quota(QuotaLimit::KeyLength, 4)));
+    let result = quota.check(&kf, &small, &[0; 11], None);
+    assert_eq!(result, Err(KeyFileError::Quota(QuotaLimit::KeyFileSize, 10)));
+
+    // Test entry limit only stops new keys
+    let quota = Quota::new().max_entries(2);
+    let result = quota.check(&kf, &expired, &expired, None);
+    assert_eq!(result, Err(KeyFileError::Quota(QuotaLimit::Entries, 2)));
+    assert!(quota.check(&kf, &small, &expired, Some(&small)).is_ok());
+
+    // Test byte limit counts the replaced keyfile as removed
+    let quota = Quota::new().max_bytes(12);
+    let result = quota.check(&kf, &expired, &small, None);
+    assert!(result.is_ok());
+    let result = quota.check(&kf, &expired, &large, Some(&small));
+    assert_eq!(result, Err(KeyFileError::Quota(QuotaLimit::Bytes, 12)));
+    assert!(quota.check(&kf, &expired, &large, Some(&large)).is_ok());
+}
+
+// ===========================================================================
+//
+// ===========================================================================
@@ -0,0 +1,248 @@
+// test_replication.rs
+// Copyright (C) 2017 authors and contributors (see AUTHORS file)
+//
+// This file is released under the MIT License.
+
+// ===========================================================================
+// Externs
+// ===========================================================================
+
+
+// Stdlib externs
+
+// Third-party externs
+extern crate bytes;
+extern crate futures;
+extern crate rmpv;
+extern crate tempdir;
+extern crate tokio_io;
+
+// Local externs
+extern crate safesec;
+
+
+// ===========================================================================
+// Imports
+// ===========================================================================
+
+
+// Stdlib imports
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Third-party imports
+
+use bytes::BytesMut;
+use futures::{Future, Sink};
+use futures::sync::mpsc;
+use rmpv::Value;
+use tempdir::TempDir;
+use tokio_io::codec::{Decoder, Encoder};
+
+// Local imports
+
+use safesec::{Config, Storage, serve};
+use safesec::network::codec::MsgPackCodec;
+use safesec::network::rpc::{Message, RpcResponse};
+use safesec::network::server::ServerMessage;
+use safesec::protocol::message::{AuthError, AuthMessage, SessionType};
+use safesec::service::state::SessionInfo;
+use safesec::service::state::auth::{AuthRequest, AuthResponse};
+use safesec::storage::Quota;
+use safesec::storage::lmdb::Init;
+
+
+// ===========================================================================
+// Helpers
+// ===========================================================================
+
+
+// Start a server on its own thread, returning its control channel
+fn start_server(
+    tmpdir: &TempDir, port: u16, primary: Option<SocketAddr>
+) -> (mpsc::Sender<ServerMessage>, JoinHandle<()>)
+{
+    let dbdir = tmpdir.path().join("sec.db");
+    fs::create_dir(&dbdir).unwrap();
+    let config = Config {
+        name: "safesec".to_string(),
+        dbdir: dbdir,
+        bindaddr: format!("127.0.0.1:{}", port).parse().unwrap(),
+        storage: Storage::Lmdb,
+        sweep_interval: 0,
+        quota: Quota::new(),
+        primary: primary,
+        workers: 2,
+        threads: 2,
+        cache: 0,
+        backup_dir: None,
+        dbinit: Init::new().change_log(1000),
+    };
+    let (tx, rx) = mpsc::channel::<ServerMessage>(1);
+    let child = thread::spawn(move || if let Err(e) = serve(&config, rx) {
+        panic!("Server failed with {}", e);
+    });
+    thread::sleep(Duration::from_millis(500));
+    (tx, child)
+}
+
+
+// Auth session using a blocking socket
+struct Client {
+    socket: TcpStream,
+    buf: BytesMut,
+    msgid: u32,
+}
+
+
+impl Client {
+    fn connect(port: u16) -> Self
+    {
+        let socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        let mut client = Self {
+            socket: socket,
+            buf: BytesMut::new(),
+            msgid: 0,
+        };
+        client.send(SessionInfo::new(SessionType::Auth, vec![]).into());
+        client
+    }
+
+    fn send(&mut self, msg: Message)
+    {
+        let mut buf = BytesMut::new();
+        MsgPackCodec.encode(msg.into(), &mut buf).unwrap();
+        self.socket.write_all(&buf).unwrap();
+    }
+
+    fn request(&mut self, code: AuthMessage, args: Vec<Value>)
+        -> AuthResponse
+    {
+        self.msgid += 1;
+        self.send(AuthRequest::new(self.msgid, code, args).into());
+        loop {
+            if let Some(value) = MsgPackCodec.decode(&mut self.buf).unwrap() {
+                let msg = Message::from(value).unwrap();
+                let response = AuthResponse::from(msg).unwrap();
+                assert_eq!(response.message_id(), self.msgid);
+                return response;
+            }
+            let mut data = [0; 4096];
+            let n = self.socket.read(&mut data).unwrap();
+            assert!(n > 0, "Server closed the connection");
+            self.buf.extend_from_slice(&data[..n]);
+        }
+    }
+}
+
+
+fn status_field(status: &Value, name: &str) -> Value
+{
+    let fields = status.as_map().unwrap();
+    fields
+        .iter()
+        .find(|field| field.0.as_str() == Some(name))
+        .map(|field| field.1.clone())
+        .unwrap()
+}
+
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+
+#[test]
+fn follower_copies_primary()
+{
+    // Start a primary and a follower
+    let (primary_dir, follower_dir) = (
+        TempDir::new("safesec_primary").unwrap(),
+        TempDir::new("safesec_follower").unwrap(),
+    );
+    let primary_addr = "127.0.0.1:12350".parse().unwrap();
+    let (primary_tx, primary) = start_server(&primary_dir, 12350, None);
+    let (follower_tx, follower) =
+        start_server(&follower_dir, 12351, Some(primary_addr));
+
+    // Write a keyfile on the primary
+    let key = Value::from(b"42".to_vec());
+    let file = Value::from(b"answer".to_vec());
+    let mut client = Client::connect(12350);
+    let args = vec![key.clone(), file.clone()];
+    let response = client.request(AuthMessage::CreateKeyFile, args);
+    assert_eq!(response.error_code(), AuthError::Nil);
+
+    // Test the keyfile can be read from the follower once it has caught up
+    let mut reader = Client::connect(12351);
+    let mut found = None;
+    for _ in 0..50 {
+        let args = vec![key.clone()];
+        let response = reader.request(AuthMessage::GetKeyFile, args);
+        if response.error_code() == AuthError::Nil {
+            found = Some(response.result().clone());
+            break;
+        }
+        thread::sleep(Duration::from_millis(100));
+    }
+    assert_eq!(found, Some(file));
+
+    // Test the follower reports it has caught up with the primary
+    let response = reader.request(AuthMessage::ReplicationStatus, vec![]);
+    assert_eq!(response.error_code(), AuthError::Nil);
+    let status = response.result().clone();
+    assert_eq!(status_field(&status, "role"), Value::from("follower"));
+    assert_eq!(status_field(&status, "lag"), Value::from(0));
+    assert_eq!(status_field(&status, "sequence"), Value::from(1));
+    assert_eq!(status_field(&status, "error"), Value::Nil);
+
+    // Test the primary reports its latest change
+    let response = client.request(AuthMessage::ReplicationStatus, vec![]);
+    let status = response.result().clone();
+    assert_eq!(status_field(&status, "role"), Value::from("primary"));
+    assert_eq!(status_field(&status, "latest"), Value::from(1));
+
+    // Test the follower refuses writes
+    let args = vec![Value::from(b"43".to_vec()), Value::from(b"no".to_vec())];
+    let response = reader.request(AuthMessage::CreateKeyFile, args);
+    assert_eq!(response.error_code(), AuthError::ReadOnly);
+
+    // Shut down both servers, follower first
+    drop((client, reader));
+    follower_tx.send(ServerMessage::Shutdown).wait().unwrap();
+    follower.join().unwrap();
+    primary_tx.send(ServerMessage::Shutdown).wait().unwrap();
+    primary.join().unwrap();
+}
+
+
+#[test]
+fn follower_reports_errors()
+{
+    // Start a follower whose primary isn't running
+    let tmpdir = TempDir::new("safesec_follower").unwrap();
+    let primary_addr = "127.0.0.1:12354".parse().unwrap();
+    let (tx, child) = start_server(&tmpdir, 12353, Some(primary_addr));
+
+    // Test the follower reports why it can't copy changes
+    let mut client = Client::connect(12353);
+    let response = client.request(AuthMessage::ReplicationStatus, vec![]);
+    assert_eq!(response.error_code(), AuthError::Nil);
+    let error = status_field(response.result(), "error");
+    let errmsg = error.as_str().unwrap();
+    assert!(errmsg.starts_with("Replication from 127.0.0.1:12354 failed"));
+
+    // Shut down the follower
+    drop(client);
+    tx.send(ServerMessage::Shutdown).wait().unwrap();
+    child.join().unwrap();
+}
+
+
+// ===========================================================================
+//
+// ===========================================================================
@@ -36,6 +36,7 @@ extern crate safesec;
 use std::collections::HashMap;
 use std::io;
 use std::mem;
+use std::net::SocketAddr;
 use std::thread;
 use std::time::Duration;
 
@@ -57,11 +58,12 @@ use safesec::network::codec::MsgPackCodec;
 use safesec::network::rpc::{Message, MessageType, RpcMessage, RpcResponse};
 use safesec::network::server::ServerMessage;
 use safesec::protocol::message::{AuthError, AuthMessage, AuthNotice,
-                                 BootNotice, SessionType};
+                                 BootNotice, ReplicaNotice, SessionType};
 use safesec::serve;
 use safesec::service::state::SessionInfo;
 use safesec::service::state::auth::{AuthInfo, AuthRequest, AuthResponse};
 use safesec::service::state::boot::{BootInfo, BootResponse};
+use safesec::service::state::replica::{ReplicaInfo, ReplicaResponse};
 
 
 // ===========================================================================
@@ -109,6 +111,9 @@ impl ClientSession {
             SessionType::Auth => {
                 AuthInfo::new(AuthNotice::Done, vec![]).into()
             }
+            SessionType::Replica => {
+                ReplicaInfo::new(ReplicaNotice::Done, vec![]).into()
+            }
         };
         self.send_msg(msg)
     }
@@ -457,6 +462,16 @@ impl SessionRead {
                     Err(_) => None,
                 }
             }
+            SessionType::Replica => {
+                match ReplicaResponse::from(msg) {
+                    Ok(response) => {
+                        let id = response.message_id();
+                        let msg: Message = response.into();
+                        Some((id, msg))
+                    }
+                    Err(_) => None,
+                }
+            }
         }
     }
 
@@ -629,7 +644,11 @@ impl HelloWorld {
                 );
                 Box::new(f)
             }
-            AuthError::DatabaseError => {
+            AuthError::KeyFileExists => unreachable!(),
+            AuthError::KeyFileNotFound => unreachable!(),
+
+            // Any other error code is a database error
+            _ => {
                 let err = io::Error::new(
                     io::ErrorKind::Other,
                     "Database error creating keyfile 42",
@@ -637,9 +656,6 @@ impl HelloWorld {
                 let f = future::err::<(Self, ClientSession), io::Error>(err);
                 Box::new(f)
             }
-            AuthError::KeyFileExists => unreachable!(),
-            AuthError::KeyFileNotFound => unreachable!(),
-            // _ => unreachable!(),
         }
     }
 }
@@ -679,7 +695,7 @@ fn hello_world(socket: TcpStream) -> FutureSession
 // ===========================================================================
 
 
-fn client_adddata(control: mpsc::Sender<ServerMessage>)
+fn client_adddata(address: SocketAddr, control: mpsc::Sender<ServerMessage>)
     -> io::Result<String>
 {
     // Create event loop
@@ -688,7 +704,6 @@ fn client_adddata(control: mpsc::Sender<ServerMessage>)
     // let mut codec = MsgPackCodec;
 
     // Connect to remote server
-    let address = "127.0.0.1:12345".parse().unwrap();
     let socket = TcpStream::connect(&address, &handle);
 
     // Set up request and response
@@ -725,9 +740,11 @@ use std::fs;
 use chrono::prelude::*;
 use tempdir::TempDir;
 
-use safesec::Config;
-use safesec::storage::KeyFileBuilder;
-use safesec::storage::lmdb::KeyFile;
+use safesec::{Config, Storage};
+use safesec::storage::{KeyFileBuilder, KeyFileNamespaces, Quota};
+use safesec::serve_with;
+use safesec::storage::lmdb::{Init, KeyFile};
+use safesec::storage::memory::MemoryKeyFile;
 
 fn _mktempdir() -> TempDir
 {
@@ -768,6 +785,15 @@ fn test_rpcserver()
         name: "safesec".to_string(),
         dbdir: dbdir,
         bindaddr: address,
+        storage: Storage::Lmdb,
+        sweep_interval: 0,
+        quota: Quota::new(),
+        primary: None,
+        workers: 2,
+        threads: 2,
+        cache: 0,
+        backup_dir: None,
+        dbinit: Init::new(),
     };
 
     // Create command channel
@@ -782,7 +808,7 @@ fn test_rpcserver()
     thread::sleep(Duration::from_millis(500));
 
     // Start client
-    let res = client_adddata(tx);
+    let res = client_adddata(address, tx);
 
     let val = match res {
         Ok(t) => {
@@ -800,6 +826,104 @@ fn test_rpcserver()
 }
 
 
+
+#[test]
+fn test_rpcserver_with_store()
+{
+    // Create bind address
+    let tmpdir = _mktempdir();
+    let address = "127.0.0.1:12346".parse().unwrap();
+
+    // Create a config whose storage settings are ignored
+    let config = Config {
+        name: "safesec".to_string(),
+        dbdir: tmpdir.path().to_owned(),
+        bindaddr: address,
+        storage: Storage::Lmdb,
+        sweep_interval: 0,
+        quota: Quota::new(),
+        primary: None,
+        workers: 2,
+        threads: 2,
+        cache: 0,
+        backup_dir: None,
+        dbinit: Init::new(),
+    };
+
+    // Create command channel
+    let (tx, rx) = mpsc::channel::<ServerMessage>(1);
+
+    // Start server with a store that doesn't touch dbdir
+    let child = thread::spawn(move || {
+        let store = MemoryKeyFile::new("temp", None);
+        if let Err(e) = serve_with(&config, store, rx) {
+            panic!("Server failed with {}", e);
+        }
+    });
+
+    thread::sleep(Duration::from_millis(500));
+
+    // Start client
+    let res = client_adddata(address, tx);
+
+    child.join().unwrap();
+    assert!(res.is_ok());
+    assert!(!tmpdir.path().join("data.mdb").exists());
+}
+
+
+#[test]
+fn test_rpcserver_sweeps_namespaces()
+{
+    // Create a namespace holding a keyfile that expired a second ago
+    let tmpdir = _mktempdir();
+    let dbdir = tmpdir.path().to_owned();
+    let address = "127.0.0.1:12347".parse().unwrap();
+    let key = b"42".to_vec();
+    let expires = UTC::now().timestamp() as u64 - 1;
+    {
+        let kf = KeyFile::new("temp", Some(dbdir.as_path()));
+        let mut namespaces = kf.open_namespaces().unwrap();
+        namespaces.create_namespace("team").unwrap();
+        let store = namespaces.namespace("team").unwrap();
+        let mut store = store.write().unwrap();
+        let mut txn = store.begin();
+        txn.set(&key, &key).expire(&key, Some(expires));
+        store.commit(txn).unwrap();
+        assert_eq!(store.usage().unwrap(), (1, 2));
+    }
+
+    // Create a config that sweeps every second
+    let config = Config {
+        name: "safesec".to_string(),
+        dbdir: dbdir.clone(),
+        bindaddr: address,
+        storage: Storage::Lmdb,
+        sweep_interval: 1,
+        quota: Quota::new(),
+        primary: None,
+        workers: 2,
+        threads: 2,
+        cache: 0,
+        backup_dir: None,
+        dbinit: Init::new(),
+    };
+
+    // Run the server long enough for a sweep
+    let (tx, rx) = mpsc::channel::<ServerMessage>(1);
+    let child = thread::spawn(move || if let Err(e) = serve(&config, rx) {
+        panic!("Server failed with {}", e);
+    });
+    thread::sleep(Duration::from_millis(2500));
+    tx.send(ServerMessage::Shutdown).wait().unwrap();
+    child.join().unwrap();
+
+    // Test the expired keyfile was purged from the namespace
+    let kf = KeyFile::new("temp", Some(dbdir.as_path()));
+    let store = kf.open_namespaces().unwrap().namespace("team").unwrap();
+    assert_eq!(store.read().unwrap().usage().unwrap(), (0, 0));
+}
+
 // ===========================================================================
 //
 // ===========================================================================
@@ -0,0 +1,316 @@
+// test_watch.rs
+// Copyright (C) 2017 authors and contributors (see AUTHORS file)
+//
+// This file is released under the MIT License.
+
+// ===========================================================================
+// Externs
+// ===========================================================================
+
+
+// Stdlib externs
+
+// Third-party externs
+extern crate bytes;
+extern crate futures;
+extern crate rmpv;
+extern crate tempdir;
+extern crate tokio_io;
+
+// Local externs
+extern crate safesec;
+
+
+// ===========================================================================
+// Imports
+// ===========================================================================
+
+
+// Stdlib imports
+
+use std::fs;
+use std::io::{Read, Write};
+use std::net::{SocketAddr, TcpStream};
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+// Third-party imports
+
+use bytes::BytesMut;
+use futures::{Future, Sink};
+use futures::sync::mpsc;
+use rmpv::Value;
+use tempdir::TempDir;
+use tokio_io::codec::{Decoder, Encoder};
+
+// Local imports
+
+use safesec::{Config, Storage, serve};
+use safesec::network::codec::MsgPackCodec;
+use safesec::network::rpc::{Message, RpcNotice, RpcResponse};
+use safesec::network::server::ServerMessage;
+use safesec::protocol::message::{AuthError, AuthMessage, AuthNotice,
+                                 SessionType};
+use safesec::service::state::SessionInfo;
+use safesec::service::state::auth::{AuthInfo, AuthRequest,
+                                    AuthResponse};
+use safesec::storage::Quota;
+use safesec::storage::lmdb::Init;
+
+
+// ===========================================================================
+// Helpers
+// ===========================================================================
+
+
+// Start a server on its own thread, returning its control channel
+fn start_server(
+    tmpdir: &TempDir, port: u16, sweep_interval: u64,
+    primary: Option<SocketAddr>
+) -> (mpsc::Sender<ServerMessage>, JoinHandle<()>)
+{
+    let dbdir = tmpdir.path().join("sec.db");
+    fs::create_dir(&dbdir).unwrap();
+    let config = Config {
+        name: "safesec".to_string(),
+        dbdir: dbdir,
+        bindaddr: format!("127.0.0.1:{}", port).parse().unwrap(),
+        storage: Storage::Lmdb,
+        sweep_interval: sweep_interval,
+        quota: Quota::new(),
+        primary: primary,
+        workers: 2,
+        threads: 2,
+        cache: 0,
+        backup_dir: None,
+        dbinit: Init::new().change_log(1000),
+    };
+    let (tx, rx) = mpsc::channel::<ServerMessage>(1);
+    let child = thread::spawn(move || if let Err(e) = serve(&config, rx) {
+        panic!("Server failed with {}", e);
+    });
+    thread::sleep(Duration::from_millis(500));
+    (tx, child)
+}
+
+
+// Auth session using a blocking socket
+struct Client {
+    socket: TcpStream,
+    buf: BytesMut,
+    msgid: u32,
+}
+
+
+impl Client {
+    fn connect(port: u16) -> Self
+    {
+        let socket = TcpStream::connect(("127.0.0.1", port)).unwrap();
+        socket.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        let mut client = Self {
+            socket: socket,
+            buf: BytesMut::new(),
+            msgid: 0,
+        };
+        client.send(SessionInfo::new(SessionType::Auth, vec![]).into());
+        client
+    }
+
+    fn send(&mut self, msg: Message)
+    {
+        let mut buf = BytesMut::new();
+        MsgPackCodec.encode(msg.into(), &mut buf).unwrap();
+        self.socket.write_all(&buf).unwrap();
+    }
+
+    // Wait for the next message from the server
+    fn receive(&mut self) -> Message
+    {
+        loop {
+            if let Some(value) = MsgPackCodec.decode(&mut self.buf).unwrap() {
+                return Message::from(value).unwrap();
+            }
+            let mut data = [0; 4096];
+            let n = self.socket.read(&mut data).unwrap();
+            assert!(n > 0, "Server closed the connection");
+            self.buf.extend_from_slice(&data[..n]);
+        }
+    }
+
+    fn request(&mut self, code: AuthMessage, args: Vec<Value>)
+        -> AuthResponse
+    {
+        self.msgid += 1;
+        self.send(AuthRequest::new(self.msgid, code, args).into());
+        let response = AuthResponse::from(self.receive()).unwrap();
+        assert_eq!(response.message_id(), self.msgid);
+        response
+    }
+}
+
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+
+#[test]
+fn watch_pushes_notifications()
+{
+    // Start a server and a session watching keys starting with "team/"
+    let tmpdir = TempDir::new("safesec_watch").unwrap();
+    let (tx, child) = start_server(&tmpdir, 12352, 0, None);
+    let mut watcher = Client::connect(12352);
+    let args = vec![Value::from(b"team/".to_vec()), Value::from(true)];
+    let response = watcher.request(AuthMessage::Watch, args);
+    assert_eq!(response.error_code(), AuthError::Nil);
+
+    // Create, change and delete keyfiles from a second session
+    let mut client = Client::connect(12352);
+    let (key, other) = (b"team/42".to_vec(), b"other".to_vec());
+    let requests = vec![
+        (
+            AuthMessage::CreateKeyFile,
+            vec![Value::from(other.clone()), Value::from(other.clone())],
+        ),
+        (
+            AuthMessage::CreateKeyFile,
+            vec![Value::from(key.clone()), Value::from(b"1".to_vec())],
+        ),
+        (
+            AuthMessage::ChangeKeyFile,
+            vec![Value::from(key.clone()), Value::from(b"2".to_vec())],
+        ),
+        (AuthMessage::DeleteKeyFile, vec![Value::from(key.clone())]),
+    ];
+    for (code, args) in requests {
+        let response = client.request(code, args);
+        assert_eq!(response.error_code(), AuthError::Nil);
+    }
+
+    // Test the watching session is told about each change to the watched
+    // key, in order, on its own connection
+    let expected = vec![
+        AuthNotice::KeyFileCreated,
+        AuthNotice::KeyFileChanged,
+        AuthNotice::KeyFileDeleted,
+    ];
+    for notice in expected {
+        let info = AuthInfo::from(watcher.receive()).unwrap();
+        assert_eq!(info.message_code(), notice);
+        assert_eq!(info.message_args(), &vec![Value::from(key.clone())]);
+    }
+
+    // Test the watching session can still make requests
+    let args = vec![Value::from(other.clone())];
+    let response = watcher.request(AuthMessage::GetKeyFile, args);
+    assert_eq!(response.result(), &Value::from(other));
+
+    // Shut down the server
+    drop((watcher, client));
+    tx.send(ServerMessage::Shutdown).wait().unwrap();
+    child.join().unwrap();
+}
+
+
+#[test]
+fn watch_reports_swept_keyfiles()
+{
+    // Start a server sweeping every second and a session watching "42"
+    let tmpdir = TempDir::new("safesec_watch").unwrap();
+    let (tx, child) = start_server(&tmpdir, 12355, 1, None);
+    let mut watcher = Client::connect(12355);
+    let key = b"42".to_vec();
+    let args = vec![Value::from(key.clone()), Value::from(false)];
+    let response = watcher.request(AuthMessage::Watch, args);
+    assert_eq!(response.error_code(), AuthError::Nil);
+
+    // Create a keyfile that expires at once
+    let mut client = Client::connect(12355);
+    let args = vec![
+        Value::from(key.clone()),
+        Value::from(key.clone()),
+        Value::from(0),
+    ];
+    let response = client.request(AuthMessage::CreateKeyFile, args);
+    assert_eq!(response.error_code(), AuthError::Nil);
+
+    // Test the watching session is told the keyfile was created and then
+    // deleted by the sweeper
+    let expected =
+        vec![AuthNotice::KeyFileCreated, AuthNotice::KeyFileDeleted];
+    for notice in expected {
+        let info = AuthInfo::from(watcher.receive()).unwrap();
+        assert_eq!(info.message_code(), notice);
+        assert_eq!(info.message_args(), &vec![Value::from(key.clone())]);
+    }
+
+    // Shut down the server
+    drop((watcher, client));
+    tx.send(ServerMessage::Shutdown).wait().unwrap();
+    child.join().unwrap();
+}
+
+
+#[test]
+fn watch_reports_replicated_changes()
+{
+    // Start a primary, a follower and a session on the follower watching
+    // "42"
+    let (primary_dir, follower_dir) = (
+        TempDir::new("safesec_watch").unwrap(),
+        TempDir::new("safesec_watch").unwrap(),
+    );
+    let primary_addr = "127.0.0.1:12356".parse().unwrap();
+    let (primary_tx, primary) = start_server(&primary_dir, 12356, 0, None);
+    let (follower_tx, follower) =
+        start_server(&follower_dir, 12357, 0, Some(primary_addr));
+    let mut watcher = Client::connect(12357);
+    let key = b"42".to_vec();
+    let args = vec![Value::from(key.clone()), Value::from(false)];
+    let response = watcher.request(AuthMessage::Watch, args);
+    assert_eq!(response.error_code(), AuthError::Nil);
+
+    // Create, change and delete the keyfile on the primary
+    let mut client = Client::connect(12356);
+    let requests = vec![
+        (
+            AuthMessage::CreateKeyFile,
+            vec![Value::from(key.clone()), Value::from(b"1".to_vec())],
+        ),
+        (
+            AuthMessage::ChangeKeyFile,
+            vec![Value::from(key.clone()), Value::from(b"2".to_vec())],
+        ),
+        (AuthMessage::DeleteKeyFile, vec![Value::from(key.clone())]),
+    ];
+    for (code, args) in requests {
+        let response = client.request(code, args);
+        assert_eq!(response.error_code(), AuthError::Nil);
+    }
+
+    // Test the watching session is told about each change once the
+    // follower has copied it
+    let expected = vec![
+        AuthNotice::KeyFileCreated,
+        AuthNotice::KeyFileChanged,
+        AuthNotice::KeyFileDeleted,
+    ];
+    for notice in expected {
+        let info = AuthInfo::from(watcher.receive()).unwrap();
+        assert_eq!(info.message_code(), notice);
+        assert_eq!(info.message_args(), &vec![Value::from(key.clone())]);
+    }
+
+    // Shut down both servers
+    drop((watcher, client));
+    follower_tx.send(ServerMessage::Shutdown).wait().unwrap();
+    follower.join().unwrap();
+    primary_tx.send(ServerMessage::Shutdown).wait().unwrap();
+    primary.join().unwrap();
+}
+
+
+// ===========================================================================
+//
+// ===========================================================================
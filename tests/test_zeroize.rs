@@ -0,0 +1,150 @@
+// test_zeroize.rs
+// Copyright (C) 2017 authors and contributors (see AUTHORS file)
+//
+// This file is released under the MIT License.
+
+// ===========================================================================
+// Externs
+// ===========================================================================
+
+
+// Stdlib externs
+
+// Third-party externs
+extern crate bytes;
+extern crate rmpv;
+extern crate tempdir;
+extern crate tokio_io;
+
+// Local externs
+extern crate safesec;
+
+
+// ===========================================================================
+// Imports
+// ===========================================================================
+
+
+// Stdlib imports
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::slice;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+
+// Third-party imports
+
+use bytes::BytesMut;
+use rmpv::Value;
+use tempdir::TempDir;
+use tokio_io::codec::Encoder;
+
+// Local imports
+
+use safesec::network::codec::MsgPackCodec;
+use safesec::network::rpc::{Message, RpcResponse};
+use safesec::protocol::message::{AuthError, AuthMessage};
+use safesec::service::state::{KeyFileDB, SessionState, State};
+use safesec::service::state::auth::{AuthRequest, ProcessAuthMessage};
+use safesec::storage::KeyFileStore;
+use safesec::storage::cache::CachedStore;
+use safesec::storage::lmdb::{Init, KeyFile};
+use safesec::util::zeroize;
+
+
+// ===========================================================================
+// Allocator
+// ===========================================================================
+
+
+// Keyfile looked for in every block of memory freed while armed
+const SECRET: &[u8] = b"keyfile that must not outlive its response";
+
+
+static ARMED: AtomicBool = AtomicBool::new(false);
+static LEAKED: AtomicBool = AtomicBool::new(false);
+
+
+// Notes whether memory holding the secret is ever freed without being
+// overwritten first
+struct Watchful;
+
+
+unsafe impl GlobalAlloc for Watchful {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8
+    {
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout)
+    {
+        if ARMED.load(Ordering::SeqCst) {
+            let block = slice::from_raw_parts(ptr, layout.size());
+            if block.windows(SECRET.len()).any(|w| w == SECRET) {
+                LEAKED.store(true, Ordering::SeqCst);
+            }
+        }
+        System.dealloc(ptr, layout)
+    }
+}
+
+
+#[global_allocator]
+static ALLOCATOR: Watchful = Watchful;
+
+
+// ===========================================================================
+// Tests
+// ===========================================================================
+
+
+// Read the keyfile of key with a GetKeyFile request and encode the response
+// as it would be sent, returning the encoded bytes
+fn encoded_read(db: &KeyFileDB, key: &[u8]) -> BytesMut
+{
+    let args = vec![Value::from(key.to_vec())];
+    let req = AuthRequest::new(1, AuthMessage::GetKeyFile, args);
+    let state = Box::new(ProcessAuthMessage::new(db.clone()));
+    let response = match state.change(req.into()) {
+        Ok(State::ProcessAuthMessage(_, Some(r))) => r,
+        _ => panic!("Expected a response"),
+    };
+    assert_eq!(response.error_code(), AuthError::Nil);
+    let msg: Message = response.into();
+    let mut buf = BytesMut::new();
+    MsgPackCodec.encode(msg.into(), &mut buf).unwrap();
+    buf
+}
+
+
+#[test]
+fn read_keyfiles_are_wiped()
+{
+    // Create a cached keyfile store holding the secret
+    let tmpdir = TempDir::new("safesec_zeroize").unwrap();
+    let init = Init::new().path(tmpdir.path());
+    let mut store = KeyFile::with_init("temp", init);
+    let key = b"42".to_vec();
+    store.set(&key, &SECRET.to_vec()).unwrap();
+    let store: KeyFileDB =
+        Arc::new(RwLock::new(CachedStore::new(store, 10)));
+
+    // Read the keyfile from the store and then from the cache, wiping what
+    // would have been written to the socket
+    ARMED.store(true, Ordering::SeqCst);
+    for _ in 0..2 {
+        let mut buf = encoded_read(&store, &key);
+        assert!(buf.windows(SECRET.len()).any(|w| w == SECRET));
+        zeroize(&mut buf[..]);
+    }
+    drop(store);
+    ARMED.store(false, Ordering::SeqCst);
+
+    // Test no copy of the keyfile was freed without being wiped
+    assert!(!LEAKED.load(Ordering::SeqCst));
+}
+
+
+// ===========================================================================
+//
+// ===========================================================================